@@ -0,0 +1,273 @@
+//! Splitting the supported input formats into packets.
+
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+
+use bt_hci::transport::Direction;
+use bt_hci::{btsnoop, ControllerToHostPacket, FromHciBytes, FromHciBytesError, HostToControllerPacket, PacketKind};
+
+/// Largest record accepted from a btsnoop file.
+const MAX_RECORD_LEN: usize = 1 << 16;
+
+/// The offset of the first byte of a packet and its direction.
+pub type Marker = (usize, Direction);
+
+/// Format of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Detect the format from the contents.
+    Auto,
+    /// Binary stream of packets, each starting with its H4 packet indicator.
+    H4,
+    /// H4 packets written as hex bytes, see [`parse_hex`].
+    Hex,
+    /// A btsnoop capture file.
+    Btsnoop,
+}
+
+impl Format {
+    /// Resolve [`Format::Auto`] by looking at `data`.
+    pub fn detect(self, data: &[u8]) -> Format {
+        match self {
+            Format::Auto if data.starts_with(b"btsnoop\0") => Format::Btsnoop,
+            Format::Auto if data.iter().all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace()) => Format::Hex,
+            Format::Auto => Format::H4,
+            format => format,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Format::Auto),
+            "h4" => Ok(Format::H4),
+            "hex" => Ok(Format::Hex),
+            "btsnoop" => Ok(Format::Btsnoop),
+            _ => Err(format!("unknown format `{}`", s)),
+        }
+    }
+}
+
+/// A single packet found in the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// The direction of the packet.
+    pub direction: Direction,
+    /// The time the packet was captured in microseconds since the Unix epoch, if known.
+    pub timestamp_micros: Option<u64>,
+    /// The packet, starting with its H4 packet indicator.
+    pub data: Vec<u8>,
+}
+
+/// Errors which stop decoding the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A hex dump contains something other than hex bytes.
+    InvalidHex { line: usize, token: String },
+    /// The packet starting at `offset` has an unknown indicator or is cut short.
+    InvalidPacket { offset: usize, error: FromHciBytesError },
+    /// A btsnoop file could not be read.
+    Btsnoop(btsnoop::Error<Infallible>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidHex { line, token } => write!(f, "invalid hex `{}` on line {}", token, line),
+            Error::InvalidPacket {
+                offset,
+                error: FromHciBytesError::InvalidSize,
+            } => write!(f, "truncated packet at offset {}", offset),
+            Error::InvalidPacket { offset, .. } => write!(f, "invalid packet indicator at offset {}", offset),
+            Error::Btsnoop(err) => write!(f, "invalid btsnoop file: {:?}", err),
+        }
+    }
+}
+
+/// Split `data` into packets.
+///
+/// Decoding stops at the first error, the packets before it are returned along with the error.
+pub fn parse(format: Format, data: &[u8]) -> (Vec<Record>, Option<Error>) {
+    match format.detect(data) {
+        Format::Btsnoop => parse_btsnoop(data),
+        Format::Hex => match parse_hex(&String::from_utf8_lossy(data)) {
+            Ok((bytes, markers)) => parse_h4(&bytes, &markers),
+            Err(err) => (Vec::new(), Some(err)),
+        },
+        _ => parse_h4(data, &[]),
+    }
+}
+
+/// Convert a hex dump to bytes.
+///
+/// Bytes may be separated by whitespace or punctuation and prefixed with `0x`, so output like `01 03 0c 00`,
+/// `0x01,0x03,0x0c,0x00`, `[01, 03, 0c, 00]` and `01030c00` is accepted. A packet may span several lines. Lines
+/// starting with `#` are ignored, and a line starting with `<` or `>` marks the next packet as sent to or received
+/// from the controller. Returns the bytes along with the offsets of the direction markers.
+pub fn parse_hex(text: &str) -> Result<(Vec<u8>, Vec<Marker>), Error> {
+    let mut bytes = Vec::new();
+    let mut markers = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let mut line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix('<') {
+            markers.push((bytes.len(), Direction::Sent));
+            line = rest;
+        } else if let Some(rest) = line.strip_prefix('>') {
+            markers.push((bytes.len(), Direction::Received));
+            line = rest;
+        }
+
+        for token in line
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|t| !t.is_empty())
+        {
+            let digits = token
+                .strip_prefix("0x")
+                .or_else(|| token.strip_prefix("0X"))
+                .unwrap_or(token);
+            let invalid = || Error::InvalidHex {
+                line: i + 1,
+                token: token.to_string(),
+            };
+            if digits.is_empty() || digits.len() % 2 != 0 {
+                return Err(invalid());
+            }
+            for pair in digits.as_bytes().chunks(2) {
+                let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+                bytes.push(u8::from_str_radix(pair, 16).map_err(|_| invalid())?);
+            }
+        }
+    }
+    Ok((bytes, markers))
+}
+
+/// Split a stream of H4 packets.
+///
+/// Each entry in `markers` sets the direction of the first packet starting at or after its offset. Without a marker,
+/// commands are taken to be sent to the controller and all other packets to be received from it.
+pub fn parse_h4(data: &[u8], markers: &[Marker]) -> (Vec<Record>, Option<Error>) {
+    let mut records = Vec::new();
+    let mut markers = markers.iter().peekable();
+    let mut offset = 0;
+    while offset < data.len() {
+        let mut direction = None;
+        while let Some((_, dir)) = markers.next_if(|(at, _)| *at <= offset) {
+            direction = Some(*dir);
+        }
+
+        let len = match packet_len(&data[offset..]) {
+            Ok(len) => len,
+            Err(error) => return (records, Some(Error::InvalidPacket { offset, error })),
+        };
+        let data = &data[offset..offset + len];
+        let direction = direction.unwrap_or(match data[0] {
+            1 => Direction::Sent,
+            _ => Direction::Received,
+        });
+        records.push(Record {
+            direction,
+            timestamp_micros: None,
+            data: data.to_vec(),
+        });
+        offset += len;
+    }
+    (records, None)
+}
+
+/// The length of the H4 packet at the start of `data`, including its indicator.
+fn packet_len(data: &[u8]) -> Result<usize, FromHciBytesError> {
+    let (kind, _) = PacketKind::from_hci_bytes(data)?;
+    let rest = match kind {
+        PacketKind::Cmd => HostToControllerPacket::from_hci_bytes(data)?.1,
+        _ => ControllerToHostPacket::from_hci_bytes(data)?.1,
+    };
+    Ok(data.len() - rest.len())
+}
+
+fn parse_btsnoop(data: &[u8]) -> (Vec<Record>, Option<Error>) {
+    let mut records = Vec::new();
+    let mut reader = match btsnoop::Reader::new(data) {
+        Ok(reader) => reader,
+        Err(err) => return (records, Some(Error::Btsnoop(err))),
+    };
+    let mut buf = vec![0; MAX_RECORD_LEN];
+    loop {
+        match reader.next(&mut buf) {
+            Ok(Some(record)) => {
+                let mut data = Vec::with_capacity(record.data.len() + 1);
+                data.push(record.kind as u8);
+                data.extend_from_slice(record.data);
+                records.push(Record {
+                    direction: record.direction,
+                    timestamp_micros: Some(record.timestamp_micros),
+                    data,
+                });
+            }
+            Ok(None) => return (records, None),
+            Err(err) => return (records, Some(Error::Btsnoop(err))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Format::Auto.detect(b"btsnoop\0\0\0\0\x01"), Format::Btsnoop);
+        assert_eq!(Format::Auto.detect(b"01 03 0c 00\n"), Format::Hex);
+        assert_eq!(Format::Auto.detect(&[0x01, 0x03, 0x0c, 0x00]), Format::H4);
+        assert_eq!(Format::H4.detect(b"01"), Format::H4);
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        let text = "# reset\n< 0x01,0x03\n0x0c 0x00\n> [04, 0e, 04, 01, 03, 0c, 00]\n04050400400013\n";
+        let (bytes, markers) = parse_hex(text).unwrap();
+        assert_eq!(bytes, [1, 3, 12, 0, 4, 14, 4, 1, 3, 12, 0, 4, 5, 4, 0, 0x40, 0, 0x13]);
+        assert_eq!(markers, [(0, Direction::Sent), (4, Direction::Received)]);
+
+        assert_eq!(
+            parse_hex("01 0g"),
+            Err(Error::InvalidHex {
+                line: 1,
+                token: "0g".to_string()
+            })
+        );
+        assert_eq!(
+            parse_hex("\n013"),
+            Err(Error::InvalidHex {
+                line: 2,
+                token: "013".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_h4() {
+        let data = [2, 0x40, 0, 1, 0, 0xaa, 1, 3, 12, 0, 2, 0x40, 0, 1, 0, 0xbb, 4, 14, 4];
+        let (records, err) = parse_h4(&data, &[(0, Direction::Sent)]);
+        let directions: Vec<_> = records.iter().map(|r| r.direction).collect();
+        assert_eq!(directions, [Direction::Sent, Direction::Sent, Direction::Received]);
+        assert_eq!(records[1].data, [1, 3, 12, 0]);
+        assert_eq!(
+            err,
+            Some(Error::InvalidPacket {
+                offset: 16,
+                error: FromHciBytesError::InvalidSize
+            })
+        );
+
+        let (records, err) = parse_h4(&[7, 0], &[]);
+        assert!(records.is_empty());
+        assert_eq!(err.unwrap().to_string(), "invalid packet indicator at offset 0");
+    }
+}
@@ -0,0 +1,148 @@
+//! Decode Bluetooth HCI traffic from H4 byte streams, hex dumps or btsnoop files.
+//!
+//! Packets are printed as btmon-style text, or as one JSON object per line with `--json`.
+
+use std::io::{self, BufWriter, Read, Write};
+use std::process::ExitCode;
+use std::{env, fs};
+
+use input::Format;
+
+mod input;
+mod output;
+
+const USAGE: &str = "\
+Usage: bt-hci-decode [OPTIONS] [FILE]
+
+Decode the HCI packets in FILE, or in stdin if FILE is missing or `-`.
+
+Options:
+  -f, --format <FORMAT>  Input format: auto, h4, hex or btsnoop [default: auto]
+  -j, --json             Print one JSON object per packet instead of text
+  -h, --help             Print this help
+
+Hex input may separate bytes with whitespace or punctuation. Lines starting with `#`
+are comments, and a line starting with `<` or `>` marks the next packet as sent to or
+received from the controller.
+";
+
+/// Command line arguments.
+#[derive(Debug, PartialEq, Eq)]
+struct Args {
+    format: Format,
+    json: bool,
+    path: Option<String>,
+}
+
+/// Parse the command line, returning `None` if help was requested.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut parsed = Args {
+        format: Format::Auto,
+        json: false,
+        path: None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-j" | "--json" => parsed.json = true,
+            "-f" | "--format" => {
+                let format = args.next().ok_or_else(|| format!("missing value for `{}`", arg))?;
+                parsed.format = format.parse()?;
+            }
+            "-" => parsed.path = None,
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ if parsed.path.is_some() => return Err(format!("unexpected argument `{}`", arg)),
+            _ => parsed.path = Some(arg),
+        }
+    }
+    Ok(Some(parsed))
+}
+
+fn read_input(path: Option<&str>) -> io::Result<Vec<u8>> {
+    match path {
+        Some(path) => fs::read(path),
+        None => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            Ok(data)
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprint!("error: {}\n\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let data = match read_input(args.path.as_deref()) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let (records, err) = input::parse(args.format, &data);
+    let start = records.iter().find_map(|r| r.timestamp_micros).unwrap_or_default();
+    let mut out = BufWriter::new(io::stdout().lock());
+    let res = records.iter().enumerate().try_for_each(|(i, record)| {
+        if args.json {
+            output::json(&mut out, i + 1, record)
+        } else {
+            output::text(&mut out, record, start)
+        }
+    });
+    if let Err(err) = res.and_then(|_| out.flush()) {
+        eprintln!("error: {}", err);
+        return ExitCode::FAILURE;
+    }
+
+    match err {
+        Some(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+        None => ExitCode::SUCCESS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Args>, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            parse(&["--json", "-f", "hex", "log.txt"]),
+            Ok(Some(Args {
+                format: Format::Hex,
+                json: true,
+                path: Some("log.txt".to_string()),
+            }))
+        );
+        assert_eq!(
+            parse(&["-"]),
+            Ok(Some(Args {
+                format: Format::Auto,
+                json: false,
+                path: None,
+            }))
+        );
+        assert_eq!(parse(&["-h"]), Ok(None));
+        assert_eq!(parse(&["--format"]), Err("missing value for `--format`".to_string()));
+        assert_eq!(parse(&["-f", "pcap"]), Err("unknown format `pcap`".to_string()));
+        assert_eq!(parse(&["a", "b"]), Err("unexpected argument `b`".to_string()));
+    }
+}
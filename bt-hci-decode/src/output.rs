@@ -0,0 +1,133 @@
+//! Rendering decoded packets as text or JSON.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use bt_hci::dissect::Dissect;
+use bt_hci::transport::Direction;
+
+use crate::input::Record;
+
+/// Indentation of the detail lines of a [`Dissect`] description.
+const INDENT: &str = "        ";
+
+/// Write `record` as a btmon-style description.
+///
+/// If the record has a timestamp, the time since `start` is appended to the first line.
+pub fn text<W: Write>(out: &mut W, record: &Record, start: u64) -> io::Result<()> {
+    let description = Dissect::h4(record.direction, &record.data).to_string();
+    let (header, details) = match description.split_once('\n') {
+        Some((header, details)) => (header, Some(details)),
+        None => (description.as_str(), None),
+    };
+    write!(out, "{}", header)?;
+    if let Some(timestamp) = record.timestamp_micros {
+        let elapsed = timestamp.saturating_sub(start);
+        write!(out, "  [{}.{:06}]", elapsed / 1_000_000, elapsed % 1_000_000)?;
+    }
+    writeln!(out)?;
+    if let Some(details) = details {
+        writeln!(out, "{}", details)?;
+    }
+    Ok(())
+}
+
+/// Write `record` as a single line JSON object.
+pub fn json<W: Write>(out: &mut W, index: usize, record: &Record) -> io::Result<()> {
+    let description = Dissect::h4(record.direction, &record.data).to_string();
+    let mut lines = description.lines();
+    // Drop the direction marker, which is part of the object already
+    let summary = lines.next().unwrap_or_default().get(2..).unwrap_or_default();
+
+    let mut obj = String::new();
+    write!(obj, "{{\"index\":{},\"direction\":", index).unwrap();
+    string(
+        &mut obj,
+        match record.direction {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        },
+    );
+    if let Some(timestamp) = record.timestamp_micros {
+        write!(obj, ",\"timestamp_micros\":{}", timestamp).unwrap();
+    }
+    obj.push_str(",\"summary\":");
+    string(&mut obj, summary);
+    obj.push_str(",\"details\":[");
+    for (i, line) in lines.enumerate() {
+        if i > 0 {
+            obj.push(',');
+        }
+        string(&mut obj, line.strip_prefix(INDENT).unwrap_or(line));
+    }
+    obj.push_str("],\"data\":\"");
+    for byte in &record.data {
+        write!(obj, "{:02x}", byte).unwrap();
+    }
+    obj.push_str("\"}");
+    writeln!(out, "{}", obj)
+}
+
+/// Append `s` as a JSON string.
+fn string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reset_complete() -> Record {
+        Record {
+            direction: Direction::Received,
+            timestamp_micros: Some(2_500_000),
+            data: vec![0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00],
+        }
+    }
+
+    #[test]
+    fn test_text() {
+        let mut out = Vec::new();
+        text(&mut out, &reset_complete(), 1_000_000).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "> HCI Event: Command Complete (0x0e) plen 4  [1.500000]
+        Reset (0x03|0x0003) ncmd 1
+        Status: Success (0x00)
+"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let mut out = Vec::new();
+        json(&mut out, 3, &reset_complete()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"index":3,"direction":"received","timestamp_micros":2500000,"#,
+                r#""summary":"HCI Event: Command Complete (0x0e) plen 4","#,
+                r#""details":["Reset (0x03|0x0003) ncmd 1","Status: Success (0x00)"],"data":"040e0401030c00"}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_json_string() {
+        let mut out = String::new();
+        string(&mut out, "a\"b\\c\u{1}");
+        assert_eq!(out, r#""a\"b\\c\u0001""#);
+    }
+}
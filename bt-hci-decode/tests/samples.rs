@@ -0,0 +1,93 @@
+//! Decode the sample captures in `tests/data` and compare against the expected output next to them.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn data(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data").join(name)
+}
+
+fn decode(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bt-hci-decode"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn check(sample: &str, args: &[&str], expected: &str) {
+    let path = data(sample);
+    let mut args = args.to_vec();
+    args.push(path.to_str().unwrap());
+    let output = decode(&args, &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        std::fs::read_to_string(data(expected)).unwrap()
+    );
+}
+
+#[test]
+fn test_hex() {
+    check("connection.hex", &[], "connection.hex.txt");
+}
+
+#[test]
+fn test_hex_json() {
+    check("connection.hex", &["--json"], "connection.hex.jsonl");
+}
+
+#[test]
+fn test_h4() {
+    check("connection.h4", &[], "connection.h4.txt");
+}
+
+#[test]
+fn test_btsnoop() {
+    check("connection.btsnoop", &[], "connection.btsnoop.txt");
+}
+
+#[test]
+fn test_btsnoop_json() {
+    // The capture starts at 2023-11-14 22:13:20 UTC
+    check("connection.btsnoop", &["--json"], "connection.btsnoop.jsonl");
+}
+
+#[test]
+fn test_stdin() {
+    let h4 = std::fs::read(data("connection.h4")).unwrap();
+    let output = decode(&["--format", "h4"], &h4);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        std::fs::read_to_string(data("connection.h4.txt")).unwrap()
+    );
+}
+
+#[test]
+fn test_truncated() {
+    let output = decode(&["-"], b"01 03 0c 00\n04 0e 04 01 03\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "< HCI Command: Reset (0x03|0x0003) plen 0\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "error: truncated packet at offset 4\n"
+    );
+}
+
+#[test]
+fn test_usage() {
+    let output = decode(&["--bogus"], &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("error: unknown option `--bogus`\n\nUsage: bt-hci-decode"));
+}
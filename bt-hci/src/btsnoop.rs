@@ -0,0 +1,425 @@
+//! Reading and writing HCI traffic in the btsnoop file format.
+//!
+//! btsnoop files can be opened with Wireshark or `btmon -r`. A file starts with a 16 byte header followed by one
+//! record per packet. Every record stores the packet direction, whether it is a command/event or a data packet,
+//! and a timestamp.
+//!
+//! ```
+//! use bt_hci::btsnoop::{Datalink, Direction, Reader, Writer};
+//! use bt_hci::cmd::controller_baseband::Reset;
+//!
+//! let mut log = [0u8; 64];
+//! let mut writer = Writer::new(&mut log[..], Datalink::H4).unwrap();
+//! writer.write_sent(&Reset::new()).unwrap();
+//!
+//! let mut reader = Reader::new(&log[..]).unwrap();
+//! let mut buf = [0; 259];
+//! let record = reader.next(&mut buf).unwrap().unwrap();
+//! assert_eq!(record.direction, Direction::Sent);
+//! assert_eq!(record.data, [0x03, 0x0c, 0x00]);
+//! ```
+
+use bt_hci_driver::{PacketKind, PacketToController};
+use embedded_io::ReadExactError;
+
+use crate::capture::read_or_eof;
+pub use crate::capture::{NoTimestamp, Packet, TimestampSource};
+use crate::transport::tee::Sink;
+pub use crate::transport::Direction;
+use crate::{FromHciBytes, FromHciBytesError, WritePacketToHost};
+
+/// The identification pattern every btsnoop file starts with.
+const MAGIC: [u8; 8] = *b"btsnoop\0";
+/// The only btsnoop version in use.
+const VERSION: u32 = 1;
+/// Microseconds between midnight January 1st, 0 AD (the btsnoop epoch) and the Unix epoch.
+const UNIX_EPOCH_OFFSET: u64 = 0x00dc_ddb3_0f2f_8000;
+
+const FLAG_RECEIVED: u32 = 0x01;
+const FLAG_CMD_EVENT: u32 = 0x02;
+
+/// The datalink type of a btsnoop file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Datalink {
+    /// Un-encapsulated HCI (H1).
+    ///
+    /// Packets are stored without a packet indicator, the kind of packet is derived from the record flags. This
+    /// cannot distinguish between ACL, synchronous and isochronous data, which are all read back as ACL data.
+    H1,
+    /// HCI UART (H4), packets are stored with their packet indicator.
+    H4,
+}
+
+impl Datalink {
+    /// The datalink type code stored in the file header.
+    pub const fn to_raw(self) -> u32 {
+        match self {
+            Self::H1 => 1001,
+            Self::H4 => 1002,
+        }
+    }
+
+    /// Returns the datalink for a type code stored in a file header, if it is supported.
+    pub const fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            1001 => Some(Self::H1),
+            1002 => Some(Self::H4),
+            _ => None,
+        }
+    }
+}
+
+/// Writes HCI packets to a btsnoop file.
+pub struct Writer<W, T = NoTimestamp> {
+    writer: W,
+    datalink: Datalink,
+    timestamps: T,
+}
+
+impl<W: embedded_io::Write> Writer<W> {
+    /// Create a new writer without timestamps and write the file header.
+    pub fn new(writer: W, datalink: Datalink) -> Result<Self, W::Error> {
+        Self::with_timestamps(writer, datalink, NoTimestamp)
+    }
+}
+
+impl<W: embedded_io::Write, T: TimestampSource> Writer<W, T> {
+    /// Create a new writer which stamps every record with the time from `timestamps` and write the file header.
+    pub fn with_timestamps(mut writer: W, datalink: Datalink, timestamps: T) -> Result<Self, W::Error> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        writer.write_all(&datalink.to_raw().to_be_bytes())?;
+        Ok(Self {
+            writer,
+            datalink,
+            timestamps,
+        })
+    }
+
+    /// Record a packet sent from the host to the controller.
+    pub fn write_sent<P: PacketToController>(&mut self, pkt: &P) -> Result<(), W::Error> {
+        self.write_header(Direction::Sent, P::KIND, pkt.size())?;
+        pkt.write_hci(&mut self.writer)
+    }
+
+    /// Record a packet received from the controller.
+    pub fn write_received<P: WritePacketToHost>(&mut self, pkt: &P) -> Result<(), W::Error> {
+        self.write_header(Direction::Received, pkt.kind(), pkt.size())?;
+        pkt.write_hci(&mut self.writer)
+    }
+
+    /// Record an already serialized packet, `data` must not include the packet indicator.
+    pub fn write_record(&mut self, direction: Direction, kind: PacketKind, data: &[u8]) -> Result<(), W::Error> {
+        self.write_header(direction, kind, data.len())?;
+        self.writer.write_all(data)
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<(), W::Error> {
+        self.writer.flush()
+    }
+
+    /// Consume the writer, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_header(&mut self, direction: Direction, kind: PacketKind, len: usize) -> Result<(), W::Error> {
+        let len = match self.datalink {
+            Datalink::H1 => len as u32,
+            Datalink::H4 => len as u32 + 1,
+        };
+        let mut flags = match direction {
+            Direction::Sent => 0,
+            Direction::Received => FLAG_RECEIVED,
+        };
+        if matches!(kind, PacketKind::Cmd | PacketKind::Event) {
+            flags |= FLAG_CMD_EVENT;
+        }
+        let timestamp = self.timestamps.now_micros().saturating_add(UNIX_EPOCH_OFFSET);
+
+        let mut header = [0; 24];
+        header[0..4].copy_from_slice(&len.to_be_bytes());
+        header[4..8].copy_from_slice(&len.to_be_bytes());
+        header[8..12].copy_from_slice(&flags.to_be_bytes());
+        // header[12..16] holds the cumulative number of dropped packets, which is always 0
+        header[16..24].copy_from_slice(&timestamp.to_be_bytes());
+        self.writer.write_all(&header)?;
+        if self.datalink == Datalink::H4 {
+            self.writer.write_all(&[kind as u8])?;
+        }
+        Ok(())
+    }
+}
+
+/// Records every packet seen by a [`TeeTransport`](crate::transport::tee::TeeTransport).
+///
+/// Errors from the underlying writer are ignored, so that logging never interferes with the transport.
+impl<W: embedded_io::Write, T: TimestampSource> Sink for Writer<W, T> {
+    fn packet(&mut self, direction: Direction, kind: PacketKind, data: &[u8]) {
+        let _ = self.write_record(direction, kind, data);
+    }
+}
+
+/// Errors from reading a btsnoop file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// The file does not start with a btsnoop version 1 header.
+    InvalidHeader,
+    /// The file uses a datalink type other than [`Datalink::H1`] or [`Datalink::H4`].
+    UnsupportedDatalink(u32),
+    /// A record is larger than the provided buffer.
+    BufferTooSmall,
+    /// A record is empty or has an invalid packet indicator.
+    InvalidRecord,
+    /// Error from the underlying reader.
+    Read(ReadExactError<E>),
+}
+
+impl<E> From<ReadExactError<E>> for Error<E> {
+    fn from(value: ReadExactError<E>) -> Self {
+        Self::Read(value)
+    }
+}
+
+/// A single packet recorded in a btsnoop file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Record<'a> {
+    /// The direction of the packet.
+    pub direction: Direction,
+    /// The kind of packet.
+    pub kind: PacketKind,
+    /// The time the packet was recorded in microseconds since the Unix epoch.
+    pub timestamp_micros: u64,
+    /// The length of the packet before it was truncated, not including the packet indicator.
+    pub original_len: usize,
+    /// The number of packets dropped by the recorder since the start of the file.
+    pub drops: u32,
+    /// The recorded bytes of the packet, not including the packet indicator.
+    pub data: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    /// Returns `true` if the packet was recorded completely.
+    pub fn is_complete(&self) -> bool {
+        self.data.len() == self.original_len
+    }
+
+    /// Decode the recorded packet.
+    pub fn packet(&self) -> Result<Packet<'a>, FromHciBytesError> {
+        Packet::decode(self.direction, self.kind, self.data)
+    }
+}
+
+/// Reads HCI packets from a btsnoop file.
+pub struct Reader<R> {
+    reader: R,
+    datalink: Datalink,
+}
+
+impl<R: embedded_io::Read> Reader<R> {
+    /// Create a new reader and read the file header.
+    pub fn new(mut reader: R) -> Result<Self, Error<R::Error>> {
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+        let version = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        if header[..8] != MAGIC || version != VERSION {
+            return Err(Error::InvalidHeader);
+        }
+        let datalink = u32::from_be_bytes([header[12], header[13], header[14], header[15]]);
+        let datalink = Datalink::from_raw(datalink).ok_or(Error::UnsupportedDatalink(datalink))?;
+        Ok(Self { reader, datalink })
+    }
+
+    /// The datalink type of the file.
+    pub fn datalink(&self) -> Datalink {
+        self.datalink
+    }
+
+    /// Read the next record into `buf`.
+    ///
+    /// Returns `Ok(None)` at the end of the file.
+    pub fn next<'b>(&mut self, buf: &'b mut [u8]) -> Result<Option<Record<'b>>, Error<R::Error>> {
+        let mut header = [0; 24];
+        if !read_or_eof(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+
+        let field = |i: usize| u32::from_be_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        let original_len = field(0) as usize;
+        let included_len = field(4) as usize;
+        let flags = field(8);
+        let drops = field(12);
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&header[16..24]);
+        let timestamp_micros = u64::from_be_bytes(timestamp).saturating_sub(UNIX_EPOCH_OFFSET);
+
+        if buf.len() < included_len {
+            return Err(Error::BufferTooSmall);
+        }
+        let data = &mut buf[..included_len];
+        self.reader.read_exact(data)?;
+        let data: &'b [u8] = data;
+
+        let direction = if flags & FLAG_RECEIVED != 0 {
+            Direction::Received
+        } else {
+            Direction::Sent
+        };
+        let (kind, data, original_len) = match self.datalink {
+            Datalink::H1 => {
+                let kind = match (flags & FLAG_CMD_EVENT != 0, direction) {
+                    (true, Direction::Sent) => PacketKind::Cmd,
+                    (true, Direction::Received) => PacketKind::Event,
+                    (false, _) => PacketKind::AclData,
+                };
+                (kind, data, original_len)
+            }
+            Datalink::H4 => {
+                let (kind, data) = PacketKind::from_hci_bytes(data).map_err(|_| Error::InvalidRecord)?;
+                (kind, data, original_len.saturating_sub(1))
+            }
+        };
+
+        Ok(Some(Record {
+            direction,
+            kind,
+            timestamp_micros,
+            original_len,
+            drops,
+            data,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::cmd::Command;
+    use crate::data::{AclBroadcastFlag, AclPacket, AclPacketBoundary};
+    use crate::event::{Event, EventPacket};
+    use crate::param::ConnHandle;
+    use crate::{ControllerToHostPacket, HostToControllerPacket};
+
+    const RESET_COMPLETE: [u8; 6] = [0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+
+    #[test]
+    fn test_write_h4() {
+        let mut log = [0u8; 128];
+        let mut time = 1_000_000;
+        let mut writer = Writer::with_timestamps(&mut log[..], Datalink::H4, || {
+            time += 1;
+            time
+        })
+        .unwrap();
+        writer.write_sent(&Reset::new()).unwrap();
+        let event = EventPacket::from_hci_bytes_complete(&RESET_COMPLETE).unwrap();
+        writer.write_received(&event).unwrap();
+        writer.into_inner();
+
+        assert_eq!(&log[..16], b"btsnoop\0\x00\x00\x00\x01\x00\x00\x03\xea");
+        let sent = &log[16..];
+        assert_eq!(&sent[..12], [0, 0, 0, 4, 0, 0, 0, 4, 0, 0, 0, 2]);
+        assert_eq!(&sent[16..24], (UNIX_EPOCH_OFFSET + 1_000_001).to_be_bytes());
+        assert_eq!(&sent[24..28], [0x01, 0x03, 0x0c, 0x00]);
+        let received = &sent[28..];
+        assert_eq!(&received[..12], [0, 0, 0, 7, 0, 0, 0, 7, 0, 0, 0, 3]);
+        assert_eq!(received[24], 0x04);
+        assert_eq!(&received[25..31], RESET_COMPLETE);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for datalink in [Datalink::H1, Datalink::H4] {
+            let mut log = [0u8; 256];
+            let mut writer = Writer::new(&mut log[..], datalink).unwrap();
+            writer.write_sent(&Reset::new()).unwrap();
+            let event = EventPacket::from_hci_bytes_complete(&RESET_COMPLETE).unwrap();
+            writer.write_received(&event).unwrap();
+            let acl = AclPacket::new(
+                ConnHandle::new(0x40),
+                AclPacketBoundary::FirstFlushable,
+                AclBroadcastFlag::PointToPoint,
+                &[1, 2, 3],
+            );
+            writer.write_received(&acl).unwrap();
+            let len = 16 + 3 * 24 + 3 + 6 + 7 + if datalink == Datalink::H4 { 3 } else { 0 };
+            let remaining = writer.into_inner().len();
+            assert_eq!(log.len() - remaining, len);
+
+            let mut reader = Reader::new(&log[..len]).unwrap();
+            assert_eq!(reader.datalink(), datalink);
+            let mut buf = [0; 259];
+
+            let record = reader.next(&mut buf).unwrap().unwrap();
+            assert_eq!(record.direction, Direction::Sent);
+            assert_eq!(record.kind, PacketKind::Cmd);
+            assert_eq!(record.timestamp_micros, 0);
+            assert!(record.is_complete());
+            let Packet::Sent(HostToControllerPacket::Cmd(cmd)) = record.packet().unwrap() else {
+                panic!("expected a command");
+            };
+            assert_eq!(Command::try_from(cmd), Ok(Command::Reset(Reset::new())));
+
+            let record = reader.next(&mut buf).unwrap().unwrap();
+            assert_eq!(record.direction, Direction::Received);
+            let Packet::Received(ControllerToHostPacket::Event(event)) = record.packet().unwrap() else {
+                panic!("expected an event");
+            };
+            assert!(matches!(Event::try_from(event), Ok(Event::CommandComplete(_))));
+
+            let record = reader.next(&mut buf).unwrap().unwrap();
+            let Packet::Received(ControllerToHostPacket::Acl(acl)) = record.packet().unwrap() else {
+                panic!("expected ACL data");
+            };
+            assert_eq!(acl.handle(), ConnHandle::new(0x40));
+            assert_eq!(acl.data(), [1, 2, 3]);
+
+            assert_eq!(reader.next(&mut buf), Ok(None));
+        }
+    }
+
+    #[test]
+    fn test_timestamp() {
+        // A Reset command sent at 2023-11-14 22:13:20 UTC, as stored by btmon
+        let log = b"btsnoop\0\x00\x00\x00\x01\x00\x00\x03\xea\
+            \x00\x00\x00\x04\x00\x00\x00\x04\x00\x00\x00\x02\x00\x00\x00\x00\
+            \x00\xe2\xe7\xd7\x27\x4d\xc0\x00\
+            \x01\x03\x0c\x00";
+        let mut reader = Reader::new(&log[..]).unwrap();
+        let mut buf = [0; 259];
+        let record = reader.next(&mut buf).unwrap().unwrap();
+        assert_eq!(record.timestamp_micros, 1_700_000_000_000_000);
+    }
+
+    #[test]
+    fn test_read_errors() {
+        assert!(matches!(
+            Reader::new(&b"btsnoop\0\0\0\0\x02\0\0\x03\xea"[..]),
+            Err(Error::InvalidHeader)
+        ));
+        assert!(matches!(
+            Reader::new(&b"btsnoop\0\0\0\0\x01\0\0\x03\xe8"[..]),
+            Err(Error::UnsupportedDatalink(1000))
+        ));
+        assert!(matches!(
+            Reader::new(&b"btsnoop"[..]),
+            Err(Error::Read(ReadExactError::UnexpectedEof))
+        ));
+
+        let mut log = [0u8; 64];
+        let mut writer = Writer::new(&mut log[..], Datalink::H4).unwrap();
+        writer.write_sent(&Reset::new()).unwrap();
+        let mut reader = Reader::new(&log[..16 + 24 + 4]).unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(reader.next(&mut buf), Err(Error::BufferTooSmall));
+
+        let mut reader = Reader::new(&log[..16 + 24 + 2]).unwrap();
+        let mut buf = [0; 259];
+        assert_eq!(reader.next(&mut buf), Err(Error::Read(ReadExactError::UnexpectedEof)));
+    }
+}
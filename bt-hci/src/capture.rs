@@ -0,0 +1,83 @@
+//! Types shared by the packet capture file formats.
+
+use bt_hci_driver::PacketKind;
+use embedded_io::ReadExactError;
+
+use crate::transport::Direction;
+use crate::{ControllerToHostPacket, FromHciBytesError, HostToControllerPacket};
+
+/// A source of record timestamps.
+pub trait TimestampSource {
+    /// The current time in microseconds since the Unix epoch.
+    ///
+    /// Sources without a wall clock may return the time since boot instead.
+    fn now_micros(&mut self) -> u64;
+}
+
+impl<F: FnMut() -> u64> TimestampSource for F {
+    fn now_micros(&mut self) -> u64 {
+        self()
+    }
+}
+
+/// A [`TimestampSource`] for devices without a clock, every record is stamped with the Unix epoch.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoTimestamp;
+
+impl TimestampSource for NoTimestamp {
+    fn now_micros(&mut self) -> u64 {
+        0
+    }
+}
+
+/// A packet decoded from a capture file.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Packet<'a> {
+    /// A packet sent from the host to the controller.
+    Sent(HostToControllerPacket<'a>),
+    /// A packet received from the controller.
+    Received(ControllerToHostPacket<'a>),
+}
+
+impl<'a> Packet<'a> {
+    /// Decode a packet of the given kind, `data` must not include the packet indicator.
+    pub fn decode(direction: Direction, kind: PacketKind, data: &'a [u8]) -> Result<Self, FromHciBytesError> {
+        match direction {
+            Direction::Sent => HostToControllerPacket::from_hci_bytes_with_kind(kind, data).map(|(x, _)| Self::Sent(x)),
+            Direction::Received => {
+                ControllerToHostPacket::from_hci_bytes_with_kind(kind, data).map(|(x, _)| Self::Received(x))
+            }
+        }
+    }
+}
+
+/// Fill `buf` from `reader`, returning `false` if the reader is at the end of the file before the first byte.
+pub(crate) fn read_or_eof<R: embedded_io::Read>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> Result<bool, ReadExactError<R::Error>> {
+    let n = reader.read(buf).map_err(ReadExactError::Other)?;
+    if n == 0 && !buf.is_empty() {
+        return Ok(false);
+    }
+    reader.read_exact(&mut buf[n..])?;
+    Ok(true)
+}
+
+/// Read and discard `len` bytes from `reader`, using `scratch` as a temporary buffer.
+#[cfg(any(test, feature = "pcap"))]
+pub(crate) fn skip<R: embedded_io::Read>(
+    reader: &mut R,
+    mut len: usize,
+    scratch: &mut [u8],
+) -> Result<(), ReadExactError<R::Error>> {
+    let mut byte = [0];
+    let scratch = if scratch.is_empty() { &mut byte[..] } else { scratch };
+    while len > 0 {
+        let n = len.min(scratch.len());
+        reader.read_exact(&mut scratch[..n])?;
+        len -= n;
+    }
+    Ok(())
+}
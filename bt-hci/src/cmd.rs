@@ -5,13 +5,14 @@ use core::future::Future;
 use embedded_io::ErrorType;
 
 use crate::controller::{ControllerCmdAsync, ControllerCmdSync};
-use crate::{param, FixedSizeValue, FromHciBytes, WriteHci};
+use crate::{param, FixedSizeValue, FromHciBytes, FromHciBytesError, ReadHci, ReadHciError, WriteHci};
 
 pub mod controller_baseband;
 pub mod info;
 pub mod le;
 pub mod link_control;
 pub mod status;
+pub mod testing;
 
 /// The 6-bit Opcode Group Field (OGF)
 ///
@@ -76,6 +77,106 @@ impl Opcode {
     }
 }
 
+param! {
+    /// The header of an HCI command packet.
+    struct CmdPacketHeader {
+        opcode: Opcode,
+        params_len: u8,
+    }
+}
+
+/// A raw HCI command packet
+///
+/// Use [`Command::try_from`] to decode the parameters of a known command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CmdPacket<'a> {
+    /// The opcode of the command.
+    pub opcode: Opcode,
+    /// Command parameters.
+    pub params: &'a [u8],
+}
+
+impl<'a> CmdPacket<'a> {
+    fn from_header_hci_bytes(header: CmdPacketHeader, data: &'a [u8]) -> Result<(Self, &'a [u8]), FromHciBytesError> {
+        let params_len = usize::from(header.params_len);
+        if data.len() < params_len {
+            return Err(FromHciBytesError::InvalidSize);
+        }
+        let (params, rest) = data.split_at(params_len);
+        Ok((
+            Self {
+                opcode: header.opcode,
+                params,
+            },
+            rest,
+        ))
+    }
+}
+
+impl<'de> FromHciBytes<'de> for CmdPacket<'de> {
+    fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), FromHciBytesError> {
+        let (header, data) = CmdPacketHeader::from_hci_bytes(data)?;
+        Self::from_header_hci_bytes(header, data)
+    }
+}
+
+impl<'de> ReadHci<'de> for CmdPacket<'de> {
+    const MAX_LEN: usize = 258;
+
+    fn read_hci<R: embedded_io::Read>(mut reader: R, buf: &'de mut [u8]) -> Result<Self, ReadHciError<R::Error>> {
+        let mut header = [0; 3];
+        reader.read_exact(&mut header)?;
+        let (header, _) = CmdPacketHeader::from_hci_bytes(&header)?;
+        let params_len = usize::from(header.params_len);
+        if buf.len() < params_len {
+            Err(ReadHciError::BufferTooSmall)
+        } else {
+            let (buf, _) = buf.split_at_mut(params_len);
+            reader.read_exact(buf)?;
+            let (pkt, _) = Self::from_header_hci_bytes(header, buf)?;
+            Ok(pkt)
+        }
+    }
+
+    async fn read_hci_async<R: embedded_io_async::Read>(
+        mut reader: R,
+        buf: &'de mut [u8],
+    ) -> Result<Self, ReadHciError<R::Error>> {
+        let mut header = [0; 3];
+        reader.read_exact(&mut header).await?;
+        let (header, _) = CmdPacketHeader::from_hci_bytes(&header)?;
+        let params_len = usize::from(header.params_len);
+        if buf.len() < params_len {
+            Err(ReadHciError::BufferTooSmall)
+        } else {
+            let (buf, _) = buf.split_at_mut(params_len);
+            reader.read_exact(buf).await?;
+            let (pkt, _) = Self::from_header_hci_bytes(header, buf)?;
+            Ok(pkt)
+        }
+    }
+}
+
+impl WriteHci for CmdPacket<'_> {
+    #[inline(always)]
+    fn size(&self) -> usize {
+        3 + self.params.len()
+    }
+
+    fn write_hci<W: embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+        self.opcode.write_hci(&mut writer)?;
+        writer.write_all(&[self.params.len() as u8])?;
+        writer.write_all(self.params)
+    }
+
+    async fn write_hci_async<W: embedded_io_async::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+        self.opcode.write_hci_async(&mut writer).await?;
+        writer.write_all(&[self.params.len() as u8]).await?;
+        writer.write_all(self.params).await
+    }
+}
+
 /// An error type for HCI commands
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -122,6 +223,47 @@ pub trait AsyncCmd: Cmd {
     }
 }
 
+/// Writes the return parameters following the status in `data`, or returns `None` if they can't be decoded.
+pub(crate) type DissectReturnFn = fn(&[u8], &mut dyn core::fmt::Write) -> Option<core::fmt::Result>;
+
+/// Selects the [`DissectReturnFn`] of a command in [`describe`].
+///
+/// `(&DissectReturn::<C>::new()).dissect_return()` resolves to [`DissectSync`] if `C` is a [`SyncCmd`] with a `Debug`
+/// return type, and to [`DissectAsync`] otherwise, so [`cmd`] doesn't require `Debug` of the commands it defines.
+struct DissectReturn<C>(core::marker::PhantomData<C>);
+
+impl<C> DissectReturn<C> {
+    const fn new() -> Self {
+        Self(core::marker::PhantomData)
+    }
+}
+
+trait DissectSync {
+    fn dissect_return(&self) -> DissectReturnFn;
+}
+
+impl<C: SyncCmd> DissectSync for DissectReturn<C>
+where
+    C::Return: core::fmt::Debug,
+{
+    fn dissect_return(&self) -> DissectReturnFn {
+        |data, w| {
+            let ret = C::Return::from_hci_bytes_complete(data).ok()?;
+            Some(core::write!(w, "{:#?}", ret))
+        }
+    }
+}
+
+trait DissectAsync {
+    fn dissect_return(&self) -> DissectReturnFn;
+}
+
+impl<C> DissectAsync for &DissectReturn<C> {
+    fn dissect_return(&self) -> DissectReturnFn {
+        |_, _| None
+    }
+}
+
 /// Type representing the buffer for a command response.
 pub trait CmdReturnBuf: Copy + AsRef<[u8]> + AsMut<[u8]> {
     /// Length of buffer.
@@ -172,6 +314,346 @@ pub trait SyncCmd: Cmd {
     }
 }
 
+macro_rules! commands {
+    ($($module:ident::$name:ident$(<$life:lifetime>)?,)+) => {
+        /// An HCI command decoded into its typed representation
+        ///
+        /// Commands are identified by their opcode. Commands with an opcode this crate does not define are returned as
+        /// [`Command::Unknown`].
+        #[non_exhaustive]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+        pub enum Command<'a> {
+            $(
+                #[allow(missing_docs)]
+                $name($module::$name$(<$life>)?),
+            )+
+            /// LE Set Periodic Advertising Subevent Data, with its subevents borrowed from the packet and parsed
+            /// lazily since the slice held by [`le::LeSetPeriodicAdvSubeventData`] cannot be decoded without allocating
+            LeSetPeriodicAdvSubeventData(le::LeSetPeriodicAdvSubeventDataRef<'a>),
+            /// A command with an unknown opcode
+            Unknown {
+                /// The command opcode
+                opcode: Opcode,
+                /// The bytes of the command parameters
+                params: &'a [u8],
+            },
+        }
+
+        impl Command<'_> {
+            /// The opcode of this command.
+            pub fn opcode(&self) -> Opcode {
+                match self {
+                    $(Self::$name(_) => <$module::$name as Cmd>::OPCODE,)+
+                    Self::LeSetPeriodicAdvSubeventData(_) => <le::LeSetPeriodicAdvSubeventData as Cmd>::OPCODE,
+                    Self::Unknown { opcode, .. } => *opcode,
+                }
+            }
+
+            /// The parameters of this command, used by [`crate::dissect`].
+            pub(crate) fn params_debug(&self) -> Option<&dyn core::fmt::Debug> {
+                match self {
+                    $(Self::$name(x) => Some(x.params()),)+
+                    Self::LeSetPeriodicAdvSubeventData(x) => Some(x),
+                    Self::Unknown { .. } => None,
+                }
+            }
+        }
+
+        /// The name of the command with `opcode` and the formatter of its return parameters, used by
+        /// [`crate::dissect`].
+        pub(crate) fn describe(opcode: Opcode) -> Option<(&'static str, DissectReturnFn)> {
+            match opcode {
+                $(
+                    $module::$name::OPCODE => Some((
+                        stringify!($name),
+                        (&DissectReturn::<$module::$name>::new()).dissect_return(),
+                    )),
+                )+
+                le::LeSetPeriodicAdvSubeventData::OPCODE => Some((
+                    "LeSetPeriodicAdvSubeventData",
+                    (&DissectReturn::<le::LeSetPeriodicAdvSubeventData>::new()).dissect_return(),
+                )),
+                _ => None,
+            }
+        }
+
+        impl<'a> TryFrom<CmdPacket<'a>> for Command<'a> {
+            type Error = FromHciBytesError;
+            fn try_from(packet: CmdPacket<'a>) -> Result<Self, Self::Error> {
+                match packet.opcode {
+                    $(
+                        $module::$name::OPCODE => {
+                            let params = <$module::$name as Cmd>::Params::from_hci_bytes_complete(packet.params)?;
+                            Ok(Self::$name(params.into()))
+                        }
+                    )+
+                    le::LeSetPeriodicAdvSubeventData::OPCODE => Ok(Self::LeSetPeriodicAdvSubeventData(
+                        le::LeSetPeriodicAdvSubeventDataRef::from_hci_bytes_complete(packet.params)?,
+                    )),
+                    opcode => Ok(Self::Unknown {
+                        opcode,
+                        params: packet.params,
+                    }),
+                }
+            }
+        }
+
+        impl WriteHci for Command<'_> {
+            #[inline(always)]
+            fn size(&self) -> usize {
+                match self {
+                    $(Self::$name(x) => x.size(),)+
+                    Self::LeSetPeriodicAdvSubeventData(x) => x.size(),
+                    Self::Unknown { params, .. } => 3 + params.len(),
+                }
+            }
+
+            fn write_hci<W: embedded_io::Write>(&self, writer: W) -> Result<(), W::Error> {
+                match self {
+                    $(Self::$name(x) => x.write_hci(writer),)+
+                    Self::LeSetPeriodicAdvSubeventData(x) => x.write_hci(writer),
+                    Self::Unknown { opcode, params } => CmdPacket {
+                        opcode: *opcode,
+                        params,
+                    }
+                    .write_hci(writer),
+                }
+            }
+
+            async fn write_hci_async<W: embedded_io_async::Write>(&self, writer: W) -> Result<(), W::Error> {
+                match self {
+                    $(Self::$name(x) => x.write_hci_async(writer).await,)+
+                    Self::LeSetPeriodicAdvSubeventData(x) => x.write_hci_async(writer).await,
+                    Self::Unknown { opcode, params } => {
+                        CmdPacket {
+                            opcode: *opcode,
+                            params,
+                        }
+                        .write_hci_async(writer)
+                        .await
+                    }
+                }
+            }
+        }
+
+        #[cfg(test)]
+        const OPCODES: &[Opcode] = &[
+            $(<$module::$name as Cmd>::OPCODE,)+
+            <le::LeSetPeriodicAdvSubeventData as Cmd>::OPCODE,
+        ];
+    };
+}
+
+commands! {
+    link_control::Inquiry,
+    link_control::InquiryCancel,
+    link_control::ExitPeriodicInquiryMode,
+    link_control::CreateConnection,
+    link_control::Disconnect,
+    link_control::CreateConnectionCancel,
+    link_control::AcceptConnectionRequest,
+    link_control::RejectConnectionRequest,
+    link_control::LinkKeyRequestReply,
+    link_control::LinkKeyRequestNegativeReply,
+    link_control::PinCodeRequestReply,
+    link_control::ChangeConnectionPacketType,
+    link_control::AuthenticationRequested,
+    link_control::SetConnectionEncryption,
+    link_control::ChangeConnectionLinkKey,
+    link_control::LinkKeySelection,
+    link_control::RemoteNameRequest,
+    link_control::RemoteNameRequestCancel,
+    link_control::ReadRemoteSupportedFeatures,
+    link_control::ReadRemoteExtendedFeatures,
+    link_control::ReadRemoteVersionInformation,
+    link_control::ReadClockOffset,
+    link_control::ReadLmpHandle,
+    link_control::SetupSynchronousConnection,
+    link_control::AcceptSynchronousConnectionRequest,
+    link_control::RejectSynchronousConnectionRequest,
+    link_control::IoCapabilityRequestReply,
+    link_control::UserConfirmationRequestReply,
+    link_control::UserConfirmationRequestNegativeReply,
+    link_control::UserPasskeyRequestReply,
+    link_control::UserPasskeyRequestNegativeReply,
+    link_control::RemoteOobDataRequestReply,
+    link_control::RemoteOobDataRequestNegativeReply,
+    link_control::IoCapabilityRequestNegativeReply,
+    link_control::EnhancedSetupSynchronousConnection,
+    link_control::EnhancedAcceptSynchronousConnectionRequest,
+    link_control::TruncatedPage,
+    link_control::TruncatedPageCancel,
+    link_control::SetConnectionlessPeripheralBroadcast,
+    link_control::SetConnectionlessPeripheralBroadcastReceive,
+    link_control::StartSynchronizationTrain,
+    link_control::ReceiveSynchronizationTrain,
+    link_control::RemoteOobExtendedDataRequestReply,
+    controller_baseband::SetEventMask,
+    controller_baseband::Reset,
+    controller_baseband::ReadTransmitPowerLevel,
+    controller_baseband::SetControllerToHostFlowControl,
+    controller_baseband::HostBufferSize,
+    controller_baseband::HostNumberOfCompletedPackets<'a>,
+    controller_baseband::SetEventMaskPage2,
+    controller_baseband::ReadAuthenticatedPayloadTimeout,
+    controller_baseband::WriteAuthenticatedPayloadTimeout,
+    controller_baseband::ReadStoredLinkKey,
+    info::ReadLocalVersionInformation,
+    info::ReadLocalSupportedCmds,
+    info::ReadLocalSupportedFeatures,
+    info::ReadLocalExtendedFeatures,
+    info::ReadBdAddr,
+    status::ReadRssi,
+    testing::ReadLoopbackMode,
+    testing::WriteLoopbackMode,
+    testing::EnableDeviceUnderTestMode,
+    testing::WriteSimplePairingDebugMode,
+    testing::WriteSecureConnectionsTestMode,
+    testing::EnableImplementationUnderTestMode,
+    le::LeSetEventMask,
+    le::LeReadBufferSize,
+    le::LeReadLocalSupportedFeatures,
+    le::LeSetRandomAddr,
+    le::LeSetAdvParams,
+    le::LeReadAdvPhysicalChannelTxPower,
+    le::LeSetAdvData,
+    le::LeSetScanResponseData,
+    le::LeSetAdvEnable,
+    le::LeSetScanParams,
+    le::LeSetScanEnable,
+    le::LeCreateConn,
+    le::LeCreateConnCancel,
+    le::LeReadFilterAcceptListSize,
+    le::LeClearFilterAcceptList,
+    le::LeAddDeviceToFilterAcceptList,
+    le::LeRemoveDeviceFromFilterAcceptList,
+    le::LeConnUpdate,
+    le::LeSetHostChannelClassification,
+    le::LeReadChannelMap,
+    le::LeReadRemoteFeatures,
+    le::LeEncrypt,
+    le::LeRand,
+    le::LeEnableEncryption,
+    le::LeReceiverTest,
+    le::LeRemoteConnectionParameterRequestReply,
+    le::LeRemoteConnectionParameterRequestNegativeReply,
+    le::LeLongTermKeyRequestReply,
+    le::LeLongTermKeyRequestNegativeReply,
+    le::LeReadSupportedStates,
+    le::LeTransmitterTest,
+    le::LeTestEnd,
+    le::LeSetDataLength,
+    le::LeReadSuggestedDefaultDataLength,
+    le::LeWriteSuggestedDefaultDataLength,
+    le::LeAddDeviceToResolvingList,
+    le::LeRemoveDeviceFromResolvingList,
+    le::LeClearResolvingList,
+    le::LeReadResolvingListSize,
+    le::LeSetAddrResolutionEnable,
+    le::LeSetResolvablePrivateAddrTimeout,
+    le::LeReadMaxDataLength,
+    le::LeReadPhy,
+    le::LeSetDefaultPhy,
+    le::LeSetPhy,
+    le::LeReceiverTestV2,
+    le::LeTransmitterTestV2,
+    le::LeSetAdvSetRandomAddr,
+    le::LeSetExtAdvParams,
+    le::LeSetExtAdvParamsV2,
+    le::LeSetExtAdvData<'a>,
+    le::LeSetExtScanResponseData<'a>,
+    le::LeSetExtAdvEnable<'a>,
+    le::LeReadMaxAdvDataLength,
+    le::LeReadNumberOfSupportedAdvSets,
+    le::LeRemoveAdvSet,
+    le::LeClearAdvSets,
+    le::LeSetPeriodicAdvParams,
+    le::LeSetPeriodicAdvParamsV2,
+    le::LeSetPeriodicAdvData<'a>,
+    le::LeSetPeriodicAdvEnable,
+    le::LeSetExtScanParams,
+    le::LeSetExtScanEnable,
+    le::LeExtCreateConn,
+    le::LeExtCreateConnV2,
+    le::LePeriodicAdvCreateSync,
+    le::LePeriodicAdvCreateSyncCancel,
+    le::LePeriodicAdvTerminateSync,
+    le::LeAddDeviceToPeriodicAdvList,
+    le::LeRemoveDeviceFromPeriodicAdvList,
+    le::LeClearPeriodicAdvList,
+    le::LeReadPeriodicAdvListSize,
+    le::LeReadTransmitPower,
+    le::LeReadRfPathCompensation,
+    le::LeWriteRfPathCompensation,
+    le::LeSetPrivacyMode,
+    le::LeSetConnectionlessCteTransmitParams<'a>,
+    le::LeSetConnectionlessCteTransmitEnable,
+    le::LeSetConnCteTransmitParams<'a>,
+    le::LeConnCteResponseEnable,
+    le::LeReadAntennaInformation,
+    le::LeSetPeriodicAdvReceiveEnable,
+    le::LeReadBufferSizeV2,
+    le::LePeriodicAdvSyncTransfer,
+    le::LePeriodicAdvSetInfoTransfer,
+    le::LeSetPeriodicAdvSyncTransferParams,
+    le::LeSetDefaultPeriodicAdvSyncTransferParams,
+    le::LeRequestPeerSca,
+    le::LeSetHostFeature,
+    le::LeEnhancedReadTransmitPowerLevel,
+    le::LeReadRemoteTransmitPowerLevel,
+    le::LeSetPathLossReportingParams,
+    le::LeSetPathLossReportingEnable,
+    le::LeSetTransmitPowerReportingEnable,
+    le::LeSetDataRelatedAddrChanges,
+    le::LeSetDefaultSubrate,
+    le::LeSubrateRequest,
+    le::LeSetPeriodicAdvResponseData<'a>,
+    le::LeSetPeriodicSyncSubevent<'a>,
+    le::LeSetHostFeatureV2,
+    le::LeFrameSpaceUpdate,
+    le::LeConnectionRateRequest,
+    le::LeSetDefaultRateParameters,
+    le::LeReadMinimumSupportedConnectionInterval,
+}
+
+impl<'de> FromHciBytes<'de> for Command<'de> {
+    fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), FromHciBytesError> {
+        let (packet, rest) = CmdPacket::from_hci_bytes(data)?;
+        Ok((Self::try_from(packet)?, rest))
+    }
+}
+
+impl<'de> ReadHci<'de> for Command<'de> {
+    const MAX_LEN: usize = 258;
+
+    fn read_hci<R: embedded_io::Read>(reader: R, buf: &'de mut [u8]) -> Result<Self, ReadHciError<R::Error>> {
+        let packet = CmdPacket::read_hci(reader, buf)?;
+        Ok(Self::try_from(packet)?)
+    }
+
+    async fn read_hci_async<R: embedded_io_async::Read>(
+        reader: R,
+        buf: &'de mut [u8],
+    ) -> Result<Self, ReadHciError<R::Error>> {
+        let packet = CmdPacket::read_hci_async(reader, buf).await?;
+        Ok(Self::try_from(packet)?)
+    }
+}
+
+/// Splits a command packet (without the packet indicator) into the parameters of command `C` and any remaining bytes.
+///
+/// Returns [`FromHciBytesError::InvalidValue`](crate::FromHciBytesError::InvalidValue) if the packet carries a different
+/// opcode.
+#[doc(hidden)]
+pub fn split_params<C: Cmd>(data: &[u8]) -> Result<(&[u8], &[u8]), FromHciBytesError> {
+    let (packet, rest) = CmdPacket::from_hci_bytes(data)?;
+    if packet.opcode != C::OPCODE {
+        return Err(FromHciBytesError::InvalidValue);
+    }
+    Ok((packet.params, rest))
+}
+
 #[doc(hidden)]
 #[macro_export]
 macro_rules! cmd {
@@ -413,8 +895,8 @@ macro_rules! cmd {
             }
         }
 
-        impl$(<$life>)? ::bt_hci_driver::PacketToController for $name$(<$life>)? {
-            const KIND: ::bt_hci_driver::PacketKind = ::bt_hci_driver::PacketKind::Cmd;
+        impl$(<$life>)? $crate::PacketToController for $name$(<$life>)? {
+            const KIND: $crate::PacketKind = $crate::PacketKind::Cmd;
 
             #[inline(always)]
             fn size(&self) -> usize {
@@ -447,6 +929,11 @@ macro_rules! cmd {
             }
         }
 
+        $crate::cmd! {
+            FROM_HCI_BYTES
+            $name$(<$life>)? = $params
+        }
+
         $crate::cmd! {
             RETURN
             $name$(<$life>)? {
@@ -457,6 +944,36 @@ macro_rules! cmd {
             }
         }
     };
+    (
+        FROM_HCI_BYTES
+        $name:ident<$life:lifetime> = $params:ty
+    ) => {
+        impl<$life> $crate::FromHciBytes<$life> for $name<$life>
+        where
+            $params: $crate::FromHciBytes<$life>,
+        {
+            fn from_hci_bytes(data: &$life [u8]) -> Result<(Self, &$life [u8]), $crate::FromHciBytesError> {
+                let (params, rest) = $crate::cmd::split_params::<Self>(data)?;
+                let params = <$params as $crate::FromHciBytes>::from_hci_bytes_complete(params)?;
+                Ok((Self(params), rest))
+            }
+        }
+    };
+    (
+        FROM_HCI_BYTES
+        $name:ident = $params:ty
+    ) => {
+        impl<'de> $crate::FromHciBytes<'de> for $name
+        where
+            $params: $crate::FromHciBytes<'de>,
+        {
+            fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), $crate::FromHciBytesError> {
+                let (params, rest) = $crate::cmd::split_params::<Self>(data)?;
+                let params = <$params as $crate::FromHciBytes>::from_hci_bytes_complete(params)?;
+                Ok((Self(params), rest))
+            }
+        }
+    };
     (
         RETURN
         $name:ident$(<$life:lifetime>)? {
@@ -506,3 +1023,166 @@ macro_rules! cmd {
 }
 
 pub use cmd;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::cmd::le::{LeExtCreateConn, LeSetAdvEnable, LeSetPeriodicAdvSubeventData};
+    use crate::param::{AddrKind, AdvHandle, BdAddr, Duration, InitiatingPhy, LePeriodicAdvSubeventData, PhyParams};
+    use crate::{ControllerToHostPacket, HostToControllerPacket, PacketKind};
+
+    fn find_command(opcode: Opcode, buf: &mut [u8; 258]) -> Option<usize> {
+        for fill in 0..=255 {
+            for len in 0..=255 {
+                buf[..2].copy_from_slice(&opcode.to_raw().to_le_bytes());
+                buf[2] = len as u8;
+                buf[3..3 + len].fill(fill);
+                if let Ok(cmd) = Command::from_hci_bytes_complete(&buf[..3 + len]) {
+                    if !matches!(cmd, Command::Unknown { .. }) {
+                        return Some(3 + len);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_decode_every_command() {
+        let mut buf = [0; 258];
+        let mut out = [0; 258];
+        for &opcode in OPCODES {
+            let len = find_command(opcode, &mut buf)
+                .unwrap_or_else(|| ::core::panic!("no encoding found for command {:#06x}", opcode.to_raw()));
+            let cmd = Command::from_hci_bytes_complete(&buf[..len]).unwrap();
+            assert_eq!(cmd.opcode(), opcode);
+            assert_eq!(cmd.size(), len);
+            cmd.write_hci(&mut out[..]).unwrap();
+            assert_eq!(&out[..len], &buf[..len], "command {:#06x}", opcode.to_raw());
+        }
+    }
+
+    #[test]
+    fn test_decode_periodic_adv_subevent_data() {
+        let subevents = [
+            LePeriodicAdvSubeventData {
+                subevent: 1,
+                response_slot_start: 2,
+                response_slot_count: 3,
+                subevent_data: &[0xaa, 0xbb],
+            },
+            LePeriodicAdvSubeventData {
+                subevent: 4,
+                response_slot_start: 5,
+                response_slot_count: 6,
+                subevent_data: &[],
+            },
+        ];
+        let mut buf = [0; 16];
+        let cmd = LeSetPeriodicAdvSubeventData::new(AdvHandle::new(7), &subevents);
+        cmd.write_hci(&mut buf[..]).unwrap();
+        let data = &buf[..cmd.size()];
+
+        let Command::LeSetPeriodicAdvSubeventData(decoded) = Command::from_hci_bytes_complete(data).unwrap() else {
+            ::core::panic!("unexpected command");
+        };
+        assert_eq!(decoded.adv_handle, AdvHandle::new(7));
+        assert_eq!(decoded.subevents.len(), 2);
+        let mut iter = decoded.subevents.iter();
+        assert_eq!(iter.next(), Some(Ok(subevents[0])));
+        assert_eq!(iter.next(), Some(Ok(subevents[1])));
+        assert_eq!(iter.next(), None);
+
+        let mut out = [0; 16];
+        decoded.write_hci(&mut out[..]).unwrap();
+        assert_eq!(&out[..decoded.size()], data);
+    }
+
+    #[test]
+    fn test_decode_unknown_command() {
+        let data = [0x01, 0xfc, 2, 0xaa, 0xbb];
+        let cmd = Command::from_hci_bytes_complete(&data).unwrap();
+        assert_eq!(
+            cmd,
+            Command::Unknown {
+                opcode: Opcode::new(OpcodeGroup::VENDOR_SPECIFIC, 0x001),
+                params: &[0xaa, 0xbb],
+            }
+        );
+        let mut out = [0; 5];
+        cmd.write_hci(&mut out[..]).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_decode_typed_command() {
+        let data = [0x0a, 0x20, 1, 1];
+        let (cmd, rest) = Command::from_hci_bytes(&data).unwrap();
+        assert_eq!(cmd, Command::LeSetAdvEnable(LeSetAdvEnable::new(true)));
+        assert!(rest.is_empty());
+
+        let (cmd, _) = LeSetAdvEnable::from_hci_bytes(&data).unwrap();
+        assert_eq!(cmd, LeSetAdvEnable::new(true));
+        assert_eq!(Reset::from_hci_bytes(&data), Err(FromHciBytesError::InvalidValue));
+        assert_eq!(Command::from_hci_bytes(&data[..3]), Err(FromHciBytesError::InvalidSize));
+    }
+
+    #[test]
+    fn test_decode_ext_create_conn() {
+        let phy = InitiatingPhy {
+            scan_interval: Duration::from_millis(60),
+            scan_window: Duration::from_millis(30),
+            conn_interval_min: Duration::from_millis(30),
+            conn_interval_max: Duration::from_millis(50),
+            max_latency: 0,
+            supervision_timeout: Duration::from_millis(400),
+            min_ce_len: Duration::from_millis(0),
+            max_ce_len: Duration::from_millis(0),
+        };
+        let cmd = LeExtCreateConn::new(
+            false,
+            AddrKind::PUBLIC,
+            AddrKind::RANDOM,
+            BdAddr::new([1, 2, 3, 4, 5, 6]),
+            PhyParams {
+                le_1m_phy: Some(phy),
+                le_2m_phy: None,
+                le_coded_phy: Some(phy),
+            },
+        );
+        let mut buf = [0; 258];
+        cmd.write_hci(&mut buf[..]).unwrap();
+        let (decoded, _) = Command::from_hci_bytes(&buf[..cmd.size()]).unwrap();
+        assert_eq!(decoded, Command::LeExtCreateConn(cmd));
+
+        // Reserved PHY bits cannot be represented
+        buf[12] |= 0x08;
+        assert_eq!(
+            Command::from_hci_bytes(&buf[..cmd.size()]),
+            Err(FromHciBytesError::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn test_host_to_controller_packet() {
+        let data = [0x01, 0x03, 0x0c, 0x00];
+        let (pkt, _) = HostToControllerPacket::from_hci_bytes(&data).unwrap();
+        assert_eq!(pkt.kind(), PacketKind::Cmd);
+        let HostToControllerPacket::Cmd(pkt) = pkt else {
+            unreachable!()
+        };
+        assert_eq!(Command::try_from(pkt), Ok(Command::Reset(Reset::new())));
+
+        let mut buf = [0; 258];
+        let pkt = HostToControllerPacket::read_hci(&data[..], &mut buf).unwrap();
+        assert!(matches!(
+            pkt,
+            HostToControllerPacket::Cmd(CmdPacket { params: &[], .. })
+        ));
+
+        let event = [0x04, 0x0e, 0x00];
+        assert!(HostToControllerPacket::from_hci_bytes(&event).is_err());
+        assert!(ControllerToHostPacket::from_hci_bytes(&data).is_err());
+    }
+}
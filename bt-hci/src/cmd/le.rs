@@ -4,12 +4,12 @@ use crate::param::{
     AddrKind, AdvChannelMap, AdvEventProps, AdvFilterPolicy, AdvHandle, AdvKind, AdvPhyOptions, AdvSet, AllPhys,
     BdAddr, ChannelMap, ConnHandle, ConnIntervalGroup, CteKind, CteMask, Duration, DurationU8, ExtDuration,
     FilterDuplicates, InitiatingPhy, LeDataRelatedAddrChangeReasons, LeEventMask, LeFeatureMask,
-    LePeriodicAdvCreateSyncOptions, LePeriodicAdvReceiveEnable, LePeriodicAdvSubeventData,
+    LePeriodicAdvCreateSyncOptions, LePeriodicAdvReceiveEnable, LePeriodicAdvSubeventData, LePeriodicAdvSubevents,
     LePeriodicAdvSyncTransferMode, LeScanKind, Operation, PeriodicAdvProps, PhyKind, PhyMask, PhyOptions, PhyParams,
     PrivacyMode, RemoteConnectionParamsRejectReason, ScanningFilterPolicy, ScanningPhy, SpacingTypes,
     SwitchingSamplingRates, SyncHandle,
 };
-use crate::{cmd, WriteHci};
+use crate::{cmd, FromHciBytes, FromHciBytesError, WriteHci};
 
 cmd! {
     /// LE Set Event Mask command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html#UUID-8d6890a5-79b9-ba8a-2079-4efa3128263c)
@@ -329,7 +329,7 @@ cmd! {
 
 cmd! {
     /// LE Transmitter Test command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html#UUID-15c2cfce-06a0-5da7-5cbb-45c1896cca8d)
-    LeTransmitterTest(LE, 0x001c) {
+    LeTransmitterTest(LE, 0x001e) {
         LeTransmitterTestParams {
             tx_frequency: u8,
             length_of_test_data: u8,
@@ -751,6 +751,20 @@ impl WriteHci for LeSetExtScanParamsParams {
     }
 }
 
+impl<'de> FromHciBytes<'de> for LeSetExtScanParamsParams {
+    fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), FromHciBytesError> {
+        let (own_addr_kind, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (scanning_filter_policy, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (scanning_phys, data) = FromHciBytes::from_hci_bytes(data)?;
+        let params = Self {
+            own_addr_kind,
+            scanning_filter_policy,
+            scanning_phys,
+        };
+        Ok((params, data))
+    }
+}
+
 cmd! {
     /// LE Set Extended Scan Enable command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html#UUID-bfe8407c-4def-2ded-51dd-e47cf9e8916c)
     LeSetExtScanEnable(LE, 0x0042) {
@@ -834,6 +848,24 @@ impl WriteHci for LeExtCreateConnParams {
     }
 }
 
+impl<'de> FromHciBytes<'de> for LeExtCreateConnParams {
+    fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), FromHciBytesError> {
+        let (initiator_filter_policy, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (own_addr_kind, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (peer_addr_kind, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (peer_addr, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (initiating_phys, data) = FromHciBytes::from_hci_bytes(data)?;
+        let params = Self {
+            initiator_filter_policy,
+            own_addr_kind,
+            peer_addr_kind,
+            peer_addr,
+            initiating_phys,
+        };
+        Ok((params, data))
+    }
+}
+
 cmd! {
     BASE
     /// LE Extended Create Connection (v2) command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html#UUID-1dad213e-f660-2937-c94d-7a3162e94105)
@@ -918,6 +950,28 @@ impl WriteHci for LeExtCreateConnV2Params {
     }
 }
 
+impl<'de> FromHciBytes<'de> for LeExtCreateConnV2Params {
+    fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), FromHciBytesError> {
+        let (adv_handle, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (subevent, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (initiator_filter_policy, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (own_addr_kind, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (peer_addr_kind, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (peer_addr, data) = FromHciBytes::from_hci_bytes(data)?;
+        let (initiating_phys, data) = FromHciBytes::from_hci_bytes(data)?;
+        let params = Self {
+            adv_handle,
+            subevent,
+            initiator_filter_policy,
+            own_addr_kind,
+            peer_addr_kind,
+            peer_addr,
+            initiating_phys,
+        };
+        Ok((params, data))
+    }
+}
+
 cmd! {
     /// LE Periodic Advertising Create Sync command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html#UUID-29188ef0-bf80-7807-2c96-385e7d9782ed)
     LePeriodicAdvCreateSync(LE, 0x0044) {
@@ -1337,6 +1391,55 @@ impl WriteHci for LeSetPeriodicAdvSubeventDataParams<'_, '_> {
     }
 }
 
+/// LE Set Periodic Advertising Subevent Data command decoded from its HCI bytes [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html#UUID-927cb8c3-4a12-6154-d2f2-384f4a10f0a4)
+///
+/// This is the form of [`LeSetPeriodicAdvSubeventData`] returned in [`Command`](super::Command), whose subevents are
+/// parsed lazily by [`LePeriodicAdvSubevents::iter`] instead of being collected into a slice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LeSetPeriodicAdvSubeventDataRef<'a> {
+    /// Which advertising handle to use.
+    pub adv_handle: AdvHandle,
+    /// List of sub events used.
+    pub subevents: LePeriodicAdvSubevents<'a>,
+}
+
+impl<'de> FromHciBytes<'de> for LeSetPeriodicAdvSubeventDataRef<'de> {
+    fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), FromHciBytesError> {
+        let (adv_handle, data) = AdvHandle::from_hci_bytes(data)?;
+        let (subevents, rest) = LePeriodicAdvSubevents::from_hci_bytes(data)?;
+        Ok((Self { adv_handle, subevents }, rest))
+    }
+}
+
+impl WriteHci for LeSetPeriodicAdvSubeventDataRef<'_> {
+    #[inline(always)]
+    fn size(&self) -> usize {
+        let params_len = self.adv_handle.size() + self.subevents.size();
+        3 + params_len
+    }
+
+    #[inline(always)]
+    fn write_hci<W: ::embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+        let params_len = self.size() - 3;
+        <LeSetPeriodicAdvSubeventData as super::Cmd>::OPCODE.write_hci(&mut writer)?;
+        (params_len as u8).write_hci(&mut writer)?;
+        self.adv_handle.write_hci(&mut writer)?;
+        self.subevents.write_hci(&mut writer)
+    }
+
+    #[inline(always)]
+    async fn write_hci_async<W: ::embedded_io_async::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+        let params_len = self.size() - 3;
+        <LeSetPeriodicAdvSubeventData as super::Cmd>::OPCODE
+            .write_hci_async(&mut writer)
+            .await?;
+        (params_len as u8).write_hci_async(&mut writer).await?;
+        self.adv_handle.write_hci_async(&mut writer).await?;
+        self.subevents.write_hci_async(&mut writer).await
+    }
+}
+
 cmd! {
     /// LE Set Periodic Advertising Response Data command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html#UUID-8ada75aa-8e1f-c742-6441-8dd1164fc646)
     LeSetPeriodicAdvResponseData(LE, 0x0083) {
@@ -1437,3 +1540,21 @@ cmd! {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::*;
+
+    #[test]
+    fn test_transmitter_test() {
+        let cmd = LeTransmitterTest::new(0x13, 0x25, 0x02);
+        assert_eq!(LeTransmitterTest::OPCODE.group(), OpcodeGroup::LE);
+        assert_eq!(LeTransmitterTest::OPCODE.cmd(), 0x001e);
+        assert_ne!(LeTransmitterTest::OPCODE, LeReadSupportedStates::OPCODE);
+
+        let mut buf = [0; 6];
+        cmd.write_hci(&mut buf[..]).unwrap();
+        assert_eq!(buf, [0x1e, 0x20, 0x03, 0x13, 0x25, 0x02]);
+    }
+}
@@ -0,0 +1,87 @@
+//! Testing commands [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html)
+
+use super::cmd;
+use crate::param::{ConnHandle, LoopbackMode};
+
+cmd! {
+    /// Read Loopback Mode command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html)
+    ReadLoopbackMode(TESTING, 0x0001) {
+        Params = ();
+        Return = LoopbackMode;
+    }
+}
+
+cmd! {
+    /// Write Loopback Mode command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html)
+    ///
+    /// In local loopback mode, the controller sends every command back in a
+    /// [`LoopbackCommand`](crate::event::LoopbackCommand) event, and every data packet back to the host.
+    WriteLoopbackMode(TESTING, 0x0002) {
+        Params = LoopbackMode;
+        Return = ();
+    }
+}
+
+cmd! {
+    /// Enable Device Under Test Mode command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html)
+    EnableDeviceUnderTestMode(TESTING, 0x0003) {
+        Params = ();
+        Return = ();
+    }
+}
+
+cmd! {
+    /// Write Simple Pairing Debug Mode command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html)
+    ///
+    /// When enabled, the controller uses the debug key pair for Secure Simple Pairing.
+    WriteSimplePairingDebugMode(TESTING, 0x0004) {
+        Params = bool;
+        Return = ();
+    }
+}
+
+cmd! {
+    /// Write Secure Connections Test Mode command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html)
+    WriteSecureConnectionsTestMode(TESTING, 0x000a) {
+        WriteSecureConnectionsTestModeParams {
+            dm1_acl_u_disabled: bool,
+            esco_loopback_enabled: bool,
+        }
+        Return = ConnHandle;
+        Handle = handle: ConnHandle;
+    }
+}
+
+cmd! {
+    /// Enable Implementation Under Test Mode command [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/host-controller-interface-functional-specification.html)
+    EnableImplementationUnderTestMode(TESTING, 0x000c) {
+        Params = ();
+        Return = ();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::*;
+    use crate::WriteHci;
+
+    #[test]
+    fn test_write_loopback_mode() {
+        let cmd = WriteLoopbackMode::new(LoopbackMode::Local);
+        assert_eq!(WriteLoopbackMode::OPCODE.group(), OpcodeGroup::TESTING);
+        assert_eq!(WriteLoopbackMode::OPCODE.cmd(), 0x0002);
+
+        let mut buf = [0; 4];
+        cmd.write_hci(&mut buf[..]).unwrap();
+        assert_eq!(buf, [0x02, 0x18, 1, 0x01]);
+    }
+
+    #[test]
+    fn test_write_secure_connections_test_mode() {
+        let cmd = WriteSecureConnectionsTestMode::new(ConnHandle::new(0x0001), true, false);
+        let mut buf = [0; 7];
+        cmd.write_hci(&mut buf[..]).unwrap();
+        assert_eq!(buf, [0x0a, 0x18, 4, 0x01, 0x00, 0x01, 0x00]);
+    }
+}
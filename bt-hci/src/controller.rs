@@ -9,18 +9,20 @@ use core::task::Poll;
 
 use bt_hci_driver::ReadHciError;
 use cmd::controller_baseband::Reset;
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::raw::{NoopRawMutex, RawMutex};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::semaphore::{GreedySemaphore, Semaphore};
 use embassy_sync::signal::Signal;
-use embassy_sync::waitqueue::AtomicWaker;
+use embassy_sync::waitqueue::MultiWakerRegistration;
 use embedded_io::ErrorType;
-use futures_intrusive::sync::LocalSemaphore;
 
 use crate::cmd::{Cmd, CmdReturnBuf};
 use crate::event::{CommandComplete, CommandCompleteWithStatus, CommandStatus, EventKind};
 use crate::param::{RemainingBytes, Status};
 use crate::transport::Transport;
-use crate::{cmd, data, ControllerToHostPacket, FixedSizeValue, FromHciBytes};
+use crate::{cmd, data, ControllerToHostPacket, FromHciBytes};
 
+pub mod baud;
 pub mod blocking;
 
 /// Trait representing a HCI controller which supports async operations.
@@ -64,12 +66,17 @@ pub trait ControllerCmdAsync<C: cmd::AsyncCmd + ?Sized>: Controller {
 ///
 /// The contract is that before sending a command, a slot is reserved, which
 /// returns a signal handle that can be used to await a response.
-pub struct ExternalController<T, const SLOTS: usize> {
+///
+/// The `M` parameter selects the [`RawMutex`] guarding the command slots. The default
+/// [`NoopRawMutex`] is suitable when the controller is only used from a single executor.
+/// Use a `CriticalSectionRawMutex` or `ThreadModeRawMutex` to share the controller across
+/// executors, interrupt priorities or threads.
+pub struct ExternalController<T, const SLOTS: usize, M: RawMutex = NoopRawMutex> {
     transport: T,
-    slots: ControllerState<SLOTS>,
+    slots: ControllerState<M, SLOTS>,
 }
 
-impl<T, const SLOTS: usize> ExternalController<T, SLOTS> {
+impl<T, const SLOTS: usize, M: RawMutex> ExternalController<T, SLOTS, M> {
     /// Create a new instance.
     pub fn new(transport: T) -> Self {
         Self {
@@ -79,14 +86,14 @@ impl<T, const SLOTS: usize> ExternalController<T, SLOTS> {
     }
 }
 
-impl<T, const SLOTS: usize> ErrorType for ExternalController<T, SLOTS>
+impl<T, const SLOTS: usize, M: RawMutex> ErrorType for ExternalController<T, SLOTS, M>
 where
     T: ErrorType,
 {
     type Error = T::Error;
 }
 
-impl<T, const SLOTS: usize> Controller for ExternalController<T, SLOTS>
+impl<T, const SLOTS: usize, M: RawMutex> Controller for ExternalController<T, SLOTS, M>
 where
     T: Transport,
     T::Error: From<ReadHciError<Infallible>>,
@@ -150,7 +157,7 @@ where
     }
 }
 
-impl<T, const SLOTS: usize> blocking::Controller for ExternalController<T, SLOTS>
+impl<T, const SLOTS: usize, M: RawMutex> blocking::Controller for ExternalController<T, SLOTS, M>
 where
     T: crate::transport::blocking::Transport,
     T::Error: From<ReadHciError<Infallible>>,
@@ -259,14 +266,28 @@ where
     }
 }
 
-impl<T, C, const SLOTS: usize> ControllerCmdSync<C> for ExternalController<T, SLOTS>
+impl<T, C, const SLOTS: usize, M: RawMutex> ControllerCmdSync<C> for ExternalController<T, SLOTS, M>
 where
     T: Transport,
     C: cmd::SyncCmd,
-    C::Return: FixedSizeValue,
     T::Error: From<ReadHciError<Infallible>>,
 {
     async fn exec(&self, cmd: &C) -> Result<C::Return, cmd::Error<Self::Error>> {
+        self.exec_then(cmd, || async { Ok(()) }).await
+    }
+}
+
+impl<T, const SLOTS: usize, M: RawMutex> ExternalController<T, SLOTS, M>
+where
+    T: Transport,
+    T::Error: From<ReadHciError<Infallible>>,
+{
+    /// Execute `cmd`, running `written` once the command has been written to the transport.
+    async fn exec_then<C, F>(&self, cmd: &C, written: impl FnOnce() -> F) -> Result<C::Return, cmd::Error<T::Error>>
+    where
+        C: cmd::SyncCmd,
+        F: Future<Output = Result<(), T::Error>>,
+    {
         let mut retval: C::ReturnBuf = C::ReturnBuf::new();
 
         //info!("Executing command with opcode {}", C::OPCODE);
@@ -276,6 +297,7 @@ where
         });
 
         self.transport.write(cmd).await.map_err(cmd::Error::Io)?;
+        written().await.map_err(cmd::Error::Io)?;
 
         let result = slot.wait().await;
         let return_param_bytes = RemainingBytes::from_hci_bytes_complete(&retval.as_ref()[..result.len]).unwrap();
@@ -291,7 +313,7 @@ where
     }
 }
 
-impl<T, C, const SLOTS: usize> ControllerCmdAsync<C> for ExternalController<T, SLOTS>
+impl<T, C, const SLOTS: usize, M: RawMutex> ControllerCmdAsync<C> for ExternalController<T, SLOTS, M>
 where
     T: Transport,
     C: cmd::AsyncCmd,
@@ -311,11 +333,11 @@ where
     }
 }
 
-struct ControllerState<const SLOTS: usize> {
-    permits: LocalSemaphore,
-    slots: RefCell<[CommandSlot; SLOTS]>,
-    signals: [Signal<NoopRawMutex, CommandResponse>; SLOTS],
-    waker: AtomicWaker,
+struct ControllerState<M: RawMutex, const SLOTS: usize> {
+    permits: GreedySemaphore<M>,
+    slots: Mutex<M, RefCell<[CommandSlot; SLOTS]>>,
+    signals: [Signal<M, CommandResponse>; SLOTS],
+    waiters: Mutex<M, RefCell<MultiWakerRegistration<SLOTS>>>,
 }
 
 struct CommandResponse {
@@ -325,109 +347,136 @@ struct CommandResponse {
 
 enum CommandSlot {
     Empty,
-    Pending { opcode: u16, event: *mut [u8] },
+    Pending { opcode: u16, event: ReturnBuffer },
+    Completed { opcode: u16 },
 }
 
-impl<const SLOTS: usize> Default for ControllerState<SLOTS> {
+/// Pointer to the return parameter buffer of a command waiting for completion.
+///
+/// The buffer is owned by the future executing the command. It is only written while the slot is
+/// [`CommandSlot::Pending`] and the slot mutex is held, and the owning future releases its slot
+/// under the same mutex before the buffer goes out of scope.
+struct ReturnBuffer(*mut [u8]);
+
+// Safety: the pointer is only dereferenced with the slot mutex held and while the owning future
+// keeps the buffer alive, which does not depend on which thread completes the command.
+unsafe impl Send for ReturnBuffer {}
+
+impl<M: RawMutex, const SLOTS: usize> Default for ControllerState<M, SLOTS> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<const SLOTS: usize> ControllerState<SLOTS> {
+impl<M: RawMutex, const SLOTS: usize> ControllerState<M, SLOTS> {
     const EMPTY_SLOT: CommandSlot = CommandSlot::Empty;
     #[allow(clippy::declare_interior_mutable_const)]
-    const EMPTY_SIGNAL: Signal<NoopRawMutex, CommandResponse> = Signal::new();
+    const EMPTY_SIGNAL: Signal<M, CommandResponse> = Signal::new();
 
     fn new() -> Self {
         Self {
-            permits: LocalSemaphore::new(true, 1),
-            slots: RefCell::new([Self::EMPTY_SLOT; SLOTS]),
+            permits: GreedySemaphore::new(1),
+            slots: Mutex::new(RefCell::new([Self::EMPTY_SLOT; SLOTS])),
             signals: [Self::EMPTY_SIGNAL; SLOTS],
-            waker: AtomicWaker::new(),
+            waiters: Mutex::new(RefCell::new(MultiWakerRegistration::new())),
         }
     }
 
     fn complete(&self, op: cmd::Opcode, status: Status, num_hci_command_packets: usize, data: &[u8]) {
-        let mut slots = self.slots.borrow_mut();
-        for (idx, slot) in slots.iter_mut().enumerate() {
-            match slot {
-                CommandSlot::Pending { opcode, event } if *opcode == op.to_raw() => {
-                    if !data.is_empty() {
-                        assert!(!event.is_null());
-                        // Safety: since the slot is in pending, the caller stack will be valid.
-                        unsafe { (&mut (**event))[..data.len()].copy_from_slice(data) };
+        self.slots.lock(|slots| {
+            let mut slots = slots.borrow_mut();
+            for (idx, slot) in slots.iter_mut().enumerate() {
+                match slot {
+                    CommandSlot::Pending { opcode, event } if *opcode == op.to_raw() => {
+                        if !data.is_empty() {
+                            assert!(!event.0.is_null());
+                            // Safety: since the slot is in pending, the caller stack will be valid.
+                            unsafe { (&mut (*event.0))[..data.len()].copy_from_slice(data) };
+                        }
+                        self.signals[idx].signal(CommandResponse {
+                            status,
+                            len: data.len(),
+                        });
+                        // The waiting future now reads the buffer, so it must not be written again.
+                        *slot = CommandSlot::Completed { opcode: op.to_raw() };
+                        if op != Reset::OPCODE {
+                            break;
+                        }
                     }
-                    self.signals[idx].signal(CommandResponse {
-                        status,
-                        len: data.len(),
-                    });
-                    if op != Reset::OPCODE {
-                        break;
+                    CommandSlot::Pending { opcode, event: _ } if op == Reset::OPCODE => {
+                        // Signal other commands
+                        let opcode = *opcode;
+                        self.signals[idx].signal(CommandResponse {
+                            status: Status::CONTROLLER_BUSY,
+                            len: 0,
+                        });
+                        *slot = CommandSlot::Completed { opcode };
                     }
+                    _ => {}
                 }
-                CommandSlot::Pending { opcode: _, event: _ } if op == Reset::OPCODE => {
-                    // Signal other commands
-                    self.signals[idx].signal(CommandResponse {
-                        status: Status::CONTROLLER_BUSY,
-                        len: 0,
-                    });
-                }
-                _ => {}
             }
-        }
+        });
 
-        // Adjust the semaphore permits ensuring we don't grant more than num_hci_cmd_pkts
-        self.permits
-            .release(num_hci_command_packets.saturating_sub(self.permits.permits()));
+        // The controller reports how many command packets it is able to accept right now
+        self.permits.set(num_hci_command_packets);
     }
 
     fn release_slot(&self, idx: usize) {
-        let mut slots = self.slots.borrow_mut();
-        slots[idx] = CommandSlot::Empty;
-    }
-
-    async fn acquire(&self, op: cmd::Opcode, event: *mut [u8]) -> (&Signal<NoopRawMutex, CommandResponse>, usize) {
-        let to_acquire = if op == Reset::OPCODE { self.permits.permits() } else { 1 };
-        let mut permit = self.permits.acquire(to_acquire).await;
-        permit.disarm();
-        poll_fn(|cx| match self.acquire_slot(op, event) {
-            Some(ret) => Poll::Ready(ret),
-            None => {
-                self.waker.register(cx.waker());
-                Poll::Pending
+        self.slots.lock(|slots| {
+            let mut slots = slots.borrow_mut();
+            slots[idx] = CommandSlot::Empty;
+        });
+        self.waiters.lock(|waiters| waiters.borrow_mut().wake());
+    }
+
+    async fn acquire(&self, op: cmd::Opcode, event: *mut [u8]) -> (&Signal<M, CommandResponse>, usize) {
+        if op == Reset::OPCODE {
+            // Reset is always allowed, claim whatever permits are available without waiting.
+            if let Some(permit) = self.permits.try_acquire_all(0) {
+                permit.disarm();
+            }
+        } else {
+            let Ok(permit) = self.permits.acquire(1).await;
+            permit.disarm();
+        }
+        poll_fn(|cx| {
+            // Register before looking for a slot, so a slot released in between still wakes us
+            self.waiters.lock(|waiters| waiters.borrow_mut().register(cx.waker()));
+            match self.acquire_slot(op, event) {
+                Some(ret) => Poll::Ready(ret),
+                None => Poll::Pending,
             }
         })
         .await
     }
 
-    fn acquire_slot(
-        &self,
-        op: cmd::Opcode,
-        event: *mut [u8],
-    ) -> Option<(&Signal<NoopRawMutex, CommandResponse>, usize)> {
-        let mut slots = self.slots.borrow_mut();
-        // Make sure there are no existing command with this opcode
-        for slot in slots.iter() {
-            match slot {
-                CommandSlot::Pending { opcode, event: _ } if *opcode == op.to_raw() => {
-                    return None;
+    fn acquire_slot(&self, op: cmd::Opcode, event: *mut [u8]) -> Option<(&Signal<M, CommandResponse>, usize)> {
+        self.slots.lock(|slots| {
+            let mut slots = slots.borrow_mut();
+            // Make sure there are no existing command with this opcode
+            for slot in slots.iter() {
+                match slot {
+                    CommandSlot::Pending { opcode, event: _ } | CommandSlot::Completed { opcode }
+                        if *opcode == op.to_raw() =>
+                    {
+                        return None;
+                    }
+                    _ => {}
                 }
-                _ => {}
             }
-        }
-        // Reserve our slot
-        for (idx, slot) in slots.iter_mut().enumerate() {
-            if matches!(slot, CommandSlot::Empty) {
-                *slot = CommandSlot::Pending {
-                    opcode: op.to_raw(),
-                    event,
-                };
-                self.signals[idx].reset();
-                return Some((&self.signals[idx], idx));
+            // Reserve our slot
+            for (idx, slot) in slots.iter_mut().enumerate() {
+                if matches!(slot, CommandSlot::Empty) {
+                    *slot = CommandSlot::Pending {
+                        opcode: op.to_raw(),
+                        event: ReturnBuffer(event),
+                    };
+                    self.signals[idx].reset();
+                    return Some((&self.signals[idx], idx));
+                }
             }
-        }
-        None
+            None
+        })
     }
 }
 
@@ -452,70 +501,185 @@ impl<F: FnOnce()> Drop for OnDrop<F> {
 
 #[cfg(test)]
 mod tests {
-    use bt_hci_driver::{PacketKind, PacketToController, PacketToHost};
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 
     use super::*;
+    use crate::cmd::SyncCmd;
+    use crate::mock::{MockTransport, Step};
 
-    pub struct TestTransport<'d> {
-        pub rx: &'d [u8],
+    const RESET: Step = Step::Write(&[1, 0x03, 0x0c, 0]);
+
+    #[futures_test::test]
+    pub async fn test_can_handle_unsolicited_command_complete() {
+        let script = [Step::Read(&[
+            4, 0x0e, 3, // header
+            1, 0, 0, // special command
+        ])];
+        let c: ExternalController<MockTransport, 10> = ExternalController::new(MockTransport::new(&script));
+
+        let mut buf = c.alloc_buf().unwrap();
+        let pkt = c.read(&mut buf).await;
+        assert!(pkt.is_ok());
     }
 
-    #[derive(Clone, Copy, Debug, PartialEq)]
-    pub struct Error;
+    #[test]
+    fn test_controller_is_send_sync_with_sync_mutex() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ExternalController<(), 4, CriticalSectionRawMutex>>();
+        assert_send_sync::<ExternalController<MockTransport<CriticalSectionRawMutex>, 4, CriticalSectionRawMutex>>();
+    }
 
-    impl core::fmt::Display for Error {
-        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-            write!(f, "{:?}", self)
-        }
+    #[futures_test::test]
+    pub async fn test_exec_with_sync_mutex() {
+        let script = [
+            RESET,
+            Step::Read(&[
+                4, 0x0e, 4, // header
+                1, 0x03, 0x0c, // reset opcode
+                0,    // success
+            ]),
+        ];
+        let c: ExternalController<MockTransport<CriticalSectionRawMutex>, 4, CriticalSectionRawMutex> =
+            ExternalController::new(MockTransport::new(&script));
+
+        let mut buf = c.alloc_buf().unwrap();
+        let (res, _) = embassy_futures::join::join(Reset::new().exec(&c), c.read(&mut buf)).await;
+        assert!(res.is_ok());
+        c.transport.assert_done();
+    }
+
+    #[test]
+    fn test_all_slot_waiters_are_woken() {
+        use core::pin::pin;
+        use core::task::Context;
+
+        use futures_test::task::new_count_waker;
+
+        use crate::cmd::OpcodeGroup;
+
+        let op = |ocf| cmd::Opcode::new(OpcodeGroup::LE, ocf);
+        let no_return = || core::ptr::slice_from_raw_parts_mut(core::ptr::null_mut(), 0);
+        let state = ControllerState::<NoopRawMutex, 2>::new();
+        state.permits.set(4);
+        let (_, first) = state.acquire_slot(op(1), no_return()).unwrap();
+        let (_, second) = state.acquire_slot(op(2), no_return()).unwrap();
+
+        let mut a = pin!(state.acquire(op(3), no_return()));
+        let mut b = pin!(state.acquire(op(4), no_return()));
+        let (waker_a, woken_a) = new_count_waker();
+        let (waker_b, woken_b) = new_count_waker();
+        assert!(a.as_mut().poll(&mut Context::from_waker(&waker_a)).is_pending());
+        assert!(b.as_mut().poll(&mut Context::from_waker(&waker_b)).is_pending());
+
+        state.release_slot(first);
+        assert_eq!(woken_a.get(), 1);
+        assert_eq!(woken_b.get(), 1);
+        let Poll::Ready((_, idx)) = a.as_mut().poll(&mut Context::from_waker(&waker_a)) else {
+            panic!("expected a free slot");
+        };
+        assert!(b.as_mut().poll(&mut Context::from_waker(&waker_b)).is_pending());
+
+        state.release_slot(second);
+        assert_eq!(woken_b.get(), 2);
+        assert!(b.as_mut().poll(&mut Context::from_waker(&waker_b)).is_ready());
+        state.release_slot(idx);
     }
 
-    impl core::error::Error for Error {}
+    #[futures_test::test]
+    pub async fn test_duplicate_command_complete_is_ignored() {
+        let script = [
+            RESET,
+            Step::Read(&[
+                4, 0x0e, 4, // header
+                1, 0x03, 0x0c, // reset opcode
+                0,    // success
+            ]),
+            Step::Read(&[
+                4, 0x0e, 4, // header
+                1, 0x03, 0x0c, // reset opcode
+                0x0c, // command disallowed
+            ]),
+        ];
+        let c: ExternalController<MockTransport<CriticalSectionRawMutex>, 4, CriticalSectionRawMutex> =
+            ExternalController::new(MockTransport::new(&script));
 
-    impl From<ReadHciError<Infallible>> for Error {
-        fn from(_: ReadHciError<Infallible>) -> Self {
-            Self
+        let mut buf = c.alloc_buf().unwrap();
+        let (res, _) = embassy_futures::join::join(Reset::new().exec(&c), c.read(&mut buf)).await;
+        assert!(res.is_ok());
+        c.transport.assert_done();
+    }
+
+    /// A length-prefixed return value, which is not a [`FixedSizeValue`](crate::FixedSizeValue).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Name {
+        pub len: u8,
+        pub bytes: [u8; 8],
+    }
+
+    impl<'de> FromHciBytes<'de> for Name {
+        fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), crate::FromHciBytesError> {
+            let (name, rest) = <&[u8]>::from_hci_bytes(data)?;
+            let mut bytes = [0; 8];
+            bytes
+                .get_mut(..name.len())
+                .ok_or(crate::FromHciBytesError::InvalidSize)?
+                .copy_from_slice(name);
+            Ok((
+                Self {
+                    len: name.len() as u8,
+                    bytes,
+                },
+                rest,
+            ))
         }
     }
 
-    impl ErrorType for TestTransport<'_> {
-        type Error = Error;
-    }
-    impl embedded_io::Error for Error {
-        fn kind(&self) -> embedded_io::ErrorKind {
-            embedded_io::ErrorKind::Other
+    impl<'de> crate::ReadHci<'de> for Name {
+        const MAX_LEN: usize = 9;
+
+        fn read_hci<R: embedded_io::Read>(mut reader: R, buf: &'de mut [u8]) -> Result<Self, ReadHciError<R::Error>> {
+            reader.read_exact(&mut buf[..1])?;
+            let len = 1 + usize::from(buf[0]);
+            reader.read_exact(&mut buf[1..len])?;
+            Ok(Self::from_hci_bytes(&buf[..len])?.0)
         }
-    }
-    impl Transport for TestTransport<'_> {
-        fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> impl Future<Output = Result<P, Self::Error>> {
-            async {
-                let to_read = rx.len().min(self.rx.len());
-                let mut reader = &self.rx[..to_read];
-                let kind = PacketKind::read(&mut reader)?;
-                let pkt = P::read_hci(kind, &mut reader, rx)?;
-                if !reader.is_empty() {
-                    return Err(Error);
-                }
-                Ok(pkt)
-            }
+
+        async fn read_hci_async<R: embedded_io_async::Read>(
+            mut reader: R,
+            buf: &'de mut [u8],
+        ) -> Result<Self, ReadHciError<R::Error>> {
+            reader.read_exact(&mut buf[..1]).await?;
+            let len = 1 + usize::from(buf[0]);
+            reader.read_exact(&mut buf[1..len]).await?;
+            Ok(Self::from_hci_bytes(&buf[..len])?.0)
         }
+    }
 
-        fn write<T: PacketToController>(&self, _val: &T) -> impl Future<Output = Result<(), Self::Error>> {
-            async { todo!() }
+    crate::cmd! {
+        /// Read a name of variable length
+        ReadName(VENDOR_SPECIFIC, 0x0001) {
+            Params = ();
+            Return = Name;
         }
     }
 
     #[futures_test::test]
-    pub async fn test_can_handle_unsolicited_command_complete() {
-        let t = TestTransport {
-            rx: &[
-                4, 0x0e, 3, // header
-                1, 0, 0, // special command
-            ],
-        };
-        let c: ExternalController<_, 10> = ExternalController::new(t);
+    pub async fn test_exec_variable_length_return() {
+        let script = [
+            Step::Write(&[1, 0x01, 0xfc, 0]),
+            Step::Read(&[
+                4, 0x0e, 7, // header
+                1, 0x01, 0xfc, // opcode
+                0,    // success
+                2, b'h', b'i', // name
+            ]),
+        ];
+        let c: ExternalController<MockTransport, 4> = ExternalController::new(MockTransport::new(&script));
 
         let mut buf = c.alloc_buf().unwrap();
-        let pkt = c.read(&mut buf).await;
-        assert!(pkt.is_ok());
+        let (res, _) = embassy_futures::join::join(ReadName::new().exec(&c), c.read(&mut buf)).await;
+        let name = res.unwrap();
+        assert_eq!(&name.bytes[..usize::from(name.len)], b"hi");
+        c.transport.assert_done();
     }
 }
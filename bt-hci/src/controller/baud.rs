@@ -0,0 +1,216 @@
+//! Switching the baud rate of a controller on a UART.
+//!
+//! Most UART controllers boot at 115200 baud and must be switched to a higher rate with a vendor command, e.g.
+//! Infineon's `UpdateUartBaudRate`, after which the host reconfigures its own UART.
+//! [`ExternalController::switch_baud_rate`] runs this sequence.
+
+use core::cell::Cell;
+use core::convert::Infallible;
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::Poll;
+
+use bt_hci_driver::ReadHciError;
+use embassy_sync::blocking_mutex::raw::{NoopRawMutex, RawMutex};
+use embassy_sync::signal::Signal;
+
+use super::{Controller, ExternalController};
+use crate::cmd;
+use crate::transport::{Error, SerialTransport};
+
+/// The baud rate at which a controller sends the Command Complete event of a baud rate command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReplyRate {
+    /// The controller replies at the old rate, and switches to the new rate afterwards.
+    Old,
+    /// The controller switches to the new rate as soon as it receives the command, and replies at the new rate.
+    New,
+}
+
+impl<TM, R, W, E, const SLOTS: usize, M> ExternalController<SerialTransport<TM, R, W>, SLOTS, M>
+where
+    TM: RawMutex,
+    M: RawMutex,
+    R: embedded_io_async::Read<Error = E>,
+    W: embedded_io_async::Write<Error = E>,
+    E: embedded_io::Error,
+    Error<E>: From<ReadHciError<Infallible>>,
+{
+    /// Switch the baud rate of the controller with the vendor command `cmd`, and of the host with `reconfigure`.
+    ///
+    /// `reconfigure` is called with the UART halves once the controller has replied if `reply` is [`ReplyRate::Old`],
+    /// or as soon as the command has been written if it is [`ReplyRate::New`], so the reply is read at the new rate.
+    ///
+    /// This reads from the controller until the command completes, dropping any other packet. No other task may read
+    /// from the controller meanwhile, so call this before starting the host stack.
+    pub async fn switch_baud_rate<C: cmd::SyncCmd>(
+        &self,
+        cmd: &C,
+        reply: ReplyRate,
+        reconfigure: impl FnOnce(&mut R, &mut W),
+    ) -> Result<C::Return, cmd::Error<Error<E>>> {
+        let reconfigure = Cell::new(Some(reconfigure));
+        let written = Signal::<NoopRawMutex, ()>::new();
+        let ret = {
+            let mut exec = pin!(self.exec_then(cmd, || async {
+                if reply == ReplyRate::New {
+                    if let Some(f) = reconfigure.take() {
+                        self.transport.reconfigure(f).await?;
+                    }
+                }
+                written.signal(());
+                Ok(())
+            }));
+            let mut drive = pin!(async {
+                if reply == ReplyRate::New {
+                    written.wait().await;
+                }
+                let mut buf = [0; 259];
+                loop {
+                    if let Err(e) = Controller::read(self, &mut buf).await {
+                        break e;
+                    }
+                    debug!("[hci] dropped a packet while switching baud rate");
+                }
+            });
+            poll_fn(|cx| {
+                if let Poll::Ready(ret) = exec.as_mut().poll(cx) {
+                    return Poll::Ready(ret);
+                }
+                match drive.as_mut().poll(cx) {
+                    Poll::Ready(e) => Poll::Ready(Err(cmd::Error::Io(e))),
+                    Poll::Pending => Poll::Pending,
+                }
+            })
+            .await?
+        };
+        if let Some(f) = reconfigure.take() {
+            self.transport.reconfigure(f).await.map_err(cmd::Error::Io)?;
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::vendor::cypress::UpdateUartBaudRate;
+
+    /// One half of a UART, exchanging bytes tagged with the baud rate they are sent at.
+    ///
+    /// Bytes received at another rate than the configured one read as garbage. Reading pends once all bytes are read.
+    struct Uart {
+        baud: u32,
+        rx: VecDeque<(u32, u8)>,
+        tx: Vec<(u32, u8)>,
+    }
+
+    impl Uart {
+        fn new(rx: &[(u32, &[u8])]) -> Self {
+            Self {
+                baud: 115_200,
+                rx: rx
+                    .iter()
+                    .flat_map(|&(baud, bytes)| bytes.iter().map(move |&b| (baud, b)))
+                    .collect(),
+                tx: Vec::new(),
+            }
+        }
+    }
+
+    impl embedded_io::ErrorType for Uart {
+        type Error = Infallible;
+    }
+
+    impl embedded_io_async::Read for Uart {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            if self.rx.is_empty() {
+                // Like a UART with nothing left to receive
+                core::future::pending::<()>().await;
+            }
+            let mut n = 0;
+            while n < buf.len() {
+                let Some((baud, b)) = self.rx.pop_front() else {
+                    break;
+                };
+                buf[n] = if baud == self.baud { b } else { 0xff };
+                n += 1;
+            }
+            Ok(n)
+        }
+    }
+
+    impl embedded_io_async::Write for Uart {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.tx.extend(buf.iter().map(|&b| (self.baud, b)));
+            Ok(buf.len())
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    type Serial = SerialTransport<NoopRawMutex, Uart, Uart>;
+
+    const CMD: &[u8] = &[0x01, 0x18, 0xfc, 0x06, 0x00, 0x00, 0xc0, 0xc6, 0x2d, 0x00];
+    const REPLY: &[u8] = &[0x04, 0x0e, 0x04, 0x01, 0x18, 0xfc, 0x00];
+
+    fn switch(r: &mut Uart, w: &mut Uart) {
+        r.baud = 3_000_000;
+        w.baud = 3_000_000;
+    }
+
+    #[futures_test::test]
+    async fn test_reply_at_old_rate() {
+        let rx = [(115_200, &[0x04, 0x10, 0x01, 0x00][..]), (115_200, REPLY)];
+        let c: ExternalController<Serial, 4> = ExternalController::new(Serial::new(Uart::new(&rx), Uart::new(&[])));
+        c.switch_baud_rate(&UpdateUartBaudRate::with_baud_rate(3_000_000), ReplyRate::Old, switch)
+            .await
+            .unwrap();
+        c.transport
+            .reconfigure(|r, w| {
+                assert_eq!((r.baud, w.baud), (3_000_000, 3_000_000));
+                assert!(w.tx.iter().all(|&(baud, _)| baud == 115_200));
+                assert_eq!(w.tx.iter().map(|&(_, b)| b).collect::<Vec<_>>()[..], CMD[..]);
+            })
+            .await
+            .unwrap();
+    }
+
+    #[futures_test::test]
+    async fn test_reply_at_new_rate() {
+        let rx = [(3_000_000, REPLY)];
+        let c: ExternalController<Serial, 4> = ExternalController::new(Serial::new(Uart::new(&rx), Uart::new(&[])));
+        c.switch_baud_rate(&UpdateUartBaudRate::with_baud_rate(3_000_000), ReplyRate::New, switch)
+            .await
+            .unwrap();
+        c.transport
+            .reconfigure(|r, w| {
+                assert_eq!((r.baud, w.baud), (3_000_000, 3_000_000));
+                assert!(w.tx.iter().all(|&(baud, _)| baud == 115_200));
+                assert!(r.rx.is_empty());
+            })
+            .await
+            .unwrap();
+    }
+
+    #[futures_test::test]
+    async fn test_reply_at_wrong_rate() {
+        let rx = [(3_000_000, REPLY)];
+        let c: ExternalController<Serial, 4> = ExternalController::new(Serial::new(Uart::new(&rx), Uart::new(&[])));
+        let res = c
+            .switch_baud_rate(&UpdateUartBaudRate::with_baud_rate(3_000_000), ReplyRate::Old, switch)
+            .await;
+        assert!(matches!(res, Err(cmd::Error::Io(Error::Read(_)))));
+        c.transport
+            .reconfigure(|r, _| assert_eq!(r.baud, 115_200))
+            .await
+            .unwrap();
+    }
+}
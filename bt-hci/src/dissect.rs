@@ -0,0 +1,499 @@
+//! Human readable descriptions of HCI packets.
+//!
+//! [`Dissect`] renders a packet as multi-line text in the style of `btmon`: a header line naming the packet and its
+//! opcode or event code, followed by the decoded parameters with named status codes and bitfields. It only uses
+//! [`core::fmt`], so it is suitable for logs and test failure messages without an allocator.
+//!
+//! ```
+//! use bt_hci::dissect::Dissect;
+//! use bt_hci::transport::Direction;
+//!
+//! let complete = [0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+//! assert_eq!(
+//!     format!("{}", Dissect::h4(Direction::Received, &complete)),
+//!     "> HCI Event: Command Complete (0x0e) plen 4\n        Reset (0x03|0x0003) ncmd 1\n        Status: Success (0x00)",
+//! );
+//! ```
+
+use core::fmt::{self, Write};
+
+use crate::cmd::{self, CmdPacket, Command, Opcode};
+use crate::event::le::LeEventKind;
+use crate::event::{CommandComplete, CommandStatus, Event, EventPacket};
+use crate::param::Status;
+use crate::transport::Direction;
+use crate::{FromHciBytes, PacketKind};
+
+/// Indentation of the lines following the header line.
+const INDENT: &str = "        ";
+
+/// Number of bytes per line of a hex dump.
+const HEX_LINE_LEN: usize = 16;
+
+/// A btmon-style description of an HCI packet, created from its serialized form.
+///
+/// The header line starts with `<` for packets sent to the controller and `>` for packets received from it. The
+/// output does not end with a newline.
+#[derive(Debug, Clone, Copy)]
+pub struct Dissect<'a> {
+    direction: Direction,
+    kind: Option<PacketKind>,
+    data: &'a [u8],
+}
+
+impl<'a> Dissect<'a> {
+    /// Describe a packet of `kind`, `data` must not include the packet indicator.
+    pub fn new(direction: Direction, kind: PacketKind, data: &'a [u8]) -> Self {
+        Self {
+            direction,
+            kind: Some(kind),
+            data,
+        }
+    }
+
+    /// Describe a packet starting with its H4 packet indicator.
+    pub fn h4(direction: Direction, data: &'a [u8]) -> Self {
+        match PacketKind::from_hci_bytes(data) {
+            Ok((kind, data)) => Self::new(direction, kind, data),
+            Err(_) => Self {
+                direction,
+                kind: None,
+                data,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Dissect<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (marker, dir) = match self.direction {
+            Direction::Sent => ('<', "TX"),
+            Direction::Received => ('>', "RX"),
+        };
+        write!(f, "{} ", marker)?;
+        match self.kind {
+            Some(PacketKind::Cmd) => command(f, self.data),
+            Some(PacketKind::Event) => event(f, self.data),
+            Some(PacketKind::AclData) => acl(f, dir, self.data),
+            Some(PacketKind::SyncData) => sync(f, dir, self.data),
+            Some(PacketKind::IsoData) => iso(f, dir, self.data),
+            None => {
+                f.write_str("Unknown packet")?;
+                hex(f, self.data)
+            }
+        }
+    }
+}
+
+fn command(f: &mut fmt::Formatter<'_>, data: &[u8]) -> fmt::Result {
+    let Ok((packet, _)) = CmdPacket::from_hci_bytes(data) else {
+        f.write_str("HCI Command: malformed packet")?;
+        return hex(f, data);
+    };
+    let name = cmd::describe(packet.opcode).map(|(name, _)| name);
+    write!(
+        f,
+        "HCI Command: {} {} plen {}",
+        Name(name),
+        OpcodeRef(packet.opcode),
+        packet.params.len()
+    )?;
+    match Command::try_from(packet) {
+        Ok(cmd) => match cmd.params_debug() {
+            Some(_) if packet.params.is_empty() => Ok(()),
+            Some(params) => line(f, format_args!("{:#?}", params)),
+            None => hex(f, packet.params),
+        },
+        Err(err) => {
+            line(f, format_args!("Invalid parameters: {:?}", err))?;
+            hex(f, packet.params)
+        }
+    }
+}
+
+fn event(f: &mut fmt::Formatter<'_>, data: &[u8]) -> fmt::Result {
+    let Ok((packet, _)) = EventPacket::from_hci_bytes(data) else {
+        f.write_str("HCI Event: malformed packet")?;
+        return hex(f, data);
+    };
+    write!(
+        f,
+        "HCI Event: {} ({:#04x}) plen {}",
+        Name(packet.kind.name()),
+        packet.kind.0,
+        packet.data.len()
+    )?;
+    match Event::try_from(packet.clone()) {
+        Ok(Event::CommandComplete(e)) => command_complete(f, &e),
+        Ok(Event::CommandStatus(e)) => command_status(f, &e),
+        Ok(Event::Le(e)) => {
+            line(f, format_args!("{} ({:#04x})", Name(e.kind().name()), e.kind().0))?;
+            line(f, format_args!("{:#?}", e.params_debug()))
+        }
+        Ok(e) => match e.params_debug() {
+            Some(params) => line(f, format_args!("{:#?}", params)),
+            None => hex(f, packet.data),
+        },
+        Err(err) => {
+            if let Some((&subevent, _)) = packet.data.split_first().filter(|_| packet.kind.0 == 0x3e) {
+                let name = LeEventKind(subevent).name();
+                line(f, format_args!("{} ({:#04x})", Name(name), subevent))?;
+            }
+            line(f, format_args!("Invalid parameters: {:?}", err))?;
+            hex(f, packet.data)
+        }
+    }
+}
+
+fn command_complete(f: &mut fmt::Formatter<'_>, e: &CommandComplete<'_>) -> fmt::Result {
+    let describe = cmd::describe(e.cmd_opcode);
+    if e.has_status() {
+        line(
+            f,
+            format_args!(
+                "{} {} ncmd {}",
+                Name(describe.map(|(name, _)| name)),
+                OpcodeRef(e.cmd_opcode),
+                e.num_hci_cmd_pkts
+            ),
+        )?;
+    } else {
+        line(f, format_args!("ncmd {}", e.num_hci_cmd_pkts))?;
+    }
+
+    let Some((&status, params)) = e.bytes.split_first() else {
+        return Ok(());
+    };
+    line(f, format_args!("Status: {}", StatusRef(Status::new(status))))?;
+    if params.is_empty() {
+        return Ok(());
+    }
+    let mut w = Indented::new(f);
+    match describe.and_then(|(_, dissect_return)| dissect_return(params, &mut w)) {
+        Some(res) => res,
+        None => hex(f, params),
+    }
+}
+
+fn command_status(f: &mut fmt::Formatter<'_>, e: &CommandStatus) -> fmt::Result {
+    let name = cmd::describe(e.cmd_opcode).map(|(name, _)| name);
+    line(
+        f,
+        format_args!("{} {} ncmd {}", Name(name), OpcodeRef(e.cmd_opcode), e.num_hci_cmd_pkts),
+    )?;
+    line(f, format_args!("Status: {}", StatusRef(e.status)))
+}
+
+fn acl(f: &mut fmt::Formatter<'_>, dir: &str, data: &[u8]) -> fmt::Result {
+    let Some((header, payload)) = data.split_first_chunk::<4>() else {
+        f.write_str("ACL Data: malformed packet")?;
+        return hex(f, data);
+    };
+    let handle = u16::from_le_bytes([header[0], header[1]]);
+    let dlen = u16::from_le_bytes([header[2], header[3]]);
+    write!(
+        f,
+        "ACL Data {}: Handle {} flags {:#04x} dlen {}",
+        dir,
+        handle & 0xfff,
+        handle >> 12,
+        dlen
+    )?;
+    hex(f, payload)
+}
+
+fn sync(f: &mut fmt::Formatter<'_>, dir: &str, data: &[u8]) -> fmt::Result {
+    let Some((header, payload)) = data.split_first_chunk::<3>() else {
+        f.write_str("SCO Data: malformed packet")?;
+        return hex(f, data);
+    };
+    let handle = u16::from_le_bytes([header[0], header[1]]);
+    write!(
+        f,
+        "SCO Data {}: Handle {} flags {:#04x} dlen {}",
+        dir,
+        handle & 0xfff,
+        handle >> 12,
+        header[2]
+    )?;
+    hex(f, payload)
+}
+
+fn iso(f: &mut fmt::Formatter<'_>, dir: &str, data: &[u8]) -> fmt::Result {
+    let Some((header, payload)) = data.split_first_chunk::<4>() else {
+        f.write_str("ISO Data: malformed packet")?;
+        return hex(f, data);
+    };
+    let handle = u16::from_le_bytes([header[0], header[1]]);
+    let dlen = u16::from_le_bytes([header[2], header[3]]);
+    write!(
+        f,
+        "ISO Data {}: Handle {} flags {:#04x} dlen {}",
+        dir,
+        handle & 0xfff,
+        (handle >> 12) & 0x7,
+        dlen & 0x3fff
+    )?;
+    hex(f, payload)
+}
+
+/// Write an indented line.
+fn line(f: &mut fmt::Formatter<'_>, args: fmt::Arguments<'_>) -> fmt::Result {
+    Indented::new(f).write_fmt(args)
+}
+
+/// Write `data` as indented lines of hex bytes.
+fn hex(f: &mut fmt::Formatter<'_>, data: &[u8]) -> fmt::Result {
+    for chunk in data.chunks(HEX_LINE_LEN) {
+        let mut w = Indented::new(f);
+        for (i, byte) in chunk.iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            write!(w, "{}{:02x}", sep, byte)?;
+        }
+    }
+    Ok(())
+}
+
+/// Starts a new indented line on the first write, and indents every following line.
+struct Indented<'a, 'b> {
+    f: &'a mut fmt::Formatter<'b>,
+    started: bool,
+}
+
+impl<'a, 'b> Indented<'a, 'b> {
+    fn new(f: &'a mut fmt::Formatter<'b>) -> Self {
+        Self { f, started: false }
+    }
+}
+
+impl Write for Indented<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !self.started {
+            self.started = true;
+            self.f.write_char('\n')?;
+            self.f.write_str(INDENT)?;
+        }
+        for (i, part) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.f.write_char('\n')?;
+                self.f.write_str(INDENT)?;
+            }
+            self.f.write_str(part)?;
+        }
+        Ok(())
+    }
+}
+
+/// A type name like `LeSetEventMask` split into words, as `LE Set Event Mask`.
+struct Name<'a>(Option<&'a str>);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(name) = self.0 else {
+            return f.write_str("Unknown");
+        };
+        let mut start = 0;
+        let ends = name
+            .char_indices()
+            .skip(1)
+            .filter(|(_, c)| c.is_ascii_uppercase())
+            .map(|(i, _)| i)
+            .chain([name.len()]);
+        for end in ends {
+            if start > 0 {
+                f.write_char(' ')?;
+            }
+            match &name[start..end] {
+                "Le" => f.write_str("LE")?,
+                word => f.write_str(word)?,
+            }
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+/// An opcode as its OGF and OCF.
+struct OpcodeRef(Opcode);
+
+impl fmt::Display for OpcodeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let raw = self.0.to_raw();
+        write!(f, "({:#04x}|{:#06x})", raw >> 10, raw & 0x3ff)
+    }
+}
+
+/// A status as its name and value.
+struct StatusRef(Status);
+
+impl fmt::Display for StatusRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({:#04x})", self.0, self.0.into_inner())
+    }
+}
+
+/// Format a bitfield as its name followed by the names of the set bits, used by `param!`.
+#[doc(hidden)]
+pub fn debug_bitfield(f: &mut fmt::Formatter<'_>, name: &str, bytes: &[u8], flags: &[(usize, &str)]) -> fmt::Result {
+    write!(f, "{} ", name)?;
+    let mut set = f.debug_set();
+    for bit in (0..bytes.len() * 8).filter(|bit| bytes[bit / 8] & (1 << (bit % 8)) != 0) {
+        match flags.iter().find(|(b, _)| *b == bit) {
+            Some((_, flag)) => set.entry(&format_args!("{}", flag)),
+            None => set.entry(&format_args!("bit {}", bit)),
+        };
+    }
+    set.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::format;
+
+    use super::*;
+
+    fn h4(direction: Direction, data: &[u8]) -> std::string::String {
+        format!("{}", Dissect::h4(direction, data))
+    }
+
+    #[test]
+    fn test_command() {
+        assert_eq!(
+            h4(Direction::Sent, &[0x01, 0x03, 0x0c, 0x00]).as_str(),
+            "< HCI Command: Reset (0x03|0x0003) plen 0"
+        );
+        assert_eq!(
+            h4(Direction::Sent, &[0x01, 0x0a, 0x20, 0x01, 0x01]).as_str(),
+            "< HCI Command: LE Set Adv Enable (0x08|0x000a) plen 1\n        true"
+        );
+        assert_eq!(
+            h4(Direction::Sent, &[0x01, 0x01, 0x20, 0x08, 0x03, 0, 0, 0, 0, 0, 0, 0x80]).as_str(),
+            "< HCI Command: LE Set Event Mask (0x08|0x0001) plen 8
+        LeEventMask {
+            is_le_conn_complete_enabled,
+            is_le_adv_report_enabled,
+            bit 63,
+        }"
+        );
+    }
+
+    #[test]
+    fn test_unknown_and_invalid_command() {
+        assert_eq!(
+            h4(Direction::Sent, &[0x01, 0x01, 0xfc, 0x02, 0xaa, 0xbb]).as_str(),
+            "< HCI Command: Unknown (0x3f|0x0001) plen 2\n        aa bb"
+        );
+        assert_eq!(
+            h4(Direction::Sent, &[0x01, 0x0a, 0x20, 0x01, 0x02]).as_str(),
+            "< HCI Command: LE Set Adv Enable (0x08|0x000a) plen 1\n        Invalid parameters: InvalidValue\n        02"
+        );
+        assert_eq!(
+            h4(Direction::Sent, &[0x01, 0x03, 0x0c, 0x01]).as_str(),
+            "< HCI Command: malformed packet\n        03 0c 01"
+        );
+    }
+
+    #[test]
+    fn test_command_complete() {
+        let read_bd_addr = [0x04, 0x0e, 0x0a, 0x01, 0x09, 0x10, 0x00, 1, 2, 3, 4, 5, 6];
+        assert_eq!(
+            h4(Direction::Received, &read_bd_addr).as_str(),
+            "> HCI Event: Command Complete (0x0e) plen 10
+        Read Bd Addr (0x04|0x0009) ncmd 1
+        Status: Success (0x00)
+        BdAddr(
+            [
+                1,
+                2,
+                3,
+                4,
+                5,
+                6,
+            ],
+        )"
+        );
+
+        let failed = [0x04, 0x0e, 0x04, 0x01, 0x09, 0x10, 0x0c];
+        assert_eq!(
+            h4(Direction::Received, &failed).as_str(),
+            "> HCI Event: Command Complete (0x0e) plen 4
+        Read Bd Addr (0x04|0x0009) ncmd 1
+        Status: Command Disallowed (0x0c)"
+        );
+    }
+
+    #[test]
+    fn test_command_status() {
+        assert_eq!(
+            h4(Direction::Received, &[0x04, 0x0f, 0x04, 0x00, 0x01, 0x0d, 0x20]).as_str(),
+            "> HCI Event: Command Status (0x0f) plen 4
+        LE Create Conn (0x08|0x000d) ncmd 1
+        Status: Success (0x00)"
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        assert_eq!(
+            h4(Direction::Received, &[0x04, 0x05, 0x04, 0x00, 0x40, 0x00, 0x13]).as_str(),
+            "> HCI Event: Disconnection Complete (0x05) plen 4
+        DisconnectionComplete {
+            status: Success,
+            handle: ConnHandle(
+                64,
+            ),
+            reason: Remote User Terminated Connection,
+        }"
+        );
+        assert_eq!(
+            h4(Direction::Received, &[0x04, 0x3e, 0x02, 0x7f, 0xaa]).as_str(),
+            "> HCI Event: LE Meta Event (0x3e) plen 2
+        Unknown (0x7f)
+        Invalid parameters: InvalidValue
+        7f aa"
+        );
+        assert_eq!(
+            h4(Direction::Received, &[0x04, 0xfe, 0x01, 0xaa]).as_str(),
+            "> HCI Event: Unknown (0xfe) plen 1\n        aa"
+        );
+    }
+
+    #[test]
+    fn test_data() {
+        let acl = [
+            0x02, 0x40, 0x20, 0x11, 0x00, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+        ];
+        assert_eq!(
+            h4(Direction::Sent, &acl).as_str(),
+            "< ACL Data TX: Handle 64 flags 0x02 dlen 17
+        00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f
+        10"
+        );
+        assert_eq!(
+            h4(Direction::Received, &[0x03, 0x01, 0x10, 0x01, 0xaa]).as_str(),
+            "> SCO Data RX: Handle 1 flags 0x01 dlen 1\n        aa"
+        );
+        assert_eq!(
+            h4(Direction::Received, &[0x05, 0x01, 0x60, 0x01, 0x00, 0xaa]).as_str(),
+            "> ISO Data RX: Handle 1 flags 0x06 dlen 1\n        aa"
+        );
+        assert_eq!(
+            h4(Direction::Sent, &[0x09, 0x01]).as_str(),
+            "< Unknown packet\n        09 01"
+        );
+    }
+
+    #[test]
+    fn test_debug_bitfield() {
+        use crate::param::{CmdMask, LmpFeatureMask};
+
+        let features = LmpFeatureMask::new().set_3_slot_packets(true).set_le(true);
+        assert_eq!(
+            format!("{:?}", features).as_str(),
+            "LmpFeatureMask {supports_3_slot_packets, supports_le}"
+        );
+        assert_eq!(format!("{:?}", CmdMask::default()).as_str(), "CmdMask {}");
+    }
+}
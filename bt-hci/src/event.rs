@@ -7,7 +7,7 @@ use crate::param::{
     IoCapability, KeyFlag, KeypressNotificationType, LinkKeyType, LinkType, LmpFeatureMask, MaxSlots, Mode,
     OobDataPresent, PacketType, PageScanRepetitionMode, RemainingBytes, Role, ServiceType, Status,
 };
-use crate::{AsHciBytes, FromHciBytes, FromHciBytesError, ReadHci, ReadHciError};
+use crate::{AsHciBytes, FromHciBytes, FromHciBytesError, ReadHci, ReadHciError, WriteHci};
 
 pub mod le;
 
@@ -140,6 +140,78 @@ macro_rules! events {
             }
         }
 
+        impl Event<'_> {
+            /// The kind of this event.
+            pub fn kind(&self) -> EventKind {
+                match self {
+                    $(Self::$name(_) => EventKind::$name,)+
+                    Self::Le(_) => EventKind::Le,
+                    Self::Unknown { code, .. } => EventKind(*code),
+                }
+            }
+
+            fn params_size(&self) -> usize {
+                match self {
+                    $(Self::$name(x) => $crate::WriteHci::size(x),)+
+                    Self::Le(x) => $crate::WriteHci::size(x),
+                    Self::Unknown { params, .. } => params.len(),
+                }
+            }
+
+            /// The parameters of this event, used by [`crate::dissect`].
+            pub(crate) fn params_debug(&self) -> Option<&dyn core::fmt::Debug> {
+                match self {
+                    $(Self::$name(x) => Some(x),)+
+                    Self::Le(_) | Self::Unknown { .. } => None,
+                }
+            }
+        }
+
+        impl EventKind {
+            /// The name of this kind of event, used by [`crate::dissect`].
+            pub(crate) fn name(self) -> Option<&'static str> {
+                match self {
+                    $(Self::$name => Some(stringify!($name)),)+
+                    Self::Le => Some("LeMetaEvent"),
+                    _ => None,
+                }
+            }
+        }
+
+        /// An event can carry at most 255 parameter bytes. Writing an event whose parameters are larger panics in
+        /// debug builds; in release builds the parameters are truncated to 255 bytes so the packet stays well-formed.
+        impl $crate::WriteHci for Event<'_> {
+            #[inline(always)]
+            fn size(&self) -> usize {
+                2 + params_len(self.params_size())
+            }
+
+            fn write_hci<W: ::embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                let len = params_len(self.params_size());
+                writer.write_all(&[self.kind().0, len as u8])?;
+                let mut writer = Truncate::new(writer, len);
+                match self {
+                    $(Self::$name(x) => $crate::WriteHci::write_hci(x, &mut writer),)+
+                    Self::Le(x) => $crate::WriteHci::write_hci(x, &mut writer),
+                    Self::Unknown { params, .. } => ::embedded_io::Write::write_all(&mut writer, params),
+                }
+            }
+
+            async fn write_hci_async<W: ::embedded_io_async::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                let len = params_len(self.params_size());
+                writer.write_all(&[self.kind().0, len as u8]).await?;
+                let mut writer = Truncate::new(writer, len);
+                match self {
+                    $(Self::$name(x) => $crate::WriteHci::write_hci_async(x, &mut writer).await,)+
+                    Self::Le(x) => $crate::WriteHci::write_hci_async(x, &mut writer).await,
+                    Self::Unknown { params, .. } => ::embedded_io_async::Write::write_all(&mut writer, params).await,
+                }
+            }
+        }
+
+        #[cfg(test)]
+        const EVENT_CODES: &[u8] = &[$($code),+];
+
         $(
             $(#[$attrs])*
             #[derive(Debug, Clone, Copy, Hash)]
@@ -170,6 +242,25 @@ macro_rules! events {
             impl<'a> $crate::event::EventParams<'a> for $name$(<$life>)? {
                 const EVENT_CODE: u8 = $code;
             }
+
+            #[automatically_derived]
+            #[allow(unused_mut, unused_variables)]
+            impl$(<$life>)? $crate::WriteHci for $name$(<$life>)? {
+                #[inline(always)]
+                fn size(&self) -> usize {
+                    $(<$ty as $crate::WriteHci>::size(&self.$field) +)* 0
+                }
+
+                fn write_hci<W: ::embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                    $(<$ty as $crate::WriteHci>::write_hci(&self.$field, &mut writer)?;)*
+                    Ok(())
+                }
+
+                async fn write_hci_async<W: ::embedded_io_async::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                    $(<$ty as $crate::WriteHci>::write_hci_async(&self.$field, &mut writer).await?;)*
+                    Ok(())
+                }
+            }
         )+
     };
 }
@@ -648,7 +739,81 @@ impl<'de> FromHciBytes<'de> for EventPacket<'de> {
     fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), FromHciBytesError> {
         let (header, data) = EventPacketHeader::from_hci_bytes(data)?;
         let pkt = Self::from_header_hci_bytes(header, data)?;
-        Ok((pkt, &[]))
+        let rest = &data[pkt.data.len()..];
+        Ok((pkt, rest))
+    }
+}
+
+/// An event packet can carry at most 255 parameter bytes. Writing a packet with more data panics in debug builds; in
+/// release builds the data is truncated to 255 bytes so the packet stays well-formed.
+impl WriteHci for EventPacket<'_> {
+    #[inline(always)]
+    fn size(&self) -> usize {
+        2 + params_len(self.data.len())
+    }
+
+    fn write_hci<W: embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+        let data = &self.data[..params_len(self.data.len())];
+        writer.write_all(&[self.kind.0, data.len() as u8])?;
+        writer.write_all(data)
+    }
+
+    async fn write_hci_async<W: embedded_io_async::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+        let data = &self.data[..params_len(self.data.len())];
+        writer.write_all(&[self.kind.0, data.len() as u8]).await?;
+        writer.write_all(data).await
+    }
+}
+
+/// Clamps an event's parameter length to the 255 bytes its one-byte length field can describe.
+fn params_len(len: usize) -> usize {
+    debug_assert!(len <= usize::from(u8::MAX), "event parameters exceed 255 bytes");
+    len.min(usize::from(u8::MAX))
+}
+
+/// A writer that passes through the first `remaining` bytes and silently drops the rest.
+struct Truncate<W> {
+    inner: W,
+    remaining: usize,
+}
+
+impl<W> Truncate<W> {
+    fn new(inner: W, remaining: usize) -> Self {
+        Self { inner, remaining }
+    }
+}
+
+impl<W: embedded_io::ErrorType> embedded_io::ErrorType for Truncate<W> {
+    type Error = W::Error;
+}
+
+impl<W: embedded_io::Write> embedded_io::Write for Truncate<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(buf.len());
+        }
+        let n = self.inner.write(&buf[..buf.len().min(self.remaining)])?;
+        self.remaining -= n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+}
+
+impl<W: embedded_io_async::Write> embedded_io_async::Write for Truncate<W> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(buf.len());
+        }
+        let n = self.inner.write(&buf[..buf.len().min(self.remaining)]).await?;
+        self.remaining -= n;
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().await
     }
 }
 
@@ -927,8 +1092,54 @@ impl Iterator for InquiryResultIter<'_> {
     }
 }
 
+/// Write `items` into `buf` in the column-major inquiry result format, returning the written bytes.
+fn encode_inquiry_results<'a>(
+    items: &[InquiryResultItem],
+    kind: InquiryResultKind,
+    buf: &'a mut [u8],
+) -> Result<&'a [u8], FromHciBytesError> {
+    let n = items.len();
+    // Both layouts take 14 bytes per response, the RSSI replacing one reserved byte
+    if n > usize::from(u8::MAX) || buf.len() < 14 * n {
+        return Err(FromHciBytesError::InvalidSize);
+    }
+    let reserved_size = match kind {
+        InquiryResultKind::Standard => n * 2,
+        InquiryResultKind::WithRssi => n,
+    };
+    let page_scan_off = n * 6;
+    let class_off = page_scan_off + n + reserved_size;
+    let clock_off = class_off + n * 3;
+    let rssi_off = clock_off + n * 2;
+
+    let (buf, _) = buf.split_at_mut(14 * n);
+    buf.fill(0);
+    for (i, item) in items.iter().enumerate() {
+        buf[i * 6..][..6].copy_from_slice(item.bd_addr.raw());
+        buf[page_scan_off + i] = item.page_scan_repetition_mode.map_or(0, |m| m as u8);
+        buf[class_off + i * 3..][..3].copy_from_slice(&item.class_of_device.unwrap_or_default());
+        buf[clock_off + i * 2..][..2].copy_from_slice(item.clock_offset.unwrap_or_default().as_hci_bytes());
+        if kind == InquiryResultKind::WithRssi {
+            buf[rssi_off + i] = item.rssi.unwrap_or_default() as u8;
+        }
+    }
+    Ok(buf)
+}
+
 /// Inquiry result event containing multiple responses
-impl InquiryResult<'_> {
+impl<'a> InquiryResult<'a> {
+    /// Encode `items` into `buf` and return them as an inquiry result event.
+    ///
+    /// Missing fields are encoded as zero and `rssi` is ignored. Fails with
+    /// [`FromHciBytesError::InvalidSize`] if `buf` is too small to hold the responses.
+    pub fn encode(items: &[InquiryResultItem], buf: &'a mut [u8]) -> Result<Self, FromHciBytesError> {
+        let bytes = encode_inquiry_results(items, InquiryResultKind::Standard, buf)?;
+        Ok(Self {
+            num_responses: items.len() as u8,
+            bytes: RemainingBytes::new(bytes),
+        })
+    }
+
     /// Returns an iterator over all valid inquiry result items.
     pub fn iter(&self) -> InquiryResultIter<'_> {
         let bytes = self.bytes.as_hci_bytes();
@@ -938,7 +1149,19 @@ impl InquiryResult<'_> {
 }
 
 /// Inquiry result event containing multiple responses with RSSI
-impl InquiryResultWithRssi<'_> {
+impl<'a> InquiryResultWithRssi<'a> {
+    /// Encode `items` into `buf` and return them as an inquiry result with RSSI event.
+    ///
+    /// Missing fields are encoded as zero. Fails with [`FromHciBytesError::InvalidSize`] if `buf`
+    /// is too small to hold the responses.
+    pub fn encode(items: &[InquiryResultItem], buf: &'a mut [u8]) -> Result<Self, FromHciBytesError> {
+        let bytes = encode_inquiry_results(items, InquiryResultKind::WithRssi, buf)?;
+        Ok(Self {
+            num_responses: items.len() as u8,
+            bytes: RemainingBytes::new(bytes),
+        })
+    }
+
     /// Returns an iterator over all valid inquiry result items.
     pub fn iter(&self) -> InquiryResultIter<'_> {
         let bytes = self.bytes.as_hci_bytes();
@@ -953,6 +1176,7 @@ mod tests {
     use crate::cmd::OpcodeGroup;
     use crate::event::le::LeEventPacket;
     use crate::param::*;
+    use crate::{ControllerToHostPacket, PacketKind};
 
     #[test]
     fn test_inquiry_result() {
@@ -1317,6 +1541,14 @@ mod tests {
         assert_eq!(event.cmd_opcode, Opcode::new(OpcodeGroup::new(0), 0));
     }
 
+    #[test]
+    fn test_event_packet_rest() {
+        let data = [0x0e, 3, 1, 0, 0, 0x04, 0x0f];
+        let (event, rest) = EventPacket::from_hci_bytes(&data).unwrap();
+        assert_eq!(event.data, [1, 0, 0]);
+        assert_eq!(rest, [0x04, 0x0f]);
+    }
+
     #[test]
     fn test_normal_command_complete() {
         let opcode = Opcode::new(OpcodeGroup::LE, 0x000D).to_raw().to_le_bytes();
@@ -1497,4 +1729,265 @@ mod tests {
 
         assert_eq!(e.utp_data, &[0xAA, 0xBB, 0xCC]);
     }
+
+    /// Find parameters made of `prefix` and a repeated fill byte that decode as an event with `code`.
+    fn find_event(code: u8, prefix: &[u8], buf: &mut [u8; 257]) -> Option<usize> {
+        for fill in 0..4 {
+            for len in prefix.len()..=255 {
+                buf[0] = code;
+                buf[1] = len as u8;
+                buf[2..][..prefix.len()].copy_from_slice(prefix);
+                buf[2 + prefix.len()..2 + len].fill(fill);
+                if let Ok(event) = Event::from_hci_bytes_complete(&buf[..2 + len]) {
+                    if !matches!(event, Event::Unknown { .. }) {
+                        return Some(2 + len);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_write_every_event() {
+        let mut buf = [0; 257];
+        let mut out = [0; 257];
+        let events = EVENT_CODES.iter().map(|&code| (code, None));
+        let le_events = le::SUBEVENT_CODES.iter().map(|&sub| (EventKind::Le.0, Some(sub)));
+        for (code, sub) in events.chain(le_events) {
+            let prefix = sub.as_slice();
+            let len = find_event(code, prefix, &mut buf)
+                .unwrap_or_else(|| ::core::panic!("no encoding found for event {:#04x} {:?}", code, sub));
+            let event = Event::from_hci_bytes_complete(&buf[..len]).unwrap();
+            assert_eq!(event.kind(), EventKind(code));
+            assert_eq!(event.size(), len);
+            event.write_hci(&mut out[..]).unwrap();
+            assert_eq!(&out[..len], &buf[..len], "event {:#04x} {:?}", code, sub);
+        }
+    }
+
+    #[test]
+    fn test_write_unknown_event() {
+        let data = [0xf0, 2, 0xaa, 0xbb];
+        let event = Event::from_hci_bytes_complete(&data).unwrap();
+        assert!(matches!(event, Event::Unknown { code: 0xf0, .. }));
+        let mut out = [0; 4];
+        event.write_hci(&mut out[..]).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "event parameters exceed 255 bytes"))]
+    fn test_write_oversized_event() {
+        let params = [0xaa; 300];
+        let event = Event::Unknown {
+            code: 0xf0,
+            params: &params,
+        };
+        assert_eq!(event.size(), 257);
+        let mut out = [0; 300];
+        let mut w = &mut out[..];
+        event.write_hci(&mut w).unwrap();
+        let written = 300 - w.len();
+        assert_eq!(written, 257);
+        assert_eq!(out[..2], [0xf0, 0xff]);
+        assert!(out[2..257].iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn test_write_disconnection_complete() {
+        let event = Event::DisconnectionComplete(DisconnectionComplete {
+            status: Status::SUCCESS,
+            handle: ConnHandle::new(0x40),
+            reason: Status::REMOTE_USER_TERMINATED_CONN,
+        });
+        let mut out = [0; 6];
+        event.write_hci(&mut out[..]).unwrap();
+        assert_eq!(out, [0x05, 4, 0x00, 0x40, 0x00, 0x13]);
+    }
+
+    #[test]
+    fn test_encode_inquiry_result() {
+        let items = [
+            InquiryResultItem {
+                bd_addr: BdAddr::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+                page_scan_repetition_mode: Some(PageScanRepetitionMode::R1),
+                class_of_device: Some([0x20, 0x04, 0x00]),
+                clock_offset: ClockOffset::from_hci_bytes(&[0x34, 0x12]).ok().map(|(c, _)| c),
+                rssi: Some(-50),
+            },
+            InquiryResultItem {
+                bd_addr: BdAddr::new([0x11, 0x12, 0x13, 0x14, 0x15, 0x16]),
+                page_scan_repetition_mode: Some(PageScanRepetitionMode::R2),
+                class_of_device: Some([0x30, 0x05, 0x01]),
+                clock_offset: ClockOffset::from_hci_bytes(&[0x78, 0x56]).ok().map(|(c, _)| c),
+                rssi: Some(-60),
+            },
+        ];
+
+        let mut buf = [0; 28];
+        let event = InquiryResult::encode(&items, &mut buf).unwrap();
+        let mut out = [0; 29];
+        event.write_hci(&mut out[..]).unwrap();
+        assert_eq!(
+            out,
+            [
+                0x02, // num_responses
+                0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // addr 1
+                0x11, 0x12, 0x13, 0x14, 0x15, 0x16, // addr 2
+                0x01, 0x02, // R1, R2
+                0x00, 0x00, 0x00, 0x00, // reserved
+                0x20, 0x04, 0x00, 0x30, 0x05, 0x01, // class of device
+                0x34, 0x12, 0x78, 0x56, // clock offsets
+            ]
+        );
+        let decoded = event.iter().map(|item| item.rssi).collect::<heapless::Vec<_, 2>>();
+        assert_eq!(decoded.as_slice(), [None, None]);
+
+        let mut buf = [0; 28];
+        let event = InquiryResultWithRssi::encode(&items, &mut buf).unwrap();
+        assert!(event.iter().eq(items.iter().cloned()));
+
+        let mut buf = [0; 27];
+        assert_eq!(
+            InquiryResult::encode(&items, &mut buf).unwrap_err(),
+            FromHciBytesError::InvalidSize
+        );
+    }
+
+    #[test]
+    fn test_encode_le_adv_reports() {
+        let reports = [
+            LeAdvReport {
+                event_kind: LeAdvEventKind::AdvInd,
+                addr_kind: AddrKind::PUBLIC,
+                addr: BdAddr::new([1, 2, 3, 4, 5, 6]),
+                data: &[2, 0x01, 0x06],
+                rssi: -40,
+            },
+            LeAdvReport {
+                event_kind: LeAdvEventKind::ScanRsp,
+                addr_kind: AddrKind::RANDOM,
+                addr: BdAddr::new([6, 5, 4, 3, 2, 1]),
+                data: &[],
+                rssi: -70,
+            },
+        ];
+        let mut buf = [0; 64];
+        let event = Event::Le(LeEvent::LeAdvertisingReport(le::LeAdvertisingReport {
+            reports: LeAdvReports::encode(&reports, &mut buf).unwrap(),
+        }));
+
+        let mut out = [0; 64];
+        event.write_hci(&mut out[..]).unwrap();
+        let Ok(Event::Le(LeEvent::LeAdvertisingReport(e))) = Event::from_hci_bytes_complete(&out[..event.size()])
+        else {
+            panic!("expected advertising report");
+        };
+        assert!(e.reports.iter().map(Result::unwrap).eq(reports));
+    }
+
+    #[test]
+    fn test_encode_le_ext_adv_reports() {
+        let reports = [LeExtAdvReport {
+            event_kind: LeExtAdvEventKind::new().set_connectable(true),
+            addr_kind: AddrKind::RANDOM,
+            addr: BdAddr::new([1, 2, 3, 4, 5, 0xc6]),
+            primary_adv_phy: PhyKind::Le1M,
+            secondary_adv_phy: Some(PhyKind::Le2M),
+            adv_sid: 3,
+            tx_power: 127,
+            rssi: -40,
+            adv_interval: Duration::from_u16(0),
+            direct_addr_kind: AddrKind::PUBLIC,
+            direct_addr: BdAddr::default(),
+            data: &[0xaa; 100],
+        }];
+        let mut buf = [0; 128];
+        let event = Event::Le(LeEvent::LeExtendedAdvertisingReport(le::LeExtendedAdvertisingReport {
+            reports: LeExtAdvReports::encode(&reports, &mut buf).unwrap(),
+        }));
+
+        let mut out = [0; 257];
+        event.write_hci(&mut out[..]).unwrap();
+        let Ok(Event::Le(LeEvent::LeExtendedAdvertisingReport(e))) =
+            Event::from_hci_bytes_complete(&out[..event.size()])
+        else {
+            panic!("expected extended advertising report");
+        };
+        assert!(e.reports.iter().map(Result::unwrap).eq(reports));
+
+        let mut buf = [0; 64];
+        assert!(LeExtAdvReports::encode(&reports, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_encode_le_periodic_adv_response_reports() {
+        let reports = [
+            LePeriodicAdvertisingResponseReport {
+                tx_power: 10,
+                rssi: -30,
+                cte_type: CteKind::AoA,
+                response_slot: 0,
+                data_status: DataStatus::Complete,
+                data_length: 2,
+                data: &[0xaa, 0xbb],
+            },
+            LePeriodicAdvertisingResponseReport {
+                tx_power: -10,
+                rssi: 30,
+                cte_type: CteKind::NoCte,
+                response_slot: 1,
+                data_status: DataStatus::Incomplete,
+                data_length: 1,
+                data: &[0xcc],
+            },
+        ];
+        let mut buf = [0; 16];
+        let reports = LePeriodicAdvertisingResponseReports::encode(&reports, &mut buf).unwrap();
+        let mut out = [0; 16];
+        reports.write_hci(&mut out[..]).unwrap();
+        assert_eq!(
+            &out[..reports.size()],
+            [0x02, 0x0a, 0xf6, 0xe2, 0x1e, 0x00, 0xff, 0x00, 0x01, 0x00, 0x01, 0x02, 0x01, 0xaa, 0xbb, 0xcc]
+        );
+        assert_eq!(reports.get(1).unwrap().data, &[0xcc]);
+    }
+
+    #[test]
+    fn test_encode_le_cs_subevent_step_data() {
+        let steps = [
+            LeCsSubeventStepEntry {
+                step_mode: 1,
+                step_channel: 10,
+                step_data_length: 2,
+                step_data: &[0x11, 0x22],
+            },
+            LeCsSubeventStepEntry {
+                step_mode: 2,
+                step_channel: 20,
+                step_data_length: 0,
+                step_data: &[],
+            },
+        ];
+        let mut buf = [0; 16];
+        let data = LeCsSubeventStepData::encode(&steps, &mut buf).unwrap();
+        assert!(data.iter().eq(steps));
+        let mut out = [0; 9];
+        data.write_hci(&mut out[..]).unwrap();
+        assert_eq!(out, [0x02, 1, 2, 10, 20, 2, 0, 0x11, 0x22]);
+    }
+
+    #[test]
+    fn test_write_packet_to_host() {
+        use crate::WritePacketToHost;
+
+        let data = [0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+        let pkt = ControllerToHostPacket::from_hci_bytes_complete(&data).unwrap();
+        assert_eq!(WritePacketToHost::kind(&pkt), PacketKind::Event);
+        assert_eq!(WritePacketToHost::size(&pkt), 6);
+        let mut out = [0; 6];
+        WritePacketToHost::write_hci(&pkt, &mut out[..]).unwrap();
+        assert_eq!(out, data[1..]);
+    }
 }
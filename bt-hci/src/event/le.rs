@@ -89,6 +89,58 @@ macro_rules! le_events {
             }
         }
 
+        impl LeEvent<'_> {
+            /// The kind of this LE meta event.
+            pub fn kind(&self) -> LeEventKind {
+                match self {
+                    $(Self::$name(_) => LeEventKind::$name,)+
+                }
+            }
+
+            /// The parameters of this event, used by [`crate::dissect`].
+            pub(crate) fn params_debug(&self) -> &dyn core::fmt::Debug {
+                match self {
+                    $(Self::$name(x) => x,)+
+                }
+            }
+        }
+
+        impl LeEventKind {
+            /// The name of this kind of LE meta event, used by [`crate::dissect`].
+            pub(crate) fn name(self) -> Option<&'static str> {
+                match self {
+                    $(Self::$name => Some(stringify!($name)),)+
+                    _ => None,
+                }
+            }
+        }
+
+        impl $crate::WriteHci for LeEvent<'_> {
+            #[inline(always)]
+            fn size(&self) -> usize {
+                1 + match self {
+                    $(Self::$name(x) => $crate::WriteHci::size(x),)+
+                }
+            }
+
+            fn write_hci<W: ::embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                writer.write_all(&[self.kind().0])?;
+                match self {
+                    $(Self::$name(x) => $crate::WriteHci::write_hci(x, writer),)+
+                }
+            }
+
+            async fn write_hci_async<W: ::embedded_io_async::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                writer.write_all(&[self.kind().0]).await?;
+                match self {
+                    $(Self::$name(x) => $crate::WriteHci::write_hci_async(x, writer).await,)+
+                }
+            }
+        }
+
+        #[cfg(test)]
+        pub(super) const SUBEVENT_CODES: &[u8] = &[$($code),+];
+
         impl<'a> $crate::FromHciBytes<'a> for LeEvent<'a> {
             fn from_hci_bytes(data: &'a [u8]) -> Result<(Self, &'a [u8]), FromHciBytesError> {
                 let (subcode, data) = data.split_first().ok_or(FromHciBytesError::InvalidSize)?;
@@ -128,6 +180,25 @@ macro_rules! le_events {
             impl<'a> LeEventParams<'a> for $name$(<$life>)? {
                 const SUBEVENT_CODE: u8 = $code;
             }
+
+            #[automatically_derived]
+            #[allow(unused_mut, unused_variables)]
+            impl$(<$life>)? $crate::WriteHci for $name$(<$life>)? {
+                #[inline(always)]
+                fn size(&self) -> usize {
+                    $(<$ty as $crate::WriteHci>::size(&self.$field) +)* 0
+                }
+
+                fn write_hci<W: ::embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                    $(<$ty as $crate::WriteHci>::write_hci(&self.$field, &mut writer)?;)*
+                    Ok(())
+                }
+
+                async fn write_hci_async<W: ::embedded_io_async::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                    $(<$ty as $crate::WriteHci>::write_hci_async(&self.$field, &mut writer).await?;)*
+                    Ok(())
+                }
+            }
         )+
     };
 }
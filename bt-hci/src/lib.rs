@@ -2,21 +2,35 @@
 #![warn(missing_docs)]
 #![no_std]
 
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
 use core::future::Future;
 
 use bt_hci_driver::blocking::TryError;
-use bt_hci_driver::PacketToHost;
 use embedded_io::ReadExactError;
 
 mod fmt;
 
+#[cfg(any(test, feature = "btsnoop"))]
+pub mod btsnoop;
+#[cfg(any(test, feature = "btsnoop", feature = "pcap"))]
+mod capture;
 pub mod cmd;
 pub mod controller;
 pub mod data;
+pub mod dissect;
 pub mod event;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod param;
+#[cfg(any(test, feature = "pcap"))]
+pub mod pcap;
+#[cfg(any(test, feature = "pcap"))]
+pub mod pcapng;
 pub mod transport;
-pub use bt_hci_driver::{PacketKind, ReadHciError};
+pub mod vendor;
+pub use bt_hci_driver::{PacketKind, PacketToController, PacketToHost, ReadHciError};
 pub use btuuid as uuid;
 
 /// Errors from parsing HCI data.
@@ -111,6 +125,64 @@ pub trait WriteHci {
     fn write_hci_async<W: embedded_io_async::Write>(&self, writer: W) -> impl Future<Output = Result<(), W::Error>>;
 }
 
+/// An HCI packet from the controller to the host that can be serialized.
+///
+/// This is the writing counterpart of [`PacketToHost`], for use on the controller side of a transport
+/// by controller implementations, simulators and test fixtures. See
+/// [`SerialTransport::write_to_host`](transport::SerialTransport::write_to_host).
+pub trait WritePacketToHost {
+    /// The kind of this packet.
+    fn kind(&self) -> PacketKind;
+
+    /// Returns the size of the packet in bytes, excluding the packet indicator.
+    fn size(&self) -> usize;
+
+    /// Write this packet to the provided writer, excluding the packet indicator.
+    fn write_hci<W: embedded_io::Write>(&self, writer: W) -> Result<(), W::Error>;
+
+    /// Write this packet to the provided writer, excluding the packet indicator, async version.
+    fn write_hci_async<W: embedded_io_async::Write>(&self, writer: W) -> impl Future<Output = Result<(), W::Error>>;
+}
+
+macro_rules! write_packet_to_host {
+    ($($ty:ty => $kind:ident),+ $(,)?) => {
+        $(
+            impl WritePacketToHost for $ty {
+                #[inline(always)]
+                fn kind(&self) -> PacketKind {
+                    PacketKind::$kind
+                }
+
+                #[inline(always)]
+                fn size(&self) -> usize {
+                    <Self as WriteHci>::size(self)
+                }
+
+                #[inline(always)]
+                fn write_hci<W: embedded_io::Write>(&self, writer: W) -> Result<(), W::Error> {
+                    <Self as WriteHci>::write_hci(self, writer)
+                }
+
+                #[inline(always)]
+                fn write_hci_async<W: embedded_io_async::Write>(
+                    &self,
+                    writer: W,
+                ) -> impl Future<Output = Result<(), W::Error>> {
+                    <Self as WriteHci>::write_hci_async(self, writer)
+                }
+            }
+        )+
+    };
+}
+
+write_packet_to_host! {
+    event::Event<'_> => Event,
+    event::EventPacket<'_> => Event,
+    data::AclPacket<'_> => AclData,
+    data::SyncPacket<'_> => SyncData,
+    data::IsoPacket<'_> => IsoData,
+}
+
 /// Marker trait for HCI values that have a known, fixed size
 ///
 /// # Safety
@@ -273,6 +345,39 @@ impl<'a> ControllerToHostPacket<'a> {
     }
 }
 
+impl WritePacketToHost for ControllerToHostPacket<'_> {
+    fn kind(&self) -> PacketKind {
+        ControllerToHostPacket::kind(self)
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Self::Acl(pkt) => WriteHci::size(pkt),
+            Self::Sync(pkt) => WriteHci::size(pkt),
+            Self::Event(pkt) => WriteHci::size(pkt),
+            Self::Iso(pkt) => WriteHci::size(pkt),
+        }
+    }
+
+    fn write_hci<W: embedded_io::Write>(&self, writer: W) -> Result<(), W::Error> {
+        match self {
+            Self::Acl(pkt) => WriteHci::write_hci(pkt, writer),
+            Self::Sync(pkt) => WriteHci::write_hci(pkt, writer),
+            Self::Event(pkt) => WriteHci::write_hci(pkt, writer),
+            Self::Iso(pkt) => WriteHci::write_hci(pkt, writer),
+        }
+    }
+
+    async fn write_hci_async<W: embedded_io_async::Write>(&self, writer: W) -> Result<(), W::Error> {
+        match self {
+            Self::Acl(pkt) => WriteHci::write_hci_async(pkt, writer).await,
+            Self::Sync(pkt) => WriteHci::write_hci_async(pkt, writer).await,
+            Self::Event(pkt) => WriteHci::write_hci_async(pkt, writer).await,
+            Self::Iso(pkt) => WriteHci::write_hci_async(pkt, writer).await,
+        }
+    }
+}
+
 impl<'de> FromHciBytes<'de> for ControllerToHostPacket<'de> {
     fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), FromHciBytesError> {
         let (kind, data) = PacketKind::from_hci_bytes(data)?;
@@ -346,3 +451,134 @@ impl<'de> PacketToHost<'de> for ControllerToHostPacket<'de> {
         }
     }
 }
+
+/// Type representing valid deserialized HCI packets sent from the host to the controller.
+///
+/// Command packets can be decoded further with [`cmd::Command::try_from`].
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HostToControllerPacket<'a> {
+    /// Command packet.
+    Cmd(cmd::CmdPacket<'a>),
+    /// ACL packet.
+    Acl(data::AclPacket<'a>),
+    /// Sync packet.
+    Sync(data::SyncPacket<'a>),
+    /// Isochronous packet.
+    Iso(data::IsoPacket<'a>),
+}
+
+impl<'a> HostToControllerPacket<'a> {
+    /// The packet kind.
+    pub fn kind(&self) -> PacketKind {
+        match self {
+            Self::Cmd(_) => PacketKind::Cmd,
+            Self::Acl(_) => PacketKind::AclData,
+            Self::Sync(_) => PacketKind::SyncData,
+            Self::Iso(_) => PacketKind::IsoData,
+        }
+    }
+
+    /// Deserialize data assuming a specific kind of packet.
+    pub fn from_hci_bytes_with_kind(
+        kind: PacketKind,
+        data: &'a [u8],
+    ) -> Result<(HostToControllerPacket<'a>, &'a [u8]), FromHciBytesError> {
+        match kind {
+            PacketKind::Cmd => cmd::CmdPacket::from_hci_bytes(data).map(|(x, y)| (Self::Cmd(x), y)),
+            PacketKind::AclData => data::AclPacket::from_hci_bytes(data).map(|(x, y)| (Self::Acl(x), y)),
+            PacketKind::SyncData => data::SyncPacket::from_hci_bytes(data).map(|(x, y)| (Self::Sync(x), y)),
+            PacketKind::Event => Err(FromHciBytesError::InvalidValue),
+            PacketKind::IsoData => data::IsoPacket::from_hci_bytes(data).map(|(x, y)| (Self::Iso(x), y)),
+        }
+    }
+}
+
+/// Writes the packet without its packet indicator, see [`HostToControllerPacket::kind`].
+impl WriteHci for HostToControllerPacket<'_> {
+    fn size(&self) -> usize {
+        match self {
+            Self::Cmd(pkt) => WriteHci::size(pkt),
+            Self::Acl(pkt) => WriteHci::size(pkt),
+            Self::Sync(pkt) => WriteHci::size(pkt),
+            Self::Iso(pkt) => WriteHci::size(pkt),
+        }
+    }
+
+    fn write_hci<W: embedded_io::Write>(&self, writer: W) -> Result<(), W::Error> {
+        match self {
+            Self::Cmd(pkt) => WriteHci::write_hci(pkt, writer),
+            Self::Acl(pkt) => WriteHci::write_hci(pkt, writer),
+            Self::Sync(pkt) => WriteHci::write_hci(pkt, writer),
+            Self::Iso(pkt) => WriteHci::write_hci(pkt, writer),
+        }
+    }
+
+    async fn write_hci_async<W: embedded_io_async::Write>(&self, writer: W) -> Result<(), W::Error> {
+        match self {
+            Self::Cmd(pkt) => WriteHci::write_hci_async(pkt, writer).await,
+            Self::Acl(pkt) => WriteHci::write_hci_async(pkt, writer).await,
+            Self::Sync(pkt) => WriteHci::write_hci_async(pkt, writer).await,
+            Self::Iso(pkt) => WriteHci::write_hci_async(pkt, writer).await,
+        }
+    }
+}
+
+impl<'de> FromHciBytes<'de> for HostToControllerPacket<'de> {
+    fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), FromHciBytesError> {
+        let (kind, data) = PacketKind::from_hci_bytes(data)?;
+        Self::from_hci_bytes_with_kind(kind, data)
+    }
+}
+
+impl<'de> ReadHci<'de> for HostToControllerPacket<'de> {
+    const MAX_LEN: usize = 258;
+
+    fn read_hci<R: embedded_io::Read>(mut reader: R, buf: &'de mut [u8]) -> Result<Self, ReadHciError<R::Error>> {
+        let mut kind = [0];
+        reader.read_exact(&mut kind)?;
+        let (kind, _) = PacketKind::from_hci_bytes(&kind)?;
+        <Self as PacketToHost>::read_hci(kind, &mut reader, buf)
+    }
+
+    async fn read_hci_async<R: embedded_io_async::Read>(
+        mut reader: R,
+        buf: &'de mut [u8],
+    ) -> Result<Self, ReadHciError<R::Error>> {
+        let mut kind = [0u8];
+        reader.read_exact(&mut kind).await?;
+        let (kind, _) = PacketKind::from_hci_bytes(&kind)?;
+        <Self as PacketToHost>::read_hci_async(kind, &mut reader, buf).await
+    }
+}
+
+/// Allows the controller side of a [`Transport`](crate::transport::Transport) to read packets sent by the host.
+impl<'de> PacketToHost<'de> for HostToControllerPacket<'de> {
+    fn read_hci<R: embedded_io::Read>(
+        kind: PacketKind,
+        reader: &mut R,
+        buf: &'de mut [u8],
+    ) -> Result<Self, ReadHciError<R::Error>> {
+        match kind {
+            PacketKind::Cmd => cmd::CmdPacket::read_hci(reader, buf).map(Self::Cmd),
+            PacketKind::AclData => data::AclPacket::read_hci(reader, buf).map(Self::Acl),
+            PacketKind::SyncData => data::SyncPacket::read_hci(reader, buf).map(Self::Sync),
+            PacketKind::Event => Err(ReadHciError::InvalidValue),
+            PacketKind::IsoData => data::IsoPacket::read_hci(reader, buf).map(Self::Iso),
+        }
+    }
+
+    async fn read_hci_async<R: embedded_io_async::Read>(
+        kind: PacketKind,
+        reader: &mut R,
+        buf: &'de mut [u8],
+    ) -> Result<Self, ReadHciError<R::Error>> {
+        match kind {
+            PacketKind::Cmd => cmd::CmdPacket::read_hci_async(reader, buf).await.map(Self::Cmd),
+            PacketKind::AclData => data::AclPacket::read_hci_async(reader, buf).await.map(Self::Acl),
+            PacketKind::SyncData => data::SyncPacket::read_hci_async(reader, buf).await.map(Self::Sync),
+            PacketKind::Event => Err(ReadHciError::InvalidValue),
+            PacketKind::IsoData => data::IsoPacket::read_hci_async(reader, buf).await.map(Self::Iso),
+        }
+    }
+}
@@ -0,0 +1,552 @@
+//! Scripted transport and controller mocks for testing HCI interactions without hardware.
+//!
+//! A test describes the expected conversation as a list of [`Step`]s. Every packet the host writes must
+//! match the next [`Step::Write`], and every [`Step::Read`] is handed to the host in order. All packets are
+//! given in H4 format, i.e. with the [`PacketKind`] indicator byte first.
+//!
+//! ```
+//! # embassy_futures::block_on(async {
+//! use bt_hci::cmd::info::ReadBdAddr;
+//! use bt_hci::cmd::SyncCmd;
+//! use bt_hci::mock::{MockController, Step};
+//!
+//! let script = [
+//!     Step::Write(&[0x01, 0x09, 0x10, 0x00]),
+//!     Step::Read(&[0x04, 0x0e, 0x0a, 0x01, 0x09, 0x10, 0x00, 1, 2, 3, 4, 5, 6]),
+//! ];
+//! let controller: MockController = MockController::new(&script);
+//! let addr = ReadBdAddr::new().exec(&controller).await.unwrap();
+//! assert_eq!(addr.raw(), [1, 2, 3, 4, 5, 6]);
+//! controller.assert_done();
+//! # });
+//! ```
+//!
+//! Mismatches panic with a side by side description of the expected and actual packet.
+
+use core::cell::Cell;
+use core::convert::Infallible;
+use core::fmt;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use bt_hci_driver::{PacketKind, PacketToController, PacketToHost, ReadHciError, WithIndicator};
+use embassy_sync::blocking_mutex::raw::{NoopRawMutex, RawMutex};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::AtomicWaker;
+use embedded_io::ErrorType;
+
+use crate::cmd::{self, Opcode};
+use crate::controller::{blocking, Controller, ControllerCmdAsync, ControllerCmdSync};
+use crate::dissect::Dissect;
+use crate::event::{CommandComplete, CommandCompleteWithStatus, CommandStatus, EventKind, EventPacket};
+use crate::transport::{Direction, Transport};
+use crate::{data, ControllerToHostPacket, FromHciBytes, FromHciBytesError};
+
+pub mod sim;
+
+/// Maximum size of a packet written by the host, including the indicator byte.
+pub const MAX_WRITE_LEN: usize = 1024;
+
+/// A single step of a scripted HCI conversation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step<'a> {
+    /// A packet the host is expected to write to the controller.
+    Write(&'a [u8]),
+    /// A packet the controller delivers to the host.
+    Read(&'a [u8]),
+}
+
+/// Error type for the mocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A scripted packet could not be parsed.
+    Read(ReadHciError<Infallible>),
+    /// All steps of the script have been consumed.
+    Exhausted,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl core::error::Error for Error {}
+
+impl embedded_io::Error for Error {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            Self::Read(e) => embedded_io::Error::kind(e),
+            Self::Exhausted => embedded_io::ErrorKind::BrokenPipe,
+        }
+    }
+}
+
+impl From<ReadHciError<Infallible>> for Error {
+    fn from(e: ReadHciError<Infallible>) -> Self {
+        Self::Read(e)
+    }
+}
+
+impl From<FromHciBytesError> for Error {
+    fn from(e: FromHciBytesError) -> Self {
+        Self::Read(e.into())
+    }
+}
+
+/// A [`Transport`] that checks written packets against a script and replays canned responses.
+///
+/// Implements both the async and [`blocking`](crate::transport::blocking::Transport) transport traits, so it
+/// can be used with [`ExternalController`](crate::controller::ExternalController) or any other consumer of a
+/// transport.
+pub struct MockTransport<'a, M: RawMutex = NoopRawMutex> {
+    script: &'a [Step<'a>],
+    pos: Mutex<M, Cell<usize>>,
+    waker: AtomicWaker,
+}
+
+impl<'a, M: RawMutex> MockTransport<'a, M> {
+    /// Create a new instance running the given script.
+    pub fn new(script: &'a [Step<'a>]) -> Self {
+        Self {
+            script,
+            pos: Mutex::new(Cell::new(0)),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// The steps that have not been consumed yet.
+    pub fn remaining(&self) -> &'a [Step<'a>] {
+        &self.script[self.pos.lock(Cell::get)..]
+    }
+
+    /// Panic if there are steps left in the script.
+    pub fn assert_done(&self) {
+        if let Some(step) = self.remaining().first() {
+            ::core::panic!(
+                "mock HCI script not complete, {} step(s) left, next:\n{}",
+                self.remaining().len(),
+                StepDump(step)
+            );
+        }
+    }
+
+    /// Take the next step if it is a packet for the host.
+    fn take_read(&self) -> Poll<Option<&'a [u8]>> {
+        self.pos.lock(|pos| match self.script.get(pos.get()) {
+            Some(Step::Read(bytes)) => {
+                pos.set(pos.get() + 1);
+                Poll::Ready(Some(*bytes))
+            }
+            Some(Step::Write(_)) => Poll::Pending,
+            None => Poll::Ready(None),
+        })
+    }
+
+    /// Check a packet written by the host against the next step.
+    fn check_write<P: PacketToController>(&self, tx: &P) {
+        let mut buf = [0; MAX_WRITE_LEN];
+        let len = WithIndicator::new(tx).size();
+        if len > buf.len() {
+            ::core::panic!("mock HCI packet of {} bytes exceeds MAX_WRITE_LEN", len);
+        }
+        unwrap!(WithIndicator::new(tx).write_hci(&mut buf[..]));
+        let actual = &buf[..len];
+
+        self.pos.lock(|pos| match self.script.get(pos.get()) {
+            Some(Step::Write(expected)) if *expected == actual => pos.set(pos.get() + 1),
+            Some(Step::Write(expected)) => ::core::panic!("{}", Diff { expected, actual }),
+            Some(step) => ::core::panic!(
+                "mock HCI packet written while a read was expected\nexpected:\n{}\nactual:\n{}",
+                StepDump(step),
+                Dump(Direction::Sent, actual)
+            ),
+            None => ::core::panic!(
+                "unexpected HCI packet written after end of script:\n{}",
+                Dump(Direction::Sent, actual)
+            ),
+        });
+        self.waker.wake();
+    }
+
+    async fn next_read(&self) -> Option<&'a [u8]> {
+        poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            self.take_read()
+        })
+        .await
+    }
+
+    fn parse<'b, P: PacketToHost<'b>>(bytes: &[u8], rx: &'b mut [u8]) -> Result<P, Error> {
+        let mut reader = bytes;
+        let kind = PacketKind::read(&mut reader)?;
+        Ok(P::read_hci(kind, &mut reader, rx)?)
+    }
+}
+
+impl<M: RawMutex> ErrorType for MockTransport<'_, M> {
+    type Error = Error;
+}
+
+impl<M: RawMutex> Transport for MockTransport<'_, M> {
+    async fn read<'b, P: PacketToHost<'b>>(&self, rx: &'b mut [u8]) -> Result<P, Self::Error> {
+        let bytes = self.next_read().await.ok_or(Error::Exhausted)?;
+        Self::parse(bytes, rx)
+    }
+
+    async fn write<P: PacketToController>(&self, tx: &P) -> Result<(), Self::Error> {
+        self.check_write(tx);
+        Ok(())
+    }
+}
+
+impl<M: RawMutex> crate::transport::blocking::Transport for MockTransport<'_, M> {
+    fn read<'b, P: PacketToHost<'b>>(&self, rx: &'b mut [u8]) -> Result<P, blocking::TryError<Self::Error>> {
+        match self.take_read() {
+            Poll::Pending => Err(blocking::TryError::Busy),
+            Poll::Ready(None) => Err(blocking::TryError::Error(Error::Exhausted)),
+            Poll::Ready(Some(bytes)) => Self::parse(bytes, rx).map_err(blocking::TryError::Error),
+        }
+    }
+
+    fn write<P: PacketToController>(&self, tx: &P) -> Result<(), blocking::TryError<Self::Error>> {
+        self.check_write(tx);
+        Ok(())
+    }
+}
+
+/// A [`Controller`] that executes any command against a script.
+///
+/// Commands are checked against the next [`Step::Write`], after which the next step must be the
+/// [`CommandComplete`] or [`CommandStatus`] event for that command. Unlike
+/// [`ExternalController`](crate::controller::ExternalController), no concurrent [`Controller::read`] is needed for
+/// commands to complete.
+pub struct MockController<'a, M: RawMutex = NoopRawMutex> {
+    transport: MockTransport<'a, M>,
+}
+
+impl<'a, M: RawMutex> MockController<'a, M> {
+    /// Create a new instance running the given script.
+    pub fn new(script: &'a [Step<'a>]) -> Self {
+        Self {
+            transport: MockTransport::new(script),
+        }
+    }
+
+    /// The underlying transport.
+    pub fn transport(&self) -> &MockTransport<'a, M> {
+        &self.transport
+    }
+
+    /// Panic if there are steps left in the script.
+    pub fn assert_done(&self) {
+        self.transport.assert_done()
+    }
+
+    /// The next step if it is a packet for the host.
+    ///
+    /// A pending read means the script expects another write before the response, which is reported the
+    /// same way as the end of the script.
+    fn take_response(&self) -> Option<&'a [u8]> {
+        match self.transport.take_read() {
+            Poll::Ready(bytes) => bytes,
+            Poll::Pending => None,
+        }
+    }
+
+    fn response(&self, bytes: Option<&'a [u8]>, opcode: Opcode, expected: EventKind) -> EventPacket<'a> {
+        let Some(bytes) = bytes else {
+            ::core::panic!(
+                "mock HCI script ended while waiting for the response to opcode {:#06x}",
+                opcode.to_raw()
+            );
+        };
+        match ControllerToHostPacket::from_hci_bytes_complete(bytes) {
+            Ok(ControllerToHostPacket::Event(event)) if event.kind == expected => event,
+            _ => ::core::panic!(
+                "expected {:?} for opcode {:#06x} in mock HCI script, found:\n{}",
+                expected,
+                opcode.to_raw(),
+                Dump(Direction::Received, bytes)
+            ),
+        }
+    }
+
+    fn command_complete<C: cmd::SyncCmd>(&self, bytes: Option<&'a [u8]>) -> Result<C::Return, cmd::Error<Error>> {
+        let event = self.response(bytes, C::OPCODE, EventKind::CommandComplete);
+        let e = CommandComplete::from_hci_bytes_complete(event.data).map_err(|e| cmd::Error::Io(Error::from(e)))?;
+        let e: CommandCompleteWithStatus = e.try_into().map_err(|e| cmd::Error::Io(Error::from(e)))?;
+        if e.cmd_opcode != C::OPCODE {
+            ::core::panic!(
+                "mock HCI command complete for opcode {:#06x}, expected {:#06x}",
+                e.cmd_opcode.to_raw(),
+                C::OPCODE.to_raw()
+            );
+        }
+        Ok(e.to_result::<C>()?)
+    }
+
+    fn command_status<C: cmd::AsyncCmd>(&self, bytes: Option<&'a [u8]>) -> Result<(), cmd::Error<Error>> {
+        let event = self.response(bytes, C::OPCODE, EventKind::CommandStatus);
+        let e = CommandStatus::from_hci_bytes_complete(event.data).map_err(|e| cmd::Error::Io(Error::from(e)))?;
+        if e.cmd_opcode != C::OPCODE {
+            ::core::panic!(
+                "mock HCI command status for opcode {:#06x}, expected {:#06x}",
+                e.cmd_opcode.to_raw(),
+                C::OPCODE.to_raw()
+            );
+        }
+        Ok(e.status.to_result()?)
+    }
+}
+
+impl<M: RawMutex> ErrorType for MockController<'_, M> {
+    type Error = Error;
+}
+
+impl<M: RawMutex> Controller for MockController<'_, M> {
+    type Buffer<'b> = [u8; 259];
+
+    fn alloc_buf(&self) -> Result<Self::Buffer<'_>, Self::Error> {
+        Ok([0; 259])
+    }
+
+    async fn write_acl_data(&self, packet: &data::AclPacket<'_>) -> Result<(), Self::Error> {
+        Transport::write(&self.transport, packet).await
+    }
+
+    async fn write_sync_data(&self, packet: &data::SyncPacket<'_>) -> Result<(), Self::Error> {
+        Transport::write(&self.transport, packet).await
+    }
+
+    async fn write_iso_data(&self, packet: &data::IsoPacket<'_>) -> Result<(), Self::Error> {
+        Transport::write(&self.transport, packet).await
+    }
+
+    async fn read<'b>(&self, buf: &'b mut Self::Buffer<'_>) -> Result<ControllerToHostPacket<'b>, Self::Error> {
+        Transport::read(&self.transport, &mut buf[..]).await
+    }
+}
+
+impl<M: RawMutex> blocking::Controller for MockController<'_, M> {
+    type Buffer<'b> = [u8; 259];
+
+    fn alloc_buf(&self) -> Result<Self::Buffer<'_>, Self::Error> {
+        Ok([0; 259])
+    }
+
+    fn write_acl_data(&self, packet: &data::AclPacket<'_>) -> Result<(), Self::Error> {
+        self.transport.check_write(packet);
+        Ok(())
+    }
+
+    fn write_sync_data(&self, packet: &data::SyncPacket<'_>) -> Result<(), Self::Error> {
+        self.transport.check_write(packet);
+        Ok(())
+    }
+
+    fn write_iso_data(&self, packet: &data::IsoPacket<'_>) -> Result<(), Self::Error> {
+        self.transport.check_write(packet);
+        Ok(())
+    }
+
+    fn try_write_acl_data(&self, packet: &data::AclPacket<'_>) -> Result<(), blocking::TryError<Self::Error>> {
+        self.transport.check_write(packet);
+        Ok(())
+    }
+
+    fn try_write_sync_data(&self, packet: &data::SyncPacket<'_>) -> Result<(), blocking::TryError<Self::Error>> {
+        self.transport.check_write(packet);
+        Ok(())
+    }
+
+    fn try_write_iso_data(&self, packet: &data::IsoPacket<'_>) -> Result<(), blocking::TryError<Self::Error>> {
+        self.transport.check_write(packet);
+        Ok(())
+    }
+
+    fn read<'b>(&self, buf: &'b mut Self::Buffer<'_>) -> Result<ControllerToHostPacket<'b>, Self::Error> {
+        match self.try_read(buf) {
+            Ok(pkt) => Ok(pkt),
+            Err(blocking::TryError::Error(e)) => Err(e),
+            // Nobody else can write while we block, so waiting would never finish
+            Err(blocking::TryError::Busy) => ::core::panic!(
+                "mock HCI read while a write was expected:\n{}",
+                StepDump(&self.transport.remaining()[0])
+            ),
+        }
+    }
+
+    fn try_read<'b>(
+        &self,
+        buf: &'b mut Self::Buffer<'_>,
+    ) -> Result<ControllerToHostPacket<'b>, blocking::TryError<Self::Error>> {
+        crate::transport::blocking::Transport::read(&self.transport, &mut buf[..])
+    }
+}
+
+impl<C: cmd::SyncCmd, M: RawMutex> ControllerCmdSync<C> for MockController<'_, M> {
+    async fn exec(&self, cmd: &C) -> Result<C::Return, cmd::Error<Self::Error>> {
+        self.transport.check_write(cmd);
+        self.command_complete::<C>(self.take_response())
+    }
+}
+
+impl<C: cmd::AsyncCmd, M: RawMutex> ControllerCmdAsync<C> for MockController<'_, M> {
+    async fn exec(&self, cmd: &C) -> Result<(), cmd::Error<Self::Error>> {
+        self.transport.check_write(cmd);
+        self.command_status::<C>(self.take_response())
+    }
+}
+
+/// Human readable description of an H4 packet, followed by its raw bytes.
+struct Dump<'a>(Direction, &'a [u8]);
+
+impl fmt::Display for Dump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n  {:02x?}", Dissect::h4(self.0, self.1), self.1)
+    }
+}
+
+struct StepDump<'a, 'b>(&'b Step<'a>);
+
+impl fmt::Display for StepDump<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Step::Write(bytes) => write!(f, "{}", Dump(Direction::Sent, bytes)),
+            Step::Read(bytes) => write!(f, "{}", Dump(Direction::Received, bytes)),
+        }
+    }
+}
+
+/// Mismatch between an expected and an actual packet.
+struct Diff<'a> {
+    expected: &'a [u8],
+    actual: &'a [u8],
+}
+
+impl fmt::Display for Diff<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offset = self
+            .expected
+            .iter()
+            .zip(self.actual)
+            .position(|(a, b)| a != b)
+            .unwrap_or(self.expected.len().min(self.actual.len()));
+        writeln!(f, "mock HCI packet mismatch at byte {}", offset)?;
+        writeln!(f, "expected:\n{}", Dump(Direction::Sent, self.expected))?;
+        write!(f, "actual:\n{}", Dump(Direction::Sent, self.actual))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::join::join;
+
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::cmd::info::ReadBdAddr;
+    use crate::cmd::le::LeReadRemoteFeatures;
+    use crate::cmd::{AsyncCmd, SyncCmd};
+    use crate::controller::ExternalController;
+    use crate::data::{AclBroadcastFlag, AclPacket, AclPacketBoundary};
+    use crate::param::{BdAddr, ConnHandle, Error as HciError};
+
+    const READ_BD_ADDR: &[u8] = &[0x01, 0x09, 0x10, 0x00];
+    const READ_BD_ADDR_COMPLETE: &[u8] = &[0x04, 0x0e, 0x0a, 0x01, 0x09, 0x10, 0x00, 1, 2, 3, 4, 5, 6];
+
+    #[futures_test::test]
+    async fn test_sync_command() {
+        let script = [Step::Write(READ_BD_ADDR), Step::Read(READ_BD_ADDR_COMPLETE)];
+        let c: MockController = MockController::new(&script);
+        let addr = ReadBdAddr::new().exec(&c).await.unwrap();
+        assert_eq!(addr, BdAddr::new([1, 2, 3, 4, 5, 6]));
+        c.assert_done();
+    }
+
+    #[futures_test::test]
+    async fn test_async_command_error() {
+        let script = [
+            Step::Write(&[0x01, 0x16, 0x20, 0x02, 0x01, 0x00]),
+            Step::Read(&[0x04, 0x0f, 0x04, 0x0c, 0x01, 0x16, 0x20]),
+        ];
+        let c: MockController = MockController::new(&script);
+        let res = LeReadRemoteFeatures::new(ConnHandle::new(1)).exec(&c).await;
+        assert!(matches!(res, Err(cmd::Error::Hci(HciError::CMD_DISALLOWED))));
+        c.assert_done();
+    }
+
+    #[futures_test::test]
+    async fn test_acl_and_events() {
+        let script = [
+            Step::Write(&[0x02, 0x01, 0x20, 0x02, 0x00, 0xaa, 0xbb]),
+            Step::Read(&[0x04, 0x13, 0x05, 0x01, 0x01, 0x00, 0x01, 0x00]),
+        ];
+        let c: MockController = MockController::new(&script);
+        let pkt = AclPacket::new(
+            ConnHandle::new(1),
+            AclPacketBoundary::FirstFlushable,
+            AclBroadcastFlag::PointToPoint,
+            &[0xaa, 0xbb],
+        );
+        c.write_acl_data(&pkt).await.unwrap();
+        let mut buf = Controller::alloc_buf(&c).unwrap();
+        let pkt = Controller::read(&c, &mut buf).await.unwrap();
+        assert!(matches!(pkt, ControllerToHostPacket::Event(e) if e.kind == EventKind::NumberOfCompletedPackets));
+        assert_eq!(Controller::read(&c, &mut buf).await.unwrap_err(), Error::Exhausted);
+    }
+
+    #[futures_test::test]
+    async fn test_external_controller_over_mock_transport() {
+        let script = [
+            Step::Write(&[0x01, 0x03, 0x0c, 0x00]),
+            Step::Read(&[0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00]),
+            Step::Write(READ_BD_ADDR),
+            Step::Read(READ_BD_ADDR_COMPLETE),
+        ];
+        let c: ExternalController<MockTransport, 2> = ExternalController::new(MockTransport::new(&script));
+        let mut buf = Controller::alloc_buf(&c).unwrap();
+        let commands = async {
+            Reset::new().exec(&c).await.unwrap();
+            ReadBdAddr::new().exec(&c).await.unwrap()
+        };
+        let (addr, rest) = join(commands, Controller::read(&c, &mut buf)).await;
+        assert_eq!(addr, BdAddr::new([1, 2, 3, 4, 5, 6]));
+        assert!(matches!(rest, Err(Error::Exhausted)));
+    }
+
+    #[test]
+    fn test_blocking_transport() {
+        use crate::transport::blocking::Transport;
+
+        let script = [Step::Read(READ_BD_ADDR_COMPLETE), Step::Write(READ_BD_ADDR)];
+        let t: MockTransport = MockTransport::new(&script);
+        let mut buf = [0; 259];
+        let pkt: ControllerToHostPacket = Transport::read(&t, &mut buf).unwrap();
+        assert!(matches!(pkt, ControllerToHostPacket::Event(_)));
+        let mut buf = [0; 259];
+        assert!(matches!(
+            Transport::read::<ControllerToHostPacket>(&t, &mut buf),
+            Err(blocking::TryError::Busy)
+        ));
+        Transport::write(&t, &ReadBdAddr::new()).unwrap();
+        t.assert_done();
+    }
+
+    #[futures_test::test]
+    #[should_panic(expected = "mock HCI packet mismatch at byte 1")]
+    async fn test_mismatch_panics() {
+        let script = [Step::Write(READ_BD_ADDR), Step::Read(READ_BD_ADDR_COMPLETE)];
+        let c: MockController = MockController::new(&script);
+        let _ = Reset::new().exec(&c).await;
+    }
+
+    #[test]
+    #[should_panic(expected = "1 step(s) left")]
+    fn test_assert_done_panics() {
+        let script = [Step::Write(READ_BD_ADDR)];
+        let c: MockController = MockController::new(&script);
+        c.assert_done();
+    }
+}
@@ -0,0 +1,1723 @@
+//! In-process virtual LE controller for running hosts against each other without a radio.
+//!
+//! Several [`VirtualController`]s attach to a shared [`Air`]. Each of them implements [`Controller`] together with
+//! [`ControllerCmdSync`] and [`ControllerCmdAsync`] for every command, so a host can use it in place of an
+//! [`ExternalController`](crate::controller::ExternalController). Commands outside the supported subset complete
+//! with [`UNKNOWN_CMD`](crate::param::Error::UNKNOWN_CMD).
+//!
+//! The supported subset covers:
+//!
+//! * legacy and extended advertising, including advertising set duration and event limits,
+//! * passive and active scanning with legacy or extended advertising reports,
+//! * connection establishment with [`LeCreateConn`] and [`LeExtCreateConn`], and [`Disconnect`],
+//! * ACL data exchange between connected controllers,
+//! * LE encryption, which only compares the long term keys given by both hosts.
+//!
+//! Time only passes when [`Air::advance`] is called, which makes every run deterministic. Advertising events
+//! happen exactly once per advertising interval and every scanner hears every advertising event, regardless of the
+//! scan interval and window. ACL data and all other link layer procedures complete immediately.
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use bt_hci_driver::{PacketKind, PacketToController};
+use embassy_sync::blocking_mutex::raw::{NoopRawMutex, RawMutex};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::AtomicWaker;
+use embedded_io::ErrorType;
+
+use crate::cmd::controller_baseband::{
+    HostBufferSize, Reset, SetControllerToHostFlowControl, SetEventMask, SetEventMaskPage2,
+};
+use crate::cmd::info::{ReadBdAddr, ReadLocalVersionInformation, ReadLocalVersionInformationReturn};
+use crate::cmd::le::{
+    LeClearAdvSets, LeCreateConn, LeCreateConnCancel, LeCreateConnParams, LeEnableEncryption, LeEnableEncryptionParams,
+    LeExtCreateConn, LeExtCreateConnParams, LeLongTermKeyRequestNegativeReply, LeLongTermKeyRequestReply,
+    LeLongTermKeyRequestReplyParams, LeRand, LeReadBufferSize, LeReadBufferSizeReturn, LeReadLocalSupportedFeatures,
+    LeReadMaxAdvDataLength, LeReadNumberOfSupportedAdvSets, LeReadRemoteFeatures, LeRemoveAdvSet, LeSetAdvData,
+    LeSetAdvDataParams, LeSetAdvEnable, LeSetAdvParams, LeSetAdvParamsParams, LeSetAdvSetRandomAddr,
+    LeSetAdvSetRandomAddrParams, LeSetEventMask, LeSetExtAdvData, LeSetExtAdvDataParams, LeSetExtAdvEnable,
+    LeSetExtAdvEnableParams, LeSetExtAdvParams, LeSetExtAdvParamsParams, LeSetExtScanEnable, LeSetExtScanEnableParams,
+    LeSetExtScanParams, LeSetExtScanParamsParams, LeSetExtScanResponseData, LeSetExtScanResponseDataParams,
+    LeSetRandomAddr, LeSetScanEnable, LeSetScanEnableParams, LeSetScanParams, LeSetScanParamsParams,
+    LeSetScanResponseData, LeSetScanResponseDataParams,
+};
+use crate::cmd::link_control::{Disconnect, DisconnectParams};
+use crate::cmd::{self, Cmd, Opcode};
+use crate::controller::{Controller, ControllerCmdAsync, ControllerCmdSync};
+use crate::data::{self, AclBroadcastFlag};
+use crate::event::le::{
+    LeAdvertisingReport, LeAdvertisingSetTerminated, LeConnectionComplete, LeEnhancedConnectionComplete, LeEventParams,
+    LeExtendedAdvertisingReport, LeLongTermKeyRequest, LeReadRemoteFeaturesComplete,
+};
+use crate::event::{DisconnectionComplete, EncryptionChangeV1, EventKind, EventParams, NumberOfCompletedPackets};
+use crate::param::{
+    AddrKind, AdvEventProps, AdvHandle, AdvKind, BdAddr, ClockAccuracy, ConnHandle, ConnHandleCompletedPackets,
+    CoreSpecificationVersion, Duration, EncryptionEnabledLevel, Error, FilterDuplicates, LeAdvEventKind, LeAdvReport,
+    LeConnRole, LeExtAdvEventKind, LeExtAdvReport, LeFeatureMask, LeScanKind, Operation, PhyKind, PhyParams, Status,
+};
+use crate::{ControllerToHostPacket, FromHciBytes, WriteHci};
+
+/// Maximum number of advertising sets per controller.
+pub const MAX_ADV_SETS: usize = 4;
+/// Maximum number of connections per controller.
+pub const MAX_CONNS: usize = 4;
+/// Maximum length of extended advertising and scan response data.
+///
+/// This is the most that fits in a single extended advertising report, so reports are never fragmented.
+pub const MAX_EXT_ADV_DATA_LEN: usize = 229;
+/// Maximum length of ACL data, as returned by [`LeReadBufferSize`].
+pub const MAX_ACL_DATA_LEN: u16 = 251;
+/// Number of ACL packets the controller buffers, as returned by [`LeReadBufferSize`].
+pub const NUM_ACL_PACKETS: u8 = 8;
+/// RSSI reported for every advertising report.
+pub const RSSI: i8 = -40;
+
+const QUEUE_LEN: usize = 32;
+const PACKET_LEN: usize = 259;
+const LEGACY_ADV_DATA_LEN: usize = 31;
+const MAX_SEEN: usize = 16;
+const TX_POWER_NOT_AVAILABLE: i8 = 127;
+
+/// The simulated air medium shared by a group of [`VirtualController`]s.
+///
+/// `NODES` is the maximum number of controllers that can be attached. The raw mutex `M` must be a sync one, e.g.
+/// `CriticalSectionRawMutex`, if the controllers are used from different threads.
+pub struct Air<M: RawMutex = NoopRawMutex, const NODES: usize = 2> {
+    state: Mutex<M, RefCell<AirState<NODES>>>,
+    wakers: [AtomicWaker; NODES],
+}
+
+impl<M: RawMutex, const NODES: usize> Default for Air<M, NODES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: RawMutex, const NODES: usize> Air<M, NODES> {
+    /// Create a new, empty air medium at time zero.
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(AirState {
+                now: 0,
+                nodes: [const { None }; NODES],
+            })),
+            wakers: [const { AtomicWaker::new() }; NODES],
+        }
+    }
+
+    /// Attach a new controller with the given public address.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `NODES` controllers are already attached.
+    pub fn attach(&self, addr: BdAddr) -> VirtualController<'_, M, NODES> {
+        let node = self.with(|state| {
+            let idx = unwrap!(state.nodes.iter().position(Option::is_none));
+            state.nodes[idx] = Some(Node::new(addr, idx));
+            idx
+        });
+        VirtualController { air: self, node }
+    }
+
+    /// The current simulated time in microseconds.
+    pub fn now(&self) -> u64 {
+        self.with(|state| state.now)
+    }
+
+    /// Let `micros` microseconds of simulated time pass, running all radio activity that happens meanwhile.
+    pub fn advance(&self, micros: u64) {
+        self.with(|state| {
+            let end = state.now + micros;
+            while let Some((at, node, slot)) = state.next_adv_event(end) {
+                state.now = at;
+                state.adv_event(node, slot);
+            }
+            state.now = end;
+        });
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut AirState<NODES>) -> R) -> R {
+        let ret = self.state.lock(|state| f(&mut state.borrow_mut()));
+        for waker in self.wakers.iter() {
+            waker.wake();
+        }
+        ret
+    }
+}
+
+/// A virtual LE controller attached to an [`Air`].
+pub struct VirtualController<'a, M: RawMutex = NoopRawMutex, const NODES: usize = 2> {
+    air: &'a Air<M, NODES>,
+    node: usize,
+}
+
+impl<M: RawMutex, const NODES: usize> VirtualController<'_, M, NODES> {
+    /// The public address of this controller.
+    pub fn addr(&self) -> BdAddr {
+        self.air.with(|state| state.node(self.node).addr)
+    }
+
+    fn command<C: Cmd>(&self, cmd: &C, ret: &mut [u8]) -> Result<usize, Error> {
+        let mut buf = [0; PACKET_LEN];
+        let len = PacketToController::size(cmd);
+        unwrap!(PacketToController::write_hci(cmd, &mut buf[..]));
+        self.air
+            .with(|state| state.command(self.node, C::OPCODE, &buf[3..len], ret))
+    }
+}
+
+impl<M: RawMutex, const NODES: usize> ErrorType for VirtualController<'_, M, NODES> {
+    type Error = Infallible;
+}
+
+impl<M: RawMutex, const NODES: usize> Controller for VirtualController<'_, M, NODES> {
+    type Buffer<'b> = [u8; PACKET_LEN];
+
+    fn alloc_buf(&self) -> Result<Self::Buffer<'_>, Self::Error> {
+        Ok([0; PACKET_LEN])
+    }
+
+    async fn write_acl_data(&self, packet: &data::AclPacket<'_>) -> Result<(), Self::Error> {
+        self.air.with(|state| state.acl(self.node, packet));
+        Ok(())
+    }
+
+    async fn write_sync_data(&self, _packet: &data::SyncPacket<'_>) -> Result<(), Self::Error> {
+        // No synchronous connections in the simulation
+        Ok(())
+    }
+
+    async fn write_iso_data(&self, _packet: &data::IsoPacket<'_>) -> Result<(), Self::Error> {
+        // No isochronous channels in the simulation
+        Ok(())
+    }
+
+    async fn read<'b>(&self, buf: &'b mut Self::Buffer<'_>) -> Result<ControllerToHostPacket<'b>, Self::Error> {
+        let len = poll_fn(|cx| {
+            self.air.wakers[self.node].register(cx.waker());
+            self.air.state.lock(|state| {
+                let mut state = state.borrow_mut();
+                match state.node_mut(self.node).queue.pop() {
+                    Some(packet) => {
+                        buf[..packet.len].copy_from_slice(packet.bytes());
+                        Poll::Ready(packet.len)
+                    }
+                    None => Poll::Pending,
+                }
+            })
+        })
+        .await;
+        Ok(unwrap!(ControllerToHostPacket::from_hci_bytes_complete(&buf[..len])))
+    }
+}
+
+impl<C: cmd::SyncCmd, M: RawMutex, const NODES: usize> ControllerCmdSync<C> for VirtualController<'_, M, NODES> {
+    async fn exec(&self, cmd: &C) -> Result<C::Return, cmd::Error<Self::Error>> {
+        let mut ret = [0; PACKET_LEN];
+        let len = self.command(cmd, &mut ret)?;
+        C::Return::from_hci_bytes_complete(&ret[..len]).or(Err(cmd::Error::Hci(Error::INVALID_HCI_PARAMETERS)))
+    }
+}
+
+impl<C: cmd::AsyncCmd, M: RawMutex, const NODES: usize> ControllerCmdAsync<C> for VirtualController<'_, M, NODES> {
+    async fn exec(&self, cmd: &C) -> Result<(), cmd::Error<Self::Error>> {
+        self.command(cmd, &mut [])?;
+        Ok(())
+    }
+}
+
+/// A complete H4 packet queued for the host.
+#[derive(Clone, Copy)]
+struct Packet {
+    buf: [u8; PACKET_LEN],
+    len: usize,
+}
+
+impl Packet {
+    fn new(kind: PacketKind) -> Self {
+        let mut buf = [0; PACKET_LEN];
+        buf[0] = kind as u8;
+        Self { buf, len: 1 }
+    }
+
+    fn event(code: u8) -> Self {
+        Self::new(PacketKind::Event).push(&code).push(&0u8)
+    }
+
+    fn le_event(subevent: u8) -> Self {
+        Self::event(EventKind::Le.0).push(&subevent)
+    }
+
+    fn push<T: WriteHci>(mut self, value: &T) -> Self {
+        unwrap!(value.write_hci(&mut self.buf[self.len..]));
+        self.len += value.size();
+        if self.buf[0] == PacketKind::Event as u8 && self.len >= 3 {
+            self.buf[2] = (self.len - 3) as u8;
+        }
+        self
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+struct Queue {
+    packets: [Packet; QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl Queue {
+    fn new() -> Self {
+        Self {
+            packets: [Packet::new(PacketKind::Event); QUEUE_LEN],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, packet: Packet) {
+        if self.len == QUEUE_LEN {
+            warn!("[sim] host queue full, dropping packet");
+            return;
+        }
+        self.packets[(self.head + self.len) % QUEUE_LEN] = packet;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Packet> {
+        (self.len > 0).then(|| {
+            let packet = self.packets[self.head];
+            self.head = (self.head + 1) % QUEUE_LEN;
+            self.len -= 1;
+            packet
+        })
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct AdvData {
+    buf: [u8; MAX_EXT_ADV_DATA_LEN],
+    len: usize,
+}
+
+impl AdvData {
+    const EMPTY: Self = Self {
+        buf: [0; MAX_EXT_ADV_DATA_LEN],
+        len: 0,
+    };
+
+    fn set(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.len = 0;
+        self.append(data)
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<(), Error> {
+        let end = self.len + data.len();
+        if end > self.buf.len() {
+            return Err(Error::MEMORY_CAPACITY_EXCEEDED);
+        }
+        self.buf[self.len..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Advertiser {
+    enabled: bool,
+    props: AdvEventProps,
+    interval: u64,
+    next: u64,
+    own_addr_kind: AddrKind,
+    peer_addr: BdAddr,
+    random_addr: Option<BdAddr>,
+    secondary_phy: PhyKind,
+    sid: u8,
+    data: AdvData,
+    scan_rsp: AdvData,
+    deadline: Option<u64>,
+    max_events: u8,
+    events: u8,
+}
+
+impl Advertiser {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            props: AdvEventProps::new()
+                .set_legacy_adv(true)
+                .set_connectable_adv(true)
+                .set_scannable_adv(true),
+            // 1.28 s, the default advertising interval
+            interval: 1_280_000,
+            next: 0,
+            own_addr_kind: AddrKind::PUBLIC,
+            peer_addr: BdAddr::default(),
+            random_addr: None,
+            secondary_phy: PhyKind::Le1M,
+            sid: 0xff,
+            data: AdvData::EMPTY,
+            scan_rsp: AdvData::EMPTY,
+            deadline: None,
+            max_events: 0,
+            events: 0,
+        }
+    }
+
+    fn legacy_event_kind(&self) -> LeAdvEventKind {
+        let props = self.props;
+        match (props.connectable_adv(), props.scannable_adv(), props.directed_adv()) {
+            (true, _, true) => LeAdvEventKind::AdvDirectInd,
+            (true, _, false) => LeAdvEventKind::AdvInd,
+            (false, true, _) => LeAdvEventKind::AdvScanInd,
+            (false, false, _) => LeAdvEventKind::AdvNonconnInd,
+        }
+    }
+
+    fn ext_event_kind(&self) -> LeExtAdvEventKind {
+        let props = self.props;
+        LeExtAdvEventKind::new()
+            .set_connectable(props.connectable_adv())
+            .set_scannable(props.scannable_adv())
+            .set_directed(props.directed_adv())
+            .set_legacy(props.legacy_adv())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AdvSlot {
+    Legacy,
+    Set(usize),
+}
+
+struct Scanner {
+    enabled: bool,
+    ext: bool,
+    active: bool,
+    filter_duplicates: bool,
+    seen: [(BdAddr, bool); MAX_SEEN],
+    num_seen: usize,
+}
+
+impl Scanner {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            ext: false,
+            active: false,
+            filter_duplicates: false,
+            seen: [(BdAddr::default(), false); MAX_SEEN],
+            num_seen: 0,
+        }
+    }
+
+    /// Returns `true` if the report should be delivered to the host.
+    fn report(&mut self, addr: BdAddr, scan_rsp: bool) -> bool {
+        if !self.filter_duplicates {
+            return true;
+        }
+        if self.seen[..self.num_seen].contains(&(addr, scan_rsp)) {
+            return false;
+        }
+        if self.num_seen < MAX_SEEN {
+            self.seen[self.num_seen] = (addr, scan_rsp);
+            self.num_seen += 1;
+        }
+        true
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Initiator {
+    ext: bool,
+    own_addr_kind: AddrKind,
+    peer_addr_kind: AddrKind,
+    peer_addr: BdAddr,
+    interval: Duration<1_250>,
+    latency: u16,
+    timeout: Duration<10_000>,
+}
+
+#[derive(Clone, Copy)]
+struct Conn {
+    handle: ConnHandle,
+    peer: usize,
+    peer_handle: ConnHandle,
+    role: LeConnRole,
+    ltk: Option<[u8; 16]>,
+}
+
+struct Node {
+    addr: BdAddr,
+    random_addr: Option<BdAddr>,
+    legacy_adv: Advertiser,
+    adv_sets: [Option<(AdvHandle, Advertiser)>; MAX_ADV_SETS],
+    scanner: Scanner,
+    initiator: Option<Initiator>,
+    conns: [Option<Conn>; MAX_CONNS],
+    next_handle: u16,
+    rng: u64,
+    queue: Queue,
+}
+
+impl Node {
+    fn new(addr: BdAddr, idx: usize) -> Self {
+        Self {
+            addr,
+            random_addr: None,
+            legacy_adv: Advertiser::new(),
+            adv_sets: [None; MAX_ADV_SETS],
+            scanner: Scanner::new(),
+            initiator: None,
+            conns: [None; MAX_CONNS],
+            next_handle: 0,
+            rng: 0x2545_f491_4f6c_dd1d ^ (idx as u64 + 1),
+            queue: Queue::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.random_addr = None;
+        self.legacy_adv = Advertiser::new();
+        self.adv_sets = [None; MAX_ADV_SETS];
+        self.scanner = Scanner::new();
+        self.initiator = None;
+        self.conns = [None; MAX_CONNS];
+        self.queue.clear();
+    }
+
+    fn adv(&mut self, slot: AdvSlot) -> &mut Advertiser {
+        match slot {
+            AdvSlot::Legacy => &mut self.legacy_adv,
+            AdvSlot::Set(idx) => &mut unwrap!(self.adv_sets[idx].as_mut()).1,
+        }
+    }
+
+    fn adv_set(&self, handle: AdvHandle) -> Option<usize> {
+        self.adv_sets
+            .iter()
+            .position(|set| set.is_some_and(|(h, _)| h == handle))
+    }
+
+    /// The address kind and address used with `own_addr_kind`, and for advertising with the given random address.
+    fn own_addr(&self, own_addr_kind: AddrKind, random_addr: Option<BdAddr>) -> (AddrKind, BdAddr) {
+        if is_random(own_addr_kind) {
+            (AddrKind::RANDOM, random_addr.or(self.random_addr).unwrap_or(self.addr))
+        } else {
+            (AddrKind::PUBLIC, self.addr)
+        }
+    }
+
+    fn conn(&self, handle: ConnHandle) -> Option<Conn> {
+        self.conns.iter().flatten().find(|conn| conn.handle == handle).copied()
+    }
+
+    fn conn_mut(&mut self, handle: ConnHandle) -> Option<&mut Conn> {
+        self.conns.iter_mut().flatten().find(|conn| conn.handle == handle)
+    }
+
+    fn has_free_conn(&self) -> bool {
+        self.conns.iter().any(Option::is_none)
+    }
+
+    fn add_conn(&mut self, peer: usize, peer_handle: ConnHandle, role: LeConnRole) -> ConnHandle {
+        let handle = ConnHandle::new(self.next_handle);
+        self.next_handle = (self.next_handle + 1) % 0x0f00;
+        let slot = unwrap!(self.conns.iter_mut().find(|conn| conn.is_none()));
+        *slot = Some(Conn {
+            handle,
+            peer,
+            peer_handle,
+            role,
+            ltk: None,
+        });
+        handle
+    }
+
+    fn remove_conn(&mut self, handle: ConnHandle) {
+        for slot in self.conns.iter_mut() {
+            if slot.is_some_and(|conn| conn.handle == handle) {
+                *slot = None;
+            }
+        }
+    }
+
+    fn rand(&mut self) -> [u8; 8] {
+        // xorshift64, deterministic for a given node
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng.to_le_bytes()
+    }
+}
+
+struct AirState<const NODES: usize> {
+    now: u64,
+    nodes: [Option<Node>; NODES],
+}
+
+impl<const NODES: usize> AirState<NODES> {
+    fn node(&self, idx: usize) -> &Node {
+        unwrap!(self.nodes[idx].as_ref())
+    }
+
+    fn node_mut(&mut self, idx: usize) -> &mut Node {
+        unwrap!(self.nodes[idx].as_mut())
+    }
+
+    fn send(&mut self, idx: usize, packet: Packet) {
+        self.node_mut(idx).queue.push(packet);
+    }
+
+    fn others(&self, idx: usize) -> impl Iterator<Item = usize> + '_ {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(move |(i, node)| *i != idx && node.is_some())
+            .map(|(i, _)| i)
+    }
+
+    /// The earliest advertising event at or before `end`, ties broken by node and advertiser order.
+    fn next_adv_event(&self, end: u64) -> Option<(u64, usize, AdvSlot)> {
+        let mut next: Option<(u64, usize, AdvSlot)> = None;
+        for (idx, node) in self.nodes.iter().enumerate() {
+            let Some(node) = node else { continue };
+            let legacy = core::iter::once((AdvSlot::Legacy, &node.legacy_adv));
+            let sets = node
+                .adv_sets
+                .iter()
+                .enumerate()
+                .filter_map(|(i, set)| set.as_ref().map(|(_, adv)| (AdvSlot::Set(i), adv)));
+            for (slot, adv) in legacy.chain(sets).filter(|(_, adv)| adv.enabled) {
+                let at = adv.deadline.map_or(adv.next, |deadline| deadline.min(adv.next));
+                if at <= end && next.is_none_or(|(t, _, _)| at < t) {
+                    next = Some((at, idx, slot));
+                }
+            }
+        }
+        next
+    }
+
+    fn adv_event(&mut self, idx: usize, slot: AdvSlot) {
+        let now = self.now;
+        let node = self.node_mut(idx);
+        let adv = *node.adv(slot);
+        if adv.deadline.is_some_and(|deadline| deadline <= now) {
+            self.stop_adv(idx, slot, Status::ADV_TIMEOUT, ConnHandle::new(0));
+            return;
+        }
+        let (addr_kind, addr) = node.own_addr(adv.own_addr_kind, adv.random_addr);
+
+        if adv.props.connectable_adv() {
+            let initiator = self.others(idx).find(|&other| {
+                let node = self.node(other);
+                node.has_free_conn()
+                    && node.initiator.is_some_and(|init| {
+                        init.peer_addr == addr
+                            && is_random(init.peer_addr_kind) == is_random(addr_kind)
+                            && (!adv.props.directed_adv() || adv.peer_addr == node.own_addr(init.own_addr_kind, None).1)
+                    })
+            });
+            if let Some(initiator) = initiator {
+                if self.node(idx).has_free_conn() {
+                    self.connect(idx, slot, (addr_kind, addr), initiator);
+                    return;
+                }
+            }
+        }
+
+        for other in 0..NODES {
+            if other != idx && self.nodes[other].is_some() {
+                self.deliver_reports(other, &adv, addr_kind, addr);
+            }
+        }
+
+        let adv = self.node_mut(idx).adv(slot);
+        adv.next += adv.interval;
+        adv.events = adv.events.saturating_add(1);
+        if adv.max_events != 0 && adv.events >= adv.max_events {
+            self.stop_adv(idx, slot, Status::LIMIT_REACHED, ConnHandle::new(0));
+        }
+    }
+
+    fn deliver_reports(&mut self, idx: usize, adv: &Advertiser, addr_kind: AddrKind, addr: BdAddr) {
+        let node = self.node_mut(idx);
+        let scanner = &mut node.scanner;
+        if !scanner.enabled || (!scanner.ext && !adv.props.legacy_adv()) {
+            return;
+        }
+        if adv.props.directed_adv() && adv.peer_addr != node.addr && Some(adv.peer_addr) != node.random_addr {
+            return;
+        }
+        let scan_rsp = scanner.active && adv.props.scannable_adv();
+        let responses = [(false, adv.data.bytes()), (true, adv.scan_rsp.bytes())];
+        for (is_scan_rsp, data) in responses.into_iter().take(1 + usize::from(scan_rsp)) {
+            let scanner = &mut self.node_mut(idx).scanner;
+            if !scanner.report(addr, is_scan_rsp) {
+                continue;
+            }
+            let packet = if scanner.ext {
+                let mut event_kind = adv.ext_event_kind();
+                if is_scan_rsp {
+                    event_kind = event_kind.set_scan_response(true);
+                }
+                let report = LeExtAdvReport {
+                    event_kind,
+                    addr_kind,
+                    addr,
+                    primary_adv_phy: PhyKind::Le1M,
+                    secondary_adv_phy: (!adv.props.legacy_adv()).then_some(adv.secondary_phy),
+                    adv_sid: adv.sid,
+                    tx_power: TX_POWER_NOT_AVAILABLE,
+                    rssi: RSSI,
+                    adv_interval: Duration::from_u16(0),
+                    direct_addr_kind: AddrKind::PUBLIC,
+                    direct_addr: if adv.props.directed_adv() {
+                        adv.peer_addr
+                    } else {
+                        BdAddr::default()
+                    },
+                    data,
+                };
+                Packet::le_event(LeExtendedAdvertisingReport::SUBEVENT_CODE)
+                    .push(&1u8)
+                    .push(&report)
+            } else {
+                let report = LeAdvReport {
+                    event_kind: if is_scan_rsp {
+                        LeAdvEventKind::ScanRsp
+                    } else {
+                        adv.legacy_event_kind()
+                    },
+                    addr_kind,
+                    addr,
+                    data,
+                    rssi: RSSI,
+                };
+                Packet::le_event(LeAdvertisingReport::SUBEVENT_CODE)
+                    .push(&1u8)
+                    .push(&report)
+            };
+            self.send(idx, packet);
+        }
+    }
+
+    fn connect(&mut self, idx: usize, slot: AdvSlot, (addr_kind, addr): (AddrKind, BdAddr), initiator: usize) {
+        let init = unwrap!(self.node_mut(initiator).initiator.take());
+        let (init_addr_kind, init_addr) = self.node(initiator).own_addr(init.own_addr_kind, None);
+        let events = self.node_mut(idx).adv(slot).events;
+        self.node_mut(idx).adv(slot).enabled = false;
+
+        let peripheral_handle = self
+            .node_mut(idx)
+            .add_conn(initiator, ConnHandle::new(0), LeConnRole::Peripheral);
+        let central_handle = self
+            .node_mut(initiator)
+            .add_conn(idx, peripheral_handle, LeConnRole::Central);
+        unwrap!(self.node_mut(idx).conn_mut(peripheral_handle)).peer_handle = central_handle;
+
+        let central = ConnComplete {
+            status: Status::SUCCESS,
+            handle: central_handle,
+            role: LeConnRole::Central,
+            peer_addr_kind: addr_kind,
+            peer_addr: addr,
+            init,
+        };
+        self.send(initiator, central.packet(init.ext));
+
+        let peripheral = ConnComplete {
+            handle: peripheral_handle,
+            role: LeConnRole::Peripheral,
+            peer_addr_kind: init_addr_kind,
+            peer_addr: init_addr,
+            ..central
+        };
+        self.send(idx, peripheral.packet(slot != AdvSlot::Legacy));
+        if let AdvSlot::Set(set) = slot {
+            let (adv_handle, _) = unwrap!(self.node(idx).adv_sets[set]);
+            let packet = Packet::le_event(LeAdvertisingSetTerminated::SUBEVENT_CODE)
+                .push(&Status::SUCCESS)
+                .push(&adv_handle)
+                .push(&peripheral_handle)
+                .push(&events.saturating_add(1));
+            self.send(idx, packet);
+        }
+    }
+
+    fn stop_adv(&mut self, idx: usize, slot: AdvSlot, status: Status, handle: ConnHandle) {
+        let node = self.node_mut(idx);
+        let adv = node.adv(slot);
+        adv.enabled = false;
+        let events = adv.events;
+        if let AdvSlot::Set(set) = slot {
+            let (adv_handle, _) = unwrap!(node.adv_sets[set]);
+            let packet = Packet::le_event(LeAdvertisingSetTerminated::SUBEVENT_CODE)
+                .push(&status)
+                .push(&adv_handle)
+                .push(&handle)
+                .push(&events);
+            self.send(idx, packet);
+        }
+    }
+
+    fn disconnect(&mut self, idx: usize, conn: Conn, local_reason: Status, remote_reason: Status) {
+        self.node_mut(idx).remove_conn(conn.handle);
+        self.node_mut(conn.peer).remove_conn(conn.peer_handle);
+        let local = Packet::event(DisconnectionComplete::EVENT_CODE)
+            .push(&Status::SUCCESS)
+            .push(&conn.handle)
+            .push(&local_reason);
+        self.send(idx, local);
+        let remote = Packet::event(DisconnectionComplete::EVENT_CODE)
+            .push(&Status::SUCCESS)
+            .push(&conn.peer_handle)
+            .push(&remote_reason);
+        self.send(conn.peer, remote);
+    }
+
+    fn encryption_change(&mut self, idx: usize, handle: ConnHandle, status: Status, enabled: EncryptionEnabledLevel) {
+        let packet = Packet::event(EncryptionChangeV1::EVENT_CODE)
+            .push(&status)
+            .push(&handle)
+            .push(&enabled);
+        self.send(idx, packet);
+    }
+
+    fn acl(&mut self, idx: usize, packet: &data::AclPacket<'_>) {
+        let Some(conn) = self.node(idx).conn(packet.handle()) else {
+            warn!("[sim] dropping ACL data for unknown connection");
+            return;
+        };
+        let forwarded = data::AclPacket::new(
+            conn.peer_handle,
+            packet.boundary_flag(),
+            AclBroadcastFlag::PointToPoint,
+            packet.data(),
+        );
+        self.send(conn.peer, Packet::new(PacketKind::AclData).push(&forwarded));
+        let completed = [ConnHandleCompletedPackets::new(conn.handle, 1)];
+        let packet = Packet::event(NumberOfCompletedPackets::EVENT_CODE).push(&&completed[..]);
+        self.send(idx, packet);
+    }
+
+    /// Run a command, writing its return parameters to `ret`.
+    ///
+    /// Returns the length of the return parameters, or the status the command failed with.
+    fn command(&mut self, idx: usize, opcode: Opcode, params: &[u8], ret: &mut [u8]) -> Result<usize, Error> {
+        let now = self.now;
+        let node = self.node_mut(idx);
+        match opcode {
+            Reset::OPCODE => {
+                let conns = node.conns;
+                for conn in conns.into_iter().flatten() {
+                    self.node_mut(conn.peer).remove_conn(conn.peer_handle);
+                    let packet = Packet::event(DisconnectionComplete::EVENT_CODE)
+                        .push(&Status::SUCCESS)
+                        .push(&conn.peer_handle)
+                        .push(&Status::CONN_TIMEOUT);
+                    self.send(conn.peer, packet);
+                }
+                self.node_mut(idx).reset();
+                Ok(0)
+            }
+            SetEventMask::OPCODE
+            | SetEventMaskPage2::OPCODE
+            | LeSetEventMask::OPCODE
+            | HostBufferSize::OPCODE
+            | SetControllerToHostFlowControl::OPCODE => Ok(0),
+            ReadBdAddr::OPCODE => reply(ret, &node.addr),
+            ReadLocalVersionInformation::OPCODE => reply(
+                ret,
+                &ReadLocalVersionInformationReturn {
+                    hci_version: CoreSpecificationVersion::VERSION_5_4,
+                    hci_subversion: 0,
+                    lmp_version: CoreSpecificationVersion::VERSION_5_4,
+                    company_identifier: 0xffff,
+                    lmp_subversion: 0,
+                },
+            ),
+            LeReadBufferSize::OPCODE => reply(
+                ret,
+                &LeReadBufferSizeReturn {
+                    le_acl_data_packet_length: MAX_ACL_DATA_LEN,
+                    total_num_le_acl_data_packets: NUM_ACL_PACKETS,
+                },
+            ),
+            LeReadLocalSupportedFeatures::OPCODE => reply(ret, &features()),
+            LeRand::OPCODE => reply(ret, &node.rand()),
+            LeSetRandomAddr::OPCODE => {
+                node.random_addr = Some(parse(params)?);
+                Ok(0)
+            }
+
+            // Legacy advertising
+            LeSetAdvParams::OPCODE => {
+                let p: LeSetAdvParamsParams = parse(params)?;
+                if node.legacy_adv.enabled {
+                    return Err(Error::CMD_DISALLOWED);
+                }
+                let props = AdvEventProps::new().set_legacy_adv(true);
+                let props = match p.adv_kind {
+                    AdvKind::AdvInd => props.set_connectable_adv(true).set_scannable_adv(true),
+                    AdvKind::AdvDirectIndHigh | AdvKind::AdvDirectIndLow => {
+                        props.set_connectable_adv(true).set_directed_adv(true)
+                    }
+                    AdvKind::AdvScanInd => props.set_scannable_adv(true),
+                    AdvKind::AdvNonconnInd => props,
+                };
+                let (min, max) = (p.adv_interval_min, p.adv_interval_max);
+                let interval = adv_interval(min.as_micros(), max.as_micros())?;
+                let adv = &mut node.legacy_adv;
+                adv.props = props;
+                adv.interval = interval;
+                adv.own_addr_kind = p.own_addr_kind;
+                adv.peer_addr = p.peer_addr;
+                Ok(0)
+            }
+            LeSetAdvData::OPCODE => {
+                let p: LeSetAdvDataParams = parse(params)?;
+                let data = p.data;
+                let data = data
+                    .get(..usize::from(p.data_len))
+                    .ok_or(Error::INVALID_HCI_PARAMETERS)?;
+                node.legacy_adv.data.set(data).map(|_| 0)
+            }
+            LeSetScanResponseData::OPCODE => {
+                let p: LeSetScanResponseDataParams = parse(params)?;
+                let data = p.data;
+                let data = data
+                    .get(..usize::from(p.data_len))
+                    .ok_or(Error::INVALID_HCI_PARAMETERS)?;
+                node.legacy_adv.scan_rsp.set(data).map(|_| 0)
+            }
+            LeSetAdvEnable::OPCODE => {
+                let enable: bool = parse(params)?;
+                let adv = node.legacy_adv;
+                if enable && is_random(adv.own_addr_kind) && node.random_addr.is_none() {
+                    return Err(Error::INVALID_HCI_PARAMETERS);
+                }
+                if enable && !adv.enabled {
+                    node.legacy_adv.next = now;
+                    node.legacy_adv.events = 0;
+                }
+                node.legacy_adv.enabled = enable;
+                Ok(0)
+            }
+
+            // Extended advertising
+            LeReadMaxAdvDataLength::OPCODE => reply(ret, &(MAX_EXT_ADV_DATA_LEN as u16)),
+            LeReadNumberOfSupportedAdvSets::OPCODE => reply(ret, &(MAX_ADV_SETS as u8)),
+            LeSetExtAdvParams::OPCODE => {
+                let p: LeSetExtAdvParamsParams = parse(params)?;
+                let interval = adv_interval(
+                    p.primary_adv_interval_min.as_micros(),
+                    p.primary_adv_interval_max.as_micros(),
+                )?;
+                let idx = match node.adv_set(p.adv_handle) {
+                    Some(idx) if unwrap!(node.adv_sets[idx]).1.enabled => return Err(Error::CMD_DISALLOWED),
+                    Some(idx) => idx,
+                    None => {
+                        let idx = node
+                            .adv_sets
+                            .iter()
+                            .position(Option::is_none)
+                            .ok_or(Error::MEMORY_CAPACITY_EXCEEDED)?;
+                        node.adv_sets[idx] = Some((p.adv_handle, Advertiser::new()));
+                        idx
+                    }
+                };
+                let (_, adv) = unwrap!(node.adv_sets[idx].as_mut());
+                adv.props = p.adv_event_props;
+                adv.interval = interval;
+                adv.own_addr_kind = p.own_addr_kind;
+                adv.peer_addr = p.peer_addr;
+                adv.secondary_phy = p.secondary_adv_phy;
+                adv.sid = p.adv_sid;
+                let tx_power = if p.adv_tx_power == TX_POWER_NOT_AVAILABLE {
+                    0i8
+                } else {
+                    p.adv_tx_power
+                };
+                reply(ret, &tx_power)
+            }
+            LeSetAdvSetRandomAddr::OPCODE => {
+                let p: LeSetAdvSetRandomAddrParams = parse(params)?;
+                let idx = node.adv_set(p.adv_handle).ok_or(Error::UNKNOWN_ADV_IDENTIFIER)?;
+                node.adv(AdvSlot::Set(idx)).random_addr = Some(p.random_addr);
+                Ok(0)
+            }
+            LeSetExtAdvData::OPCODE => {
+                let p: LeSetExtAdvDataParams = parse(params)?;
+                let idx = node.adv_set(p.adv_handle).ok_or(Error::UNKNOWN_ADV_IDENTIFIER)?;
+                let adv = node.adv(AdvSlot::Set(idx));
+                set_ext_data(&mut adv.data, adv.props.legacy_adv(), p.operation, p.adv_data).map(|_| 0)
+            }
+            LeSetExtScanResponseData::OPCODE => {
+                let p: LeSetExtScanResponseDataParams = parse(params)?;
+                let idx = node.adv_set(p.adv_handle).ok_or(Error::UNKNOWN_ADV_IDENTIFIER)?;
+                let adv = node.adv(AdvSlot::Set(idx));
+                set_ext_data(
+                    &mut adv.scan_rsp,
+                    adv.props.legacy_adv(),
+                    p.operation,
+                    p.scan_response_data,
+                )
+                .map(|_| 0)
+            }
+            LeSetExtAdvEnable::OPCODE => {
+                let p: LeSetExtAdvEnableParams = parse(params)?;
+                if !p.enable && p.sets.is_empty() {
+                    for (_, adv) in node.adv_sets.iter_mut().flatten() {
+                        adv.enabled = false;
+                    }
+                    return Ok(0);
+                }
+                for set in p.sets {
+                    let idx = node.adv_set(set.adv_handle).ok_or(Error::UNKNOWN_ADV_IDENTIFIER)?;
+                    let random_addr = node.random_addr;
+                    let adv = node.adv(AdvSlot::Set(idx));
+                    if !p.enable {
+                        adv.enabled = false;
+                        continue;
+                    }
+                    if is_random(adv.own_addr_kind) && adv.random_addr.or(random_addr).is_none() {
+                        return Err(Error::INVALID_HCI_PARAMETERS);
+                    }
+                    let duration = set.duration;
+                    adv.enabled = true;
+                    adv.next = now;
+                    adv.events = 0;
+                    adv.max_events = set.max_ext_adv_events;
+                    adv.deadline = (duration.as_u16() != 0).then(|| now + duration.as_micros());
+                }
+                Ok(0)
+            }
+            LeRemoveAdvSet::OPCODE => {
+                let handle: AdvHandle = parse(params)?;
+                let idx = node.adv_set(handle).ok_or(Error::UNKNOWN_ADV_IDENTIFIER)?;
+                if node.adv(AdvSlot::Set(idx)).enabled {
+                    return Err(Error::CMD_DISALLOWED);
+                }
+                node.adv_sets[idx] = None;
+                Ok(0)
+            }
+            LeClearAdvSets::OPCODE => {
+                if node.adv_sets.iter().flatten().any(|(_, adv)| adv.enabled) {
+                    return Err(Error::CMD_DISALLOWED);
+                }
+                node.adv_sets = [None; MAX_ADV_SETS];
+                Ok(0)
+            }
+
+            // Scanning
+            LeSetScanParams::OPCODE => {
+                let p: LeSetScanParamsParams = parse(params)?;
+                if node.scanner.enabled {
+                    return Err(Error::CMD_DISALLOWED);
+                }
+                node.scanner.active = p.le_scan_kind == LeScanKind::Active;
+                Ok(0)
+            }
+            LeSetExtScanParams::OPCODE => {
+                let p: LeSetExtScanParamsParams = parse(params)?;
+                let phy = first_phy(p.scanning_phys).ok_or(Error::INVALID_HCI_PARAMETERS)?;
+                if node.scanner.enabled {
+                    return Err(Error::CMD_DISALLOWED);
+                }
+                node.scanner.active = phy.active_scan;
+                Ok(0)
+            }
+            LeSetScanEnable::OPCODE => {
+                let p: LeSetScanEnableParams = parse(params)?;
+                set_scan_enable(&mut node.scanner, p.enable, false, p.filter_duplicates);
+                Ok(0)
+            }
+            LeSetExtScanEnable::OPCODE => {
+                let p: LeSetExtScanEnableParams = parse(params)?;
+                set_scan_enable(
+                    &mut node.scanner,
+                    p.enable,
+                    true,
+                    p.filter_duplicates != FilterDuplicates::Disabled,
+                );
+                Ok(0)
+            }
+
+            // Connections
+            LeCreateConn::OPCODE => {
+                let p: LeCreateConnParams = parse(params)?;
+                if node.initiator.is_some() {
+                    return Err(Error::CMD_DISALLOWED);
+                }
+                if p.use_filter_accept_list {
+                    return Err(Error::UNSUPPORTED);
+                }
+                node.initiator = Some(Initiator {
+                    ext: false,
+                    own_addr_kind: p.own_addr_kind,
+                    peer_addr_kind: p.peer_addr_kind,
+                    peer_addr: p.peer_addr,
+                    interval: p.conn_interval_min,
+                    latency: p.max_latency,
+                    timeout: p.supervision_timeout,
+                });
+                Ok(0)
+            }
+            LeExtCreateConn::OPCODE => {
+                let p: LeExtCreateConnParams = parse(params)?;
+                if node.initiator.is_some() {
+                    return Err(Error::CMD_DISALLOWED);
+                }
+                if p.initiator_filter_policy {
+                    return Err(Error::UNSUPPORTED);
+                }
+                let phy = first_phy(p.initiating_phys).ok_or(Error::INVALID_HCI_PARAMETERS)?;
+                node.initiator = Some(Initiator {
+                    ext: true,
+                    own_addr_kind: p.own_addr_kind,
+                    peer_addr_kind: p.peer_addr_kind,
+                    peer_addr: p.peer_addr,
+                    interval: phy.conn_interval_min,
+                    latency: phy.max_latency,
+                    timeout: phy.supervision_timeout,
+                });
+                Ok(0)
+            }
+            LeCreateConnCancel::OPCODE => {
+                let init = node.initiator.take().ok_or(Error::CMD_DISALLOWED)?;
+                let cancelled = ConnComplete {
+                    status: Status::UNKNOWN_CONN_IDENTIFIER,
+                    handle: ConnHandle::new(0),
+                    role: LeConnRole::Central,
+                    peer_addr_kind: init.peer_addr_kind,
+                    peer_addr: init.peer_addr,
+                    init,
+                };
+                self.send(idx, cancelled.packet(init.ext));
+                Ok(0)
+            }
+            Disconnect::OPCODE => {
+                let p: DisconnectParams = parse(params)?;
+                let conn = node.conn(p.handle).ok_or(Error::UNKNOWN_CONN_IDENTIFIER)?;
+                let reason = Status::new(p.reason as u8);
+                self.disconnect(idx, conn, Status::CONN_TERMINATED_BY_LOCAL_HOST, reason);
+                Ok(0)
+            }
+            LeReadRemoteFeatures::OPCODE => {
+                let handle: ConnHandle = parse(params)?;
+                node.conn(handle).ok_or(Error::UNKNOWN_CONN_IDENTIFIER)?;
+                let packet = Packet::le_event(LeReadRemoteFeaturesComplete::SUBEVENT_CODE)
+                    .push(&Status::SUCCESS)
+                    .push(&handle)
+                    .push(&features());
+                self.send(idx, packet);
+                Ok(0)
+            }
+
+            // Encryption
+            LeEnableEncryption::OPCODE => {
+                let p: LeEnableEncryptionParams = parse(params)?;
+                let conn = node.conn_mut(p.handle).ok_or(Error::UNKNOWN_CONN_IDENTIFIER)?;
+                if conn.role != LeConnRole::Central {
+                    return Err(Error::CMD_DISALLOWED);
+                }
+                conn.ltk = Some(p.long_term_key);
+                let conn = *conn;
+                let ediv = p.encrypted_diversifier;
+                let packet = Packet::le_event(LeLongTermKeyRequest::SUBEVENT_CODE)
+                    .push(&conn.peer_handle)
+                    .push(&p.random)
+                    .push(&ediv);
+                self.send(conn.peer, packet);
+                Ok(0)
+            }
+            LeLongTermKeyRequestReply::OPCODE => {
+                let p: LeLongTermKeyRequestReplyParams = parse(params)?;
+                let handle = p.handle;
+                let conn = node.conn(handle).ok_or(Error::UNKNOWN_CONN_IDENTIFIER)?;
+                let len = reply(ret, &handle)?;
+                let central_ltk = self.node(conn.peer).conn(conn.peer_handle).and_then(|c| c.ltk);
+                if central_ltk == Some(p.long_term_key) {
+                    let level = EncryptionEnabledLevel::OnE0OrAesCcm;
+                    self.encryption_change(idx, conn.handle, Status::SUCCESS, level);
+                    self.encryption_change(conn.peer, conn.peer_handle, Status::SUCCESS, level);
+                } else {
+                    let reason = Status::CONN_TERMINATED_DUE_TO_MIC_FAILURE;
+                    self.disconnect(idx, conn, reason, reason);
+                }
+                Ok(len)
+            }
+            LeLongTermKeyRequestNegativeReply::OPCODE => {
+                let handle: ConnHandle = parse(params)?;
+                let conn = node.conn(handle).ok_or(Error::UNKNOWN_CONN_IDENTIFIER)?;
+                let len = reply(ret, &handle)?;
+                let level = EncryptionEnabledLevel::Off;
+                self.encryption_change(conn.peer, conn.peer_handle, Status::PIN_OR_KEY_MISSING, level);
+                Ok(len)
+            }
+
+            _ => {
+                warn!("[sim] unsupported command {:?}", opcode);
+                Err(Error::UNKNOWN_CMD)
+            }
+        }
+    }
+}
+
+/// Parameters shared by the LE Connection Complete and LE Enhanced Connection Complete events.
+#[derive(Clone, Copy)]
+struct ConnComplete {
+    status: Status,
+    handle: ConnHandle,
+    role: LeConnRole,
+    peer_addr_kind: AddrKind,
+    peer_addr: BdAddr,
+    init: Initiator,
+}
+
+impl ConnComplete {
+    fn packet(&self, enhanced: bool) -> Packet {
+        let packet = if enhanced {
+            Packet::le_event(LeEnhancedConnectionComplete::SUBEVENT_CODE)
+        } else {
+            Packet::le_event(LeConnectionComplete::SUBEVENT_CODE)
+        };
+        let packet = packet
+            .push(&self.status)
+            .push(&self.handle)
+            .push(&self.role)
+            .push(&self.peer_addr_kind)
+            .push(&self.peer_addr);
+        let packet = if enhanced {
+            packet.push(&BdAddr::default()).push(&BdAddr::default())
+        } else {
+            packet
+        };
+        packet
+            .push(&self.init.interval)
+            .push(&self.init.latency)
+            .push(&self.init.timeout)
+            .push(&ClockAccuracy::Ppm50)
+    }
+}
+
+fn is_random(kind: AddrKind) -> bool {
+    kind == AddrKind::RANDOM || kind == AddrKind::RESOLVABLE_PRIVATE_OR_RANDOM
+}
+
+fn features() -> LeFeatureMask {
+    LeFeatureMask::new().set_le_encryption(true).set_le_ext_adv(true)
+}
+
+fn parse<'a, T: FromHciBytes<'a>>(params: &'a [u8]) -> Result<T, Error> {
+    T::from_hci_bytes_complete(params).or(Err(Error::INVALID_HCI_PARAMETERS))
+}
+
+fn reply<T: WriteHci>(ret: &mut [u8], value: &T) -> Result<usize, Error> {
+    unwrap!(value.write_hci(&mut ret[..]));
+    Ok(value.size())
+}
+
+fn set_ext_data(data: &mut AdvData, legacy: bool, operation: Operation, fragment: &[u8]) -> Result<(), Error> {
+    if legacy && (fragment.len() > LEGACY_ADV_DATA_LEN || operation != Operation::Complete) {
+        return Err(Error::INVALID_HCI_PARAMETERS);
+    }
+    match operation {
+        Operation::Complete | Operation::FirstFragment => data.set(fragment),
+        Operation::IntermediateFragment | Operation::LastFragment => data.append(fragment),
+        Operation::Unchanged => Ok(()),
+    }
+}
+
+fn set_scan_enable(scanner: &mut Scanner, enable: bool, ext: bool, filter_duplicates: bool) {
+    if enable && !scanner.enabled {
+        scanner.num_seen = 0;
+    }
+    scanner.enabled = enable;
+    scanner.ext = ext;
+    scanner.filter_duplicates = filter_duplicates;
+}
+
+/// The parameters of the first PHY present in `phys`.
+fn first_phy<T>(phys: PhyParams<T>) -> Option<T> {
+    phys.le_1m_phy.or(phys.le_2m_phy).or(phys.le_coded_phy)
+}
+
+/// Check an advertising interval range given in microseconds, returning the interval to use.
+fn adv_interval(min: u64, max: u64) -> Result<u64, Error> {
+    // 0x0020 in units of 0.625 ms
+    if min < 20_000 || min > max {
+        return Err(Error::INVALID_HCI_PARAMETERS);
+    }
+    Ok(min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::le::LeEncrypt;
+    use crate::cmd::{AsyncCmd, SyncCmd};
+    use crate::data::AclPacketBoundary;
+    use crate::event::le::LeEvent;
+    use crate::event::Event;
+    use crate::param::{
+        AdvChannelMap, AdvFilterPolicy, AdvSet, DisconnectReason, ExtDuration, InitiatingPhy, PhyParams,
+        ScanningFilterPolicy, ScanningPhy,
+    };
+
+    const ADDR_A: BdAddr = BdAddr([0xa0, 0, 0, 0, 0, 0xc0]);
+    const ADDR_B: BdAddr = BdAddr([0xb0, 0, 0, 0, 0, 0xc0]);
+    const LTK: [u8; 16] = [7; 16];
+
+    async fn event<'b>(c: &VirtualController<'_>, buf: &'b mut [u8; PACKET_LEN]) -> Event<'b> {
+        match c.read(buf).await.unwrap() {
+            ControllerToHostPacket::Event(event) => event.try_into().unwrap(),
+            pkt => panic!("unexpected packet {:?}", pkt),
+        }
+    }
+
+    fn queued(c: &VirtualController<'_>) -> usize {
+        c.air.with(|state| state.node(c.node).queue.len)
+    }
+
+    async fn advertise_legacy(c: &VirtualController<'_>, data: &[u8]) {
+        let mut buf = [0; 31];
+        buf[..data.len()].copy_from_slice(data);
+        LeSetAdvParams::new(
+            Duration::from_millis(100),
+            Duration::from_millis(100),
+            AdvKind::AdvInd,
+            AddrKind::PUBLIC,
+            AddrKind::PUBLIC,
+            BdAddr::default(),
+            AdvChannelMap::ALL,
+            AdvFilterPolicy::Unfiltered,
+        )
+        .exec(c)
+        .await
+        .unwrap();
+        LeSetAdvData::new(data.len() as u8, buf).exec(c).await.unwrap();
+        LeSetScanResponseData::new(
+            3,
+            [
+                2, 0x09, b'b', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            ],
+        )
+        .exec(c)
+        .await
+        .unwrap();
+        LeSetAdvEnable::new(true).exec(c).await.unwrap();
+    }
+
+    async fn connect(a: &VirtualController<'_>, b: &VirtualController<'_>, air: &Air) -> (ConnHandle, ConnHandle) {
+        advertise_legacy(a, &[]).await;
+        LeCreateConn::new(
+            Duration::from_millis(60),
+            Duration::from_millis(30),
+            false,
+            AddrKind::PUBLIC,
+            a.addr(),
+            AddrKind::PUBLIC,
+            Duration::from_millis(30),
+            Duration::from_millis(50),
+            0,
+            Duration::from_secs(4),
+            Duration::from_u16(0),
+            Duration::from_u16(0),
+        )
+        .exec(b)
+        .await
+        .unwrap();
+        air.advance(1_000);
+
+        let mut buf = [0; PACKET_LEN];
+        let Event::Le(LeEvent::LeConnectionComplete(e)) = event(b, &mut buf).await else {
+            panic!("expected connection complete");
+        };
+        assert_eq!(e.status, Status::SUCCESS);
+        assert_eq!(e.role, LeConnRole::Central);
+        assert_eq!(e.peer_addr, ADDR_A);
+        assert_eq!(e.conn_interval.as_millis(), 30);
+        let central = e.handle;
+
+        let Event::Le(LeEvent::LeConnectionComplete(e)) = event(a, &mut buf).await else {
+            panic!("expected connection complete");
+        };
+        assert_eq!(e.role, LeConnRole::Peripheral);
+        assert_eq!(e.peer_addr, ADDR_B);
+        (central, e.handle)
+    }
+
+    #[futures_test::test]
+    async fn test_local_commands() {
+        let air: Air = Air::new();
+        let a = air.attach(ADDR_A);
+        assert_eq!(ReadBdAddr::new().exec(&a).await.unwrap(), ADDR_A);
+        let ret = LeReadBufferSize::new().exec(&a).await.unwrap();
+        let len = ret.le_acl_data_packet_length;
+        assert_eq!(len, MAX_ACL_DATA_LEN);
+        assert!(LeReadLocalSupportedFeatures::new()
+            .exec(&a)
+            .await
+            .unwrap()
+            .supports_le_ext_adv());
+        assert_ne!(
+            LeRand::new().exec(&a).await.unwrap(),
+            LeRand::new().exec(&a).await.unwrap()
+        );
+        let res = LeEncrypt::new([0; 16], [0; 16]).exec(&a).await;
+        assert!(matches!(res, Err(cmd::Error::Hci(Error::UNKNOWN_CMD))));
+        Reset::new().exec(&a).await.unwrap();
+    }
+
+    #[futures_test::test]
+    async fn test_legacy_advertising_and_scanning() {
+        let air: Air = Air::new();
+        let a = air.attach(ADDR_A);
+        let b = air.attach(ADDR_B);
+
+        advertise_legacy(&a, &[2, 0x01, 0x06]).await;
+        LeSetScanParams::new(
+            LeScanKind::Active,
+            Duration::from_millis(100),
+            Duration::from_millis(100),
+            AddrKind::PUBLIC,
+            ScanningFilterPolicy::BasicUnfiltered,
+        )
+        .exec(&b)
+        .await
+        .unwrap();
+        LeSetScanEnable::new(true, true).exec(&b).await.unwrap();
+
+        // Advertising events at 0, 100 and 200 ms, but duplicates are filtered
+        air.advance(250_000);
+        assert_eq!(air.now(), 250_000);
+        assert_eq!(queued(&b), 2);
+
+        let mut buf = [0; PACKET_LEN];
+        let Event::Le(LeEvent::LeAdvertisingReport(e)) = event(&b, &mut buf).await else {
+            panic!("expected advertising report");
+        };
+        let report = e.reports.iter().next().unwrap().unwrap();
+        assert_eq!(report.event_kind, LeAdvEventKind::AdvInd);
+        assert_eq!(report.addr, ADDR_A);
+        assert_eq!(report.data, &[2, 0x01, 0x06]);
+        assert_eq!(report.rssi, RSSI);
+
+        let Event::Le(LeEvent::LeAdvertisingReport(e)) = event(&b, &mut buf).await else {
+            panic!("expected advertising report");
+        };
+        let report = e.reports.iter().next().unwrap().unwrap();
+        assert_eq!(report.event_kind, LeAdvEventKind::ScanRsp);
+        assert_eq!(report.data, &[2, 0x09, b'b']);
+
+        // The advertiser does not hear itself
+        assert_eq!(queued(&a), 0);
+    }
+
+    #[futures_test::test]
+    async fn test_invalid_adv_interval() {
+        let air: Air = Air::new();
+        let a = air.attach(ADDR_A);
+        let legacy = |min, max| {
+            LeSetAdvParams::new(
+                Duration::from_u16(min),
+                Duration::from_u16(max),
+                AdvKind::AdvInd,
+                AddrKind::PUBLIC,
+                AddrKind::PUBLIC,
+                BdAddr::default(),
+                AdvChannelMap::ALL,
+                AdvFilterPolicy::Unfiltered,
+            )
+        };
+        let ext = |min, max| {
+            LeSetExtAdvParams::new(
+                AdvHandle::new(0),
+                AdvEventProps::new(),
+                ExtDuration::from_u32(min),
+                ExtDuration::from_u32(max),
+                AdvChannelMap::ALL,
+                AddrKind::PUBLIC,
+                AddrKind::PUBLIC,
+                BdAddr::default(),
+                AdvFilterPolicy::Unfiltered,
+                0,
+                PhyKind::Le1M,
+                0,
+                PhyKind::Le1M,
+                0,
+                false,
+            )
+        };
+        assert!(matches!(
+            legacy(0, 0).exec(&a).await,
+            Err(cmd::Error::Hci(Error::INVALID_HCI_PARAMETERS))
+        ));
+        assert!(matches!(
+            legacy(0x1f, 0x20).exec(&a).await,
+            Err(cmd::Error::Hci(Error::INVALID_HCI_PARAMETERS))
+        ));
+        assert!(matches!(
+            legacy(0x40, 0x30).exec(&a).await,
+            Err(cmd::Error::Hci(Error::INVALID_HCI_PARAMETERS))
+        ));
+        assert!(legacy(0x20, 0x20).exec(&a).await.is_ok());
+        assert!(matches!(
+            ext(0, 0).exec(&a).await,
+            Err(cmd::Error::Hci(Error::INVALID_HCI_PARAMETERS))
+        ));
+        assert!(matches!(
+            ext(0x40, 0x30).exec(&a).await,
+            Err(cmd::Error::Hci(Error::INVALID_HCI_PARAMETERS))
+        ));
+        assert!(ext(0x20, 0x20).exec(&a).await.is_ok());
+    }
+
+    #[futures_test::test]
+    async fn test_extended_advertising_and_scanning() {
+        let air: Air = Air::new();
+        let a = air.attach(ADDR_A);
+        let b = air.attach(ADDR_B);
+        let handle = AdvHandle::new(1);
+
+        LeSetRandomAddr::new(BdAddr::new([1, 2, 3, 4, 5, 0xc6]))
+            .exec(&a)
+            .await
+            .unwrap();
+        LeSetExtAdvParams::new(
+            handle,
+            AdvEventProps::new(),
+            ExtDuration::from_millis(20),
+            ExtDuration::from_millis(20),
+            AdvChannelMap::ALL,
+            AddrKind::RANDOM,
+            AddrKind::PUBLIC,
+            BdAddr::default(),
+            AdvFilterPolicy::Unfiltered,
+            0,
+            PhyKind::Le1M,
+            0,
+            PhyKind::Le2M,
+            3,
+            false,
+        )
+        .exec(&a)
+        .await
+        .unwrap();
+        let data = [0xaa; 200];
+        LeSetExtAdvData::new(handle, Operation::FirstFragment, false, &data[..100])
+            .exec(&a)
+            .await
+            .unwrap();
+        LeSetExtAdvData::new(handle, Operation::LastFragment, false, &data[100..])
+            .exec(&a)
+            .await
+            .unwrap();
+        let sets = [AdvSet {
+            adv_handle: handle,
+            duration: Duration::from_u16(0),
+            max_ext_adv_events: 2,
+        }];
+        LeSetExtAdvEnable::new(true, &sets).exec(&a).await.unwrap();
+
+        LeSetExtScanParams::new(
+            AddrKind::PUBLIC,
+            ScanningFilterPolicy::BasicUnfiltered,
+            PhyParams {
+                le_1m_phy: Some(ScanningPhy {
+                    active_scan: false,
+                    scan_interval: Duration::from_millis(10),
+                    scan_window: Duration::from_millis(10),
+                }),
+                le_2m_phy: None,
+                le_coded_phy: None,
+            },
+        )
+        .exec(&b)
+        .await
+        .unwrap();
+        LeSetExtScanEnable::new(
+            true,
+            FilterDuplicates::Disabled,
+            Duration::from_u16(0),
+            Duration::from_u16(0),
+        )
+        .exec(&b)
+        .await
+        .unwrap();
+
+        air.advance(100_000);
+        assert_eq!(queued(&b), 2);
+        let mut buf = [0; PACKET_LEN];
+        let Event::Le(LeEvent::LeExtendedAdvertisingReport(e)) = event(&b, &mut buf).await else {
+            panic!("expected extended advertising report");
+        };
+        let report = e.reports.iter().next().unwrap().unwrap();
+        assert!(!report.event_kind.legacy());
+        assert_eq!(report.addr_kind, AddrKind::RANDOM);
+        assert_eq!(report.addr, BdAddr::new([1, 2, 3, 4, 5, 0xc6]));
+        assert_eq!(report.secondary_adv_phy, Some(PhyKind::Le2M));
+        assert_eq!(report.adv_sid, 3);
+        assert_eq!(report.data, &data[..]);
+
+        // The set stops after two events
+        let Event::Le(LeEvent::LeAdvertisingSetTerminated(e)) = event(&a, &mut buf).await else {
+            panic!("expected advertising set terminated");
+        };
+        assert_eq!(e.status, Status::LIMIT_REACHED);
+        assert_eq!(e.adv_handle, handle);
+        assert_eq!(e.num_completed_ext_adv_evts, 2);
+    }
+
+    #[futures_test::test]
+    async fn test_connection_and_acl() {
+        let air: Air = Air::new();
+        let a = air.attach(ADDR_A);
+        let b = air.attach(ADDR_B);
+        let (central, peripheral) = connect(&a, &b, &air).await;
+
+        // Advertising stopped when the connection was made
+        air.advance(1_000_000);
+        assert_eq!(queued(&a), 0);
+
+        let pkt = data::AclPacket::new(
+            central,
+            AclPacketBoundary::FirstNonFlushable,
+            AclBroadcastFlag::PointToPoint,
+            &[1, 2, 3],
+        );
+        b.write_acl_data(&pkt).await.unwrap();
+
+        let mut buf = [0; PACKET_LEN];
+        let ControllerToHostPacket::Acl(pkt) = a.read(&mut buf).await.unwrap() else {
+            panic!("expected ACL data");
+        };
+        assert_eq!(pkt.handle(), peripheral);
+        assert_eq!(pkt.data(), &[1, 2, 3]);
+
+        let Event::NumberOfCompletedPackets(e) = event(&b, &mut buf).await else {
+            panic!("expected number of completed packets");
+        };
+        assert_eq!(e.completed_packets[0].handle().unwrap(), central);
+        assert_eq!(e.completed_packets[0].num_completed_packets().unwrap(), 1);
+
+        Disconnect::new(peripheral, DisconnectReason::RemoteUserTerminatedConn)
+            .exec(&a)
+            .await
+            .unwrap();
+        let Event::DisconnectionComplete(e) = event(&a, &mut buf).await else {
+            panic!("expected disconnection complete");
+        };
+        assert_eq!(e.handle, peripheral);
+        assert_eq!(e.reason, Status::CONN_TERMINATED_BY_LOCAL_HOST);
+        let Event::DisconnectionComplete(e) = event(&b, &mut buf).await else {
+            panic!("expected disconnection complete");
+        };
+        assert_eq!(e.handle, central);
+        assert_eq!(e.reason, Status::REMOTE_USER_TERMINATED_CONN);
+
+        let res = Disconnect::new(peripheral, DisconnectReason::RemoteUserTerminatedConn)
+            .exec(&a)
+            .await;
+        assert!(matches!(res, Err(cmd::Error::Hci(Error::UNKNOWN_CONN_IDENTIFIER))));
+    }
+
+    #[futures_test::test]
+    async fn test_encryption() {
+        let air: Air = Air::new();
+        let a = air.attach(ADDR_A);
+        let b = air.attach(ADDR_B);
+        let (central, peripheral) = connect(&a, &b, &air).await;
+
+        LeEnableEncryption::new(central, [1; 8], 0x1234, LTK)
+            .exec(&b)
+            .await
+            .unwrap();
+        let mut buf = [0; PACKET_LEN];
+        let Event::Le(LeEvent::LeLongTermKeyRequest(e)) = event(&a, &mut buf).await else {
+            panic!("expected long term key request");
+        };
+        assert_eq!(e.handle, peripheral);
+        assert_eq!(e.random_number, [1; 8]);
+        assert_eq!(e.encrypted_diversifier, 0x1234);
+
+        let ret = LeLongTermKeyRequestReply::new(peripheral, LTK).exec(&a).await.unwrap();
+        assert_eq!(ret, peripheral);
+        for (c, handle) in [(&a, peripheral), (&b, central)] {
+            let Event::EncryptionChangeV1(e) = event(c, &mut buf).await else {
+                panic!("expected encryption change");
+            };
+            assert_eq!(e.status, Status::SUCCESS);
+            assert_eq!(e.handle, handle);
+            assert_eq!(e.enabled, EncryptionEnabledLevel::OnE0OrAesCcm);
+        }
+
+        // Mismatched keys tear the connection down
+        LeEnableEncryption::new(central, [1; 8], 0x1234, LTK)
+            .exec(&b)
+            .await
+            .unwrap();
+        event(&a, &mut buf).await;
+        LeLongTermKeyRequestReply::new(peripheral, [0; 16])
+            .exec(&a)
+            .await
+            .unwrap();
+        for c in [&a, &b] {
+            let Event::DisconnectionComplete(e) = event(c, &mut buf).await else {
+                panic!("expected disconnection complete");
+            };
+            assert_eq!(e.reason, Status::CONN_TERMINATED_DUE_TO_MIC_FAILURE);
+        }
+    }
+
+    #[futures_test::test]
+    async fn test_create_connection_cancel() {
+        let air: Air = Air::new();
+        let b = air.attach(ADDR_B);
+        LeExtCreateConn::new(
+            false,
+            AddrKind::PUBLIC,
+            AddrKind::PUBLIC,
+            ADDR_A,
+            PhyParams {
+                le_1m_phy: Some(InitiatingPhy {
+                    scan_interval: Duration::from_millis(60),
+                    scan_window: Duration::from_millis(30),
+                    conn_interval_min: Duration::from_millis(30),
+                    conn_interval_max: Duration::from_millis(50),
+                    max_latency: 0,
+                    supervision_timeout: Duration::from_secs(4),
+                    min_ce_len: Duration::from_u16(0),
+                    max_ce_len: Duration::from_u16(0),
+                }),
+                le_2m_phy: None,
+                le_coded_phy: None,
+            },
+        )
+        .exec(&b)
+        .await
+        .unwrap();
+        LeCreateConnCancel::new().exec(&b).await.unwrap();
+
+        let mut buf = [0; PACKET_LEN];
+        let Event::Le(LeEvent::LeEnhancedConnectionComplete(e)) = event(&b, &mut buf).await else {
+            panic!("expected enhanced connection complete");
+        };
+        assert_eq!(e.status, Status::UNKNOWN_CONN_IDENTIFIER);
+    }
+}
@@ -63,7 +63,13 @@ impl<'a> FromHciBytes<'a> for RemainingBytes<'a> {
 }
 
 impl<'a> RemainingBytes<'a> {
-    pub(crate) fn into_inner(self) -> &'a [u8] {
+    /// Create a new instance.
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
+
+    /// Get the bytes, with the lifetime of the underlying buffer.
+    pub fn into_inner(self) -> &'a [u8] {
         self.0
     }
 }
@@ -379,6 +385,16 @@ param! {
     }
 }
 
+param! {
+    #[derive(Default)]
+    enum LoopbackMode {
+        #[default]
+        Disabled = 0,
+        Local = 1,
+        Remote = 2,
+    }
+}
+
 param!(struct CoreSpecificationVersion(u8));
 
 #[allow(missing_docs)]
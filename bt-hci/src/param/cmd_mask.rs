@@ -2,7 +2,7 @@ use crate::{ByteAlignedValue, FixedSizeValue, FromHciBytes};
 
 /// A command mask. [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core_v6.3/out/en/host-controller-interface/host-controller-interface-functional-specification.html#UUID-e2532697-4291-5379-5dd4-157ff356f0ac)
 #[repr(transparent)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct CmdMask([u8; 64]);
 
@@ -55,6 +55,13 @@ macro_rules! cmds {
                 )+
             )+
         }
+
+        impl core::fmt::Debug for CmdMask {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                const FLAGS: &[(usize, &str)] = &[$($(($octet * 8 + $bit, stringify!($getter)),)+)+];
+                crate::dissect::debug_bitfield(f, "CmdMask", &self.0, FLAGS)
+            }
+        }
     }
 }
 
@@ -279,6 +279,32 @@ impl<T> PhyParams<T> {
     }
 }
 
+impl<'de, T: FromHciBytes<'de>> FromHciBytes<'de> for PhyParams<T> {
+    fn from_hci_bytes(data: &'de [u8]) -> Result<(Self, &'de [u8]), FromHciBytesError> {
+        let (mask, mut data) = PhyMask::from_hci_bytes(data)?;
+        if mask.into_inner() & !0x07 != 0 {
+            return Err(FromHciBytesError::InvalidValue);
+        }
+
+        let mut read = |present: bool| -> Result<Option<T>, FromHciBytesError> {
+            if present {
+                let (val, rest) = T::from_hci_bytes(data)?;
+                data = rest;
+                Ok(Some(val))
+            } else {
+                Ok(None)
+            }
+        };
+
+        let params = Self {
+            le_1m_phy: read(mask.has_le_1m_phy())?,
+            le_2m_phy: read(mask.has_le_2m_phy())?,
+            le_coded_phy: read(mask.has_le_coded_phy())?,
+        };
+        Ok((params, data))
+    }
+}
+
 impl<T: WriteHci> WriteHci for PhyParams<T> {
     #[inline(always)]
     fn size(&self) -> usize {
@@ -497,6 +523,27 @@ param_slice! {
     }
 }
 
+impl LeDirectedAdvertisingReportParam {
+    /// Create a new instance.
+    pub fn new(
+        event_type: u8,
+        addr_kind: AddrKind,
+        addr: BdAddr,
+        direct_addr_kind: AddrKind,
+        direct_addr: BdAddr,
+        rssi: i8,
+    ) -> Self {
+        let mut dest = [0; 16];
+        dest[0] = event_type;
+        dest[1] = addr_kind.0;
+        dest[2..8].copy_from_slice(addr.raw());
+        dest[8] = direct_addr_kind.0;
+        dest[9..15].copy_from_slice(direct_addr.raw());
+        dest[15] = rssi as u8;
+        Self(dest)
+    }
+}
+
 param_slice! {
     [LeIQSample; 2] {
         i_sample[0]: i8,
@@ -504,12 +551,26 @@ param_slice! {
     }
 }
 
+impl LeIQSample {
+    /// Create a new instance.
+    pub fn new(i_sample: i8, q_sample: i8) -> Self {
+        Self([i_sample as u8, q_sample as u8])
+    }
+}
+
 param_slice! {
     [BisConnHandle; 2] {
         handle[0]: ConnHandle,
     }
 }
 
+impl BisConnHandle {
+    /// Create a new instance.
+    pub fn new(handle: ConnHandle) -> Self {
+        Self(handle.raw().to_le_bytes())
+    }
+}
+
 param! {
     #[derive(Default)]
     enum DataStatus {
@@ -634,7 +695,18 @@ param! {
     }
 }
 
-impl LeAdvReports<'_> {
+impl<'a> LeAdvReports<'a> {
+    /// Encode `reports` into `buf` and return them as a list of advertising reports.
+    ///
+    /// Fails with [`FromHciBytesError::InvalidSize`] if `buf` is too small to hold the reports.
+    pub fn encode(reports: &[LeAdvReport<'_>], buf: &'a mut [u8]) -> Result<Self, FromHciBytesError> {
+        let bytes = encode_n(reports, buf)?;
+        Ok(Self {
+            num_reports: reports.len() as u8,
+            bytes: RemainingBytes::new(bytes),
+        })
+    }
+
     /// Check if there are more reports available.
     pub fn is_empty(&self) -> bool {
         self.num_reports == 0
@@ -815,7 +887,18 @@ param! {
     }
 }
 
-impl LeExtAdvReports<'_> {
+impl<'a> LeExtAdvReports<'a> {
+    /// Encode `reports` into `buf` and return them as a list of extended advertising reports.
+    ///
+    /// Fails with [`FromHciBytesError::InvalidSize`] if `buf` is too small to hold the reports.
+    pub fn encode(reports: &[LeExtAdvReport<'_>], buf: &'a mut [u8]) -> Result<Self, FromHciBytesError> {
+        let bytes = encode_n(reports, buf)?;
+        Ok(Self {
+            num_reports: reports.len() as u8,
+            bytes: RemainingBytes::new(bytes),
+        })
+    }
+
     /// Check if there are more reports available.
     pub fn is_empty(&self) -> bool {
         self.num_reports == 0
@@ -907,6 +990,101 @@ impl<'a, 'b: 'a> WriteHci for &'a [LePeriodicAdvSubeventData<'b>] {
     }
 }
 
+param! {
+    struct LePeriodicAdvSubevents<'a> {
+        num_subevents: u8,
+        bytes: RemainingBytes<'a>,
+    }
+}
+
+impl<'a> LePeriodicAdvSubevents<'a> {
+    /// Check if there are more subevents available.
+    pub fn is_empty(&self) -> bool {
+        self.num_subevents == 0
+    }
+
+    /// Number of subevents.
+    pub fn len(&self) -> usize {
+        usize::from(self.num_subevents)
+    }
+
+    /// Create an iterator over the subevents.
+    pub fn iter(&self) -> LePeriodicAdvSubeventsIter<'_> {
+        LePeriodicAdvSubeventsIter {
+            len: self.len(),
+            bytes: &self.bytes,
+        }
+    }
+}
+
+/// An iterator for periodic advertising subevent data.
+pub struct LePeriodicAdvSubeventsIter<'a> {
+    len: usize,
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for LePeriodicAdvSubeventsIter<'a> {
+    type Item = Result<LePeriodicAdvSubeventData<'a>, FromHciBytesError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.len == 0 {
+            None
+        } else {
+            match LePeriodicAdvSubeventData::from_hci_bytes(self.bytes) {
+                Ok((subevent, rest)) => {
+                    self.bytes = rest;
+                    self.len -= 1;
+                    Some(Ok(subevent))
+                }
+                Err(err) => {
+                    self.len = 0;
+                    Some(Err(err))
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl ExactSizeIterator for LePeriodicAdvSubeventsIter<'_> {
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl FusedIterator for LePeriodicAdvSubeventsIter<'_> {}
+
+/// Write `items` back to back into the start of `buf`, returning the written bytes.
+fn encode_n<'a, T: WriteHci>(items: &[T], buf: &'a mut [u8]) -> Result<&'a [u8], FromHciBytesError> {
+    let len = items.iter().map(WriteHci::size).sum::<usize>();
+    if items.len() > usize::from(u8::MAX) || len > buf.len() {
+        return Err(FromHciBytesError::InvalidSize);
+    }
+    let (buf, _) = buf.split_at_mut(len);
+    let mut writer = &mut buf[..];
+    for x in items {
+        x.write_hci(&mut writer).or(Err(FromHciBytesError::InvalidSize))?;
+    }
+    Ok(buf)
+}
+
+fn write_n<T: WriteHci, W: embedded_io::Write>(items: &[T], mut writer: W) -> Result<(), W::Error> {
+    for x in items {
+        x.write_hci(&mut writer)?;
+    }
+    Ok(())
+}
+
+async fn write_n_async<T: WriteHci, W: embedded_io_async::Write>(items: &[T], mut writer: W) -> Result<(), W::Error> {
+    for x in items {
+        x.write_hci_async(&mut writer).await?;
+    }
+    Ok(())
+}
+
 #[allow(missing_docs)]
 fn read_n<T: ByteAlignedValue>(data: &[u8], n: usize) -> Result<(&[T], &[u8]), FromHciBytesError> {
     let size = n * core::mem::size_of::<T>();
@@ -945,6 +1123,35 @@ pub struct LePeriodicAdvertisingResponseReports<'a> {
 }
 
 impl<'a> LePeriodicAdvertisingResponseReports<'a> {
+    /// Encode `reports` into `buf` and return them in the column-major wire format.
+    ///
+    /// The data length of each response is taken from its `data`. Fails with
+    /// [`FromHciBytesError::InvalidSize`] if `buf` is too small to hold the reports.
+    pub fn encode(
+        reports: &[LePeriodicAdvertisingResponseReport<'_>],
+        buf: &'a mut [u8],
+    ) -> Result<Self, FromHciBytesError> {
+        let n = reports.len();
+        let len = 1 + 6 * n + reports.iter().map(|r| r.data.len()).sum::<usize>();
+        if n > usize::from(u8::MAX) || len > buf.len() || reports.iter().any(|r| r.data.len() > usize::from(u8::MAX)) {
+            return Err(FromHciBytesError::InvalidSize);
+        }
+        let (buf, _) = buf.split_at_mut(len);
+        buf[0] = n as u8;
+        let mut offset = 1 + 6 * n;
+        for (i, r) in reports.iter().enumerate() {
+            buf[1 + i] = r.tx_power as u8;
+            buf[1 + n + i] = r.rssi as u8;
+            buf[1 + 2 * n + i] = r.cte_type as u8;
+            buf[1 + 3 * n + i] = r.response_slot;
+            buf[1 + 4 * n + i] = r.data_status as u8;
+            buf[1 + 5 * n + i] = r.data.len() as u8;
+            buf[offset..offset + r.data.len()].copy_from_slice(r.data);
+            offset += r.data.len();
+        }
+        Self::from_hci_bytes_complete(buf)
+    }
+
     /// Returns `true` if there are no responses.
     pub fn is_empty(&self) -> bool {
         self.num_responses == 0
@@ -1010,6 +1217,35 @@ impl<'de> FromHciBytes<'de> for LePeriodicAdvertisingResponseReports<'de> {
     }
 }
 
+impl WriteHci for LePeriodicAdvertisingResponseReports<'_> {
+    #[inline(always)]
+    fn size(&self) -> usize {
+        1 + 6 * self.len() + self.data.len()
+    }
+
+    fn write_hci<W: ::embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+        self.num_responses.write_hci(&mut writer)?;
+        write_n(self.tx_power, &mut writer)?;
+        write_n(self.rssi, &mut writer)?;
+        write_n(self.cte_type, &mut writer)?;
+        write_n(self.response_slot, &mut writer)?;
+        write_n(self.data_status, &mut writer)?;
+        write_n(self.data_length, &mut writer)?;
+        writer.write_all(self.data)
+    }
+
+    async fn write_hci_async<W: ::embedded_io_async::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+        self.num_responses.write_hci_async(&mut writer).await?;
+        write_n_async(self.tx_power, &mut writer).await?;
+        write_n_async(self.rssi, &mut writer).await?;
+        write_n_async(self.cte_type, &mut writer).await?;
+        write_n_async(self.response_slot, &mut writer).await?;
+        write_n_async(self.data_status, &mut writer).await?;
+        write_n_async(self.data_length, &mut writer).await?;
+        writer.write_all(self.data).await
+    }
+}
+
 /// An iterator over the LePeriodicAdvertisingResponse reports.
 pub struct LePeriodicAdvertisingResponseReportsIter<'a> {
     reports: &'a LePeriodicAdvertisingResponseReports<'a>,
@@ -1066,6 +1302,30 @@ pub struct LeCsSubeventStepData<'a> {
 }
 
 impl<'a> LeCsSubeventStepData<'a> {
+    /// Encode `steps` into `buf` and return them in the column-major wire format.
+    ///
+    /// The data length of each step is taken from its `step_data`. Fails with
+    /// [`FromHciBytesError::InvalidSize`] if `buf` is too small to hold the steps.
+    pub fn encode(steps: &[LeCsSubeventStepEntry<'_>], buf: &'a mut [u8]) -> Result<Self, FromHciBytesError> {
+        let n = steps.len();
+        let len = 1 + 3 * n + steps.iter().map(|s| s.step_data.len()).sum::<usize>();
+        if n > usize::from(u8::MAX) || len > buf.len() || steps.iter().any(|s| s.step_data.len() > usize::from(u8::MAX))
+        {
+            return Err(FromHciBytesError::InvalidSize);
+        }
+        let (buf, _) = buf.split_at_mut(len);
+        buf[0] = n as u8;
+        let mut offset = 1 + 3 * n;
+        for (i, s) in steps.iter().enumerate() {
+            buf[1 + i] = s.step_mode;
+            buf[1 + n + i] = s.step_channel;
+            buf[1 + 2 * n + i] = s.step_data.len() as u8;
+            buf[offset..offset + s.step_data.len()].copy_from_slice(s.step_data);
+            offset += s.step_data.len();
+        }
+        Self::from_hci_bytes_complete(buf)
+    }
+
     /// Returns `true` if there are no steps.
     pub fn is_empty(&self) -> bool {
         self.num_steps_reported == 0
@@ -1119,6 +1379,29 @@ impl<'de> FromHciBytes<'de> for LeCsSubeventStepData<'de> {
     }
 }
 
+impl WriteHci for LeCsSubeventStepData<'_> {
+    #[inline(always)]
+    fn size(&self) -> usize {
+        1 + 3 * self.len() + self.step_data.len()
+    }
+
+    fn write_hci<W: ::embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+        writer.write_all(&[self.num_steps_reported])?;
+        writer.write_all(self.step_mode)?;
+        writer.write_all(self.step_channel)?;
+        writer.write_all(self.step_data_length)?;
+        writer.write_all(self.step_data)
+    }
+
+    async fn write_hci_async<W: ::embedded_io_async::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+        writer.write_all(&[self.num_steps_reported]).await?;
+        writer.write_all(self.step_mode).await?;
+        writer.write_all(self.step_channel).await?;
+        writer.write_all(self.step_data_length).await?;
+        writer.write_all(self.step_data).await
+    }
+}
+
 /// An iterator over LeCsSubeventStepEntry values.
 pub struct LeCsSubeventStepDataIter<'a> {
     data: &'a LeCsSubeventStepData<'a>,
@@ -209,10 +209,17 @@ macro_rules! param {
         #[doc = stringify!($name)]
         $(#[$attrs])*
         #[repr(transparent)]
-        #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[derive(Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
         #[cfg_attr(feature = "defmt", derive(defmt::Format))]
         pub struct $name(u8);
 
+        impl core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                const FLAGS: &[(usize, &str)] = &[$(($bit, stringify!($get))),+];
+                $crate::dissect::debug_bitfield(f, stringify!($name), core::slice::from_ref(&self.0), FLAGS)
+            }
+        }
+
         impl $name {
             /// Create a new instance.
             pub fn new() -> Self {
@@ -262,10 +269,17 @@ macro_rules! param {
         #[doc = stringify!($name)]
         $(#[$attrs])*
         #[repr(transparent)]
-        #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[derive(Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
         #[cfg_attr(feature = "defmt", derive(defmt::Format))]
         pub struct $name([u8; $octets]);
 
+        impl core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                const FLAGS: &[(usize, &str)] = &[$(($bit, stringify!($get))),+];
+                $crate::dissect::debug_bitfield(f, stringify!($name), &self.0, FLAGS)
+            }
+        }
+
         impl $name {
             /// Create a new instance.
             pub fn new() -> Self {
@@ -0,0 +1,404 @@
+//! Reading and writing HCI traffic in the pcap file format.
+//!
+//! Packets are stored with the `LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR` (201) link type: every packet is preceded by
+//! a 4 byte pseudo-header holding its direction, followed by the packet indicator. See [`pcapng`](crate::pcapng)
+//! for the newer pcapng format.
+//!
+//! ```
+//! use bt_hci::cmd::controller_baseband::Reset;
+//! use bt_hci::pcap::{Direction, Reader, Writer};
+//!
+//! let mut log = [0u8; 64];
+//! let mut writer = Writer::new(&mut log[..]).unwrap();
+//! writer.write_sent(&Reset::new()).unwrap();
+//!
+//! let mut reader = Reader::new(&log[..]).unwrap();
+//! let mut buf = [0; 263];
+//! let record = reader.next(&mut buf).unwrap().unwrap();
+//! assert_eq!(record.direction, Direction::Sent);
+//! assert_eq!(record.data, [0x03, 0x0c, 0x00]);
+//! ```
+
+use bt_hci_driver::{PacketKind, PacketToController};
+use embedded_io::ReadExactError;
+
+use crate::capture::read_or_eof;
+pub use crate::capture::{NoTimestamp, Packet, TimestampSource};
+use crate::transport::tee::Sink;
+pub use crate::transport::Direction;
+use crate::{FromHciBytes, FromHciBytesError, WritePacketToHost};
+
+/// The `LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR` link type.
+pub const LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR: u32 = 201;
+
+/// The maximum length of a stored packet, including pseudo-header and packet indicator.
+pub(crate) const SNAPLEN: u32 = 65535 + 4 + 1 + 4;
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+/// Length of the direction pseudo-header plus packet indicator in front of every packet.
+pub(crate) const PREFIX_LEN: usize = 5;
+
+/// Byte order of a capture file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    pub(crate) fn u16(self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        match self {
+            Self::Little => u16::from_le_bytes(b),
+            Self::Big => u16::from_be_bytes(b),
+        }
+    }
+
+    pub(crate) fn u32(self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        match self {
+            Self::Little => u32::from_le_bytes(b),
+            Self::Big => u32::from_be_bytes(b),
+        }
+    }
+}
+
+/// The pseudo-header and packet indicator written in front of every packet.
+pub(crate) fn prefix(direction: Direction, kind: PacketKind) -> [u8; PREFIX_LEN] {
+    let direction = match direction {
+        Direction::Sent => 0,
+        Direction::Received => 1,
+    };
+    [0, 0, 0, direction, kind as u8]
+}
+
+/// Split a stored packet into its pseudo-header and packet indicator and the packet itself.
+pub(crate) fn parse_prefix(data: &[u8]) -> Option<(Direction, PacketKind, &[u8])> {
+    if data.len() < PREFIX_LEN {
+        return None;
+    }
+    let direction = if data[3] & 0x01 != 0 {
+        Direction::Received
+    } else {
+        Direction::Sent
+    };
+    let (kind, data) = PacketKind::from_hci_bytes(&data[4..]).ok()?;
+    Some((direction, kind, data))
+}
+
+/// Writes HCI packets to a pcap file.
+pub struct Writer<W, T = NoTimestamp> {
+    writer: W,
+    timestamps: T,
+}
+
+impl<W: embedded_io::Write> Writer<W> {
+    /// Create a new writer without timestamps and write the file header.
+    pub fn new(writer: W) -> Result<Self, W::Error> {
+        Self::with_timestamps(writer, NoTimestamp)
+    }
+}
+
+impl<W: embedded_io::Write, T: TimestampSource> Writer<W, T> {
+    /// Create a new writer which stamps every record with the time from `timestamps` and write the file header.
+    pub fn with_timestamps(mut writer: W, timestamps: T) -> Result<Self, W::Error> {
+        let mut header = [0; 24];
+        header[0..4].copy_from_slice(&MAGIC_MICROS.to_le_bytes());
+        header[4..6].copy_from_slice(&2u16.to_le_bytes());
+        header[6..8].copy_from_slice(&4u16.to_le_bytes());
+        // header[8..16] holds the time zone offset and timestamp accuracy, which are always 0
+        header[16..20].copy_from_slice(&SNAPLEN.to_le_bytes());
+        header[20..24].copy_from_slice(&LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(Self { writer, timestamps })
+    }
+
+    /// Record a packet sent from the host to the controller.
+    pub fn write_sent<P: PacketToController>(&mut self, pkt: &P) -> Result<(), W::Error> {
+        self.write_header(Direction::Sent, P::KIND, pkt.size())?;
+        pkt.write_hci(&mut self.writer)
+    }
+
+    /// Record a packet received from the controller.
+    pub fn write_received<P: WritePacketToHost>(&mut self, pkt: &P) -> Result<(), W::Error> {
+        self.write_header(Direction::Received, pkt.kind(), pkt.size())?;
+        pkt.write_hci(&mut self.writer)
+    }
+
+    /// Record an already serialized packet, `data` must not include the packet indicator.
+    pub fn write_record(&mut self, direction: Direction, kind: PacketKind, data: &[u8]) -> Result<(), W::Error> {
+        self.write_header(direction, kind, data.len())?;
+        self.writer.write_all(data)
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<(), W::Error> {
+        self.writer.flush()
+    }
+
+    /// Consume the writer, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_header(&mut self, direction: Direction, kind: PacketKind, len: usize) -> Result<(), W::Error> {
+        let len = (PREFIX_LEN + len) as u32;
+        let timestamp = self.timestamps.now_micros();
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&((timestamp / 1_000_000) as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&((timestamp % 1_000_000) as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&len.to_le_bytes());
+        header[12..16].copy_from_slice(&len.to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&prefix(direction, kind))
+    }
+}
+
+/// Records every packet seen by a [`TeeTransport`](crate::transport::tee::TeeTransport).
+///
+/// Errors from the underlying writer are ignored, so that logging never interferes with the transport.
+impl<W: embedded_io::Write, T: TimestampSource> Sink for Writer<W, T> {
+    fn packet(&mut self, direction: Direction, kind: PacketKind, data: &[u8]) {
+        let _ = self.write_record(direction, kind, data);
+    }
+}
+
+/// Errors from reading a pcap or pcapng file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// The file does not start with a valid header.
+    InvalidHeader,
+    /// The file uses a link type other than [`LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR`].
+    UnsupportedLinktype(u32),
+    /// A record is larger than the provided buffer.
+    BufferTooSmall,
+    /// A record is malformed or too short to hold the pseudo-header and packet indicator.
+    InvalidRecord,
+    /// A pcapng file describes more interfaces than the reader can keep track of.
+    TooManyInterfaces,
+    /// Error from the underlying reader.
+    Read(ReadExactError<E>),
+}
+
+impl<E> From<ReadExactError<E>> for Error<E> {
+    fn from(value: ReadExactError<E>) -> Self {
+        Self::Read(value)
+    }
+}
+
+/// A single packet recorded in a pcap or pcapng file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Record<'a> {
+    /// The direction of the packet.
+    pub direction: Direction,
+    /// The kind of packet.
+    pub kind: PacketKind,
+    /// The time the packet was recorded in microseconds since the Unix epoch.
+    pub timestamp_micros: u64,
+    /// The length of the packet before it was truncated, not including the packet indicator.
+    pub original_len: usize,
+    /// The recorded bytes of the packet, not including the packet indicator.
+    pub data: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    /// Returns `true` if the packet was recorded completely.
+    pub fn is_complete(&self) -> bool {
+        self.data.len() == self.original_len
+    }
+
+    /// Decode the recorded packet.
+    pub fn packet(&self) -> Result<Packet<'a>, FromHciBytesError> {
+        Packet::decode(self.direction, self.kind, self.data)
+    }
+}
+
+/// Reads HCI packets from a pcap file.
+pub struct Reader<R> {
+    reader: R,
+    endian: Endian,
+    nanos: bool,
+}
+
+impl<R: embedded_io::Read> Reader<R> {
+    /// Create a new reader and read the file header.
+    ///
+    /// Files in either byte order and with microsecond or nanosecond timestamps are supported.
+    pub fn new(mut reader: R) -> Result<Self, Error<R::Error>> {
+        let mut header = [0; 24];
+        reader.read_exact(&mut header)?;
+        let (endian, nanos) = match (Endian::Little.u32(&header), Endian::Big.u32(&header)) {
+            (MAGIC_MICROS, _) => (Endian::Little, false),
+            (MAGIC_NANOS, _) => (Endian::Little, true),
+            (_, MAGIC_MICROS) => (Endian::Big, false),
+            (_, MAGIC_NANOS) => (Endian::Big, true),
+            _ => return Err(Error::InvalidHeader),
+        };
+        if endian.u16(&header[4..]) != 2 {
+            return Err(Error::InvalidHeader);
+        }
+        // The upper bits of the link type field hold FCS information which does not apply to HCI
+        let linktype = endian.u32(&header[20..]) & 0x0fff_ffff;
+        if linktype != LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR {
+            return Err(Error::UnsupportedLinktype(linktype));
+        }
+        Ok(Self { reader, endian, nanos })
+    }
+
+    /// Read the next record into `buf`.
+    ///
+    /// `buf` must be large enough to hold the pseudo-header and packet indicator in addition to the packet. Returns
+    /// `Ok(None)` at the end of the file.
+    pub fn next<'b>(&mut self, buf: &'b mut [u8]) -> Result<Option<Record<'b>>, Error<R::Error>> {
+        let mut header = [0; 16];
+        if !read_or_eof(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+        let seconds = u64::from(self.endian.u32(&header[0..]));
+        let fraction = u64::from(self.endian.u32(&header[4..]));
+        let included_len = self.endian.u32(&header[8..]) as usize;
+        let original_len = self.endian.u32(&header[12..]) as usize;
+
+        if buf.len() < included_len {
+            return Err(Error::BufferTooSmall);
+        }
+        let data = &mut buf[..included_len];
+        self.reader.read_exact(data)?;
+        let data: &'b [u8] = data;
+
+        let (direction, kind, data) = parse_prefix(data).ok_or(Error::InvalidRecord)?;
+        let fraction = if self.nanos { fraction / 1000 } else { fraction };
+        Ok(Some(Record {
+            direction,
+            kind,
+            timestamp_micros: seconds * 1_000_000 + fraction,
+            original_len: original_len.saturating_sub(PREFIX_LEN),
+            data,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::cmd::Command;
+    use crate::data::{AclBroadcastFlag, AclPacket, AclPacketBoundary};
+    use crate::event::{Event, EventPacket};
+    use crate::param::ConnHandle;
+    use crate::{ControllerToHostPacket, HostToControllerPacket};
+
+    const RESET_COMPLETE: [u8; 6] = [0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+
+    #[test]
+    fn test_write() {
+        let mut log = [0u8; 64];
+        let mut writer = Writer::with_timestamps(&mut log[..], || 1_500_000).unwrap();
+        writer.write_sent(&Reset::new()).unwrap();
+        writer.into_inner();
+
+        assert_eq!(
+            &log[..24],
+            [0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x08, 0x00, 0x01, 0x00, 201, 0, 0, 0]
+        );
+        assert_eq!(
+            &log[24..48],
+            [1, 0, 0, 0, 0x20, 0xa1, 0x07, 0, 8, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x03, 0x0c, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut log = [0u8; 256];
+        let mut time = 0;
+        let mut writer = Writer::with_timestamps(&mut log[..], || {
+            time += 250;
+            time
+        })
+        .unwrap();
+        writer.write_sent(&Reset::new()).unwrap();
+        let event = EventPacket::from_hci_bytes_complete(&RESET_COMPLETE).unwrap();
+        writer.write_received(&ControllerToHostPacket::Event(event)).unwrap();
+        let acl = AclPacket::new(
+            ConnHandle::new(0x40),
+            AclPacketBoundary::FirstFlushable,
+            AclBroadcastFlag::PointToPoint,
+            &[1, 2, 3],
+        );
+        writer.write_sent(&acl).unwrap();
+        let len = 256 - writer.into_inner().len();
+        assert_eq!(len, 24 + 3 * (16 + 5) + 3 + 6 + 7);
+
+        let mut reader = Reader::new(&log[..len]).unwrap();
+        let mut buf = [0; 263];
+
+        let record = reader.next(&mut buf).unwrap().unwrap();
+        assert_eq!(record.direction, Direction::Sent);
+        assert_eq!(record.timestamp_micros, 250);
+        assert!(record.is_complete());
+        let Packet::Sent(HostToControllerPacket::Cmd(cmd)) = record.packet().unwrap() else {
+            panic!("expected a command");
+        };
+        assert_eq!(Command::try_from(cmd), Ok(Command::Reset(Reset::new())));
+
+        let record = reader.next(&mut buf).unwrap().unwrap();
+        assert_eq!(record.timestamp_micros, 500);
+        let Packet::Received(ControllerToHostPacket::Event(event)) = record.packet().unwrap() else {
+            panic!("expected an event");
+        };
+        assert!(matches!(Event::try_from(event), Ok(Event::CommandComplete(_))));
+
+        let record = reader.next(&mut buf).unwrap().unwrap();
+        let Packet::Sent(HostToControllerPacket::Acl(acl)) = record.packet().unwrap() else {
+            panic!("expected ACL data");
+        };
+        assert_eq!(acl.handle(), ConnHandle::new(0x40));
+        assert_eq!(acl.data(), [1, 2, 3]);
+
+        assert_eq!(reader.next(&mut buf), Ok(None));
+    }
+
+    #[test]
+    fn test_read_big_endian_nanos() {
+        let mut log = [0u8; 24 + 16 + 11];
+        log[..24].copy_from_slice(&[
+            0xa1, 0xb2, 0x3c, 0x4d, 0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 201,
+        ]);
+        log[24..40].copy_from_slice(&[0, 0, 0, 2, 0, 0, 0x03, 0xe8, 0, 0, 0, 11, 0, 0, 0, 11]);
+        log[40..44].copy_from_slice(&[0, 0, 0, 1]);
+        log[44] = 0x04;
+        log[45..].copy_from_slice(&RESET_COMPLETE);
+
+        let mut reader = Reader::new(&log[..]).unwrap();
+        let mut buf = [0; 263];
+        let record = reader.next(&mut buf).unwrap().unwrap();
+        assert_eq!(record.direction, Direction::Received);
+        assert_eq!(record.kind, PacketKind::Event);
+        assert_eq!(record.timestamp_micros, 2_000_001);
+        assert_eq!(record.data, RESET_COMPLETE);
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let mut log = [0u8; 64];
+        let mut writer = Writer::new(&mut log[..]).unwrap();
+        writer.write_sent(&Reset::new()).unwrap();
+
+        let mut header = log;
+        header[20] = 187;
+        assert!(matches!(Reader::new(&header[..]), Err(Error::UnsupportedLinktype(187))));
+        header[0] = 0;
+        assert!(matches!(Reader::new(&header[..]), Err(Error::InvalidHeader)));
+
+        let mut reader = Reader::new(&log[..48]).unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(reader.next(&mut buf), Err(Error::BufferTooSmall));
+        let mut reader = Reader::new(&log[..47]).unwrap();
+        let mut buf = [0; 263];
+        assert_eq!(reader.next(&mut buf), Err(Error::Read(ReadExactError::UnexpectedEof)));
+    }
+}
@@ -0,0 +1,448 @@
+//! Reading and writing HCI traffic in the pcapng file format.
+//!
+//! The [`Writer`] creates a single section with one `LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR` interface, and stores
+//! every packet in an Enhanced Packet Block. The [`Reader`] handles files in either byte order, with multiple
+//! sections and interfaces. Packets from interfaces with other link types and blocks other than Enhanced Packet
+//! Blocks are skipped.
+//!
+//! ```
+//! use bt_hci::cmd::controller_baseband::Reset;
+//! use bt_hci::pcapng::{Direction, Reader, Writer};
+//!
+//! let mut log = [0u8; 128];
+//! let mut writer = Writer::new(&mut log[..]).unwrap();
+//! writer.write_sent(&Reset::new()).unwrap();
+//!
+//! let mut reader = Reader::new(&log[..]).unwrap();
+//! let mut buf = [0; 292];
+//! let record = reader.next(&mut buf).unwrap().unwrap();
+//! assert_eq!(record.direction, Direction::Sent);
+//! assert_eq!(record.data, [0x03, 0x0c, 0x00]);
+//! ```
+
+use bt_hci_driver::{PacketKind, PacketToController};
+
+use crate::capture::{read_or_eof, skip};
+pub use crate::capture::{NoTimestamp, Packet, TimestampSource};
+use crate::pcap::{parse_prefix, prefix, Endian, LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR, PREFIX_LEN, SNAPLEN};
+pub use crate::pcap::{Error, Record};
+use crate::transport::tee::Sink;
+pub use crate::transport::Direction;
+use crate::WritePacketToHost;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_END_OF_OPT: u16 = 0;
+const OPT_IF_TSRESOL: u16 = 9;
+
+/// The number of interfaces per section a [`Reader`] can keep track of.
+pub const MAX_INTERFACES: usize = 8;
+
+/// Writes HCI packets to a pcapng file.
+pub struct Writer<W, T = NoTimestamp> {
+    writer: W,
+    timestamps: T,
+}
+
+impl<W: embedded_io::Write> Writer<W> {
+    /// Create a new writer without timestamps and write the section header and interface description.
+    pub fn new(writer: W) -> Result<Self, W::Error> {
+        Self::with_timestamps(writer, NoTimestamp)
+    }
+}
+
+impl<W: embedded_io::Write, T: TimestampSource> Writer<W, T> {
+    /// Create a new writer which stamps every record with the time from `timestamps`, and write the section header
+    /// and interface description.
+    pub fn with_timestamps(mut writer: W, timestamps: T) -> Result<Self, W::Error> {
+        let mut shb = [0; 28];
+        shb[0..4].copy_from_slice(&SECTION_HEADER_BLOCK.to_le_bytes());
+        shb[4..8].copy_from_slice(&28u32.to_le_bytes());
+        shb[8..12].copy_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb[12..14].copy_from_slice(&1u16.to_le_bytes());
+        // Minor version 0, and an unspecified section length
+        shb[16..24].copy_from_slice(&(-1i64).to_le_bytes());
+        shb[24..28].copy_from_slice(&28u32.to_le_bytes());
+        writer.write_all(&shb)?;
+
+        // Without an if_tsresol option, timestamps are in microseconds
+        let mut idb = [0; 20];
+        idb[0..4].copy_from_slice(&INTERFACE_DESCRIPTION_BLOCK.to_le_bytes());
+        idb[4..8].copy_from_slice(&20u32.to_le_bytes());
+        idb[8..10].copy_from_slice(&(LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR as u16).to_le_bytes());
+        idb[12..16].copy_from_slice(&SNAPLEN.to_le_bytes());
+        idb[16..20].copy_from_slice(&20u32.to_le_bytes());
+        writer.write_all(&idb)?;
+
+        Ok(Self { writer, timestamps })
+    }
+
+    /// Record a packet sent from the host to the controller.
+    pub fn write_sent<P: PacketToController>(&mut self, pkt: &P) -> Result<(), W::Error> {
+        let len = self.write_header(Direction::Sent, P::KIND, pkt.size())?;
+        pkt.write_hci(&mut self.writer)?;
+        self.write_trailer(len)
+    }
+
+    /// Record a packet received from the controller.
+    pub fn write_received<P: WritePacketToHost>(&mut self, pkt: &P) -> Result<(), W::Error> {
+        let len = self.write_header(Direction::Received, pkt.kind(), pkt.size())?;
+        pkt.write_hci(&mut self.writer)?;
+        self.write_trailer(len)
+    }
+
+    /// Record an already serialized packet, `data` must not include the packet indicator.
+    pub fn write_record(&mut self, direction: Direction, kind: PacketKind, data: &[u8]) -> Result<(), W::Error> {
+        let len = self.write_header(direction, kind, data.len())?;
+        self.writer.write_all(data)?;
+        self.write_trailer(len)
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<(), W::Error> {
+        self.writer.flush()
+    }
+
+    /// Consume the writer, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Write the Enhanced Packet Block up to the packet, and return the length of the stored packet.
+    fn write_header(&mut self, direction: Direction, kind: PacketKind, len: usize) -> Result<u32, W::Error> {
+        let len = (PREFIX_LEN + len) as u32;
+        let timestamp = self.timestamps.now_micros();
+        let mut header = [0; 28];
+        header[0..4].copy_from_slice(&ENHANCED_PACKET_BLOCK.to_le_bytes());
+        header[4..8].copy_from_slice(&block_len(len).to_le_bytes());
+        // header[8..12] holds the interface id, which is always 0
+        header[12..16].copy_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        header[16..20].copy_from_slice(&(timestamp as u32).to_le_bytes());
+        header[20..24].copy_from_slice(&len.to_le_bytes());
+        header[24..28].copy_from_slice(&len.to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&prefix(direction, kind))?;
+        Ok(len)
+    }
+
+    /// Pad the packet to 32 bits and close the Enhanced Packet Block.
+    fn write_trailer(&mut self, len: u32) -> Result<(), W::Error> {
+        let padding = (4 - len as usize % 4) % 4;
+        self.writer.write_all(&[0; 3][..padding])?;
+        self.writer.write_all(&block_len(len).to_le_bytes())
+    }
+}
+
+/// The total length of an Enhanced Packet Block holding `len` bytes of packet data without options.
+fn block_len(len: u32) -> u32 {
+    32 + len.next_multiple_of(4)
+}
+
+/// Records every packet seen by a [`TeeTransport`](crate::transport::tee::TeeTransport).
+///
+/// Errors from the underlying writer are ignored, so that logging never interferes with the transport.
+impl<W: embedded_io::Write, T: TimestampSource> Sink for Writer<W, T> {
+    fn packet(&mut self, direction: Direction, kind: PacketKind, data: &[u8]) {
+        let _ = self.write_record(direction, kind, data);
+    }
+}
+
+/// An interface described in the current section.
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    linktype: u16,
+    /// Timestamp units per second.
+    units_per_sec: u64,
+}
+
+/// Reads HCI packets from a pcapng file.
+pub struct Reader<R> {
+    reader: R,
+    endian: Endian,
+    interfaces: heapless::Vec<Interface, MAX_INTERFACES>,
+}
+
+impl<R: embedded_io::Read> Reader<R> {
+    /// Create a new reader and read the first section header.
+    pub fn new(mut reader: R) -> Result<Self, Error<R::Error>> {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        if Endian::Little.u32(&header) != SECTION_HEADER_BLOCK {
+            return Err(Error::InvalidHeader);
+        }
+        let mut this = Self {
+            reader,
+            endian: Endian::Little,
+            interfaces: heapless::Vec::new(),
+        };
+        this.read_section_header(&header)?;
+        Ok(this)
+    }
+
+    /// Read the next record into `buf`.
+    ///
+    /// `buf` must be large enough to hold the body of every block in the file, which for packets is 24 bytes plus
+    /// the pseudo-header and packet indicator in addition to the packet. Returns `Ok(None)` at the end of the file.
+    pub fn next<'b>(&mut self, buf: &'b mut [u8]) -> Result<Option<Record<'b>>, Error<R::Error>> {
+        loop {
+            let mut header = [0; 8];
+            if !read_or_eof(&mut self.reader, &mut header)? {
+                return Ok(None);
+            }
+            let block_type = self.endian.u32(&header);
+            if block_type == SECTION_HEADER_BLOCK {
+                self.read_section_header(&header)?;
+                continue;
+            }
+
+            let body_len = self.block_body_len(&header)?;
+            match block_type {
+                INTERFACE_DESCRIPTION_BLOCK => {
+                    let body = self.read_body(body_len, &mut *buf)?;
+                    let interface = self.parse_interface(body)?;
+                    self.interfaces.push(interface).map_err(|_| Error::TooManyInterfaces)?;
+                }
+                ENHANCED_PACKET_BLOCK => {
+                    let body = self.read_body(body_len, &mut *buf)?;
+                    if body.len() < 20 {
+                        return Err(Error::InvalidRecord);
+                    }
+                    let interface = self.endian.u32(&body[0..]) as usize;
+                    let interface = *self.interfaces.get(interface).ok_or(Error::InvalidRecord)?;
+                    if u32::from(interface.linktype) != LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR {
+                        continue;
+                    }
+
+                    let timestamp =
+                        (u64::from(self.endian.u32(&body[4..])) << 32) | u64::from(self.endian.u32(&body[8..]));
+                    let timestamp_micros = (u128::from(timestamp) * 1_000_000 / u128::from(interface.units_per_sec))
+                        .try_into()
+                        .unwrap_or(u64::MAX);
+                    let captured_len = self.endian.u32(&body[12..]) as usize;
+                    let original_len = self.endian.u32(&body[16..]) as usize;
+                    let body_len = body.len();
+
+                    let buf: &'b [u8] = buf;
+                    let data = buf[20..body_len].get(..captured_len).ok_or(Error::InvalidRecord)?;
+                    let (direction, kind, data) = parse_prefix(data).ok_or(Error::InvalidRecord)?;
+                    return Ok(Some(Record {
+                        direction,
+                        kind,
+                        timestamp_micros,
+                        original_len: original_len.saturating_sub(PREFIX_LEN),
+                        data,
+                    }));
+                }
+                _ => skip(&mut self.reader, body_len, buf)?,
+            }
+        }
+    }
+
+    /// Handle a Section Header Block starting with `header`, and read the rest of the block.
+    fn read_section_header(&mut self, header: &[u8; 8]) -> Result<(), Error<R::Error>> {
+        let mut magic = [0; 4];
+        self.reader.read_exact(&mut magic)?;
+        self.endian = match (Endian::Little.u32(&magic), Endian::Big.u32(&magic)) {
+            (BYTE_ORDER_MAGIC, _) => Endian::Little,
+            (_, BYTE_ORDER_MAGIC) => Endian::Big,
+            _ => return Err(Error::InvalidHeader),
+        };
+        self.interfaces.clear();
+
+        let len = self.endian.u32(&header[4..]) as usize;
+        if len < 28 || len % 4 != 0 {
+            return Err(Error::InvalidHeader);
+        }
+        let mut version = [0; 2];
+        self.reader.read_exact(&mut version)?;
+        if self.endian.u16(&version) != 1 {
+            return Err(Error::InvalidHeader);
+        }
+        let mut scratch = [0; 16];
+        skip(&mut self.reader, len - 14, &mut scratch)?;
+        Ok(())
+    }
+
+    /// The length of the block after the block type and length fields, including the trailing length.
+    fn block_body_len(&self, header: &[u8; 8]) -> Result<usize, Error<R::Error>> {
+        let len = self.endian.u32(&header[4..]) as usize;
+        if len < 12 || len % 4 != 0 {
+            return Err(Error::InvalidRecord);
+        }
+        Ok(len - 8)
+    }
+
+    /// Read the body of a block into `buf`, and return it without the trailing block length.
+    fn read_body<'b>(&mut self, body_len: usize, buf: &'b mut [u8]) -> Result<&'b [u8], Error<R::Error>> {
+        let body = buf.get_mut(..body_len).ok_or(Error::BufferTooSmall)?;
+        self.reader.read_exact(body)?;
+        Ok(&body[..body_len - 4])
+    }
+
+    fn parse_interface(&self, body: &[u8]) -> Result<Interface, Error<R::Error>> {
+        if body.len() < 8 {
+            return Err(Error::InvalidRecord);
+        }
+        let mut interface = Interface {
+            linktype: self.endian.u16(body),
+            units_per_sec: 1_000_000,
+        };
+
+        let mut options = &body[8..];
+        while options.len() >= 4 {
+            let code = self.endian.u16(options);
+            let len = usize::from(self.endian.u16(&options[2..]));
+            let value = options[4..].get(..len).ok_or(Error::InvalidRecord)?;
+            match code {
+                OPT_END_OF_OPT => break,
+                OPT_IF_TSRESOL if len == 1 => {
+                    let exponent = u32::from(value[0] & 0x7f);
+                    let base: u64 = if value[0] & 0x80 != 0 { 2 } else { 10 };
+                    interface.units_per_sec = base.checked_pow(exponent).ok_or(Error::InvalidRecord)?;
+                }
+                _ => {}
+            }
+            options = options.get(4 + len.next_multiple_of(4)..).unwrap_or(&[]);
+        }
+        Ok(interface)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_io::ReadExactError;
+
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::cmd::Command;
+    use crate::data::{AclBroadcastFlag, AclPacket, AclPacketBoundary};
+    use crate::event::{Event, EventPacket};
+    use crate::param::ConnHandle;
+    use crate::{ControllerToHostPacket, FromHciBytes, HostToControllerPacket};
+
+    const RESET_COMPLETE: [u8; 6] = [0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+
+    #[test]
+    fn test_write() {
+        let mut log = [0u8; 128];
+        let mut writer = Writer::with_timestamps(&mut log[..], || 0x1_0000_0002).unwrap();
+        writer.write_sent(&Reset::new()).unwrap();
+        let len = 128 - writer.into_inner().len();
+        assert_eq!(len, 28 + 20 + 32 + 8);
+
+        assert_eq!(
+            &log[..12],
+            [0x0a, 0x0d, 0x0d, 0x0a, 28, 0, 0, 0, 0x4d, 0x3c, 0x2b, 0x1a]
+        );
+        assert_eq!(&log[28..36], [1, 0, 0, 0, 20, 0, 0, 0]);
+        assert_eq!(&log[36..38], [201, 0]);
+        let epb = &log[48..len];
+        assert_eq!(&epb[..8], [6, 0, 0, 0, 40, 0, 0, 0]);
+        assert_eq!(&epb[12..28], [1, 0, 0, 0, 2, 0, 0, 0, 8, 0, 0, 0, 8, 0, 0, 0]);
+        assert_eq!(&epb[28..36], [0, 0, 0, 0, 0x01, 0x03, 0x0c, 0x00]);
+        assert_eq!(&epb[36..], [40, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut log = [0u8; 256];
+        let mut time = 0;
+        let mut writer = Writer::with_timestamps(&mut log[..], || {
+            time += 250;
+            time
+        })
+        .unwrap();
+        writer.write_sent(&Reset::new()).unwrap();
+        let event = EventPacket::from_hci_bytes_complete(&RESET_COMPLETE).unwrap();
+        writer.write_received(&ControllerToHostPacket::Event(event)).unwrap();
+        let acl = AclPacket::new(
+            ConnHandle::new(0x40),
+            AclPacketBoundary::FirstFlushable,
+            AclBroadcastFlag::PointToPoint,
+            &[1, 2, 3],
+        );
+        writer.write_sent(&acl).unwrap();
+        let len = 256 - writer.into_inner().len();
+
+        let mut reader = Reader::new(&log[..len]).unwrap();
+        let mut buf = [0; 292];
+
+        let record = reader.next(&mut buf).unwrap().unwrap();
+        assert_eq!(record.direction, Direction::Sent);
+        assert_eq!(record.timestamp_micros, 250);
+        assert!(record.is_complete());
+        let Packet::Sent(HostToControllerPacket::Cmd(cmd)) = record.packet().unwrap() else {
+            panic!("expected a command");
+        };
+        assert_eq!(Command::try_from(cmd), Ok(Command::Reset(Reset::new())));
+
+        let record = reader.next(&mut buf).unwrap().unwrap();
+        assert_eq!(record.timestamp_micros, 500);
+        let Packet::Received(ControllerToHostPacket::Event(event)) = record.packet().unwrap() else {
+            panic!("expected an event");
+        };
+        assert!(matches!(Event::try_from(event), Ok(Event::CommandComplete(_))));
+
+        let record = reader.next(&mut buf).unwrap().unwrap();
+        let Packet::Sent(HostToControllerPacket::Acl(acl)) = record.packet().unwrap() else {
+            panic!("expected ACL data");
+        };
+        assert_eq!(acl.handle(), ConnHandle::new(0x40));
+        assert_eq!(acl.data(), [1, 2, 3]);
+
+        assert_eq!(reader.next(&mut buf), Ok(None));
+    }
+
+    #[test]
+    fn test_read_big_endian() {
+        #[rustfmt::skip]
+        let log = [
+            // Section Header Block with an option
+            0x0a, 0x0d, 0x0d, 0x0a, 0, 0, 0, 36, 0x1a, 0x2b, 0x3c, 0x4d, 0, 1, 0, 0,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 4, 0, 2, b'h', b'i', 0, 0, 0, 0, 0, 36,
+            // Interface Description Block for an Ethernet interface
+            0, 0, 0, 1, 0, 0, 0, 20, 0, 1, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 20,
+            // Interface Description Block with millisecond timestamps
+            0, 0, 0, 1, 0, 0, 0, 32, 0, 201, 0, 0, 0, 0, 0xff, 0xff, 0, 9, 0, 1, 3, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 32,
+            // Name Resolution Block
+            0, 0, 0, 4, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 16,
+            // Packet on the Ethernet interface
+            0, 0, 0, 6, 0, 0, 0, 36, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 2,
+            0xaa, 0xbb, 0, 0, 0, 0, 0, 36,
+            // Packet on the HCI interface
+            0, 0, 0, 6, 0, 0, 0, 44, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 11, 0, 0, 0, 11,
+            0, 0, 0, 1, 0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00, 0, 0, 0, 0, 44,
+        ];
+
+        let mut reader = Reader::new(&log[..]).unwrap();
+        let mut buf = [0; 64];
+        let record = reader.next(&mut buf).unwrap().unwrap();
+        assert_eq!(record.direction, Direction::Received);
+        assert_eq!(record.kind, PacketKind::Event);
+        assert_eq!(record.timestamp_micros, 7000);
+        assert_eq!(record.data, RESET_COMPLETE);
+        assert_eq!(reader.next(&mut buf), Ok(None));
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let mut log = [0u8; 128];
+        let mut writer = Writer::new(&mut log[..]).unwrap();
+        writer.write_sent(&Reset::new()).unwrap();
+
+        let mut header = log;
+        header[0] = 0;
+        assert!(matches!(Reader::new(&header[..]), Err(Error::InvalidHeader)));
+
+        let mut reader = Reader::new(&log[..88]).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(reader.next(&mut buf), Err(Error::BufferTooSmall));
+        let mut reader = Reader::new(&log[..80]).unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(reader.next(&mut buf), Err(Error::Read(ReadExactError::UnexpectedEof)));
+    }
+}
@@ -1,18 +1,48 @@
 //! HCI transport layers [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface.html)
 
+use core::cell::Cell;
+
 use bt_hci_driver::{PacketKind, PacketToController, PacketToHost};
 pub use bt_hci_driver::{Transport, WithIndicator};
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_sync::mutex::Mutex;
 use embedded_io::{ErrorType, ReadExactError};
 
+use crate::cmd::CmdPacket;
 use crate::controller::blocking::TryError;
-use crate::ReadHciError;
+use crate::data::{AclPacket, IsoPacket, SyncPacket};
+use crate::event::EventPacket;
+use crate::{ReadHci, ReadHciError, WritePacketToHost};
+
+pub mod fault;
+#[cfg(any(test, feature = "std"))]
+pub mod socket;
+#[cfg(any(test, feature = "spi"))]
+pub mod spi;
+pub mod tee;
+pub mod usb;
+#[cfg(all(any(test, feature = "std"), target_os = "linux"))]
+pub mod user_channel;
+
+/// The direction of an HCI packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    /// Sent from the host to the controller.
+    Sent,
+    /// Received by the host from the controller.
+    Received,
+}
 
 /// HCI transport layer for a split serial bus using the UART transport layer protocol [📖](https://www.bluetooth.com/wp-content/uploads/Files/Specification/HTML/Core-54/out/en/host-controller-interface/uart-transport-layer.html)
+///
+/// By default, a read fails on an invalid packet indicator and the stream stays misaligned afterwards. Use
+/// [`with_resync`](SerialTransport::with_resync) to skip corrupted input instead.
 pub struct SerialTransport<M: RawMutex, R, W> {
     reader: Mutex<M, R>,
     writer: Mutex<M, W>,
+    resync: bool,
+    discarded: embassy_sync::blocking_mutex::Mutex<M, Cell<u32>>,
 }
 
 /// Error type for HCI transport layer communication errors.
@@ -60,12 +90,36 @@ impl<E: embedded_io::Error> From<ReadHciError<E>> for Error<E> {
     }
 }
 
-impl<M: RawMutex, R: embedded_io_async::Read, W: embedded_io_async::Write> SerialTransport<M, R, W> {
+impl<M: RawMutex, R, W> SerialTransport<M, R, W> {
     /// Create a new instance.
     pub fn new(reader: R, writer: W) -> Self {
         Self {
             reader: Mutex::new(reader),
             writer: Mutex::new(writer),
+            resync: false,
+            discarded: embassy_sync::blocking_mutex::Mutex::new(Cell::new(0)),
+        }
+    }
+
+    /// Resynchronise on the next plausible packet when reading corrupted input.
+    ///
+    /// Bytes are skipped until a valid packet indicator is followed by a header whose length fits the packet kind, so
+    /// that a glitch on the line costs the packets it hit rather than every following read.
+    pub fn with_resync(mut self) -> Self {
+        self.resync = true;
+        self
+    }
+
+    /// The number of bytes skipped so far while resynchronising.
+    pub fn discarded(&self) -> u32 {
+        self.discarded.lock(Cell::get)
+    }
+
+    fn discard(&self, n: usize) {
+        if n > 0 {
+            warn!("[hci] resynchronised after discarding {} bytes", n);
+            self.discarded
+                .lock(|d| d.set(d.get().saturating_add(n.try_into().unwrap_or(u32::MAX))));
         }
     }
 }
@@ -89,13 +143,79 @@ impl<
 {
     async fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, Self::Error> {
         let mut r = self.reader.lock().await;
-        let kind = PacketKind::read_async(&mut *r).await?;
-        P::read_hci_async(kind, &mut *r, rx).await.map_err(Error::Read)
+        if !self.resync {
+            let kind = PacketKind::read_async(&mut *r).await?;
+            return P::read_hci_async(kind, &mut *r, rx).await.map_err(Error::Read);
+        }
+
+        let mut window = Window::new();
+        let kind = loop {
+            match window.scan() {
+                Scan::Need(n) => {
+                    r.read_exact(&mut window.buf[window.len..n]).await?;
+                    window.len = n;
+                }
+                Scan::Skip => window.skip(),
+                Scan::Found(kind) => break kind,
+            }
+        };
+        self.discard(window.skipped);
+        let mut reader = Prefixed {
+            prefix: window.header(),
+            reader: &mut *r,
+        };
+        P::read_hci_async(kind, &mut reader, rx).await.map_err(Error::Read)
     }
 
     async fn write<P: PacketToController>(&self, tx: &P) -> Result<(), Self::Error> {
         let mut w = self.writer.lock().await;
-        tx.write_hci_async(&mut *w).await.map_err(|e| Error::Write(e))
+        WithIndicator::new(tx)
+            .write_hci_async(&mut *w)
+            .await
+            .map_err(|e| Error::Write(e))
+    }
+}
+
+impl<
+        M: RawMutex,
+        R: embedded_io_async::Read<Error = E>,
+        W: embedded_io_async::Write<Error = E>,
+        E: embedded_io::Error,
+    > SerialTransport<M, R, W>
+{
+    /// Write a complete HCI packet to the host, preceded by its packet indicator.
+    ///
+    /// This is for the controller side of the transport, e.g. a controller implementation or a test fixture
+    /// serving a host over a serial link.
+    pub async fn write_to_host<P: WritePacketToHost>(&self, tx: &P) -> Result<(), Error<E>> {
+        let mut w = self.writer.lock().await;
+        w.write_all(&[tx.kind() as u8]).await?;
+        tx.write_hci_async(&mut *w).await.map_err(Error::Write)
+    }
+
+    /// Reconfigure the reader and writer, e.g. to change the baud rate of the UART.
+    ///
+    /// Pending writes are flushed before calling `f`. This waits for a read in progress to complete, so the reader
+    /// should be idle, as with [`ExternalController::switch_baud_rate`](crate::controller::ExternalController::switch_baud_rate).
+    pub async fn reconfigure<T>(&self, f: impl FnOnce(&mut R, &mut W) -> T) -> Result<T, Error<E>> {
+        let mut r = self.reader.lock().await;
+        let mut w = self.writer.lock().await;
+        w.flush().await?;
+        Ok(f(&mut r, &mut w))
+    }
+}
+
+impl<M: RawMutex, R: embedded_io::Read<Error = E>, W: embedded_io::Write<Error = E>, E: embedded_io::Error>
+    SerialTransport<M, R, W>
+{
+    /// Write a complete HCI packet to the host, preceded by its packet indicator, without blocking on the writer lock.
+    ///
+    /// This is the blocking version of [`write_to_host`](SerialTransport::write_to_host).
+    pub fn try_write_to_host<P: WritePacketToHost>(&self, tx: &P) -> Result<(), TryError<Error<E>>> {
+        let mut w = self.writer.try_lock().map_err(|_| TryError::Busy)?;
+        w.write_all(&[tx.kind() as u8])
+            .and_then(|_| tx.write_hci(&mut *w))
+            .map_err(|e| TryError::Error(Error::Write(e)))
     }
 }
 
@@ -104,21 +224,253 @@ impl<M: RawMutex, R: embedded_io::Read<Error = E>, W: embedded_io::Write<Error =
 {
     fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, TryError<Self::Error>> {
         let mut r = self.reader.try_lock().map_err(|_| TryError::Busy)?;
-        let kind = PacketKind::read(&mut *r)?;
-        P::read_hci(kind, &mut *r, rx)
+        if !self.resync {
+            let kind = PacketKind::read(&mut *r)?;
+            return P::read_hci(kind, &mut *r, rx)
+                .map_err(Error::Read)
+                .map_err(TryError::Error);
+        }
+
+        let mut window = Window::new();
+        let kind = loop {
+            match window.scan() {
+                Scan::Need(n) => {
+                    r.read_exact(&mut window.buf[window.len..n])
+                        .map_err(|e| TryError::Error(e.into()))?;
+                    window.len = n;
+                }
+                Scan::Skip => window.skip(),
+                Scan::Found(kind) => break kind,
+            }
+        };
+        self.discard(window.skipped);
+        let mut reader = Prefixed {
+            prefix: window.header(),
+            reader: &mut *r,
+        };
+        P::read_hci(kind, &mut reader, rx)
             .map_err(Error::Read)
             .map_err(TryError::Error)
     }
 
     fn write<P: PacketToController>(&self, tx: &P) -> Result<(), TryError<Self::Error>> {
         let mut w = self.writer.try_lock().map_err(|_| TryError::Busy)?;
-        tx.write_hci(&mut *w)
+        WithIndicator::new(tx)
+            .write_hci(&mut *w)
             .map_err(|e| Error::Write(e))
             .map_err(TryError::Error)
     }
 }
 
+/// Progress of looking for the start of a packet in a corrupted stream.
+enum Scan {
+    /// The window must hold this many bytes to decide.
+    Need(usize),
+    /// The window does not start with a plausible packet.
+    Skip,
+    /// The window holds the indicator and header of a plausible packet.
+    Found(PacketKind),
+}
+
+/// The bytes read while looking for the start of a packet: a packet indicator followed by (part of) a header.
+struct Window {
+    buf: [u8; 5],
+    len: usize,
+    skipped: usize,
+}
+
+impl Window {
+    fn new() -> Self {
+        Self {
+            buf: [0; 5],
+            len: 0,
+            skipped: 0,
+        }
+    }
+
+    fn scan(&self) -> Scan {
+        let Some(&indicator) = self.buf[..self.len].first() else {
+            return Scan::Need(1);
+        };
+        let (kind, header_len, max_len) = match indicator {
+            1 => (PacketKind::Cmd, 3, CmdPacket::MAX_LEN),
+            2 => (PacketKind::AclData, 4, AclPacket::MAX_LEN),
+            3 => (PacketKind::SyncData, 3, SyncPacket::MAX_LEN),
+            4 => (PacketKind::Event, 2, EventPacket::MAX_LEN),
+            5 => (PacketKind::IsoData, 4, IsoPacket::MAX_LEN),
+            _ => return Scan::Skip,
+        };
+        if self.len < 1 + header_len {
+            return Scan::Need(1 + header_len);
+        }
+        let header = &self.buf[1..1 + header_len];
+        let payload_len = match kind {
+            PacketKind::Cmd | PacketKind::SyncData => usize::from(header[2]),
+            PacketKind::AclData => usize::from(u16::from_le_bytes([header[2], header[3]])),
+            PacketKind::IsoData => usize::from(u16::from_le_bytes([header[2], header[3]]) & 0x3fff),
+            PacketKind::Event => usize::from(header[1]),
+        };
+        if header_len + payload_len > max_len {
+            Scan::Skip
+        } else {
+            Scan::Found(kind)
+        }
+    }
+
+    /// Drop the first byte, keeping the rest as they may hold the start of the next packet.
+    fn skip(&mut self) {
+        self.buf.copy_within(1..self.len, 0);
+        self.len -= 1;
+        self.skipped += 1;
+    }
+
+    fn header(&self) -> &[u8] {
+        &self.buf[1..self.len]
+    }
+}
+
+/// A reader returning the bytes of `prefix` before those of `reader`.
+struct Prefixed<'a, R> {
+    prefix: &'a [u8],
+    reader: &'a mut R,
+}
+
+impl<R: embedded_io::ErrorType> embedded_io::ErrorType for Prefixed<'_, R> {
+    type Error = R::Error;
+}
+
+impl<R: embedded_io::Read> embedded_io::Read for Prefixed<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.prefix.is_empty() {
+            return self.reader.read(buf);
+        }
+        let n = self.prefix.read(buf).unwrap_or_else(|e| match e {});
+        Ok(n)
+    }
+}
+
+impl<R: embedded_io_async::Read> embedded_io_async::Read for Prefixed<'_, R> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.prefix.is_empty() {
+            return self.reader.read(buf).await;
+        }
+        let n = embedded_io::Read::read(&mut self.prefix, buf).unwrap_or_else(|e| match e {});
+        Ok(n)
+    }
+}
+
 pub mod blocking {
     //! Blocking transport trait.
     pub use bt_hci_driver::blocking::Transport;
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::collections::VecDeque;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::data::{AclBroadcastFlag, AclPacket, AclPacketBoundary};
+    use crate::param::ConnHandle;
+    use crate::ControllerToHostPacket;
+
+    type Serial = SerialTransport<NoopRawMutex, VecDeque<u8>, VecDeque<u8>>;
+
+    fn written(transport: Serial) -> std::vec::Vec<u8> {
+        transport.writer.into_inner().into()
+    }
+
+    #[futures_test::test]
+    async fn test_write_indicator() {
+        let transport = Serial::new(VecDeque::new(), VecDeque::new());
+        Transport::write(&transport, &Reset::new()).await.unwrap();
+        let acl = AclPacket::new(
+            ConnHandle::new(0x40),
+            AclPacketBoundary::FirstFlushable,
+            AclBroadcastFlag::PointToPoint,
+            &[0xaa],
+        );
+        Transport::write(&transport, &acl).await.unwrap();
+        assert_eq!(
+            written(transport).as_slice(),
+            [0x01, 0x03, 0x0c, 0x00, 0x02, 0x40, 0x20, 0x01, 0x00, 0xaa]
+        );
+    }
+
+    #[test]
+    fn test_blocking_write_indicator() {
+        let transport = Serial::new(VecDeque::new(), VecDeque::new());
+        blocking::Transport::write(&transport, &Reset::new()).unwrap();
+        assert_eq!(written(transport).as_slice(), [0x01, 0x03, 0x0c, 0x00]);
+    }
+
+    #[futures_test::test]
+    async fn test_read() {
+        let transport = Serial::new(
+            VecDeque::from([0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00]),
+            VecDeque::new(),
+        );
+        let mut rx = [0; 259];
+        let packet: ControllerToHostPacket = Transport::read(&transport, &mut rx).await.unwrap();
+        let ControllerToHostPacket::Event(event) = packet else {
+            panic!("expected an event");
+        };
+        assert_eq!(event.data, [0x01, 0x03, 0x0c, 0x00]);
+    }
+
+    #[futures_test::test]
+    async fn test_read_garbage_without_resync() {
+        let transport = Serial::new(VecDeque::from([0x00, 0x04, 0x10, 0x01, 0x00]), VecDeque::new());
+        let mut rx = [0; 259];
+        let res: Result<ControllerToHostPacket, _> = Transport::read(&transport, &mut rx).await;
+        assert!(matches!(res, Err(Error::Read(ReadHciError::InvalidValue))));
+    }
+
+    #[futures_test::test]
+    async fn test_resync() {
+        let mut input = VecDeque::new();
+        // Noise without a valid indicator
+        input.extend([0x00, 0xff, 0x07]);
+        input.extend([0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00]);
+        // An ACL indicator with an implausible length, hiding the start of the next packet in its header
+        input.extend([0x02, 0x40, 0x00, 0xff, 0x04, 0x10, 0x01, 0x00]);
+        let transport = Serial::new(input, VecDeque::new()).with_resync();
+        let mut rx = [0; 259];
+
+        let packet: ControllerToHostPacket = Transport::read(&transport, &mut rx).await.unwrap();
+        let ControllerToHostPacket::Event(event) = packet else {
+            panic!("expected an event");
+        };
+        assert_eq!(event.data, [0x01, 0x03, 0x0c, 0x00]);
+        assert_eq!(transport.discarded(), 3);
+
+        let packet: ControllerToHostPacket = Transport::read(&transport, &mut rx).await.unwrap();
+        let ControllerToHostPacket::Event(event) = packet else {
+            panic!("expected an event");
+        };
+        assert_eq!(event.data, [0x00]);
+        assert_eq!(transport.discarded(), 7);
+    }
+
+    #[test]
+    fn test_blocking_resync() {
+        let mut input = VecDeque::new();
+        input.extend([0x02, 0x40, 0x00, 0x01, 0x00, 0xaa]);
+        input.extend([0x06, 0x00, 0xee, 0x02, 0x40, 0x00, 0xff, 0xff]);
+        input.extend([0x02, 0x40, 0x00, 0x02, 0x00, 0xbb, 0xcc]);
+        let transport = Serial::new(input, VecDeque::new()).with_resync();
+        let mut rx = [0; 259];
+
+        for data in [&[0xaa][..], &[0xbb, 0xcc]] {
+            let packet: ControllerToHostPacket = blocking::Transport::read(&transport, &mut rx).unwrap();
+            let ControllerToHostPacket::Acl(acl) = packet else {
+                panic!("expected ACL data");
+            };
+            assert_eq!(acl.data(), data);
+        }
+        assert_eq!(transport.discarded(), 8);
+    }
+}
@@ -0,0 +1,566 @@
+//! A transport adapter injecting faults into the packets received from the controller.
+//!
+//! [`FaultTransport`] wraps any [`Transport`] or [`blocking::Transport`] and, with configurable probabilities, drops,
+//! truncates, corrupts, duplicates or reorders the packets read from it, and injects unsolicited events after commands.
+//! This allows testing how a host copes with a misbehaving controller without having such a controller.
+//!
+//! Faults are decided by a pseudo random number generator with a fixed seed, so a failing run can be reproduced by
+//! running it again with the same seed and the same sequence of packets.
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use bt_hci_driver::{PacketKind, PacketToController, PacketToHost};
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_io::ErrorType;
+use heapless::Deque;
+
+use super::{blocking, Transport};
+use crate::controller::blocking::TryError;
+use crate::ReadHciError;
+
+/// The probabilities of the faults injected by a [`FaultTransport`], in percent.
+///
+/// Every fault is decided independently for every packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Faults {
+    /// A received packet is discarded.
+    pub drop: u8,
+    /// A received packet is cut short, so that it is shorter than its header claims.
+    pub truncate: u8,
+    /// A single bit of a received packet is flipped.
+    pub bit_flip: u8,
+    /// A received Command Complete event is delivered twice.
+    pub duplicate_cmd_complete: u8,
+    /// A received packet is held back and delivered after the next packet.
+    pub delay: u8,
+    /// An unsolicited event is delivered between a written command and the next packet from the controller.
+    pub unsolicited_event: u8,
+}
+
+/// The number of faults injected by a [`FaultTransport`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    /// Number of dropped packets.
+    pub dropped: u32,
+    /// Number of truncated packets.
+    pub truncated: u32,
+    /// Number of packets with a flipped bit.
+    pub bit_flips: u32,
+    /// Number of duplicated Command Complete events.
+    pub duplicated: u32,
+    /// Number of delayed packets.
+    pub delayed: u32,
+    /// Number of unsolicited events.
+    pub unsolicited: u32,
+}
+
+/// Error type for a [`FaultTransport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Error from the wrapped transport.
+    Transport(E),
+    /// The (possibly corrupted) packet could not be read into the requested packet type.
+    Read(ReadHciError<Infallible>),
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl<E: core::fmt::Debug> core::error::Error for Error<E> {}
+
+impl<E: embedded_io::Error> embedded_io::Error for Error<E> {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            Self::Transport(e) => e.kind(),
+            Self::Read(e) => e.kind(),
+        }
+    }
+}
+
+impl<E> From<ReadHciError<Infallible>> for Error<E> {
+    fn from(e: ReadHciError<Infallible>) -> Self {
+        Self::Read(e)
+    }
+}
+
+/// The event injected by [`Faults::unsolicited_event`] by default: a Vendor event without parameters.
+const VENDOR_EVENT: [u8; 2] = [0xff, 0x00];
+
+/// xorshift32 pseudo random number generator.
+struct Rng(u32);
+
+impl Rng {
+    fn new(seed: u32) -> Self {
+        // xorshift gets stuck at zero
+        Self(if seed == 0 { 0x9e37_79b9 } else { seed })
+    }
+
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn chance(&mut self, percent: u8) -> bool {
+        percent > 0 && self.next() % 100 < u32::from(percent)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() as usize) % n
+    }
+}
+
+/// A complete packet without its packet indicator.
+#[derive(Clone)]
+struct Packet<const N: usize> {
+    kind: PacketKind,
+    len: usize,
+    data: [u8; N],
+}
+
+impl<const N: usize> Packet<N> {
+    fn new(kind: PacketKind, data: &[u8]) -> Self {
+        let len = data.len().min(N);
+        let mut pkt = Self {
+            kind,
+            len,
+            data: [0; N],
+        };
+        pkt.data[..len].copy_from_slice(&data[..len]);
+        pkt
+    }
+
+    fn parse<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, ReadHciError<Infallible>> {
+        P::read_hci(self.kind, &mut &self.data[..self.len], rx)
+    }
+}
+
+struct State<const N: usize> {
+    rng: Rng,
+    stats: Stats,
+    pending: Deque<Packet<N>, 4>,
+    held: Option<Packet<N>>,
+    unsolicited: bool,
+}
+
+/// A transport adapter injecting [`Faults`] into the packets read from the wrapped transport.
+///
+/// Packets are read completely into an `N` byte buffer before faults are applied, so `N` must be large enough for the
+/// largest packet the controller sends.
+pub struct FaultTransport<'e, M: RawMutex, T, const N: usize = 259> {
+    inner: T,
+    faults: Faults,
+    event: &'e [u8],
+    state: embassy_sync::blocking_mutex::Mutex<M, RefCell<State<N>>>,
+    rx: Mutex<M, [u8; N]>,
+}
+
+impl<M: RawMutex, T, const N: usize> FaultTransport<'static, M, T, N> {
+    /// Create a new instance wrapping the `inner` transport, with the random number generator seeded by `seed`.
+    pub fn new(inner: T, faults: Faults, seed: u32) -> Self {
+        Self {
+            inner,
+            faults,
+            event: &VENDOR_EVENT,
+            state: embassy_sync::blocking_mutex::Mutex::new(RefCell::new(State {
+                rng: Rng::new(seed),
+                stats: Stats::default(),
+                pending: Deque::new(),
+                held: None,
+                unsolicited: false,
+            })),
+            rx: Mutex::new([0; N]),
+        }
+    }
+}
+
+impl<'e, M: RawMutex, T, const N: usize> FaultTransport<'e, M, T, N> {
+    /// Use `event` as the unsolicited event, instead of an empty Vendor event.
+    ///
+    /// `event` is a complete event packet without its packet indicator.
+    pub fn with_unsolicited_event<'f>(self, event: &'f [u8]) -> FaultTransport<'f, M, T, N> {
+        FaultTransport {
+            inner: self.inner,
+            faults: self.faults,
+            event,
+            state: self.state,
+            rx: self.rx,
+        }
+    }
+
+    /// The wrapped transport.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// The number of faults injected so far.
+    pub fn stats(&self) -> Stats {
+        self.state.lock(|state| state.borrow().stats)
+    }
+
+    /// Take the next packet injected or held back by an earlier fault.
+    fn pending(&self) -> Option<Packet<N>> {
+        self.state.lock(|state| state.borrow_mut().pending.pop_front())
+    }
+
+    /// Apply faults to a packet received from the wrapped transport, returning the packet to deliver now, if any.
+    fn receive(&self, kind: PacketKind, data: &[u8]) -> Option<Packet<N>> {
+        self.state.lock(|state| {
+            let state = &mut *state.borrow_mut();
+            let faults = &self.faults;
+            let mut pkt = Packet::new(kind, data);
+
+            if state.rng.chance(faults.drop) {
+                state.stats.dropped += 1;
+                return None;
+            }
+            let cmd_complete = kind == PacketKind::Event && pkt.data[..pkt.len].first() == Some(&0x0e);
+            if cmd_complete && state.rng.chance(faults.duplicate_cmd_complete) {
+                state.stats.duplicated += 1;
+                let _ = state.pending.push_back(pkt.clone());
+            }
+            if pkt.len > 0 && state.rng.chance(faults.truncate) {
+                state.stats.truncated += 1;
+                pkt.len = state.rng.below(pkt.len);
+            }
+            if pkt.len > 0 && state.rng.chance(faults.bit_flip) {
+                state.stats.bit_flips += 1;
+                let bit = state.rng.below(pkt.len * 8);
+                pkt.data[bit / 8] ^= 1 << (bit % 8);
+            }
+
+            // A packet held back by an earlier fault follows this one
+            if let Some(held) = state.held.take() {
+                let _ = state.pending.push_front(held);
+            } else if state.rng.chance(faults.delay) {
+                state.stats.delayed += 1;
+                state.held = Some(pkt);
+                return None;
+            }
+            if core::mem::take(&mut state.unsolicited) {
+                state.stats.unsolicited += 1;
+                let _ = state.pending.push_front(pkt);
+                return Some(Packet::new(PacketKind::Event, self.event));
+            }
+            Some(pkt)
+        })
+    }
+
+    /// Record a packet written to the wrapped transport.
+    fn sent(&self, kind: PacketKind) {
+        if kind == PacketKind::Cmd {
+            self.state.lock(|state| {
+                let state = &mut *state.borrow_mut();
+                if state.rng.chance(self.faults.unsolicited_event) {
+                    state.unsolicited = true;
+                }
+            });
+        }
+    }
+}
+
+impl<M: RawMutex, T: ErrorType, const N: usize> ErrorType for FaultTransport<'_, M, T, N> {
+    type Error = Error<T::Error>;
+}
+
+impl<M: RawMutex, T: Transport, const N: usize> Transport for FaultTransport<'_, M, T, N> {
+    async fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, Self::Error> {
+        loop {
+            if let Some(pkt) = self.pending() {
+                return pkt.parse(rx).map_err(Error::Read);
+            }
+            let mut buf = self.rx.lock().await;
+            let raw: RawPacket = self.inner.read(&mut buf[..]).await.map_err(Error::Transport)?;
+            if let Some(pkt) = self.receive(raw.kind, raw.data) {
+                return pkt.parse(rx).map_err(Error::Read);
+            }
+        }
+    }
+
+    async fn write<P: PacketToController>(&self, tx: &P) -> Result<(), Self::Error> {
+        self.inner.write(tx).await.map_err(Error::Transport)?;
+        self.sent(P::KIND);
+        Ok(())
+    }
+}
+
+impl<M: RawMutex, T: blocking::Transport, const N: usize> blocking::Transport for FaultTransport<'_, M, T, N> {
+    fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, TryError<Self::Error>> {
+        loop {
+            if let Some(pkt) = self.pending() {
+                return pkt.parse(rx).map_err(|e| TryError::Error(Error::Read(e)));
+            }
+            let mut buf = self.rx.try_lock().map_err(|_| TryError::Busy)?;
+            let raw: RawPacket = self.inner.read(&mut buf[..]).map_err(try_error)?;
+            if let Some(pkt) = self.receive(raw.kind, raw.data) {
+                return pkt.parse(rx).map_err(|e| TryError::Error(Error::Read(e)));
+            }
+        }
+    }
+
+    fn write<P: PacketToController>(&self, tx: &P) -> Result<(), TryError<Self::Error>> {
+        self.inner.write(tx).map_err(try_error)?;
+        self.sent(P::KIND);
+        Ok(())
+    }
+}
+
+fn try_error<E>(e: TryError<E>) -> TryError<Error<E>> {
+    match e {
+        TryError::Error(e) => TryError::Error(Error::Transport(e)),
+        TryError::Busy => TryError::Busy,
+    }
+}
+
+/// A complete packet, including its header, as read from the wrapped transport.
+struct RawPacket<'a> {
+    kind: PacketKind,
+    data: &'a [u8],
+}
+
+impl RawPacket<'_> {
+    fn header_len(kind: PacketKind) -> usize {
+        match kind {
+            PacketKind::Cmd | PacketKind::SyncData => 3,
+            PacketKind::AclData | PacketKind::IsoData => 4,
+            PacketKind::Event => 2,
+        }
+    }
+
+    fn payload_len(kind: PacketKind, header: &[u8]) -> usize {
+        match kind {
+            PacketKind::Cmd | PacketKind::SyncData => usize::from(header[2]),
+            PacketKind::AclData => usize::from(u16::from_le_bytes([header[2], header[3]])),
+            PacketKind::IsoData => usize::from(u16::from_le_bytes([header[2], header[3]]) & 0x3fff),
+            PacketKind::Event => usize::from(header[1]),
+        }
+    }
+}
+
+impl<'d> PacketToHost<'d> for RawPacket<'d> {
+    fn read_hci<R: embedded_io::Read>(
+        kind: PacketKind,
+        reader: &mut R,
+        buf: &'d mut [u8],
+    ) -> Result<Self, ReadHciError<R::Error>> {
+        let header_len = Self::header_len(kind);
+        if buf.len() < header_len {
+            return Err(ReadHciError::BufferTooSmall);
+        }
+        reader.read_exact(&mut buf[..header_len])?;
+        let len = header_len + Self::payload_len(kind, &buf[..header_len]);
+        if buf.len() < len {
+            return Err(ReadHciError::BufferTooSmall);
+        }
+        reader.read_exact(&mut buf[header_len..len])?;
+        let buf: &'d [u8] = buf;
+        Ok(Self {
+            kind,
+            data: &buf[..len],
+        })
+    }
+
+    async fn read_hci_async<R: embedded_io_async::Read>(
+        kind: PacketKind,
+        reader: &mut R,
+        buf: &'d mut [u8],
+    ) -> Result<Self, ReadHciError<R::Error>> {
+        let header_len = Self::header_len(kind);
+        if buf.len() < header_len {
+            return Err(ReadHciError::BufferTooSmall);
+        }
+        reader.read_exact(&mut buf[..header_len]).await?;
+        let len = header_len + Self::payload_len(kind, &buf[..header_len]);
+        if buf.len() < len {
+            return Err(ReadHciError::BufferTooSmall);
+        }
+        reader.read_exact(&mut buf[header_len..len]).await?;
+        let buf: &'d [u8] = buf;
+        Ok(Self {
+            kind,
+            data: &buf[..len],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use futures_test::test;
+
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::cmd::SyncCmd;
+    use crate::controller::{Controller, ExternalController};
+    use crate::mock::{MockTransport, Step};
+    use crate::ControllerToHostPacket;
+
+    const RESET: [u8; 4] = [0x01, 0x03, 0x0c, 0x00];
+    const RESET_COMPLETE: [u8; 7] = [0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+    const HW_ERROR: [u8; 4] = [0x04, 0x10, 0x01, 0x00];
+    const ACL: [u8; 8] = [0x02, 0x40, 0x00, 0x03, 0x00, 1, 2, 3];
+
+    type Faulty<'a> = FaultTransport<'static, NoopRawMutex, MockTransport<'a, NoopRawMutex>>;
+
+    async fn read_event<T: Transport>(transport: &T, rx: &mut [u8]) -> std::vec::Vec<u8>
+    where
+        T::Error: core::fmt::Debug,
+    {
+        let ControllerToHostPacket::Event(pkt) = Transport::read(transport, rx).await.unwrap() else {
+            panic!("expected an event");
+        };
+        let mut out = std::vec![pkt.kind.0, pkt.data.len() as u8];
+        out.extend_from_slice(pkt.data);
+        out
+    }
+
+    #[test]
+    async fn test_no_faults() {
+        let script = [Step::Write(&RESET), Step::Read(&RESET_COMPLETE), Step::Read(&ACL)];
+        let transport: Faulty = FaultTransport::new(MockTransport::new(&script), Faults::default(), 1);
+        Transport::write(&transport, &Reset::new()).await.unwrap();
+        let mut rx = [0; 259];
+        assert_eq!(read_event(&transport, &mut rx).await.as_slice(), &RESET_COMPLETE[1..]);
+        let pkt: ControllerToHostPacket = blocking::Transport::read(&transport, &mut rx).unwrap();
+        assert!(matches!(pkt, ControllerToHostPacket::Acl(_)));
+        transport.inner().assert_done();
+        assert_eq!(transport.stats(), Stats::default());
+    }
+
+    #[test]
+    async fn test_drop() {
+        let script = [Step::Read(&RESET_COMPLETE), Step::Read(&HW_ERROR)];
+        let faults = Faults {
+            drop: 100,
+            ..Default::default()
+        };
+        let transport: Faulty = FaultTransport::new(MockTransport::new(&script), faults, 1);
+        let mut rx = [0; 259];
+        // Everything is dropped, so the read runs into the end of the script
+        let res: Result<ControllerToHostPacket, _> = Transport::read(&transport, &mut rx).await;
+        assert!(matches!(res, Err(Error::Transport(crate::mock::Error::Exhausted))));
+        transport.inner().assert_done();
+        assert_eq!(transport.stats().dropped, 2);
+    }
+
+    #[test]
+    async fn test_truncate_and_bit_flip() {
+        let script = [Step::Read(&RESET_COMPLETE), Step::Read(&HW_ERROR)];
+        let faults = Faults {
+            truncate: 100,
+            ..Default::default()
+        };
+        let transport: Faulty = FaultTransport::new(MockTransport::new(&script), faults, 1);
+        let mut rx = [0; 259];
+        let res: Result<ControllerToHostPacket, _> = Transport::read(&transport, &mut rx).await;
+        assert!(matches!(res, Err(Error::Read(_))));
+        assert_eq!(transport.stats().truncated, 1);
+
+        let faults = Faults {
+            bit_flip: 100,
+            ..Default::default()
+        };
+        let script = [Step::Read(&HW_ERROR)];
+        let transport: Faulty = FaultTransport::new(MockTransport::new(&script), faults, 1);
+        let event = read_event(&transport, &mut rx).await;
+        let diff: u32 = event
+            .iter()
+            .zip(&HW_ERROR[1..])
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert_eq!(diff, 1);
+    }
+
+    #[test]
+    async fn test_duplicate_and_delay() {
+        let script = [Step::Read(&RESET_COMPLETE), Step::Read(&HW_ERROR)];
+        let faults = Faults {
+            duplicate_cmd_complete: 100,
+            ..Default::default()
+        };
+        let transport: Faulty = FaultTransport::new(MockTransport::new(&script), faults, 1);
+        let mut rx = [0; 259];
+        assert_eq!(read_event(&transport, &mut rx).await.as_slice(), &RESET_COMPLETE[1..]);
+        assert_eq!(read_event(&transport, &mut rx).await.as_slice(), &RESET_COMPLETE[1..]);
+        assert_eq!(read_event(&transport, &mut rx).await.as_slice(), &HW_ERROR[1..]);
+        assert_eq!(transport.stats().duplicated, 1);
+
+        let faults = Faults {
+            delay: 100,
+            ..Default::default()
+        };
+        let transport: Faulty = FaultTransport::new(MockTransport::new(&script), faults, 1);
+        assert_eq!(read_event(&transport, &mut rx).await.as_slice(), &HW_ERROR[1..]);
+        assert_eq!(read_event(&transport, &mut rx).await.as_slice(), &RESET_COMPLETE[1..]);
+        assert_eq!(transport.stats().delayed, 1);
+    }
+
+    #[test]
+    async fn test_unsolicited_event() {
+        let script = [Step::Write(&RESET), Step::Read(&RESET_COMPLETE)];
+        let faults = Faults {
+            unsolicited_event: 100,
+            ..Default::default()
+        };
+        let transport = FaultTransport::<NoopRawMutex, _>::new(MockTransport::<NoopRawMutex>::new(&script), faults, 1)
+            .with_unsolicited_event(&HW_ERROR[1..]);
+        Transport::write(&transport, &Reset::new()).await.unwrap();
+        let mut rx = [0; 259];
+        assert_eq!(read_event(&transport, &mut rx).await.as_slice(), &HW_ERROR[1..]);
+        assert_eq!(read_event(&transport, &mut rx).await.as_slice(), &RESET_COMPLETE[1..]);
+        transport.inner().assert_done();
+        assert_eq!(transport.stats().unsolicited, 1);
+    }
+
+    #[test]
+    async fn test_external_controller() {
+        let script = [Step::Write(&RESET), Step::Read(&RESET_COMPLETE)];
+        let faults = Faults {
+            duplicate_cmd_complete: 100,
+            ..Default::default()
+        };
+        let c: ExternalController<Faulty, 2> =
+            ExternalController::new(FaultTransport::new(MockTransport::new(&script), faults, 1));
+        let mut rx = [0; 259];
+        // The duplicated Command Complete event matches no pending command and is skipped
+        let (res, rest) = embassy_futures::join::join(Reset::new().exec(&c), Controller::read(&c, &mut rx)).await;
+        res.unwrap();
+        assert!(matches!(rest, Err(Error::Transport(crate::mock::Error::Exhausted))));
+    }
+
+    #[test]
+    async fn test_seed_is_reproducible() {
+        let script = [Step::Read(&HW_ERROR); 32];
+        let faults = Faults {
+            drop: 50,
+            ..Default::default()
+        };
+        let run = |seed| async move {
+            let transport: Faulty = FaultTransport::new(MockTransport::new(&script), faults, seed);
+            let mut rx = [0; 259];
+            let mut received = 0;
+            while Transport::read::<ControllerToHostPacket>(&transport, &mut rx)
+                .await
+                .is_ok()
+            {
+                received += 1;
+            }
+            (received, transport.stats())
+        };
+        let (received, stats) = run(42).await;
+        assert_eq!(received + stats.dropped, 32);
+        assert!(stats.dropped > 0 && received > 0);
+        assert_eq!(run(42).await, (received, stats));
+    }
+}
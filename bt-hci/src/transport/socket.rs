@@ -0,0 +1,330 @@
+//! HCI transport over TCP and Unix domain sockets.
+//!
+//! Controller emulators such as [Rootcanal](https://github.com/google/rootcanal) and the Zephyr `native_sim` board
+//! expose their HCI interface as an H4 byte stream on a socket. The transports in this module connect a
+//! [`SerialTransport`] to such a socket, so that a host stack can be run against an emulated controller on a
+//! development machine or in CI.
+//!
+//! [`TcpTransport`] and [`UnixTransport`] implement the blocking [`Transport`](super::blocking::Transport) trait on
+//! top of `std::net` and `std::os::unix::net`. With the `tokio` feature, [`AsyncTcpTransport`] and
+//! [`AsyncUnixTransport`] implement the async [`Transport`](super::Transport) trait on top of `tokio::net`.
+//!
+//! ```no_run
+//! use bt_hci::transport::socket::TcpTransport;
+//! use bt_hci::transport::blocking::Transport;
+//! use bt_hci::cmd::controller_baseband::Reset;
+//! use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+//!
+//! // Rootcanal listens for hosts on port 6402 by default
+//! let transport = TcpTransport::<NoopRawMutex>::connect("127.0.0.1:6402").unwrap();
+//! transport.write(&Reset::new()).unwrap();
+//! ```
+
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embedded_io::ErrorType;
+
+use super::SerialTransport;
+
+/// Adapter implementing the `embedded-io` traits for a `std::io` reader or writer.
+pub struct FromStd<T>(T);
+
+impl<T> FromStd<T> {
+    /// Wrap a `std::io` reader or writer.
+    pub fn new(inner: T) -> Self {
+        Self(inner)
+    }
+
+    /// Get a reference to the wrapped reader or writer.
+    pub fn inner(&self) -> &T {
+        &self.0
+    }
+
+    /// Unwrap the reader or writer.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> ErrorType for FromStd<T> {
+    type Error = io::Error;
+}
+
+impl<T: io::Read> embedded_io::Read for FromStd<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        loop {
+            match self.0.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<T: io::Write> embedded_io::Write for FromStd<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        loop {
+            match self.0.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => return res,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.flush()
+    }
+}
+
+/// Blocking H4 transport over a TCP connection.
+pub type TcpTransport<M> = SerialTransport<M, FromStd<TcpStream>, FromStd<TcpStream>>;
+
+impl<M: RawMutex> SerialTransport<M, FromStd<TcpStream>, FromStd<TcpStream>> {
+    /// Connect to a controller listening on `addr`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// Create a transport from a connected stream.
+    ///
+    /// Nagle's algorithm is disabled on the stream, as HCI traffic consists of small packets which are waited on.
+    pub fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        Ok(Self::new(FromStd(reader), FromStd(stream)))
+    }
+}
+
+/// Blocking H4 transport over a Unix domain socket.
+#[cfg(unix)]
+pub type UnixTransport<M> = SerialTransport<M, FromStd<UnixStream>, FromStd<UnixStream>>;
+
+#[cfg(unix)]
+impl<M: RawMutex> SerialTransport<M, FromStd<UnixStream>, FromStd<UnixStream>> {
+    /// Connect to a controller listening on the socket at `path`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_stream(UnixStream::connect(path)?)
+    }
+
+    /// Create a transport from a connected stream.
+    pub fn from_stream(stream: UnixStream) -> io::Result<Self> {
+        let reader = stream.try_clone()?;
+        Ok(Self::new(FromStd(reader), FromStd(stream)))
+    }
+}
+
+#[cfg(any(test, feature = "tokio"))]
+pub use asynch::*;
+
+#[cfg(any(test, feature = "tokio"))]
+mod asynch {
+    use std::io;
+    #[cfg(unix)]
+    use std::path::Path;
+
+    use embassy_sync::blocking_mutex::raw::RawMutex;
+    use embedded_io::ErrorType;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::{tcp, TcpStream, ToSocketAddrs};
+    #[cfg(unix)]
+    use tokio::net::{unix, UnixStream};
+
+    use super::SerialTransport;
+
+    /// Adapter implementing the `embedded-io-async` traits for a `tokio` reader or writer.
+    pub struct FromTokio<T>(T);
+
+    impl<T> FromTokio<T> {
+        /// Wrap a `tokio` reader or writer.
+        pub fn new(inner: T) -> Self {
+            Self(inner)
+        }
+
+        /// Get a reference to the wrapped reader or writer.
+        pub fn inner(&self) -> &T {
+            &self.0
+        }
+
+        /// Unwrap the reader or writer.
+        pub fn into_inner(self) -> T {
+            self.0
+        }
+    }
+
+    impl<T> ErrorType for FromTokio<T> {
+        type Error = io::Error;
+    }
+
+    impl<T: AsyncRead + Unpin> embedded_io_async::Read for FromTokio<T> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.0.read(buf).await
+        }
+    }
+
+    impl<T: AsyncWrite + Unpin> embedded_io_async::Write for FromTokio<T> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.0.write(buf).await
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            self.0.flush().await
+        }
+    }
+
+    /// Async H4 transport over a TCP connection.
+    pub type AsyncTcpTransport<M> = SerialTransport<M, FromTokio<tcp::OwnedReadHalf>, FromTokio<tcp::OwnedWriteHalf>>;
+
+    impl<M: RawMutex> SerialTransport<M, FromTokio<tcp::OwnedReadHalf>, FromTokio<tcp::OwnedWriteHalf>> {
+        /// Connect to a controller listening on `addr`.
+        pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+            Self::from_stream(TcpStream::connect(addr).await?)
+        }
+
+        /// Create a transport from a connected stream.
+        ///
+        /// Nagle's algorithm is disabled on the stream, as HCI traffic consists of small packets which are waited on.
+        pub fn from_stream(stream: TcpStream) -> io::Result<Self> {
+            stream.set_nodelay(true)?;
+            let (reader, writer) = stream.into_split();
+            Ok(Self::new(FromTokio(reader), FromTokio(writer)))
+        }
+    }
+
+    /// Async H4 transport over a Unix domain socket.
+    #[cfg(unix)]
+    pub type AsyncUnixTransport<M> =
+        SerialTransport<M, FromTokio<unix::OwnedReadHalf>, FromTokio<unix::OwnedWriteHalf>>;
+
+    #[cfg(unix)]
+    impl<M: RawMutex> SerialTransport<M, FromTokio<unix::OwnedReadHalf>, FromTokio<unix::OwnedWriteHalf>> {
+        /// Connect to a controller listening on the socket at `path`.
+        pub async fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+            Self::from_stream(UnixStream::connect(path).await?)
+        }
+
+        /// Create a transport from a connected stream.
+        pub fn from_stream(stream: UnixStream) -> io::Result<Self> {
+            let (reader, writer) = stream.into_split();
+            Ok(Self::new(FromTokio(reader), FromTokio(writer)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::cmd::Cmd;
+    use crate::event::Event;
+    use crate::transport::Transport;
+    use crate::ControllerToHostPacket;
+
+    const RESET: [u8; 4] = [0x01, 0x03, 0x0c, 0x00];
+    const RESET_COMPLETE: [u8; 7] = [0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+
+    /// Stand-in controller which answers a Reset command with its Command Complete event.
+    fn serve_reset(mut stream: impl Read + Write) {
+        let mut cmd = [0; 4];
+        stream.read_exact(&mut cmd).unwrap();
+        assert_eq!(cmd, RESET);
+        stream.write_all(&RESET_COMPLETE).unwrap();
+    }
+
+    fn assert_reset_complete(packet: ControllerToHostPacket<'_>) {
+        let ControllerToHostPacket::Event(event) = packet else {
+            panic!("expected an event");
+        };
+        let Ok(Event::CommandComplete(complete)) = Event::try_from(event) else {
+            panic!("expected Command Complete");
+        };
+        assert_eq!(complete.cmd_opcode, Reset::OPCODE);
+    }
+
+    fn check_reset<T: crate::transport::blocking::Transport>(transport: &T) {
+        transport.write(&Reset::new()).unwrap();
+        let mut rx = [0; 259];
+        assert_reset_complete(transport.read(&mut rx).unwrap());
+    }
+
+    #[test]
+    fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let controller = thread::spawn(move || serve_reset(listener.accept().unwrap().0));
+
+        let transport = TcpTransport::<NoopRawMutex>::connect(addr).unwrap();
+        check_reset(&transport);
+        controller.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix() {
+        use std::os::unix::net::UnixListener;
+
+        let (controller, host) = UnixStream::pair().unwrap();
+        let controller = thread::spawn(move || serve_reset(controller));
+        let transport = UnixTransport::<NoopRawMutex>::from_stream(host).unwrap();
+        check_reset(&transport);
+        controller.join().unwrap();
+
+        let dir = std::env::temp_dir().join(std::format!("bt-hci-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hci.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let controller = thread::spawn(move || serve_reset(listener.accept().unwrap().0));
+        let transport = UnixTransport::<NoopRawMutex>::connect(&path).unwrap();
+        check_reset(&transport);
+        controller.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    async fn serve_reset_async(mut stream: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin) {
+        let mut cmd = [0; 4];
+        stream.read_exact(&mut cmd).await.unwrap();
+        assert_eq!(cmd, RESET);
+        stream.write_all(&RESET_COMPLETE).await.unwrap();
+    }
+
+    async fn check_reset_async<T: Transport>(transport: &T) {
+        Transport::write(transport, &Reset::new()).await.unwrap();
+        let mut rx = [0; 259];
+        assert_reset_complete(Transport::read(transport, &mut rx).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_async_tcp() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let controller = tokio::spawn(async move { serve_reset_async(listener.accept().await.unwrap().0).await });
+
+        let transport = AsyncTcpTransport::<NoopRawMutex>::connect(addr).await.unwrap();
+        check_reset_async(&transport).await;
+        controller.await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_async_unix() {
+        let (controller, host) = tokio::net::UnixStream::pair().unwrap();
+        let controller = tokio::spawn(serve_reset_async(controller));
+
+        let transport = AsyncUnixTransport::<NoopRawMutex>::from_stream(host).unwrap();
+        check_reset_async(&transport).await;
+        controller.await.unwrap();
+    }
+}
@@ -0,0 +1,740 @@
+//! HCI transport over SPI for controllers using the BlueNRG-MS style header handshake.
+//!
+//! ST BlueNRG-MS network coprocessors and the Zephyr `hci_spi` controller sample carry H4 packets over SPI. The host
+//! starts every exchange with a 5 byte header, `0x0a` to write or `0x0b` to read, to which the controller answers with
+//! its state and buffer sizes. The controller raises an IRQ line while it has data for the host.
+//!
+//! The two flavors of the protocol differ in how the header relates to the data, see [`Protocol`]. The BlueNRG-LP
+//! family uses a different handshake and is not supported.
+//!
+//! [`SpiTransport`] implements both the async [`Transport`] trait, over [`embedded_hal_async`] devices, and the
+//! blocking [`Transport`](super::blocking::Transport) trait, over [`embedded_hal`] devices. While the controller is not
+//! ready the transport waits on the caller-supplied delay before asking again.
+
+use core::convert::Infallible;
+
+use bt_hci_driver::{PacketKind, PacketToController, PacketToHost};
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use embedded_hal::digital::InputPin;
+use embedded_hal::spi::Operation;
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiDevice;
+use embedded_io::{ErrorType, ReadExactError};
+
+use super::{blocking, Transport, WithIndicator};
+use crate::controller::blocking::TryError;
+use crate::ReadHciError;
+
+const HEADER_LEN: usize = 5;
+const WRITE_HEADER: [u8; HEADER_LEN] = [0x0a, 0x00, 0x00, 0x00, 0x00];
+const READ_HEADER: [u8; HEADER_LEN] = [0x0b, 0x00, 0x00, 0x00, 0x00];
+const READY: u8 = 0x02;
+/// Time to wait before asking a controller that is not ready again, or polling the IRQ line again.
+const RETRY_DELAY_US: u32 = 100;
+
+/// The flavor of the SPI handshake spoken by the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Protocol {
+    /// ST BlueNRG-MS.
+    ///
+    /// The data immediately follows the header within one chip select period. The controller reports the free space
+    /// of its write buffer and the number of bytes it has for the host, and packets are written and read in chunks
+    /// of at most that size.
+    BlueNrgMs,
+    /// Zephyr `hci_spi`.
+    ///
+    /// The header is exchanged on its own, the data follows in a separate chip select period. Every packet is written
+    /// and read as a whole.
+    Zephyr,
+}
+
+/// The state reported by the controller in response to a header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Status {
+    /// Number of bytes the controller accepts, or `0` if it is not ready.
+    ///
+    /// With [`Protocol::Zephyr`] any non-zero value only signals that the controller is ready for a packet.
+    pub write_len: u16,
+    /// Number of bytes the controller has for the host, or `0` if it is not ready.
+    pub read_len: u16,
+}
+
+impl Status {
+    fn parse(protocol: Protocol, header: &[u8; HEADER_LEN]) -> Self {
+        match protocol {
+            Protocol::BlueNrgMs if header[0] == READY => Self {
+                write_len: u16::from(header[1]),
+                read_len: u16::from_le_bytes([header[3], header[4]]),
+            },
+            Protocol::BlueNrgMs => Self {
+                write_len: 0,
+                read_len: 0,
+            },
+            Protocol::Zephyr => Self {
+                write_len: match header[1] {
+                    0x00 | 0xff => 0,
+                    n => u16::from(n),
+                },
+                read_len: match (header[0], header[3]) {
+                    (READY, n) if n != 0xff => u16::from(n),
+                    _ => 0,
+                },
+            },
+        }
+    }
+}
+
+/// Error type for a [`SpiTransport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<S, P> {
+    /// Error from the SPI device.
+    Spi(S),
+    /// Error waiting for the IRQ pin.
+    Irq(P),
+    /// The received data is not a valid packet of the requested type.
+    Read(ReadHciError<Infallible>),
+    /// The packet to write is larger than the transmit buffer.
+    PacketTooLarge,
+}
+
+impl<S: core::fmt::Debug, P: core::fmt::Debug> core::fmt::Display for Error<S, P> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl<S: core::fmt::Debug, P: core::fmt::Debug> core::error::Error for Error<S, P> {}
+
+impl<S: core::fmt::Debug, P: core::fmt::Debug> embedded_io::Error for Error<S, P> {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            Self::Spi(_) | Self::Irq(_) => embedded_io::ErrorKind::Other,
+            Self::Read(e) => e.kind(),
+            Self::PacketTooLarge => embedded_io::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl<S: core::fmt::Debug, P: core::fmt::Debug> From<ReadHciError<Error<S, P>>> for Error<S, P> {
+    fn from(e: ReadHciError<Error<S, P>>) -> Self {
+        match e {
+            ReadHciError::BufferTooSmall => Self::Read(ReadHciError::BufferTooSmall),
+            ReadHciError::InvalidValue => Self::Read(ReadHciError::InvalidValue),
+            ReadHciError::Read(ReadExactError::UnexpectedEof) => {
+                Self::Read(ReadHciError::Read(ReadExactError::UnexpectedEof))
+            }
+            ReadHciError::Read(ReadExactError::Other(e)) => e,
+        }
+    }
+}
+
+impl<S, P> From<ReadHciError<Infallible>> for Error<S, P> {
+    fn from(e: ReadHciError<Infallible>) -> Self {
+        Self::Read(e)
+    }
+}
+
+struct Rx<IRQ, D, const N: usize> {
+    irq: IRQ,
+    delay: D,
+    buf: [u8; N],
+    pos: usize,
+    len: usize,
+}
+
+struct Tx<D, const N: usize> {
+    delay: D,
+    buf: [u8; N],
+}
+
+/// HCI transport layer for a controller attached over SPI.
+///
+/// Data received from the controller is buffered in an `N` byte receive buffer, and packets are serialized into an
+/// `N` byte transmit buffer before they are written, so `N` must be large enough for the largest packet sent to the
+/// controller. With [`Protocol::Zephyr`] it must also hold the largest chunk the controller sends, i.e. 255 bytes.
+///
+/// The `D` delay paces the retries while the controller is not ready. The blocking implementation also uses it to poll
+/// the IRQ line.
+pub struct SpiTransport<M: RawMutex, SPI, IRQ, D, const N: usize = 259> {
+    protocol: Protocol,
+    spi: Mutex<M, SPI>,
+    rx: Mutex<M, Rx<IRQ, D, N>>,
+    tx: Mutex<M, Tx<D, N>>,
+}
+
+impl<M: RawMutex, SPI, IRQ, D: Clone, const N: usize> SpiTransport<M, SPI, IRQ, D, N> {
+    /// Create a new instance using the `spi` device, the controller's `irq` line and a `delay` provider.
+    pub fn new(spi: SPI, irq: IRQ, delay: D, protocol: Protocol) -> Self {
+        Self {
+            protocol,
+            spi: Mutex::new(spi),
+            rx: Mutex::new(Rx {
+                irq,
+                delay: delay.clone(),
+                buf: [0; N],
+                pos: 0,
+                len: 0,
+            }),
+            tx: Mutex::new(Tx { delay, buf: [0; N] }),
+        }
+    }
+}
+
+impl<M: RawMutex, SPI, IRQ, D, const N: usize> SpiTransport<M, SPI, IRQ, D, N> {
+    /// Consume the transport, returning the SPI device and the IRQ pin.
+    pub fn into_inner(self) -> (SPI, IRQ) {
+        (self.spi.into_inner(), self.rx.into_inner().irq)
+    }
+}
+
+impl<M: RawMutex, SPI: SpiDevice, IRQ: Wait, D: DelayNs, const N: usize> SpiTransport<M, SPI, IRQ, D, N> {
+    /// Query the controller's buffer state without transferring any data.
+    pub async fn status(&self) -> Result<Status, Error<SPI::Error, IRQ::Error>> {
+        let mut spi = self.spi.lock().await;
+        self.header(&mut spi, &READ_HEADER).await
+    }
+
+    async fn header(&self, spi: &mut SPI, header: &[u8; HEADER_LEN]) -> Result<Status, Error<SPI::Error, IRQ::Error>> {
+        let mut status = [0; HEADER_LEN];
+        spi.transfer(&mut status, header).await.map_err(Error::Spi)?;
+        Ok(Status::parse(self.protocol, &status))
+    }
+
+    /// Wait for the controller to have data, and read the next chunk into the receive buffer.
+    async fn fill(&self, rx: &mut Rx<IRQ, D, N>) -> Result<(), Error<SPI::Error, IRQ::Error>> {
+        loop {
+            rx.irq.wait_for_high().await.map_err(Error::Irq)?;
+            let mut spi = self.spi.lock().await;
+            let status = self.header(&mut spi, &READ_HEADER).await?;
+            if status.read_len == 0 {
+                drop(spi);
+                rx.delay.delay_us(RETRY_DELAY_US).await;
+                continue;
+            }
+            let n = usize::from(status.read_len);
+            match self.protocol {
+                Protocol::BlueNrgMs => {
+                    // The controller may have received more data since, but never less
+                    let n = n.min(N);
+                    let mut header = [0; HEADER_LEN];
+                    spi.transaction(&mut [
+                        Operation::Transfer(&mut header, &READ_HEADER),
+                        Operation::Read(&mut rx.buf[..n]),
+                    ])
+                    .await
+                    .map_err(Error::Spi)?;
+                    if header[0] != READY {
+                        drop(spi);
+                        rx.delay.delay_us(RETRY_DELAY_US).await;
+                        continue;
+                    }
+                    rx.len = n;
+                }
+                Protocol::Zephyr => {
+                    if n > N {
+                        return Err(Error::Read(ReadHciError::BufferTooSmall));
+                    }
+                    spi.read(&mut rx.buf[..n]).await.map_err(Error::Spi)?;
+                    rx.len = n;
+                }
+            }
+            rx.pos = 0;
+            return Ok(());
+        }
+    }
+}
+
+impl<M: RawMutex, SPI, IRQ, D, const N: usize> ErrorType for SpiTransport<M, SPI, IRQ, D, N>
+where
+    SPI: embedded_hal::spi::ErrorType,
+    IRQ: embedded_hal::digital::ErrorType,
+{
+    type Error = Error<SPI::Error, IRQ::Error>;
+}
+
+impl<M: RawMutex, SPI: SpiDevice, IRQ: Wait, D: DelayNs, const N: usize> Transport for SpiTransport<M, SPI, IRQ, D, N> {
+    async fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, Self::Error> {
+        let mut state = self.rx.lock().await;
+        let mut reader = Reader {
+            transport: self,
+            rx: &mut state,
+        };
+        let kind = PacketKind::read_async(&mut reader).await?;
+        Ok(P::read_hci_async(kind, &mut reader, rx).await?)
+    }
+
+    async fn write<P: PacketToController>(&self, tx: &P) -> Result<(), Self::Error> {
+        let mut guard = self.tx.lock().await;
+        let Tx { delay, buf } = &mut *guard;
+        let mut data = serialize(tx, buf)?;
+
+        while !data.is_empty() {
+            let mut spi = self.spi.lock().await;
+            let status = self.header(&mut spi, &WRITE_HEADER).await?;
+            if status.write_len == 0 {
+                drop(spi);
+                delay.delay_us(RETRY_DELAY_US).await;
+                continue;
+            }
+            match self.protocol {
+                Protocol::BlueNrgMs => {
+                    let n = usize::from(status.write_len).min(data.len());
+                    let mut header = [0; HEADER_LEN];
+                    spi.transaction(&mut [
+                        Operation::Transfer(&mut header, &WRITE_HEADER),
+                        Operation::Write(&data[..n]),
+                    ])
+                    .await
+                    .map_err(Error::Spi)?;
+                    if header[0] == READY {
+                        data = &data[n..];
+                    } else {
+                        drop(spi);
+                        delay.delay_us(RETRY_DELAY_US).await;
+                    }
+                }
+                Protocol::Zephyr => {
+                    spi.write(data).await.map_err(Error::Spi)?;
+                    data = &[];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serialize `tx` with its packet indicator into `buf`, returning the bytes to write.
+fn serialize<'b, P: PacketToController, S, I, const N: usize>(
+    tx: &P,
+    buf: &'b mut [u8; N],
+) -> Result<&'b [u8], Error<S, I>> {
+    let mut w = &mut buf[..];
+    WithIndicator::new(tx)
+        .write_hci(&mut w)
+        .map_err(|_| Error::PacketTooLarge)?;
+    let len = N - w.len();
+    Ok(&buf[..len])
+}
+
+impl<M, SPI, IRQ, D, const N: usize> SpiTransport<M, SPI, IRQ, D, N>
+where
+    M: RawMutex,
+    SPI: embedded_hal::spi::SpiDevice,
+    IRQ: InputPin,
+    D: embedded_hal::delay::DelayNs,
+{
+    /// Lock the SPI device, waiting for an exchange in progress on another thread to end.
+    fn blocking_lock(&self, delay: &mut D) -> MutexGuard<'_, M, SPI> {
+        loop {
+            if let Ok(spi) = self.spi.try_lock() {
+                return spi;
+            }
+            delay.delay_us(RETRY_DELAY_US);
+        }
+    }
+
+    fn blocking_header(
+        &self,
+        spi: &mut SPI,
+        header: &[u8; HEADER_LEN],
+    ) -> Result<Status, Error<SPI::Error, IRQ::Error>> {
+        let mut status = [0; HEADER_LEN];
+        spi.transfer(&mut status, header).map_err(Error::Spi)?;
+        Ok(Status::parse(self.protocol, &status))
+    }
+
+    /// Poll the IRQ line until the controller has data, and read the next chunk into the receive buffer.
+    ///
+    /// This is the blocking version of [`fill`](SpiTransport::fill).
+    fn blocking_fill(&self, rx: &mut Rx<IRQ, D, N>) -> Result<(), Error<SPI::Error, IRQ::Error>> {
+        loop {
+            while !rx.irq.is_high().map_err(Error::Irq)? {
+                rx.delay.delay_us(RETRY_DELAY_US);
+            }
+            let mut spi = self.blocking_lock(&mut rx.delay);
+            let status = self.blocking_header(&mut spi, &READ_HEADER)?;
+            if status.read_len == 0 {
+                drop(spi);
+                rx.delay.delay_us(RETRY_DELAY_US);
+                continue;
+            }
+            let n = usize::from(status.read_len);
+            match self.protocol {
+                Protocol::BlueNrgMs => {
+                    let n = n.min(N);
+                    let mut header = [0; HEADER_LEN];
+                    spi.transaction(&mut [
+                        Operation::Transfer(&mut header, &READ_HEADER),
+                        Operation::Read(&mut rx.buf[..n]),
+                    ])
+                    .map_err(Error::Spi)?;
+                    if header[0] != READY {
+                        drop(spi);
+                        rx.delay.delay_us(RETRY_DELAY_US);
+                        continue;
+                    }
+                    rx.len = n;
+                }
+                Protocol::Zephyr => {
+                    if n > N {
+                        return Err(Error::Read(ReadHciError::BufferTooSmall));
+                    }
+                    spi.read(&mut rx.buf[..n]).map_err(Error::Spi)?;
+                    rx.len = n;
+                }
+            }
+            rx.pos = 0;
+            return Ok(());
+        }
+    }
+}
+
+impl<M, SPI, IRQ, D, const N: usize> blocking::Transport for SpiTransport<M, SPI, IRQ, D, N>
+where
+    M: RawMutex,
+    SPI: embedded_hal::spi::SpiDevice,
+    IRQ: InputPin,
+    D: embedded_hal::delay::DelayNs,
+{
+    fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, TryError<Self::Error>> {
+        let mut state = self.rx.try_lock().map_err(|_| TryError::Busy)?;
+        let mut reader = Reader {
+            transport: self,
+            rx: &mut state,
+        };
+        let kind = PacketKind::read(&mut reader)?;
+        Ok(P::read_hci(kind, &mut reader, rx)?)
+    }
+
+    fn write<P: PacketToController>(&self, tx: &P) -> Result<(), TryError<Self::Error>> {
+        let mut guard = self.tx.try_lock().map_err(|_| TryError::Busy)?;
+        let Tx { delay, buf } = &mut *guard;
+        let mut data = serialize(tx, buf).map_err(TryError::Error)?;
+
+        while !data.is_empty() {
+            let mut spi = self.blocking_lock(delay);
+            let status = self.blocking_header(&mut spi, &WRITE_HEADER).map_err(TryError::Error)?;
+            if status.write_len == 0 {
+                drop(spi);
+                delay.delay_us(RETRY_DELAY_US);
+                continue;
+            }
+            match self.protocol {
+                Protocol::BlueNrgMs => {
+                    let n = usize::from(status.write_len).min(data.len());
+                    let mut header = [0; HEADER_LEN];
+                    spi.transaction(&mut [
+                        Operation::Transfer(&mut header, &WRITE_HEADER),
+                        Operation::Write(&data[..n]),
+                    ])
+                    .map_err(|e| TryError::Error(Error::Spi(e)))?;
+                    if header[0] == READY {
+                        data = &data[n..];
+                    } else {
+                        drop(spi);
+                        delay.delay_us(RETRY_DELAY_US);
+                    }
+                }
+                Protocol::Zephyr => {
+                    spi.write(data).map_err(|e| TryError::Error(Error::Spi(e)))?;
+                    data = &[];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Byte stream over the chunks received from the controller.
+struct Reader<'t, 'r, M: RawMutex, SPI, IRQ, D, const N: usize> {
+    transport: &'t SpiTransport<M, SPI, IRQ, D, N>,
+    rx: &'r mut Rx<IRQ, D, N>,
+}
+
+impl<M: RawMutex, SPI, IRQ, D, const N: usize> ErrorType for Reader<'_, '_, M, SPI, IRQ, D, N>
+where
+    SPI: embedded_hal::spi::ErrorType,
+    IRQ: embedded_hal::digital::ErrorType,
+{
+    type Error = Error<SPI::Error, IRQ::Error>;
+}
+
+impl<M: RawMutex, SPI, IRQ, D, const N: usize> Reader<'_, '_, M, SPI, IRQ, D, N> {
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.rx.len - self.rx.pos);
+        buf[..n].copy_from_slice(&self.rx.buf[self.rx.pos..][..n]);
+        self.rx.pos += n;
+        n
+    }
+}
+
+impl<M, SPI, IRQ, D, const N: usize> embedded_io::Read for Reader<'_, '_, M, SPI, IRQ, D, N>
+where
+    M: RawMutex,
+    SPI: embedded_hal::spi::SpiDevice,
+    IRQ: InputPin,
+    D: embedded_hal::delay::DelayNs,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.rx.pos == self.rx.len {
+            self.transport.blocking_fill(self.rx)?;
+        }
+        Ok(self.take(buf))
+    }
+}
+
+impl<M: RawMutex, SPI: SpiDevice, IRQ: Wait, D: DelayNs, const N: usize> embedded_io_async::Read
+    for Reader<'_, '_, M, SPI, IRQ, D, N>
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.rx.pos == self.rx.len {
+            self.transport.fill(self.rx).await?;
+        }
+        Ok(self.take(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embedded_hal_mock::eh1::delay::{CheckedDelay, Transaction as DelayTransaction};
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction as PinTransaction};
+    use embedded_hal_mock::eh1::spi::{Mock as SpiMock, Transaction as SpiTransaction};
+    use embedded_hal_mock::eh1::MockError;
+    use futures_test::test;
+
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::cmd::SyncCmd;
+    use crate::controller::{Controller, ExternalController};
+    use crate::ControllerToHostPacket;
+
+    const RESET: [u8; 4] = [0x01, 0x03, 0x0c, 0x00];
+    const RESET_COMPLETE: [u8; 7] = [0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+
+    fn header(header: [u8; HEADER_LEN], response: [u8; HEADER_LEN]) -> [SpiTransaction<u8>; 3] {
+        [
+            SpiTransaction::transaction_start(),
+            SpiTransaction::transfer(header.to_vec(), response.to_vec()),
+            SpiTransaction::transaction_end(),
+        ]
+    }
+
+    fn chunk(
+        header: [u8; HEADER_LEN],
+        response: [u8; HEADER_LEN],
+        data: SpiTransaction<u8>,
+    ) -> [SpiTransaction<u8>; 4] {
+        [
+            SpiTransaction::transaction_start(),
+            SpiTransaction::transfer(header.to_vec(), response.to_vec()),
+            data,
+            SpiTransaction::transaction_end(),
+        ]
+    }
+
+    fn data(data: SpiTransaction<u8>) -> [SpiTransaction<u8>; 3] {
+        [
+            SpiTransaction::transaction_start(),
+            data,
+            SpiTransaction::transaction_end(),
+        ]
+    }
+
+    fn assert_reset_complete(packet: ControllerToHostPacket<'_>) {
+        let ControllerToHostPacket::Event(event) = packet else {
+            panic!("expected an event");
+        };
+        assert_eq!(event.data, &RESET_COMPLETE[3..]);
+    }
+
+    fn bluenrg_ms_expectations() -> Vec<SpiTransaction<u8>> {
+        [
+            // Not ready yet
+            &header(WRITE_HEADER, [0x00; 5])[..],
+            // Room for 2 bytes, the command is written in two chunks
+            &header(WRITE_HEADER, [0x02, 0x02, 0x00, 0x00, 0x00]),
+            &chunk(
+                WRITE_HEADER,
+                [0x02, 0x02, 0x00, 0x00, 0x00],
+                SpiTransaction::write_vec(RESET[..2].to_vec()),
+            ),
+            &header(WRITE_HEADER, [0x02, 0x7f, 0x00, 0x00, 0x00]),
+            &chunk(
+                WRITE_HEADER,
+                [0x02, 0x7f, 0x00, 0x00, 0x00],
+                SpiTransaction::write_vec(RESET[2..].to_vec()),
+            ),
+            // The event arrives in two chunks
+            &header(READ_HEADER, [0x02, 0x7f, 0x00, 0x04, 0x00]),
+            &chunk(
+                READ_HEADER,
+                [0x02, 0x7f, 0x00, 0x04, 0x00],
+                SpiTransaction::read_vec(RESET_COMPLETE[..4].to_vec()),
+            ),
+            &header(READ_HEADER, [0x02, 0x7f, 0x00, 0x03, 0x00]),
+            &chunk(
+                READ_HEADER,
+                [0x02, 0x7f, 0x00, 0x03, 0x00],
+                SpiTransaction::read_vec(RESET_COMPLETE[4..].to_vec()),
+            ),
+        ]
+        .concat()
+    }
+
+    #[test]
+    async fn test_bluenrg_ms() {
+        let mut spi = SpiMock::new(&bluenrg_ms_expectations());
+        let mut irq = PinMock::new(&[
+            PinTransaction::wait_for_state(State::High),
+            PinTransaction::wait_for_state(State::High),
+        ]);
+        let mut delay = CheckedDelay::new(&[DelayTransaction::async_delay_us(RETRY_DELAY_US)]);
+        let transport: SpiTransport<NoopRawMutex, _, _, _> =
+            SpiTransport::new(spi.clone(), irq.clone(), delay.clone(), Protocol::BlueNrgMs);
+
+        Transport::write(&transport, &Reset::new()).await.unwrap();
+        let mut rx = [0; 259];
+        assert_reset_complete(Transport::read(&transport, &mut rx).await.unwrap());
+        spi.done();
+        irq.done();
+        delay.done();
+    }
+
+    #[test]
+    async fn test_blocking_bluenrg_ms() {
+        let mut spi = SpiMock::new(&bluenrg_ms_expectations());
+        // The IRQ line is polled until the controller has data
+        let mut irq = PinMock::new(&[
+            PinTransaction::get(State::Low),
+            PinTransaction::get(State::High),
+            PinTransaction::get(State::High),
+        ]);
+        let mut delay = CheckedDelay::new(&[
+            DelayTransaction::blocking_delay_us(RETRY_DELAY_US),
+            DelayTransaction::blocking_delay_us(RETRY_DELAY_US),
+        ]);
+        let transport: SpiTransport<NoopRawMutex, _, _, _> =
+            SpiTransport::new(spi.clone(), irq.clone(), delay.clone(), Protocol::BlueNrgMs);
+
+        blocking::Transport::write(&transport, &Reset::new()).unwrap();
+        let mut rx = [0; 259];
+        assert_reset_complete(blocking::Transport::read(&transport, &mut rx).unwrap());
+        spi.done();
+        irq.done();
+        delay.done();
+    }
+
+    #[test]
+    async fn test_zephyr() {
+        let expectations = [
+            &header(WRITE_HEADER, [0x00, 0xff, 0x00, 0x00, 0x00])[..],
+            &header(WRITE_HEADER, [0x00, 0x02, 0x00, 0x00, 0x00]),
+            &data(SpiTransaction::write_vec(RESET.to_vec())),
+            &header(READ_HEADER, [0x02, 0x02, 0x00, 0x07, 0x00]),
+            &data(SpiTransaction::read_vec(RESET_COMPLETE.to_vec())),
+        ]
+        .concat();
+        let mut spi = SpiMock::new(&expectations);
+        let mut irq = PinMock::new(&[PinTransaction::wait_for_state(State::High)]);
+        let mut delay = CheckedDelay::new(&[DelayTransaction::async_delay_us(RETRY_DELAY_US)]);
+        let transport: SpiTransport<NoopRawMutex, _, _, _> =
+            SpiTransport::new(spi.clone(), irq.clone(), delay.clone(), Protocol::Zephyr);
+
+        Transport::write(&transport, &Reset::new()).await.unwrap();
+        let mut rx = [0; 259];
+        assert_reset_complete(Transport::read(&transport, &mut rx).await.unwrap());
+        spi.done();
+        irq.done();
+        delay.done();
+    }
+
+    #[test]
+    async fn test_external_controller() {
+        let expectations = [
+            &header(WRITE_HEADER, [0x00, 0x02, 0x00, 0x00, 0x00])[..],
+            &data(SpiTransaction::write_vec(RESET.to_vec())),
+            &header(READ_HEADER, [0x02, 0x02, 0x00, 0x07, 0x00]),
+            &data(SpiTransaction::read_vec(RESET_COMPLETE.to_vec())),
+        ]
+        .concat();
+        let mut spi = SpiMock::new(&expectations);
+        // The IRQ line fails once the command has completed, ending the read loop
+        let mut irq = PinMock::new(&[
+            PinTransaction::wait_for_state(State::High),
+            PinTransaction::wait_for_state(State::High).with_error(MockError::Io(std::io::ErrorKind::Other)),
+        ]);
+        let mut delay = CheckedDelay::new(&[]);
+        let c: ExternalController<SpiTransport<NoopRawMutex, _, _, _>, 2> = ExternalController::new(SpiTransport::new(
+            spi.clone(),
+            irq.clone(),
+            delay.clone(),
+            Protocol::Zephyr,
+        ));
+
+        let mut rx = [0; 259];
+        let (res, rest) = embassy_futures::join::join(Reset::new().exec(&c), Controller::read(&c, &mut rx)).await;
+        res.unwrap();
+        assert!(matches!(rest, Err(Error::Irq(_))));
+        spi.done();
+        irq.done();
+        delay.done();
+    }
+
+    #[test]
+    async fn test_status() {
+        let expectations = [
+            &header(READ_HEADER, [0x02, 0x7f, 0x00, 0x2c, 0x01])[..],
+            &header(READ_HEADER, [0x00; 5]),
+        ]
+        .concat();
+        let mut spi = SpiMock::new(&expectations);
+        let mut delay = CheckedDelay::new(&[]);
+        let transport: SpiTransport<NoopRawMutex, _, _, _> =
+            SpiTransport::new(spi.clone(), PinMock::new(&[]), delay.clone(), Protocol::BlueNrgMs);
+        assert_eq!(
+            transport.status().await.unwrap(),
+            Status {
+                write_len: 0x7f,
+                read_len: 300
+            }
+        );
+        assert_eq!(
+            transport.status().await.unwrap(),
+            Status {
+                write_len: 0,
+                read_len: 0
+            }
+        );
+        spi.done();
+        delay.done();
+        transport.into_inner().1.done();
+    }
+
+    #[test]
+    async fn test_packet_too_large() {
+        let mut spi = SpiMock::new(&[]);
+        let mut delay = CheckedDelay::new(&[]);
+        let transport: SpiTransport<NoopRawMutex, _, _, _, 3> =
+            SpiTransport::new(spi.clone(), PinMock::new(&[]), delay.clone(), Protocol::Zephyr);
+        let res = Transport::write(&transport, &Reset::new()).await;
+        assert!(matches!(res, Err(Error::PacketTooLarge)));
+        spi.done();
+        delay.done();
+        transport.into_inner().1.done();
+    }
+}
@@ -0,0 +1,539 @@
+//! A transport adapter mirroring all HCI traffic to a [`Sink`].
+//!
+//! [`TeeTransport`] wraps the host side of any [`Transport`] or [`blocking::Transport`] and hands every packet read
+//! from or written to it to a [`Sink`], e.g. a [`LogSink`] hex dump or a [`RingBuffer`] kept around for post-mortem
+//! dumps.
+#![cfg_attr(
+    feature = "btsnoop",
+    doc = "A btsnoop [`Writer`](crate::btsnoop::Writer) is a sink too, to record a capture file."
+)]
+//! Packets are passed through unchanged.
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use bt_hci_driver::{PacketKind, PacketToController, PacketToHost};
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_io::ErrorType;
+
+use super::{blocking, Direction, Transport};
+use crate::controller::blocking::TryError;
+use crate::fmt::Bytes;
+use crate::{FromHciBytes, ReadHciError};
+
+/// A destination for the packets observed by a [`TeeTransport`].
+pub trait Sink {
+    /// Called for every packet read from or written to the transport.
+    ///
+    /// `data` does not include the packet indicator. Packets larger than the capture buffer of the [`TeeTransport`]
+    /// are truncated.
+    fn packet(&mut self, direction: Direction, kind: PacketKind, data: &[u8]);
+}
+
+impl<S: Sink + ?Sized> Sink for &mut S {
+    fn packet(&mut self, direction: Direction, kind: PacketKind, data: &[u8]) {
+        S::packet(self, direction, kind, data)
+    }
+}
+
+/// A [`Sink`] which logs every packet as a hex dump at debug level using `defmt` or `log`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogSink;
+
+impl Sink for LogSink {
+    fn packet(&mut self, direction: Direction, kind: PacketKind, data: &[u8]) {
+        debug!("[hci] {:?} {:?} {:?}", direction, kind, Bytes(data));
+    }
+}
+
+/// A [`Sink`] keeping the most recent packets in a fixed `N` byte buffer.
+///
+/// Every packet takes 3 bytes plus its length. When the buffer is full the oldest packets are discarded. Packets
+/// larger than the buffer are truncated.
+pub struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every packet in a [`RingBuffer`] is preceded by its direction and kind, and its length.
+const RECORD_HEADER_LEN: usize = 3;
+
+impl<const N: usize> RingBuffer<N> {
+    /// Create a new, empty, ring buffer.
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns `true` if the buffer holds no packets.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Discard all packets.
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+
+    /// Remove the oldest packet and copy it into `buf`, truncating it if `buf` is too small.
+    pub fn pop<'b>(&mut self, buf: &'b mut [u8]) -> Option<(Direction, PacketKind, &'b [u8])> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut header = [0; RECORD_HEADER_LEN];
+        self.copy_out(0, &mut header);
+        let len = usize::from(u16::from_le_bytes([header[1], header[2]]));
+        let n = len.min(buf.len());
+        self.copy_out(RECORD_HEADER_LEN, &mut buf[..n]);
+        self.discard(RECORD_HEADER_LEN + len);
+
+        let direction = if header[0] & 0x80 != 0 {
+            Direction::Received
+        } else {
+            Direction::Sent
+        };
+        // Only valid packet kinds are ever stored
+        let (kind, _) = unwrap!(PacketKind::from_hci_bytes(&[header[0] & 0x7f]).ok());
+        Some((direction, kind, &buf[..n]))
+    }
+
+    fn copy_out(&self, offset: usize, dst: &mut [u8]) {
+        for (i, b) in dst.iter_mut().enumerate() {
+            *b = self.buf[(self.start + offset + i) % N];
+        }
+    }
+
+    fn copy_in(&mut self, src: &[u8]) {
+        for &b in src {
+            self.buf[(self.start + self.len) % N] = b;
+            self.len += 1;
+        }
+    }
+
+    fn discard(&mut self, n: usize) {
+        self.start = (self.start + n) % N;
+        self.len -= n;
+    }
+}
+
+impl<const N: usize> Sink for RingBuffer<N> {
+    fn packet(&mut self, direction: Direction, kind: PacketKind, data: &[u8]) {
+        if N <= RECORD_HEADER_LEN {
+            return;
+        }
+        let data = &data[..data.len().min(N - RECORD_HEADER_LEN).min(usize::from(u16::MAX))];
+        while N - self.len < RECORD_HEADER_LEN + data.len() {
+            let mut header = [0; RECORD_HEADER_LEN];
+            self.copy_out(0, &mut header);
+            self.discard(RECORD_HEADER_LEN + usize::from(u16::from_le_bytes([header[1], header[2]])));
+        }
+
+        let tag = match direction {
+            Direction::Sent => kind as u8,
+            Direction::Received => kind as u8 | 0x80,
+        };
+        let len = (data.len() as u16).to_le_bytes();
+        self.copy_in(&[tag, len[0], len[1]]);
+        self.copy_in(data);
+    }
+}
+
+/// Error type for a [`TeeTransport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Error from the wrapped transport.
+    Transport(E),
+    /// The packet could not be read into the requested packet type.
+    Read(ReadHciError<Infallible>),
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl<E: core::fmt::Debug> core::error::Error for Error<E> {}
+
+impl<E: embedded_io::Error> embedded_io::Error for Error<E> {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            Self::Transport(e) => e.kind(),
+            Self::Read(e) => e.kind(),
+        }
+    }
+}
+
+impl<E> From<ReadHciError<Infallible>> for Error<E> {
+    fn from(e: ReadHciError<Infallible>) -> Self {
+        Self::Read(e)
+    }
+}
+
+/// A transport adapter passing every packet to a [`Sink`].
+///
+/// The first `N` bytes of every packet are copied into a capture buffer and passed to the sink, larger packets are
+/// truncated in the capture but passed through unchanged. Read packets are recorded after they have been read
+/// successfully, written packets after they have been written successfully.
+pub struct TeeTransport<M: RawMutex, T, S, const N: usize = 259> {
+    inner: T,
+    sink: embassy_sync::blocking_mutex::Mutex<M, RefCell<S>>,
+    tx: Mutex<M, [u8; N]>,
+}
+
+impl<M: RawMutex, T, S: Sink, const N: usize> TeeTransport<M, T, S, N> {
+    /// Create a new instance wrapping the `inner` transport.
+    pub fn new(inner: T, sink: S) -> Self {
+        Self {
+            inner,
+            sink: embassy_sync::blocking_mutex::Mutex::new(RefCell::new(sink)),
+            tx: Mutex::new([0; N]),
+        }
+    }
+
+    /// The wrapped transport.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Access the sink, e.g. to dump a [`RingBuffer`].
+    pub fn with_sink<R>(&self, f: impl FnOnce(&mut S) -> R) -> R {
+        self.sink.lock(|sink| f(&mut sink.borrow_mut()))
+    }
+
+    /// Consume the adapter, returning the wrapped transport and the sink.
+    pub fn into_inner(self) -> (T, S) {
+        (self.inner, self.sink.into_inner().into_inner())
+    }
+
+    fn record(&self, direction: Direction, kind: PacketKind, data: &[u8]) {
+        self.with_sink(|sink| sink.packet(direction, kind, data));
+    }
+}
+
+impl<M: RawMutex, T: ErrorType, S, const N: usize> ErrorType for TeeTransport<M, T, S, N> {
+    type Error = Error<T::Error>;
+}
+
+impl<M: RawMutex, T: Transport, S: Sink, const N: usize> Transport for TeeTransport<M, T, S, N> {
+    async fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, Self::Error> {
+        let pkt: Captured<P, N> = self.inner.read(rx).await.map_err(Error::Transport)?;
+        self.record(Direction::Received, pkt.kind, &pkt.capture[..pkt.len]);
+        Ok(pkt.packet)
+    }
+
+    async fn write<P: PacketToController>(&self, tx: &P) -> Result<(), Self::Error> {
+        let mut capture = self.tx.lock().await;
+        let len = serialize(tx, &mut capture[..]);
+        self.inner.write(tx).await.map_err(Error::Transport)?;
+        self.record(Direction::Sent, P::KIND, &capture[..len]);
+        Ok(())
+    }
+}
+
+impl<M: RawMutex, T: blocking::Transport, S: Sink, const N: usize> blocking::Transport for TeeTransport<M, T, S, N> {
+    fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, TryError<Self::Error>> {
+        let pkt: Captured<P, N> = self.inner.read(rx).map_err(try_error)?;
+        self.record(Direction::Received, pkt.kind, &pkt.capture[..pkt.len]);
+        Ok(pkt.packet)
+    }
+
+    fn write<P: PacketToController>(&self, tx: &P) -> Result<(), TryError<Self::Error>> {
+        let mut capture = self.tx.try_lock().map_err(|_| TryError::Busy)?;
+        let len = serialize(tx, &mut capture[..]);
+        self.inner.write(tx).map_err(try_error)?;
+        self.record(Direction::Sent, P::KIND, &capture[..len]);
+        Ok(())
+    }
+}
+
+fn try_error<E>(e: TryError<E>) -> TryError<Error<E>> {
+    match e {
+        TryError::Error(e) => TryError::Error(Error::Transport(e)),
+        TryError::Busy => TryError::Busy,
+    }
+}
+
+/// Serialize `pkt` into `buf`, truncating it if necessary, and return the number of bytes written.
+fn serialize<P: PacketToController>(pkt: &P, buf: &mut [u8]) -> usize {
+    let mut writer = Truncating { buf, len: 0 };
+    // Writing to a `Truncating` never fails
+    let _ = pkt.write_hci(&mut writer);
+    writer.len
+}
+
+struct Truncating<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl ErrorType for Truncating<'_> {
+    type Error = Infallible;
+}
+
+impl embedded_io::Write for Truncating<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, Self::Error> {
+        let n = data.len().min(self.buf.len() - self.len);
+        self.buf[self.len..][..n].copy_from_slice(&data[..n]);
+        self.len += n;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// A packet read from the wrapped transport together with a copy of its first `N` bytes.
+struct Captured<P, const N: usize> {
+    packet: P,
+    kind: PacketKind,
+    capture: [u8; N],
+    len: usize,
+}
+
+impl<'d, P: PacketToHost<'d>, const N: usize> PacketToHost<'d> for Captured<P, N> {
+    fn read_hci<R: embedded_io::Read>(
+        kind: PacketKind,
+        reader: &mut R,
+        buf: &'d mut [u8],
+    ) -> Result<Self, ReadHciError<R::Error>> {
+        let mut capture = [0; N];
+        let mut reader = Mirror {
+            inner: reader,
+            capture: &mut capture,
+            len: 0,
+        };
+        let packet = P::read_hci(kind, &mut reader, buf)?;
+        let len = reader.len;
+        Ok(Self {
+            packet,
+            kind,
+            capture,
+            len,
+        })
+    }
+
+    async fn read_hci_async<R: embedded_io_async::Read>(
+        kind: PacketKind,
+        reader: &mut R,
+        buf: &'d mut [u8],
+    ) -> Result<Self, ReadHciError<R::Error>> {
+        let mut capture = [0; N];
+        let mut reader = Mirror {
+            inner: reader,
+            capture: &mut capture,
+            len: 0,
+        };
+        let packet = P::read_hci_async(kind, &mut reader, buf).await?;
+        let len = reader.len;
+        Ok(Self {
+            packet,
+            kind,
+            capture,
+            len,
+        })
+    }
+}
+
+/// A reader copying everything read from `inner` into `capture`, truncating it if necessary.
+struct Mirror<'a, R> {
+    inner: &'a mut R,
+    capture: &'a mut [u8],
+    len: usize,
+}
+
+impl<R> Mirror<'_, R> {
+    fn mirror(&mut self, data: &[u8]) {
+        let n = data.len().min(self.capture.len() - self.len);
+        self.capture[self.len..][..n].copy_from_slice(&data[..n]);
+        self.len += n;
+    }
+}
+
+impl<R: ErrorType> ErrorType for Mirror<'_, R> {
+    type Error = R::Error;
+}
+
+impl<R: embedded_io::Read> embedded_io::Read for Mirror<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = self.inner.read(buf)?;
+        self.mirror(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<R: embedded_io_async::Read> embedded_io_async::Read for Mirror<'_, R> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = self.inner.read(buf).await?;
+        self.mirror(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use futures_test::test;
+
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::cmd::SyncCmd;
+    use crate::controller::{Controller, ExternalController};
+    use crate::mock::{MockTransport, Step};
+    use crate::ControllerToHostPacket;
+
+    const RESET: [u8; 4] = [0x01, 0x03, 0x0c, 0x00];
+    const RESET_COMPLETE: [u8; 7] = [0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+    const ACL: [u8; 8] = [0x02, 0x40, 0x00, 0x03, 0x00, 1, 2, 3];
+
+    #[test]
+    async fn test_tee_transport() {
+        let script = [Step::Write(&RESET), Step::Read(&RESET_COMPLETE), Step::Read(&ACL)];
+        let tee: TeeTransport<NoopRawMutex, _, RingBuffer<64>> =
+            TeeTransport::new(MockTransport::<NoopRawMutex>::new(&script), RingBuffer::new());
+
+        Transport::write(&tee, &Reset::new()).await.unwrap();
+        let mut rx = [0; 259];
+        let pkt: ControllerToHostPacket = Transport::read(&tee, &mut rx).await.unwrap();
+        assert!(matches!(pkt, ControllerToHostPacket::Event(_)));
+        let pkt: ControllerToHostPacket = blocking::Transport::read(&tee, &mut rx).unwrap();
+        let ControllerToHostPacket::Acl(acl) = pkt else {
+            panic!("expected ACL data");
+        };
+        assert_eq!(acl.data(), [1, 2, 3]);
+        tee.inner().assert_done();
+
+        let (_, mut ring) = tee.into_inner();
+        let mut buf = [0; 16];
+        assert_eq!(
+            ring.pop(&mut buf),
+            Some((Direction::Sent, PacketKind::Cmd, &RESET[1..]))
+        );
+        assert_eq!(
+            ring.pop(&mut buf),
+            Some((Direction::Received, PacketKind::Event, &RESET_COMPLETE[1..]))
+        );
+        assert_eq!(
+            ring.pop(&mut buf),
+            Some((Direction::Received, PacketKind::AclData, &ACL[1..]))
+        );
+        assert_eq!(ring.pop(&mut buf), None);
+    }
+
+    #[test]
+    async fn test_external_controller() {
+        let script = [Step::Write(&RESET), Step::Read(&RESET_COMPLETE)];
+        let mut ring = RingBuffer::<64>::new();
+        {
+            let c: ExternalController<TeeTransport<NoopRawMutex, _, _>, 2> = ExternalController::new(
+                TeeTransport::new(MockTransport::<NoopRawMutex>::new(&script), &mut ring),
+            );
+            let mut rx = [0; 259];
+            let (res, rest) = embassy_futures::join::join(Reset::new().exec(&c), Controller::read(&c, &mut rx)).await;
+            res.unwrap();
+            assert!(matches!(rest, Err(Error::Transport(crate::mock::Error::Exhausted))));
+        }
+
+        let mut buf = [0; 16];
+        assert_eq!(
+            ring.pop(&mut buf),
+            Some((Direction::Sent, PacketKind::Cmd, &RESET[1..]))
+        );
+        assert_eq!(
+            ring.pop(&mut buf),
+            Some((Direction::Received, PacketKind::Event, &RESET_COMPLETE[1..]))
+        );
+        assert_eq!(ring.pop(&mut buf), None);
+    }
+
+    #[test]
+    async fn test_tee_capture_too_small() {
+        let script = [Step::Read(&RESET_COMPLETE), Step::Write(&RESET)];
+        let tee: TeeTransport<NoopRawMutex, _, RingBuffer<64>, 4> =
+            TeeTransport::new(MockTransport::<NoopRawMutex>::new(&script), RingBuffer::new());
+        let mut rx = [0; 259];
+        let pkt: ControllerToHostPacket = Transport::read(&tee, &mut rx).await.unwrap();
+        let ControllerToHostPacket::Event(event) = pkt else {
+            panic!("expected an event");
+        };
+        assert_eq!(event.data, &RESET_COMPLETE[3..]);
+        blocking::Transport::write(&tee, &Reset::new()).unwrap();
+        tee.inner().assert_done();
+
+        let (_, mut ring) = tee.into_inner();
+        let mut buf = [0; 16];
+        assert_eq!(
+            ring.pop(&mut buf),
+            Some((Direction::Received, PacketKind::Event, &RESET_COMPLETE[1..5]))
+        );
+        assert_eq!(
+            ring.pop(&mut buf),
+            Some((Direction::Sent, PacketKind::Cmd, &RESET[1..]))
+        );
+    }
+
+    #[test]
+    async fn test_tee_large_packet() {
+        let mut acl = [0xaa; 5 + 300];
+        acl[..5].copy_from_slice(&[0x02, 0x40, 0x00, 0x2c, 0x01]);
+        let script = [Step::Read(&acl)];
+        let tee: TeeTransport<NoopRawMutex, _, RingBuffer<512>> =
+            TeeTransport::new(MockTransport::<NoopRawMutex>::new(&script), RingBuffer::new());
+        let mut rx = [0; 512];
+        let pkt: ControllerToHostPacket = Transport::read(&tee, &mut rx).await.unwrap();
+        let ControllerToHostPacket::Acl(pkt) = pkt else {
+            panic!("expected ACL data");
+        };
+        assert_eq!(pkt.data(), &acl[5..]);
+        tee.inner().assert_done();
+
+        let (_, mut ring) = tee.into_inner();
+        let mut buf = [0; 512];
+        assert_eq!(
+            ring.pop(&mut buf),
+            Some((Direction::Received, PacketKind::AclData, &acl[1..260]))
+        );
+    }
+
+    #[test]
+    async fn test_ring_buffer_overflow() {
+        let mut ring = RingBuffer::<16>::new();
+        ring.packet(Direction::Sent, PacketKind::Cmd, &[1; 6]);
+        ring.packet(Direction::Received, PacketKind::Event, &[2; 4]);
+        // Evicts the first packet
+        ring.packet(Direction::Received, PacketKind::IsoData, &[3; 2]);
+
+        let mut buf = [0; 3];
+        assert_eq!(
+            ring.pop(&mut buf),
+            Some((Direction::Received, PacketKind::Event, &[2; 3][..]))
+        );
+        assert_eq!(
+            ring.pop(&mut buf),
+            Some((Direction::Received, PacketKind::IsoData, &[3; 2][..]))
+        );
+        assert!(ring.is_empty());
+
+        // Truncated to fit the buffer
+        ring.packet(Direction::Sent, PacketKind::AclData, &[4; 20]);
+        let mut buf = [0; 20];
+        assert_eq!(
+            ring.pop(&mut buf),
+            Some((Direction::Sent, PacketKind::AclData, &[4; 13][..]))
+        );
+    }
+}
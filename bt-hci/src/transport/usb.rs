@@ -0,0 +1,440 @@
+//! HCI transport composed of separate channels per packet kind, as used by USB controllers.
+//!
+//! The USB transport layer carries every kind of packet on its own endpoint: commands on the control endpoint, events
+//! on an interrupt IN endpoint, ACL data on a pair of bulk endpoints and synchronous data on isochronous endpoints.
+//! Packets are sent without H4 packet indicators, the kind of a packet follows from the endpoint it is sent on.
+//!
+//! [`UsbTransport`] routes written packets to an [`EndpointOut`] based on their [`PacketKind`], and reads from
+//! whichever [`EndpointIn`] first has a packet. Endpoints are small traits which can be implemented on top of any USB
+//! stack, or on top of in-memory channels for testing.
+
+use core::convert::Infallible;
+use core::future::{poll_fn, Future};
+use core::marker::PhantomData;
+use core::pin::pin;
+use core::task::Poll;
+
+use bt_hci_driver::{PacketKind, PacketToController, PacketToHost};
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_io::ErrorType;
+
+use super::Transport;
+use crate::ReadHciError;
+
+/// A channel receiving complete packets of one kind from the controller.
+pub trait EndpointIn: ErrorType {
+    /// Read the next packet into `buf`, returning its length.
+    ///
+    /// This must be cancel safe: dropping the future before it completes must not lose a packet, as [`UsbTransport`]
+    /// waits on all of its endpoints at once and drops the reads which did not complete first.
+    fn read(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, Self::Error>>;
+}
+
+/// A channel sending complete packets of one kind to the controller.
+pub trait EndpointOut: ErrorType {
+    /// Write a complete packet.
+    fn write(&mut self, buf: &[u8]) -> impl Future<Output = Result<(), Self::Error>>;
+}
+
+impl<T: EndpointIn> EndpointIn for &mut T {
+    fn read(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, Self::Error>> {
+        T::read(self, buf)
+    }
+}
+
+impl<T: EndpointOut> EndpointOut for &mut T {
+    fn write(&mut self, buf: &[u8]) -> impl Future<Output = Result<(), Self::Error>> {
+        T::write(self, buf)
+    }
+}
+
+/// Placeholder for a channel the controller does not provide.
+///
+/// Reads never complete, and [`UsbTransport`] never writes to it.
+pub struct Disabled<E>(PhantomData<fn() -> E>);
+
+impl<E> Default for Disabled<E> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<E: embedded_io::Error> ErrorType for Disabled<E> {
+    type Error = E;
+}
+
+impl<E: embedded_io::Error> EndpointIn for Disabled<E> {
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+        core::future::pending().await
+    }
+}
+
+impl<E: embedded_io::Error> EndpointOut for Disabled<E> {
+    async fn write(&mut self, _buf: &[u8]) -> Result<(), Self::Error> {
+        core::future::pending().await
+    }
+}
+
+/// Error type for a [`UsbTransport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Error from an endpoint.
+    Endpoint(E),
+    /// The received data is not a valid packet of the requested type.
+    Read(ReadHciError<Infallible>),
+    /// There is no channel for packets of this kind.
+    Unsupported(PacketKind),
+    /// The packet to write is larger than the transmit buffer.
+    PacketTooLarge,
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl<E: core::fmt::Debug> core::error::Error for Error<E> {}
+
+impl<E: embedded_io::Error> embedded_io::Error for Error<E> {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            Self::Endpoint(e) => e.kind(),
+            Self::Read(e) => e.kind(),
+            Self::Unsupported(_) => embedded_io::ErrorKind::Unsupported,
+            Self::PacketTooLarge => embedded_io::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl<E> From<ReadHciError<Infallible>> for Error<E> {
+    fn from(e: ReadHciError<Infallible>) -> Self {
+        Self::Read(e)
+    }
+}
+
+struct Rx<E, AI, SI, II, const N: usize> {
+    event: E,
+    acl: AI,
+    sync: SI,
+    iso: II,
+    bufs: [[u8; N]; 4],
+    next: usize,
+}
+
+struct Tx<C, AO, SO, IO, const N: usize> {
+    cmd: C,
+    acl: AO,
+    sync: Option<SO>,
+    iso: Option<IO>,
+    buf: [u8; N],
+}
+
+/// HCI transport layer over separate channels per packet kind.
+///
+/// The channels are the command endpoint `C`, the event endpoint `E`, the ACL data endpoints `AI` and `AO`, and the
+/// optional synchronous data endpoints `SI` and `SO` and isochronous data endpoints `II` and `IO`.
+///
+/// Every IN endpoint reads into its own `N` byte buffer, and packets are serialized into an `N` byte buffer before
+/// they are written, so `N` must be large enough for the largest packet on any channel. Reads are served from the
+/// endpoints in turn, so that a busy channel does not starve the others.
+#[allow(clippy::type_complexity)]
+pub struct UsbTransport<M: RawMutex, C, E, AI, AO, SI, SO, II, IO, const N: usize = 259> {
+    rx: Mutex<M, Rx<E, AI, SI, II, N>>,
+    tx: Mutex<M, Tx<C, AO, SO, IO, N>>,
+}
+
+impl<M: RawMutex, C: ErrorType, E, AI, AO, const N: usize>
+    UsbTransport<M, C, E, AI, AO, Disabled<C::Error>, Disabled<C::Error>, Disabled<C::Error>, Disabled<C::Error>, N>
+{
+    /// Create a new instance with command, event and ACL data channels.
+    pub fn new(cmd: C, event: E, acl_in: AI, acl_out: AO) -> Self {
+        Self {
+            rx: Mutex::new(Rx {
+                event,
+                acl: acl_in,
+                sync: Disabled::default(),
+                iso: Disabled::default(),
+                bufs: [[0; N]; 4],
+                next: 0,
+            }),
+            tx: Mutex::new(Tx {
+                cmd,
+                acl: acl_out,
+                sync: None,
+                iso: None,
+                buf: [0; N],
+            }),
+        }
+    }
+}
+
+impl<M: RawMutex, C, E, AI, AO, SI, SO, II, IO, const N: usize> UsbTransport<M, C, E, AI, AO, SI, SO, II, IO, N> {
+    /// Add a synchronous (SCO/eSCO) data channel.
+    pub fn with_sync<SI2, SO2>(
+        self,
+        sync_in: SI2,
+        sync_out: SO2,
+    ) -> UsbTransport<M, C, E, AI, AO, SI2, SO2, II, IO, N> {
+        let rx = self.rx.into_inner();
+        let tx = self.tx.into_inner();
+        UsbTransport {
+            rx: Mutex::new(Rx {
+                event: rx.event,
+                acl: rx.acl,
+                sync: sync_in,
+                iso: rx.iso,
+                bufs: rx.bufs,
+                next: rx.next,
+            }),
+            tx: Mutex::new(Tx {
+                cmd: tx.cmd,
+                acl: tx.acl,
+                sync: Some(sync_out),
+                iso: tx.iso,
+                buf: tx.buf,
+            }),
+        }
+    }
+
+    /// Add an isochronous data channel.
+    pub fn with_iso<II2, IO2>(self, iso_in: II2, iso_out: IO2) -> UsbTransport<M, C, E, AI, AO, SI, SO, II2, IO2, N> {
+        let rx = self.rx.into_inner();
+        let tx = self.tx.into_inner();
+        UsbTransport {
+            rx: Mutex::new(Rx {
+                event: rx.event,
+                acl: rx.acl,
+                sync: rx.sync,
+                iso: iso_in,
+                bufs: rx.bufs,
+                next: rx.next,
+            }),
+            tx: Mutex::new(Tx {
+                cmd: tx.cmd,
+                acl: tx.acl,
+                sync: tx.sync,
+                iso: Some(iso_out),
+                buf: tx.buf,
+            }),
+        }
+    }
+}
+
+impl<M, C, E, AI, AO, SI, SO, II, IO, Err, const N: usize> ErrorType
+    for UsbTransport<M, C, E, AI, AO, SI, SO, II, IO, N>
+where
+    M: RawMutex,
+    C: ErrorType<Error = Err>,
+    Err: embedded_io::Error,
+{
+    type Error = Error<Err>;
+}
+
+impl<M, C, E, AI, AO, SI, SO, II, IO, Err, const N: usize> Transport
+    for UsbTransport<M, C, E, AI, AO, SI, SO, II, IO, N>
+where
+    M: RawMutex,
+    C: EndpointOut<Error = Err>,
+    E: EndpointIn<Error = Err>,
+    AI: EndpointIn<Error = Err>,
+    AO: EndpointOut<Error = Err>,
+    SI: EndpointIn<Error = Err>,
+    SO: EndpointOut<Error = Err>,
+    II: EndpointIn<Error = Err>,
+    IO: EndpointOut<Error = Err>,
+    Err: embedded_io::Error,
+{
+    async fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, Self::Error> {
+        let mut state = self.rx.lock().await;
+        let start = state.next;
+        let (i, res) = {
+            let Rx {
+                event,
+                acl,
+                sync,
+                iso,
+                bufs: [b0, b1, b2, b3],
+                ..
+            } = &mut *state;
+            let mut event = pin!(event.read(b0));
+            let mut acl = pin!(acl.read(b1));
+            let mut sync = pin!(sync.read(b2));
+            let mut iso = pin!(iso.read(b3));
+            poll_fn(|cx| {
+                for k in 0..4 {
+                    let i = (start + k) % 4;
+                    let poll = match i {
+                        0 => event.as_mut().poll(cx),
+                        1 => acl.as_mut().poll(cx),
+                        2 => sync.as_mut().poll(cx),
+                        _ => iso.as_mut().poll(cx),
+                    };
+                    if let Poll::Ready(res) = poll {
+                        return Poll::Ready((i, res));
+                    }
+                }
+                Poll::Pending
+            })
+            .await
+        };
+        state.next = (i + 1) % 4;
+
+        let len = res.map_err(Error::Endpoint)?;
+        let kind = [
+            PacketKind::Event,
+            PacketKind::AclData,
+            PacketKind::SyncData,
+            PacketKind::IsoData,
+        ][i];
+        let mut data = state.bufs[i]
+            .get(..len)
+            .ok_or(Error::Read(ReadHciError::BufferTooSmall))?;
+        P::read_hci(kind, &mut data, rx).map_err(Error::Read)
+    }
+
+    async fn write<P: PacketToController>(&self, tx: &P) -> Result<(), Self::Error> {
+        let mut state = self.tx.lock().await;
+        let Tx {
+            cmd,
+            acl,
+            sync,
+            iso,
+            buf,
+        } = &mut *state;
+        let mut w = &mut buf[..];
+        tx.write_hci(&mut w).map_err(|_| Error::PacketTooLarge)?;
+        let len = N - w.len();
+        let data = &buf[..len];
+        match P::KIND {
+            PacketKind::Cmd => cmd.write(data).await,
+            PacketKind::AclData => acl.write(data).await,
+            PacketKind::SyncData => match sync {
+                Some(sync) => sync.write(data).await,
+                None => return Err(Error::Unsupported(P::KIND)),
+            },
+            PacketKind::IsoData => match iso {
+                Some(iso) => iso.write(data).await,
+                None => return Err(Error::Unsupported(P::KIND)),
+            },
+            PacketKind::Event => return Err(Error::Unsupported(P::KIND)),
+        }
+        .map_err(Error::Endpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::channel::Channel;
+    use futures_test::test;
+
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::cmd::SyncCmd;
+    use crate::controller::{Controller, ExternalController};
+    use crate::data::{AclBroadcastFlag, AclPacket, AclPacketBoundary, SyncPacket};
+    use crate::param::ConnHandle;
+    use crate::{ControllerToHostPacket, FromHciBytes};
+
+    type Packet = heapless::Vec<u8, 16>;
+
+    struct Pipe<'a>(&'a Channel<NoopRawMutex, Packet, 4>);
+
+    impl ErrorType for Pipe<'_> {
+        type Error = Infallible;
+    }
+
+    impl EndpointIn for Pipe<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let pkt = self.0.receive().await;
+            buf[..pkt.len()].copy_from_slice(&pkt);
+            Ok(pkt.len())
+        }
+    }
+
+    impl EndpointOut for Pipe<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+            self.0.send(unwrap!(Packet::from_slice(buf).ok())).await;
+            Ok(())
+        }
+    }
+
+    fn packet(data: &[u8]) -> Packet {
+        unwrap!(Packet::from_slice(data).ok())
+    }
+
+    const RESET_COMPLETE: [u8; 6] = [0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+    const ACL: [u8; 5] = [0x40, 0x20, 0x01, 0x00, 0xaa];
+
+    #[test]
+    async fn test_write_routing() {
+        let (cmd, acl, iso) = (Channel::new(), Channel::new(), Channel::new());
+        let (event, acl_in, iso_in) = (Channel::new(), Channel::new(), Channel::new());
+        let transport: UsbTransport<NoopRawMutex, _, _, _, _, _, _, _, _> =
+            UsbTransport::new(Pipe(&cmd), Pipe(&event), Pipe(&acl_in), Pipe(&acl)).with_iso(Pipe(&iso_in), Pipe(&iso));
+
+        Transport::write(&transport, &Reset::new()).await.unwrap();
+        let pkt = AclPacket::new(
+            ConnHandle::new(0x40),
+            AclPacketBoundary::FirstFlushable,
+            AclBroadcastFlag::PointToPoint,
+            &[0xaa],
+        );
+        Transport::write(&transport, &pkt).await.unwrap();
+        assert_eq!(cmd.try_receive().unwrap().as_slice(), [0x03, 0x0c, 0x00]);
+        assert_eq!(acl.try_receive().unwrap().as_slice(), ACL);
+        assert!(iso.try_receive().is_err());
+
+        let pkt = SyncPacket::from_hci_bytes_complete(&[0x40, 0x00, 0x01, 0xaa]).unwrap();
+        let res = Transport::write(&transport, &pkt).await;
+        assert!(matches!(res, Err(Error::Unsupported(PacketKind::SyncData))));
+    }
+
+    #[test]
+    async fn test_external_controller() {
+        let (cmd, acl) = (Channel::new(), Channel::new());
+        let (event, acl_in) = (Channel::new(), Channel::new());
+        let c: ExternalController<UsbTransport<NoopRawMutex, _, _, _, _, _, _, _, _>, 2> =
+            ExternalController::new(UsbTransport::new(Pipe(&cmd), Pipe(&event), Pipe(&acl_in), Pipe(&acl)));
+        event.try_send(packet(&RESET_COMPLETE)).unwrap();
+
+        let mut rx = [0; 259];
+        // Reading never ends, it only dispatches the Command Complete event to the command
+        let res = embassy_futures::select::select(Reset::new().exec(&c), Controller::read(&c, &mut rx)).await;
+        assert!(matches!(res, embassy_futures::select::Either::First(Ok(()))));
+        assert_eq!(cmd.try_receive().unwrap().as_slice(), [0x03, 0x0c, 0x00]);
+    }
+
+    #[test]
+    async fn test_read_fairness() {
+        let (cmd, acl) = (Channel::new(), Channel::new());
+        let (event, acl_in) = (Channel::new(), Channel::new());
+        let transport: UsbTransport<NoopRawMutex, _, _, _, _, _, _, _, _> =
+            UsbTransport::new(Pipe(&cmd), Pipe(&event), Pipe(&acl_in), Pipe(&acl));
+
+        for _ in 0..2 {
+            event.try_send(packet(&RESET_COMPLETE)).unwrap();
+            acl_in.try_send(packet(&ACL)).unwrap();
+        }
+        let mut rx = [0; 259];
+        for _ in 0..2 {
+            let pkt: ControllerToHostPacket = Transport::read(&transport, &mut rx).await.unwrap();
+            let ControllerToHostPacket::Event(pkt) = pkt else {
+                panic!("expected an event");
+            };
+            assert_eq!(pkt.data, &RESET_COMPLETE[2..]);
+            let pkt: ControllerToHostPacket = Transport::read(&transport, &mut rx).await.unwrap();
+            let ControllerToHostPacket::Acl(pkt) = pkt else {
+                panic!("expected ACL data");
+            };
+            assert_eq!(pkt.data(), [0xaa]);
+        }
+
+        acl_in.try_send(packet(&ACL[..3])).unwrap();
+        let res: Result<ControllerToHostPacket, _> = Transport::read(&transport, &mut rx).await;
+        assert!(matches!(res, Err(Error::Read(_))));
+    }
+}
@@ -0,0 +1,373 @@
+//! HCI transport over a Linux HCI user channel socket.
+//!
+//! Binding an `AF_BLUETOOTH`/`BTPROTO_HCI` socket to `HCI_CHANNEL_USER` gives a process exclusive access to a
+//! controller: the kernel Bluetooth stack gets out of the way and HCI packets are passed through unchanged. Every
+//! packet is a single datagram starting with its H4 packet indicator.
+//!
+//! The controller must be powered down before its user channel can be opened, e.g. with `btmgmt --index 0 power off`,
+//! and the process needs the `CAP_NET_ADMIN` capability. Virtual controllers created through the kernel's `hci_vhci`
+//! driver (`/dev/vhci`) can be used for testing without Bluetooth hardware.
+//!
+//! [`UserChannelTransport`] implements the blocking [`Transport`](super::blocking::Transport) trait. With the `tokio`
+//! feature, [`AsyncUserChannelTransport`] implements the async [`Transport`](super::Transport) trait.
+//!
+//! ```no_run
+//! use bt_hci::transport::user_channel::UserChannelTransport;
+//! use bt_hci::transport::blocking::Transport;
+//! use bt_hci::cmd::controller_baseband::Reset;
+//!
+//! // Open hci0
+//! let transport = UserChannelTransport::open(0).unwrap();
+//! transport.write(&Reset::new()).unwrap();
+//! ```
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use bt_hci_driver::{PacketKind, PacketToController, PacketToHost};
+use embedded_io::{ErrorType, ReadExactError};
+
+use super::{blocking, Error, WithIndicator};
+use crate::controller::blocking::TryError;
+use crate::ReadHciError;
+
+/// The largest packet passed through an HCI socket, including its packet indicator.
+///
+/// This is the kernel's `HCI_MAX_FRAME_SIZE`. Received packets which are larger fail with
+/// [`ReadHciError::BufferTooSmall`].
+pub const MAX_PACKET_LEN: usize = 1028;
+
+const BTPROTO_HCI: libc::c_int = 1;
+const HCI_CHANNEL_USER: u16 = 1;
+
+/// `struct sockaddr_hci` from the kernel's `include/net/bluetooth/hci_sock.h`.
+#[repr(C)]
+struct SockaddrHci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16,
+}
+
+/// Blocking HCI transport over a Linux HCI user channel socket.
+pub struct UserChannelTransport {
+    fd: OwnedFd,
+}
+
+impl UserChannelTransport {
+    /// Open the user channel of the controller with index `dev_id`, e.g. `0` for `hci0`.
+    pub fn open(dev_id: u16) -> io::Result<Self> {
+        Ok(Self::from_fd(open(dev_id, 0)?))
+    }
+
+    /// Create a transport from a socket preserving packet boundaries.
+    ///
+    /// This is usually an already bound HCI user channel socket, but any datagram or sequenced-packet socket carrying
+    /// one H4 packet per datagram works, e.g. one half of a `UnixDatagram` pair serving a stand-in controller.
+    pub fn from_fd(fd: OwnedFd) -> Self {
+        Self { fd }
+    }
+
+    /// Consume the transport, returning the socket.
+    pub fn into_inner(self) -> OwnedFd {
+        self.fd
+    }
+}
+
+impl ErrorType for UserChannelTransport {
+    type Error = Error<io::Error>;
+}
+
+impl blocking::Transport for UserChannelTransport {
+    fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, TryError<Self::Error>> {
+        let mut buf = [0; MAX_PACKET_LEN];
+        let len = recv(self.fd.as_raw_fd(), &mut buf).map_err(|e| TryError::Error(read_error(e)))?;
+        parse(&buf, len, rx).map_err(TryError::Error)
+    }
+
+    fn write<P: PacketToController>(&self, tx: &P) -> Result<(), TryError<Self::Error>> {
+        let mut buf = [0; MAX_PACKET_LEN];
+        let len = serialize(tx, &mut buf).map_err(TryError::Error)?;
+        send(self.fd.as_raw_fd(), &buf[..len]).map_err(|e| TryError::Error(Error::Write(e)))
+    }
+}
+
+#[cfg(any(test, feature = "tokio"))]
+pub use asynch::*;
+
+#[cfg(any(test, feature = "tokio"))]
+mod asynch {
+    use std::io;
+    use std::os::fd::{AsRawFd, OwnedFd};
+
+    use bt_hci_driver::{PacketToController, PacketToHost};
+    use embedded_io::ErrorType;
+    use tokio::io::unix::AsyncFd;
+
+    use super::{open, parse, read_error, recv, send, serialize, MAX_PACKET_LEN};
+    use crate::transport::{Error, Transport};
+
+    /// Async HCI transport over a Linux HCI user channel socket, driven by the `tokio` reactor.
+    pub struct AsyncUserChannelTransport {
+        fd: AsyncFd<OwnedFd>,
+    }
+
+    impl AsyncUserChannelTransport {
+        /// Open the user channel of the controller with index `dev_id`, e.g. `0` for `hci0`.
+        ///
+        /// This must be called from within a `tokio` runtime.
+        pub fn open(dev_id: u16) -> io::Result<Self> {
+            register(open(dev_id, libc::SOCK_NONBLOCK)?)
+        }
+
+        /// Create a transport from a socket preserving packet boundaries, see
+        /// [`UserChannelTransport::from_fd`](super::UserChannelTransport::from_fd).
+        ///
+        /// The socket is switched to non-blocking mode. This must be called from within a `tokio` runtime.
+        pub fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+            set_nonblocking(&fd)?;
+            register(fd)
+        }
+
+        /// Consume the transport, returning the socket.
+        pub fn into_inner(self) -> OwnedFd {
+            self.fd.into_inner()
+        }
+    }
+
+    impl ErrorType for AsyncUserChannelTransport {
+        type Error = Error<io::Error>;
+    }
+
+    impl Transport for AsyncUserChannelTransport {
+        async fn read<'a, P: PacketToHost<'a>>(&self, rx: &'a mut [u8]) -> Result<P, Self::Error> {
+            let mut buf = [0; MAX_PACKET_LEN];
+            let len = loop {
+                let mut guard = self.fd.readable().await.map_err(read_error)?;
+                if let Ok(res) = guard.try_io(|fd| recv(fd.as_raw_fd(), &mut buf)) {
+                    break res.map_err(read_error)?;
+                }
+            };
+            parse(&buf, len, rx)
+        }
+
+        async fn write<P: PacketToController>(&self, tx: &P) -> Result<(), Self::Error> {
+            let mut buf = [0; MAX_PACKET_LEN];
+            let len = serialize(tx, &mut buf)?;
+            loop {
+                let mut guard = self.fd.writable().await.map_err(Error::Write)?;
+                if let Ok(res) = guard.try_io(|fd| send(fd.as_raw_fd(), &buf[..len])) {
+                    return res.map_err(Error::Write);
+                }
+            }
+        }
+    }
+
+    fn register(fd: OwnedFd) -> io::Result<AsyncUserChannelTransport> {
+        // SAFETY: the `OwnedFd` keeps the descriptor open, and it is only closed when the `AsyncFd` is dropped or
+        // `into_inner` is called
+        let fd = unsafe { AsyncFd::register(fd)? };
+        Ok(AsyncUserChannelTransport { fd })
+    }
+
+    fn set_nonblocking(fd: &OwnedFd) -> io::Result<()> {
+        // SAFETY: `fd` is a valid descriptor for the duration of the calls
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: as above
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Create an HCI socket bound to the user channel of controller `dev_id`.
+fn open(dev_id: u16, flags: libc::c_int) -> io::Result<OwnedFd> {
+    // SAFETY: plain system call without pointer arguments
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_BLUETOOTH,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | flags,
+            BTPROTO_HCI,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a newly created descriptor which is not owned by anything else
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let addr = SockaddrHci {
+        hci_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+        hci_dev: dev_id,
+        hci_channel: HCI_CHANNEL_USER,
+    };
+    // SAFETY: `addr` is a valid `sockaddr_hci` of the given length
+    let res = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            (&addr as *const SockaddrHci).cast(),
+            core::mem::size_of::<SockaddrHci>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// Receive one datagram into `buf`, returning its full length even if it was truncated.
+fn recv(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        // SAFETY: `buf` is valid for writes of `buf.len()` bytes
+        let n = unsafe { libc::recv(fd, buf.as_mut_ptr().cast(), buf.len(), libc::MSG_TRUNC) };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Send `buf` as one datagram.
+fn send(fd: RawFd, buf: &[u8]) -> io::Result<()> {
+    loop {
+        // SAFETY: `buf` is valid for reads of `buf.len()` bytes
+        let n = unsafe { libc::send(fd, buf.as_ptr().cast(), buf.len(), 0) };
+        if n >= 0 {
+            return if n as usize == buf.len() {
+                Ok(())
+            } else {
+                Err(io::ErrorKind::WriteZero.into())
+            };
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Parse a received datagram of `len` bytes, of which the first `buf.len()` were received.
+fn parse<'a, P: PacketToHost<'a>>(buf: &[u8], len: usize, rx: &'a mut [u8]) -> Result<P, Error<io::Error>> {
+    if len > buf.len() {
+        return Err(Error::Read(ReadHciError::BufferTooSmall));
+    }
+    let mut data = &buf[..len];
+    // Reading from a byte slice cannot fail with an I/O error
+    PacketKind::read(&mut data)
+        .and_then(|kind| P::read_hci(kind, &mut data, rx))
+        .map_err(|e| {
+            Error::Read(match e {
+                ReadHciError::BufferTooSmall => ReadHciError::BufferTooSmall,
+                ReadHciError::InvalidValue => ReadHciError::InvalidValue,
+                ReadHciError::Read(ReadExactError::UnexpectedEof) => ReadHciError::Read(ReadExactError::UnexpectedEof),
+                ReadHciError::Read(ReadExactError::Other(e)) => match e {},
+            })
+        })
+}
+
+/// Serialize `tx` with its packet indicator into `buf`, returning the number of bytes written.
+fn serialize<P: PacketToController>(tx: &P, buf: &mut [u8]) -> Result<usize, Error<io::Error>> {
+    let mut w = &mut buf[..];
+    WithIndicator::new(tx)
+        .write_hci(&mut w)
+        .map_err(|_| Error::Write(io::Error::new(io::ErrorKind::InvalidInput, "packet too large")))?;
+    let remaining = w.len();
+    Ok(buf.len() - remaining)
+}
+
+fn read_error(e: io::Error) -> Error<io::Error> {
+    Error::Read(ReadHciError::Read(ReadExactError::Other(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+    use std::thread;
+
+    use super::*;
+    use crate::cmd::controller_baseband::Reset;
+    use crate::cmd::Cmd;
+    use crate::event::Event;
+    use crate::transport::Transport;
+    use crate::ControllerToHostPacket;
+
+    const RESET: [u8; 4] = [0x01, 0x03, 0x0c, 0x00];
+    const RESET_COMPLETE: [u8; 7] = [0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+
+    fn assert_reset_complete(packet: ControllerToHostPacket<'_>) {
+        let ControllerToHostPacket::Event(event) = packet else {
+            panic!("expected an event");
+        };
+        let Ok(Event::CommandComplete(complete)) = Event::try_from(event) else {
+            panic!("expected Command Complete");
+        };
+        assert_eq!(complete.cmd_opcode, Reset::OPCODE);
+    }
+
+    /// Stand-in controller answering a Reset command with an oversized packet, followed by its Command Complete event.
+    fn serve_reset(controller: UnixDatagram) {
+        let mut cmd = [0; 16];
+        let n = controller.recv(&mut cmd).unwrap();
+        assert_eq!(cmd[..n], RESET);
+        controller.send(&[0x02; MAX_PACKET_LEN + 1]).unwrap();
+        controller.send(&RESET_COMPLETE).unwrap();
+    }
+
+    #[test]
+    fn test_user_channel() {
+        let (controller, host) = UnixDatagram::pair().unwrap();
+        let controller = thread::spawn(move || serve_reset(controller));
+
+        let transport = UserChannelTransport::from_fd(host.into());
+        blocking::Transport::write(&transport, &Reset::new()).unwrap();
+        let mut rx = [0; 259];
+        let res: Result<ControllerToHostPacket, _> = blocking::Transport::read(&transport, &mut rx);
+        assert!(matches!(
+            res,
+            Err(TryError::Error(Error::Read(ReadHciError::BufferTooSmall)))
+        ));
+        // The next read starts at the next datagram
+        assert_reset_complete(blocking::Transport::read(&transport, &mut rx).unwrap());
+        controller.join().unwrap();
+    }
+
+    #[test]
+    fn test_user_channel_invalid_indicator() {
+        let (controller, host) = UnixDatagram::pair().unwrap();
+        let transport = UserChannelTransport::from_fd(host.into());
+        controller.send(&[0x00, 0x0e]).unwrap();
+        controller.send(&[]).unwrap();
+        let mut rx = [0; 259];
+        let res: Result<ControllerToHostPacket, _> = blocking::Transport::read(&transport, &mut rx);
+        assert!(matches!(
+            res,
+            Err(TryError::Error(Error::Read(ReadHciError::InvalidValue)))
+        ));
+        let res: Result<ControllerToHostPacket, _> = blocking::Transport::read(&transport, &mut rx);
+        assert!(matches!(
+            res,
+            Err(TryError::Error(Error::Read(ReadHciError::Read(
+                ReadExactError::UnexpectedEof
+            ))))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_async_user_channel() {
+        let (controller, host) = UnixDatagram::pair().unwrap();
+        let controller = thread::spawn(move || serve_reset(controller));
+
+        let transport = AsyncUserChannelTransport::from_fd(host.into()).unwrap();
+        Transport::write(&transport, &Reset::new()).await.unwrap();
+        let mut rx = [0; 259];
+        let res: Result<ControllerToHostPacket, _> = Transport::read(&transport, &mut rx).await;
+        assert!(matches!(res, Err(Error::Read(ReadHciError::BufferTooSmall))));
+        assert_reset_complete(Transport::read(&transport, &mut rx).await.unwrap());
+        controller.join().unwrap();
+    }
+}
@@ -0,0 +1,342 @@
+//! Vendor-specific commands and events.
+//!
+//! Vendor commands use the [`VENDOR_SPECIFIC`](crate::cmd::OpcodeGroup::VENDOR_SPECIFIC) opcode group and are declared
+//! with the same [`cmd!`](crate::cmd!) macro as the standard commands. They implement [`SyncCmd`](crate::cmd::SyncCmd)
+//! or [`AsyncCmd`](crate::cmd::AsyncCmd), so they can be executed on any controller, e.g.
+//! [`ExternalController`](crate::controller::ExternalController).
+//!
+//! Vendor events are all delivered as a [`Vendor`] event, whose parameters usually start with a sub-event code. The
+//! [`vendor_events!`](crate::vendor_events!) macro declares the events of a vendor together with an enum decoding a
+//! [`Vendor`] event into the right one.
+//!
+//! ```
+//! use bt_hci::param::RemainingBytes;
+//!
+//! bt_hci::cmd! {
+//!     /// Read the firmware version of an imaginary controller.
+//!     ReadFwVersion(VENDOR_SPECIFIC, 0x0001) {
+//!         Params = ();
+//!         ReadFwVersionReturn {
+//!             major: u8,
+//!             minor: u8,
+//!         }
+//!     }
+//! }
+//!
+//! bt_hci::vendor_events! {
+//!     /// Events of an imaginary controller.
+//!     pub enum ExampleEvent: u8 {
+//!         /// The firmware crashed.
+//!         struct FatalError<'a>(0x02) {
+//!             pc: u32,
+//!             data: RemainingBytes<'a>,
+//!         }
+//!     }
+//! }
+//!
+//! let vendor = bt_hci::event::Vendor {
+//!     params: RemainingBytes::new(&[0x02, 0x78, 0x56, 0x34, 0x12, 0xaa]),
+//! };
+//! let ExampleEvent::FatalError(e) = ExampleEvent::try_from(&vendor).unwrap() else {
+//!     panic!("unexpected event");
+//! };
+//! assert_eq!(e.pc, 0x1234_5678);
+//! ```
+
+use crate::event::Vendor;
+use crate::{FromHciBytes, FromHciBytesError};
+
+#[cfg(any(test, feature = "bluenrg"))]
+pub mod bluenrg;
+#[cfg(any(test, feature = "cypress"))]
+pub mod cypress;
+#[cfg(any(test, feature = "espressif"))]
+pub mod espressif;
+#[cfg(any(test, feature = "realtek"))]
+pub mod realtek;
+#[cfg(any(test, feature = "zephyr"))]
+pub mod zephyr;
+
+/// Assert that a vendor command serializes to `expected` (opcode, length and parameters)
+#[cfg(test)]
+pub(crate) fn assert_encodes<C: crate::WriteHci>(cmd: &C, expected: &[u8]) {
+    let mut buf = [0; 258];
+    cmd.write_hci(&mut buf[..]).unwrap();
+    assert_eq!(&buf[..cmd.size()], expected);
+}
+
+/// A trait for objects which contain the parameters for a specific vendor event
+pub trait VendorEventParams<'a>: FromHciBytes<'a> {
+    /// The type of the sub-event code at the start of the [`Vendor`] event parameters, e.g. `u8` or `u16`
+    type Code: Copy + PartialEq + FromHciBytes<'a>;
+
+    /// The sub-event code these parameters are for
+    const SUBEVENT_CODE: Self::Code;
+}
+
+impl<'a> Vendor<'a> {
+    /// Decode the parameters of this event as `P`.
+    ///
+    /// Returns `None` if the event has a different sub-event code.
+    pub fn decode<P: VendorEventParams<'a>>(&self) -> Option<Result<P, FromHciBytesError>> {
+        let (code, data) = P::Code::from_hci_bytes(self.params.into_inner()).ok()?;
+        (code == P::SUBEVENT_CODE).then(|| P::from_hci_bytes_complete(data))
+    }
+}
+
+/// Declare the events of a vendor, dispatched by the sub-event code at the start of the [`Vendor`] event parameters.
+///
+/// This generates a struct for every event, implementing [`VendorEventParams`], and an enum with a variant per event
+/// and an `Unknown` variant for sub-event codes that are not declared. The enum can be decoded from a [`Vendor`] event
+/// with `TryFrom`. See the [module documentation](crate::vendor) for an example.
+#[macro_export]
+macro_rules! vendor_events {
+    (
+        $(#[$enum_attrs:meta])*
+        $vis:vis enum $enum:ident: $code_ty:ty {
+            $(
+                $(#[$attrs:meta])*
+                struct $name:ident$(<$life:lifetime>)?($code:expr) {
+                    $($field:ident: $ty:ty),*
+                    $(,)?
+                }
+            )+
+        }
+    ) => {
+        $(#[$enum_attrs])*
+        #[non_exhaustive]
+        #[derive(Debug, Clone, Hash)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+        $vis enum $enum<'a> {
+            $(
+                #[doc = stringify!($name)]
+                $name($name$(<$life>)?),
+            )+
+            /// An event with an unknown sub-event code
+            Unknown {
+                /// The sub-event code
+                code: $code_ty,
+                /// The bytes of the event parameters following the sub-event code
+                params: &'a [u8],
+            },
+        }
+
+        impl $enum<'_> {
+            /// The sub-event code of this event.
+            pub fn code(&self) -> $code_ty {
+                match self {
+                    $(Self::$name(_) => $code,)+
+                    Self::Unknown { code, .. } => *code,
+                }
+            }
+        }
+
+        impl<'a> $crate::FromHciBytes<'a> for $enum<'a> {
+            fn from_hci_bytes(data: &'a [u8]) -> Result<(Self, &'a [u8]), $crate::FromHciBytesError> {
+                let (code, data) = <$code_ty as $crate::FromHciBytes>::from_hci_bytes(data)?;
+                match code {
+                    $(
+                        $code => <$name as $crate::FromHciBytes>::from_hci_bytes(data)
+                            .map(|(x, y)| (Self::$name(x), y)),
+                    )+
+                    _ => Ok((Self::Unknown { code, params: data }, &[])),
+                }
+            }
+        }
+
+        impl<'a> TryFrom<&$crate::event::Vendor<'a>> for $enum<'a> {
+            type Error = $crate::FromHciBytesError;
+
+            fn try_from(event: &$crate::event::Vendor<'a>) -> Result<Self, Self::Error> {
+                <Self as $crate::FromHciBytes>::from_hci_bytes_complete(event.params.into_inner())
+            }
+        }
+
+        impl $crate::WriteHci for $enum<'_> {
+            #[inline(always)]
+            fn size(&self) -> usize {
+                $crate::WriteHci::size(&self.code())
+                    + match self {
+                        $(Self::$name(x) => $crate::WriteHci::size(x),)+
+                        Self::Unknown { params, .. } => params.len(),
+                    }
+            }
+
+            fn write_hci<W: ::embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                $crate::WriteHci::write_hci(&self.code(), &mut writer)?;
+                match self {
+                    $(Self::$name(x) => $crate::WriteHci::write_hci(x, writer),)+
+                    Self::Unknown { params, .. } => writer.write_all(params),
+                }
+            }
+
+            async fn write_hci_async<W: ::embedded_io_async::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                $crate::WriteHci::write_hci_async(&self.code(), &mut writer).await?;
+                match self {
+                    $(Self::$name(x) => $crate::WriteHci::write_hci_async(x, writer).await,)+
+                    Self::Unknown { params, .. } => writer.write_all(params).await,
+                }
+            }
+        }
+
+        $(
+            $(#[$attrs])*
+            #[derive(Debug, Clone, Hash)]
+            #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+            pub struct $name$(<$life>)? {
+                $(
+                    #[doc = stringify!($field)]
+                    pub $field: $ty,
+                )*
+            }
+
+            #[automatically_derived]
+            impl<'a> $crate::FromHciBytes<'a> for $name$(<$life>)? {
+                #[allow(unused_variables)]
+                fn from_hci_bytes(data: &'a [u8]) -> Result<(Self, &'a [u8]), $crate::FromHciBytesError> {
+                    $(
+                        let ($field, data) = <$ty as $crate::FromHciBytes>::from_hci_bytes(data)?;
+                    )*
+                    Ok((Self {
+                        $($field,)*
+                    }, data))
+                }
+            }
+
+            #[automatically_derived]
+            impl<'a> $crate::vendor::VendorEventParams<'a> for $name$(<$life>)? {
+                type Code = $code_ty;
+                const SUBEVENT_CODE: $code_ty = $code;
+            }
+
+            #[automatically_derived]
+            #[allow(unused_mut, unused_variables)]
+            impl$(<$life>)? $crate::WriteHci for $name$(<$life>)? {
+                #[inline(always)]
+                fn size(&self) -> usize {
+                    $(<$ty as $crate::WriteHci>::size(&self.$field) +)* 0
+                }
+
+                fn write_hci<W: ::embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                    $(<$ty as $crate::WriteHci>::write_hci(&self.$field, &mut writer)?;)*
+                    Ok(())
+                }
+
+                async fn write_hci_async<W: ::embedded_io_async::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+                    $(<$ty as $crate::WriteHci>::write_hci_async(&self.$field, &mut writer).await?;)*
+                    Ok(())
+                }
+            }
+        )+
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::{Opcode, OpcodeGroup, SyncCmd};
+    use crate::controller::{Controller, ExternalController};
+    use crate::mock::{MockTransport, Step};
+    use crate::param::{BdAddr, RemainingBytes};
+    use crate::{cmd, WriteHci};
+
+    cmd! {
+        /// Write BD_ADDR
+        WriteBdAddr(VENDOR_SPECIFIC, 0x0001) {
+            Params = BdAddr;
+        }
+    }
+
+    cmd! {
+        /// Read firmware version
+        ReadFwVersion(VENDOR_SPECIFIC, 0x0002) {
+            Params = ();
+            ReadFwVersionReturn {
+                major: u8,
+                minor: u16,
+            }
+        }
+    }
+
+    crate::vendor_events! {
+        /// Test events
+        pub enum TestEvent: u16 {
+            /// Fatal error
+            struct FatalError<'a>(0x0001) {
+                pc: u32,
+                data: RemainingBytes<'a>,
+            }
+            /// Ready
+            struct Ready(0x0102) {
+                reason: u8,
+            }
+        }
+    }
+
+    #[test]
+    fn test_vendor_cmd_opcode() {
+        assert_eq!(
+            <ReadFwVersion as crate::cmd::Cmd>::OPCODE,
+            Opcode::new(OpcodeGroup::VENDOR_SPECIFIC, 0x0002)
+        );
+        let mut buf = [0; 9];
+        WriteBdAddr::new(BdAddr::new([1, 2, 3, 4, 5, 6]))
+            .write_hci(&mut buf[..])
+            .unwrap();
+        assert_eq!(buf, [0x01, 0xfc, 6, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[futures_test::test]
+    async fn test_vendor_cmd_exec() {
+        let script = [
+            Step::Write(&[0x01, 0x02, 0xfc, 0x00]),
+            Step::Read(&[0x04, 0x0e, 0x07, 0x01, 0x02, 0xfc, 0x00, 3, 0x01, 0x02]),
+        ];
+        let c: ExternalController<MockTransport, 4> = ExternalController::new(MockTransport::new(&script));
+        let mut buf = c.alloc_buf().unwrap();
+        let (res, _) = embassy_futures::join::join(ReadFwVersion::new().exec(&c), c.read(&mut buf)).await;
+        let ret = res.unwrap();
+        assert_eq!((ret.major, ret.minor), (3, 0x0201));
+    }
+
+    #[test]
+    fn test_vendor_events() {
+        let data = [0x01, 0x00, 0x78, 0x56, 0x34, 0x12, 0xaa, 0xbb];
+        let vendor = Vendor {
+            params: RemainingBytes::new(&data),
+        };
+        let TestEvent::FatalError(e) = TestEvent::try_from(&vendor).unwrap() else {
+            panic!("expected a fatal error");
+        };
+        assert_eq!(e.pc, 0x1234_5678);
+        assert_eq!(&e.data[..], [0xaa, 0xbb]);
+        assert!(vendor.decode::<Ready>().is_none());
+        assert_eq!(vendor.decode::<FatalError>().unwrap().unwrap().pc, 0x1234_5678);
+
+        let mut out = [0; 8];
+        TestEvent::FatalError(e).write_hci(&mut out[..]).unwrap();
+        assert_eq!(out, data);
+
+        let vendor = Vendor {
+            params: RemainingBytes::new(&[0x02, 0x01, 0x05]),
+        };
+        assert!(vendor.decode::<Ready>().unwrap().is_ok());
+        assert!(matches!(
+            TestEvent::try_from(&vendor),
+            Ok(TestEvent::Ready(Ready { reason: 5 }))
+        ));
+        assert!(matches!(
+            TestEvent::try_from(&Vendor {
+                params: RemainingBytes::new(&[0x03, 0x00, 0x05]),
+            }),
+            Ok(TestEvent::Unknown { code: 3, params: [5] })
+        ));
+        assert_eq!(
+            TestEvent::try_from(&Vendor {
+                params: RemainingBytes::new(&[0x01]),
+            })
+            .unwrap_err(),
+            FromHciBytesError::InvalidSize
+        );
+    }
+}
@@ -16,5 +16,17 @@ cargo clippy --features log
 cargo clippy --features log,embassy-time
 
 cargo clippy --features serde
+cargo clippy --features mock
+cargo clippy --features btsnoop
+cargo clippy --features pcap
+cargo clippy --features std
+cargo clippy --features tokio
+cargo clippy --features spi
+cargo clippy --features bluenrg
+cargo clippy --features cypress
+cargo clippy --features espressif
+cargo clippy --features realtek
+cargo clippy --features zephyr
 
 cargo test --features embassy-time,serde
+cargo test --features mock --doc